@@ -0,0 +1,57 @@
+//! Records the git commit and build time for `GET /version`.
+
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rustc-env=GIT_COMMIT={}", git_commit());
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp());
+
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    // HEAD usually names a branch, whose ref file changes on each commit
+    let head = fs::read_to_string(".git/HEAD").unwrap_or_default();
+    if let Some(reference) = head.trim().strip_prefix("ref: ") {
+        println!("cargo:rerun-if-changed=.git/{}", reference);
+    }
+}
+
+/// `GIT_COMMIT` if set, as when building outside a checkout, otherwise the checked out
+/// commit with `-dirty` appended if the tree has uncommitted changes. Empty if unknown.
+fn git_commit() -> String {
+    if let Ok(commit) = env::var("GIT_COMMIT") {
+        return commit.trim().to_string();
+    }
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+    match git(&["rev-parse", "HEAD"]) {
+        Some(commit) if git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty()) => {
+            format!("{}-dirty", commit)
+        }
+        Some(commit) => commit,
+        None => String::new(),
+    }
+}
+
+/// Unix seconds from `SOURCE_DATE_EPOCH` for reproducible builds, otherwise now.
+fn build_timestamp() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        })
+}
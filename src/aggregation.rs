@@ -0,0 +1,242 @@
+//! Ways of reducing a sample of priority fees to a single fee.
+
+use crate::sources::Sample;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_TRIM_PERCENT: f64 = 5.0;
+pub const DEFAULT_HALF_LIFE_SLOTS: f64 = 50.0;
+
+/// The standard normal quantile for a two-sided 95% interval.
+const Z_95: f64 = 1.96;
+
+/// How a fee is read off a sample at a given percentile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    /// The plain nearest-rank percentile.
+    Percentile,
+    /// The mean after dropping the given percentage of samples from each tail. Ignores
+    /// the requested percentile, except for fee levels, which are percentiles of the
+    /// trimmed sample.
+    TrimmedMean(f64),
+    /// The percentile after clamping the given percentage of each tail to the value at
+    /// its boundary, so percentiles inside the tails read the boundary value.
+    Winsorized(f64),
+    /// The percentile with each sample weighted by the compute units it consumed, so
+    /// a flood of cheap transactions counts for less than a few heavy ones. Samples
+    /// without a CU count get no weight, unless none has one.
+    CuWeighted,
+    /// The percentile with each sample's weight halving every given number of slots
+    /// before the newest sample's, so a spike in the last few slots moves the fee even
+    /// though most of the window predates it. Samples without a slot get no weight,
+    /// unless none has one.
+    RecencyWeighted(f64),
+    /// The median across slots of each slot's own percentile, so one busy slot counts
+    /// no more than a quiet one. Samples without a slot are left out, unless none has
+    /// one.
+    PerSlot,
+    /// The percentile across slots of each slot's highest fee, the way
+    /// getRecentPrioritizationFees reports one fee per slot.
+    PerSlotMax,
+}
+
+impl Aggregation {
+    /// The fee at percentile `p` (0-100) of `fees`, which must be sorted by price.
+    pub fn fee_at(&self, fees: &[Sample], p: f64) -> u64 {
+        if fees.is_empty() {
+            return 0;
+        }
+        match *self {
+            Aggregation::Percentile => percentile(fees, p),
+            Aggregation::TrimmedMean(t) => {
+                let trimmed = trim(fees, t);
+                let sum: u128 = trimmed.iter().map(|s| s.price as u128).sum();
+                (sum / trimmed.len() as u128) as u64
+            }
+            Aggregation::Winsorized(t) => percentile(fees, p.clamp(t, 100.0 - t)),
+            Aggregation::CuWeighted => {
+                weighted_percentile(fees, p, |s| s.compute_units.unwrap_or(0) as f64)
+            }
+            Aggregation::RecencyWeighted(half_life) => {
+                let Some(newest) = fees.iter().filter_map(|s| s.slot).max() else {
+                    return percentile(fees, p);
+                };
+                weighted_percentile(fees, p, |s| match s.slot {
+                    Some(slot) => 0.5f64.powf((newest - slot) as f64 / half_life),
+                    None => 0.0,
+                })
+            }
+            Aggregation::PerSlot => percentile(&per_slot(fees, |slot| percentile(slot, p)), 50.0),
+            Aggregation::PerSlotMax => {
+                percentile(&per_slot(fees, |slot| slot[slot.len() - 1].price), p)
+            }
+        }
+    }
+
+    /// The fee at percentile `p` for fee levels, which need to vary with `p`.
+    pub fn level_at(&self, fees: &[Sample], p: f64) -> u64 {
+        match *self {
+            Aggregation::TrimmedMean(t) => percentile(trim(fees, t), p),
+            _ => self.fee_at(fees, p),
+        }
+    }
+
+    /// An approximate 95% confidence interval around `fee_at(fees, p)`. Percentiles are
+    /// bounded by the order statistics whose ranks lie 1.96 binomial standard deviations
+    /// either side of `p`; the trimmed mean by 1.96 standard errors from the winsorized
+    /// variance. Narrows as the sample grows.
+    pub fn interval_at(&self, fees: &[Sample], p: f64) -> (u64, u64) {
+        if fees.is_empty() {
+            return (0, 0);
+        }
+        match *self {
+            Aggregation::TrimmedMean(t) => trimmed_mean_interval(fees, t),
+            Aggregation::PerSlot => {
+                let slots = per_slot(fees, |slot| percentile(slot, p));
+                Aggregation::Percentile.interval_at(&slots, 50.0)
+            }
+            Aggregation::PerSlotMax => {
+                let slots = per_slot(fees, |slot| slot[slot.len() - 1].price);
+                Aggregation::Percentile.interval_at(&slots, p)
+            }
+            _ => {
+                let n = fees.len() as f64;
+                let q = p.clamp(0.0, 100.0) / 100.0;
+                let spread = Z_95 * (n * q * (1.0 - q)).sqrt();
+                let lo = ((n * q - spread).floor() / n * 100.0).clamp(0.0, 100.0);
+                let hi = ((n * q + spread).ceil() / n * 100.0).clamp(0.0, 100.0);
+                (self.fee_at(fees, lo), self.fee_at(fees, hi))
+            }
+        }
+    }
+}
+
+fn percentile(fees: &[Sample], p: f64) -> u64 {
+    if fees.is_empty() {
+        return 0;
+    }
+    let idx = ((fees.len() as f64) * p / 100.0) as usize;
+    fees[idx.min(fees.len() - 1)].price
+}
+
+/// The fee at percentile `p` (0-100) of `fees`, sorted by price, counting each sample
+/// `weight` times. Falls back to the plain percentile if every weight is zero.
+pub fn weighted_percentile(fees: &[Sample], p: f64, weight: impl Fn(&Sample) -> f64) -> u64 {
+    let total: f64 = fees.iter().map(&weight).sum();
+    if total <= 0.0 {
+        return percentile(fees, p);
+    }
+    let target = total * p / 100.0;
+    let mut cumulative = 0.0;
+    for s in fees {
+        cumulative += weight(s);
+        if cumulative > target {
+            return s.price;
+        }
+    }
+    fees[fees.len() - 1].price
+}
+
+/// One sample per slot in `fees`, sorted by price, priced at `fee` of that slot's
+/// samples (themselves sorted by price). `fees` as is if no sample has a slot.
+fn per_slot(fees: &[Sample], fee: impl Fn(&[Sample]) -> u64) -> Vec<Sample> {
+    let mut slots: BTreeMap<u64, Vec<Sample>> = BTreeMap::new();
+    for s in fees {
+        if let Some(slot) = s.slot {
+            slots.entry(slot).or_default().push(s.clone());
+        }
+    }
+    if slots.is_empty() {
+        return fees.to_vec();
+    }
+    let mut per_slot: Vec<Sample> = slots
+        .into_values()
+        .map(|samples| Sample {
+            price: fee(&samples),
+            ..samples[0].clone()
+        })
+        .collect();
+    per_slot.sort_by_key(|s| s.price);
+    per_slot
+}
+
+/// The mean of `trim(fees, t)` give or take 1.96 of its standard errors, estimated from
+/// the variance of `fees` winsorized at the same tails (Tukey and McLaughlin).
+fn trimmed_mean_interval(fees: &[Sample], t: f64) -> (u64, u64) {
+    let trimmed = trim(fees, t);
+    let (low, high) = (trimmed[0].price, trimmed[trimmed.len() - 1].price);
+    let n = fees.len() as f64;
+    let winsorized = || fees.iter().map(|s| s.price.clamp(low, high) as f64);
+    let mean = winsorized().sum::<f64>() / n;
+    let variance = winsorized().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+    let error = variance.sqrt() / (trimmed.len() as f64 / n) / n.sqrt();
+    let center = trimmed.iter().map(|s| s.price as f64).sum::<f64>() / trimmed.len() as f64;
+    (
+        (center - Z_95 * error).max(0.0) as u64,
+        (center + Z_95 * error).ceil() as u64,
+    )
+}
+
+/// `fees` without `t` percent of samples at each end; never empty if `fees` isn't.
+fn trim(fees: &[Sample], t: f64) -> &[Sample] {
+    let n = ((fees.len() as f64) * t / 100.0) as usize;
+    if 2 * n >= fees.len() {
+        let mid = fees.len() / 2;
+        return &fees[mid..fees.len().min(mid + 1)];
+    }
+    &fees[n..fees.len() - n]
+}
+
+/// Parses `percentile`, `trimmed_mean[:pct]`, `winsorized[:pct]`, `cu_weighted`,
+/// `recency_weighted[:slots]`, `per_slot` or `per_slot_max`.
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Aggregation, String> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let trim_percent = || match arg {
+            Some(v) => match v.parse::<f64>() {
+                Ok(t) if (0.0..50.0).contains(&t) => Ok(t),
+                _ => Err(format!("trim percentage must be in [0, 50): {}", v)),
+            },
+            None => Ok(DEFAULT_TRIM_PERCENT),
+        };
+        match (name, arg) {
+            ("percentile", None) => Ok(Aggregation::Percentile),
+            ("trimmed_mean", _) => Ok(Aggregation::TrimmedMean(trim_percent()?)),
+            ("winsorized", _) => Ok(Aggregation::Winsorized(trim_percent()?)),
+            ("cu_weighted", None) => Ok(Aggregation::CuWeighted),
+            ("recency_weighted", _) => match arg {
+                Some(v) => match v.parse::<f64>() {
+                    Ok(h) if h > 0.0 && h.is_finite() => Ok(Aggregation::RecencyWeighted(h)),
+                    _ => Err(format!(
+                        "half-life must be a positive number of slots: {}",
+                        v
+                    )),
+                },
+                None => Ok(Aggregation::RecencyWeighted(DEFAULT_HALF_LIFE_SLOTS)),
+            },
+            ("per_slot", None) => Ok(Aggregation::PerSlot),
+            ("per_slot_max", None) => Ok(Aggregation::PerSlotMax),
+            _ => Err(format!("unknown aggregation: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Aggregation::Percentile => write!(f, "percentile"),
+            Aggregation::TrimmedMean(t) => write!(f, "trimmed_mean:{}", t),
+            Aggregation::Winsorized(t) => write!(f, "winsorized:{}", t),
+            Aggregation::CuWeighted => write!(f, "cu_weighted"),
+            Aggregation::RecencyWeighted(h) => write!(f, "recency_weighted:{}", h),
+            Aggregation::PerSlot => write!(f, "per_slot"),
+            Aggregation::PerSlotMax => write!(f, "per_slot_max"),
+        }
+    }
+}
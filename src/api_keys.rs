@@ -0,0 +1,134 @@
+//! API keys for the binary's clients, with optional daily quotas, and per-key usage.
+
+use crate::estimator::unix_now;
+use crate::logging::utc_date;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Days of usage kept per key, including today.
+pub const USAGE_DAYS: u64 = 31;
+
+/// An API key as configured: `key[;name=N][;quota=N][;admin]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiKey {
+    pub key: String,
+    pub name: Option<String>,     // how usage reports refer to the key
+    pub daily_quota: Option<u64>, // requests per UTC day, if limited
+    pub admin: bool,              // may call `/admin/*` endpoints
+}
+
+impl ApiKey {
+    /// Parse `key[;name=N][;quota=N][;admin]`, e.g. `3f9c...;name=acme;quota=100000`.
+    pub fn parse(s: &str) -> Result<ApiKey, String> {
+        let mut parts = s.split(';').map(str::trim);
+        let key = parts.next().unwrap_or("");
+        if key.is_empty() || !key.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err("Invalid API key: keys must be printable ASCII".to_string());
+        }
+        let mut api_key = ApiKey {
+            key: key.to_string(),
+            name: None,
+            daily_quota: None,
+            admin: false,
+        };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("name", v)) if !v.is_empty() => api_key.name = Some(v.to_string()),
+                Some(("quota", v)) => match v.parse::<u64>() {
+                    Ok(n) if n > 0 => api_key.daily_quota = Some(n),
+                    _ => return Err(format!("Invalid quota for API key {}", api_key.label())),
+                },
+                None if part == "admin" => api_key.admin = true,
+                _ => {
+                    return Err(format!(
+                        "Unknown option '{}' for API key {}",
+                        part,
+                        api_key.label()
+                    ));
+                }
+            }
+        }
+        Ok(api_key)
+    }
+
+    /// The key's name, or the start of the key if it has none.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}...", self.key.chars().take(4).collect::<String>()),
+        }
+    }
+}
+
+/// The key in `keys` matching `presented`. Every key is compared in constant time, so
+/// response times don't reveal how much of a key was guessed right.
+pub fn find<'a>(keys: &'a [ApiKey], presented: &str) -> Option<&'a ApiKey> {
+    keys.iter().fold(None, |found, key| {
+        if constant_time_eq(key.key.as_bytes(), presented.as_bytes()) {
+            Some(key)
+        } else {
+            found
+        }
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Requests made with each key, per UTC day, since the process started.
+#[derive(Default)]
+pub struct Usage {
+    by_key: Mutex<HashMap<String, BTreeMap<u64, u64>>>, // key -> day number -> requests
+}
+
+/// One key's usage, as `Usage::report` lists it.
+pub struct KeyUsage {
+    pub key: ApiKey,
+    pub today: u64,
+    pub remaining: Option<u64>,   // of today's quota
+    pub days: Vec<(String, u64)>, // `YYYY-MM-DD` and requests, oldest first
+}
+
+impl Usage {
+    /// Count a request made with `key`, unless it has used up today's quota. On refusal,
+    /// returns the seconds until the quota resets at midnight UTC.
+    pub fn record(&self, key: &ApiKey) -> Result<(), u64> {
+        let now = unix_now();
+        let today = now / SECS_PER_DAY;
+        let mut by_key = self.by_key.lock().unwrap();
+        let days = by_key.entry(key.key.clone()).or_default();
+        let count = days.entry(today).or_insert(0);
+        if key.daily_quota.is_some_and(|quota| *count >= quota) {
+            return Err((today + 1) * SECS_PER_DAY - now);
+        }
+        *count += 1;
+        days.retain(|&day, _| day + USAGE_DAYS > today);
+        Ok(())
+    }
+
+    /// Usage of each of `keys`, in order; keys no longer configured aren't listed.
+    pub fn report(&self, keys: &[ApiKey]) -> Vec<KeyUsage> {
+        let today = unix_now() / SECS_PER_DAY;
+        let by_key = self.by_key.lock().unwrap();
+        keys.iter()
+            .map(|key| {
+                let days = by_key.get(&key.key);
+                let used = days.and_then(|d| d.get(&today)).copied().unwrap_or(0);
+                KeyUsage {
+                    key: key.clone(),
+                    today: used,
+                    remaining: key.daily_quota.map(|q| q.saturating_sub(used)),
+                    days: days
+                        .into_iter()
+                        .flatten()
+                        .filter(|&(&day, _)| day + USAGE_DAYS > today)
+                        .map(|(&day, &n)| (utc_date(day * SECS_PER_DAY), n))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,196 @@
+//! Decoding of ComputeBudget program instructions.
+
+use crate::rpc::Message;
+
+pub const COMPUTE_BUDGET_PROGRAM: &str = "ComputeBudget111111111111111111111111111111";
+
+const SET_COMPUTE_UNIT_LIMIT: u8 = 2;
+const SET_COMPUTE_UNIT_PRICE: u8 = 3;
+
+/// The compute budget a transaction requested. A transaction without a
+/// SetComputeUnitPrice instruction pays no priority fee.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ComputeBudget {
+    pub unit_price: Option<u64>, // micro-lamports per CU
+    pub unit_limit: Option<u32>,
+}
+
+impl ComputeBudget {
+    /// Read the ComputeBudget instructions of a message fetched from the RPC.
+    /// Returns `None` if an instruction can't be decoded.
+    pub fn from_message(message: &Message) -> Option<ComputeBudget> {
+        let mut budget = ComputeBudget::default();
+        for ix in &message.instructions {
+            if message
+                .account_keys
+                .get(ix.program_id_index)
+                .map(String::as_str)
+                != Some(COMPUTE_BUDGET_PROGRAM)
+            {
+                continue;
+            }
+            budget.apply(&ix.data.bytes()?)?;
+        }
+        Some(budget)
+    }
+
+    /// Apply one ComputeBudget instruction's data. Unrelated instructions are ignored.
+    pub fn apply(&mut self, data: &[u8]) -> Option<()> {
+        match *data.first()? {
+            SET_COMPUTE_UNIT_LIMIT => {
+                self.unit_limit = Some(u32::from_le_bytes(data.get(1..5)?.try_into().ok()?));
+            }
+            SET_COMPUTE_UNIT_PRICE => {
+                self.unit_price = Some(u64::from_le_bytes(data.get(1..9)?.try_into().ok()?));
+            }
+            _ => {}
+        }
+        Some(())
+    }
+}
+
+const BS58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Decode a base58 (Bitcoin alphabet) string, or `None` if it contains invalid characters.
+pub fn bs58_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(s.len());
+    for c in s.bytes() {
+        let mut carry = BS58_ALPHABET.iter().position(|&a| a == c)? as u32;
+        for b in bytes.iter_mut() {
+            carry += (*b as u32) * 58;
+            *b = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    // Each leading '1' encodes a leading zero byte
+    let zeros = s.bytes().take_while(|&c| c == b'1').count();
+    bytes.extend(std::iter::repeat_n(0, zeros));
+    bytes.reverse();
+    Some(bytes)
+}
+
+/// Encode bytes as base58 (Bitcoin alphabet).
+pub fn bs58_encode(bytes: &[u8]) -> String {
+    let mut digits: Vec<u8> = Vec::with_capacity(bytes.len() * 138 / 100 + 1);
+    for &b in bytes {
+        let mut carry = b as u32;
+        for d in digits.iter_mut() {
+            carry += (*d as u32) << 8;
+            *d = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    // Each leading zero byte encodes as a leading '1'
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|&d| BS58_ALPHABET[d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_unit_limits_and_prices_are_read() {
+        let mut budget = ComputeBudget::default();
+        // SetComputeUnitLimit(200_000): tag 2, then a little-endian u32
+        budget.apply(&[2, 0x40, 0x0d, 0x03, 0x00]).unwrap();
+        assert_eq!(budget.unit_limit, Some(200_000));
+        // SetComputeUnitPrice(10_000): tag 3, then a little-endian u64
+        budget.apply(&[3, 0x10, 0x27, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(budget.unit_price, Some(10_000));
+        budget
+            .apply(&[3, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
+            .unwrap();
+        assert_eq!(budget.unit_price, Some(u64::MAX));
+
+        // RequestHeapFrame and SetLoadedAccountsDataSizeLimit change neither
+        budget.apply(&[1, 0, 0, 1, 0]).unwrap();
+        budget.apply(&[4, 0, 0, 1, 0]).unwrap();
+        assert_eq!(
+            budget,
+            ComputeBudget {
+                unit_price: Some(u64::MAX),
+                unit_limit: Some(200_000),
+            }
+        );
+    }
+
+    #[test]
+    fn short_instructions_are_rejected() {
+        let mut budget = ComputeBudget::default();
+        for data in [
+            &[][..],
+            &[2, 0x40, 0x0d, 0x03],
+            &[3, 0x10, 0x27, 0, 0, 0, 0, 0],
+        ] {
+            assert_eq!(budget.apply(data), None, "{:?}", data);
+        }
+        assert_eq!(budget, ComputeBudget::default());
+    }
+
+    #[test]
+    fn budgets_are_read_from_rpc_messages() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "header": {"numRequiredSignatures": 1},
+            "accountKeys": [
+                "11111111111111111111111111111111",
+                COMPUTE_BUDGET_PROGRAM,
+            ],
+            "instructions": [
+                {"programIdIndex": 1, "accounts": [], "data": "Fj2Eoy"},
+                {"programIdIndex": 0, "accounts": [], "data": "2g"},
+                {"programIdIndex": 1, "accounts": [], "data": "3GAG5eogvTjV"},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            ComputeBudget::from_message(&message),
+            Some(ComputeBudget {
+                unit_price: Some(10_000),
+                unit_limit: Some(200_000),
+            })
+        );
+    }
+
+    #[test]
+    fn bs58_keeps_leading_zeros() {
+        assert_eq!(bs58_encode(&[0; 32]), "11111111111111111111111111111111");
+        assert_eq!(
+            bs58_decode("11111111111111111111111111111111").unwrap(),
+            [0; 32]
+        );
+        for (bytes, encoded) in [
+            (&[][..], ""),
+            (&[0], "1"),
+            (&[0, 0, 0], "111"),
+            (&[0, 0, 1, 2], "115T"),
+            (&[0x61], "2g"),
+            (b"hello world", "StV1DL6CwTryKyV"),
+        ] {
+            assert_eq!(bs58_encode(bytes), encoded);
+            assert_eq!(bs58_decode(encoded).unwrap(), bytes);
+        }
+        assert_eq!(
+            bs58_decode(COMPUTE_BUDGET_PROGRAM).unwrap()[..4],
+            [3, 6, 70, 111]
+        );
+    }
+
+    #[test]
+    fn bs58_rejects_characters_outside_the_alphabet() {
+        for s in ["0", "O", "I", "l", "1+", "abc "] {
+            assert_eq!(bs58_decode(s), None, "{:?}", s);
+        }
+    }
+}
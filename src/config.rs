@@ -0,0 +1,1505 @@
+use crate::aggregation::Aggregation;
+use crate::api_keys::ApiKey;
+use crate::config_file;
+use crate::database::{DEFAULT_DB_RETENTION, DatabaseTarget};
+use crate::estimator::{MAX_PRIORITY_FEE, SampleAge};
+use crate::kafka::DEFAULT_KAFKA_TOPIC;
+use crate::limits;
+use crate::logging::{AccessLogFormat, LogFormat};
+use crate::proxy::Proxy;
+use crate::publish::{DEFAULT_PUBLISH_TOPIC, PublishTarget};
+use crate::rate_limit::MIN_RATE;
+use crate::rpc::{BlockFilters, Commitment, RpcEncoding, TransactionOptions};
+use crate::rpc_pool::{
+    DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_COOLDOWN_SECS, DEFAULT_FAILURE_THRESHOLD,
+    DEFAULT_IDLE_TIMEOUT_SECS, DEFAULT_POOL_SIZE, DEFAULT_READ_TIMEOUT_SECS, EndpointConfig,
+    Header, RpcPool,
+};
+use crate::shared_cache::{DEFAULT_SHARED_CACHE_PREFIX, SharedCacheUrl};
+use crate::smoothing::DEFAULT_EWMA_ALPHA;
+use crate::sources::{FeeSource, TransactionSampling, fee_source_by_name, fee_source_setting_name};
+use crate::trend::DEFAULT_TREND_WINDOW;
+use crate::unix_socket::DEFAULT_SOCKET_MODE;
+use crate::webhooks::Webhook;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_RPC_URL: &str = "https://api.mainnet-beta.solana.com";
+pub const DEFAULT_CLUSTER: &str = "mainnet";
+/// Names a cluster can't take, as they start other paths than `/<cluster>/fee`.
+const RESERVED_CLUSTER_NAMES: [&str; 3] = ["admin", "fee", "v1"];
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:43278";
+pub const JUPITER_AGGREGATOR_V6: &str = "JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4";
+pub const DEFAULT_REFRESH_INTERVAL_SECS: u64 = 10;
+pub const DEFAULT_REFRESH_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_MAX_STALENESS_SECS: u64 = 300;
+pub const DEFAULT_READY_MAX_AGE_SECS: u64 = 60;
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+pub const DEFAULT_BLOCK_SCAN_SLOTS: u64 = 10;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL_SECS: u64 = 15;
+pub const DEFAULT_SAMPLE_SIZE: usize = 1000;
+pub const DEFAULT_MIN_SAMPLE_FRACTION: f64 = 0.5;
+pub const DEFAULT_MAX_RETRIES: usize = 10;
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 100_000_000;
+pub const DEFAULT_MIN_COMPUTE_UNITS: u64 = 5_000;
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 20;
+pub const DEFAULT_RATE_LIMIT_BURST: f64 = 20.0;
+
+/// Settings for a `PriorityFeeEstimator`.
+#[derive(Clone)]
+pub struct Config {
+    pub listen_addr: String, // where the binary serves HTTP, e.g. `0.0.0.0:43278` or `[::]:43278`
+    pub listen_socket: Option<String>, // Unix socket the binary also serves HTTP on
+    pub listen_socket_mode: u32, // its permissions
+    pub tls_cert_path: Option<String>, // PEM certificate chain; the binary serves HTTPS if set
+    pub tls_key_path: Option<String>, // PEM private key for `tls_cert_path`
+    pub grpc_addr: Option<String>, // where the binary also serves gRPC, if anywhere
+    pub rpc: Arc<RpcPool>,
+    pub rpc_ws_url: String, // PubSub endpoint the `logs` source subscribes to
+    pub geyser_url: Option<String>, // Yellowstone gRPC endpoint the `geyser` source subscribes to
+    pub geyser_x_token: Option<String>, // sent to it as `x-token`
+    pub rpc_allowlist: Vec<Arc<RpcPool>>, // endpoints a request may pick with `X-Rpc-Url`
+    pub health_check_interval: Duration, // how often the binary probes `rpc`
+    pub tracked_programs: Vec<String>, // the first one is the primary program
+    pub cluster: String,    // name of the cluster `rpc` is on
+    pub clusters: Vec<Cluster>, // further clusters the binary estimates fees on
+    pub refresh_interval: Duration,
+    pub refresh_timeout: Duration, // RPC calls fail once a refresh has run this long
+    pub max_staleness: Duration,   // how old an estimate the binary serves while refreshes fail
+    pub ready_max_age: Duration,   // how old an estimate the binary's `/readyz` accepts
+    pub sample_size: usize,        // transactions sampled per program
+    pub min_sample_fraction: f64,  // estimates priced from less of `sample_size` are low-confidence
+    pub max_retries: usize,        // attempts per getTransaction batch
+    pub batch_size: usize,         // signatures per getTransaction batch
+    pub batch_concurrency: usize,  // batches in flight at once
+    pub max_response_bytes: u64,   // largest getTransaction batch or getBlock response read
+    pub transaction_encoding: RpcEncoding, // how getTransaction and getBlock return transactions
+    pub max_transaction_version: Option<u8>, // newest version fetched; `None` for legacy only
+    pub fee_source: Arc<dyn FeeSource>,
+    pub commitment: Commitment, // how settled the state the sources read must be
+    pub fallback_scoped: bool, // scope getRecentPrioritizationFees to the tracked program's account
+    pub block_scan_slots: u64, // how many recent blocks BlockScanning fetches
+    pub block_scan_network: bool, // BlockScanning keeps all transactions, not just the program's
+    pub block_filters: BlockFilters, // the noise BlockScanning leaves out
+    pub max_sample_age: Option<SampleAge>, // drop samples older than this
+    pub min_compute_units: u64, // drop samples of transactions that consumed fewer
+    pub min_fee_floor: u64,    // estimates are clamped to [min_fee_floor, max_fee_clamp]
+    pub max_fee_clamp: u64,
+    pub limits_path: Option<String>, // JSON file overriding the two above, set via `/admin/limits`
+    pub include_failed: bool,        // whether failed transactions count towards the estimate
+    pub aggregation: Aggregation,    // how the server reads fees off a sample by default
+    pub ewma_alpha: f64,             // weight of the newest estimate in the smoothed fees
+    pub trend_window: usize,         // refreshes the fee trend is fitted to
+    pub history_path: Option<String>, // JSON lines file the binary persists history to
+    pub db_path: Option<String>,     // SQLite database the binary records history to instead
+    pub database_url: Option<String>, // or the PostgreSQL server it records history to
+    pub db_retention: Duration,      // how long the database keeps history
+    pub state_path: Option<String>,  // file the binary saves its latest estimates to on shutdown
+    pub shutdown_timeout: Duration,  // how long the binary drains requests and refreshes on exit
+    pub log_format: LogFormat,       // how the binary writes its logs
+    pub access_log: AccessLogFormat,
+    pub rate_limit_rps: Option<f64>, // requests per second the binary serves each client IP
+    pub rate_limit_burst: f64,
+    pub api_keys: Vec<ApiKey>, // if any, the binary requires one of these on every request
+    pub cors_origins: Vec<String>, // browser origins allowed to call the binary, or `*`
+    pub webhooks: Vec<Webhook>, // called when a fee crosses a threshold or jumps
+    pub publish: Option<PublishTarget>, // NATS or Redis the binary pushes each refresh to
+    pub publish_topic: String, // subject or channel prefix, followed by `.<program>`
+    pub kafka_brokers: Vec<String>, // `host:port` of Kafka brokers to produce each refresh to
+    pub kafka_topic: String,
+    pub shared_cache: Option<SharedCacheUrl>, // where the binary shares estimates with other replicas
+    pub shared_cache_prefix: String,          // of the shared cache's keys
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen_addr: DEFAULT_LISTEN_ADDR.to_string(),
+            listen_socket: None,
+            listen_socket_mode: DEFAULT_SOCKET_MODE,
+            tls_cert_path: None,
+            tls_key_path: None,
+            grpc_addr: None,
+            rpc: Arc::new(RpcPool::single(DEFAULT_RPC_URL)),
+            rpc_ws_url: ws_url_for(DEFAULT_RPC_URL).unwrap(),
+            geyser_url: None,
+            geyser_x_token: None,
+            rpc_allowlist: Vec::new(),
+            health_check_interval: Duration::from_secs(DEFAULT_HEALTH_CHECK_INTERVAL_SECS),
+            tracked_programs: vec![JUPITER_AGGREGATOR_V6.to_string()],
+            cluster: DEFAULT_CLUSTER.to_string(),
+            clusters: Vec::new(),
+            refresh_interval: Duration::from_secs(DEFAULT_REFRESH_INTERVAL_SECS),
+            refresh_timeout: Duration::from_secs(DEFAULT_REFRESH_TIMEOUT_SECS),
+            max_staleness: Duration::from_secs(DEFAULT_MAX_STALENESS_SECS),
+            ready_max_age: Duration::from_secs(DEFAULT_READY_MAX_AGE_SECS),
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            min_sample_fraction: DEFAULT_MIN_SAMPLE_FRACTION,
+            max_retries: DEFAULT_MAX_RETRIES,
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            transaction_encoding: RpcEncoding::Json,
+            max_transaction_version: Some(0),
+            fee_source: Arc::new(TransactionSampling::default()),
+            commitment: Commitment::Confirmed,
+            fallback_scoped: true,
+            block_scan_slots: DEFAULT_BLOCK_SCAN_SLOTS,
+            block_scan_network: false,
+            block_filters: BlockFilters::default(),
+            max_sample_age: None,
+            min_compute_units: DEFAULT_MIN_COMPUTE_UNITS,
+            min_fee_floor: 0,
+            max_fee_clamp: MAX_PRIORITY_FEE,
+            limits_path: None,
+            include_failed: true,
+            aggregation: Aggregation::Percentile,
+            ewma_alpha: DEFAULT_EWMA_ALPHA,
+            trend_window: DEFAULT_TREND_WINDOW,
+            history_path: None,
+            db_path: None,
+            database_url: None,
+            db_retention: DEFAULT_DB_RETENTION,
+            state_path: None,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            log_format: LogFormat::Text,
+            access_log: AccessLogFormat::Common,
+            rate_limit_rps: None,
+            rate_limit_burst: DEFAULT_RATE_LIMIT_BURST,
+            api_keys: Vec::new(),
+            cors_origins: Vec::new(),
+            webhooks: Vec::new(),
+            publish: None,
+            publish_topic: DEFAULT_PUBLISH_TOPIC.to_string(),
+            kafka_brokers: Vec::new(),
+            kafka_topic: DEFAULT_KAFKA_TOPIC.to_string(),
+            shared_cache: None,
+            shared_cache_prefix: DEFAULT_SHARED_CACHE_PREFIX.to_string(),
+        }
+    }
+}
+
+/// A further cluster, such as devnet, the binary estimates fees on alongside the one
+/// `rpc` is on. Everything but where it's read from is shared with the main cluster.
+#[derive(Clone)]
+pub struct Cluster {
+    pub name: String,
+    pub rpc: Arc<RpcPool>,
+    pub rpc_ws_url: String,
+    pub geyser_url: Option<String>,
+    pub geyser_x_token: Option<String>,
+    pub tracked_programs: Vec<String>,
+}
+
+impl Cluster {
+    /// The prefix of its settings' names, e.g. `DEVNET` for `devnet`.
+    pub fn var_prefix(&self) -> String {
+        self.name.to_uppercase().replace('-', "_")
+    }
+}
+
+impl Config {
+    /// Read settings from CLI flags, falling back to environment variables, then the
+    /// config file given by `--config` / `CONFIG_FILE`, then defaults.
+    pub fn load() -> Result<Config, String> {
+        let settings = Settings::load()?;
+
+        let listen_addr = settings
+            .get("--listen", "LISTEN_ADDR")
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string());
+        let listen_socket = settings
+            .get("--listen-socket", "LISTEN_SOCKET")
+            .filter(|p| !p.is_empty());
+        let listen_socket_mode = match settings.get("--listen-socket-mode", "LISTEN_SOCKET_MODE") {
+            Some(v) => u32::from_str_radix(v.trim_start_matches("0o"), 8)
+                .ok()
+                .filter(|&m| m <= 0o777)
+                .ok_or_else(|| format!("Invalid LISTEN_SOCKET_MODE: {}", v))?,
+            None => DEFAULT_SOCKET_MODE,
+        };
+        if listen_addr.is_empty() && listen_socket.is_none() {
+            return Err("LISTEN_ADDR may only be empty if LISTEN_SOCKET is set".to_string());
+        }
+        let tls_cert_path = settings
+            .get("--tls-cert", "TLS_CERT_PATH")
+            .filter(|p| !p.is_empty());
+        let tls_key_path = settings
+            .get("--tls-key", "TLS_KEY_PATH")
+            .filter(|p| !p.is_empty());
+        if tls_cert_path.is_some() != tls_key_path.is_some() {
+            return Err("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string());
+        }
+        let grpc_addr = settings
+            .get("--grpc-addr", "GRPC_ADDR")
+            .filter(|a| !a.is_empty());
+
+        // RPC_URLS (comma-separated, each `url[;weight=N][;rps=N]`) supersedes the
+        // single-endpoint RPC_URL
+        let rpc_endpoints = settings
+            .get("--rpc-urls", "RPC_URLS")
+            .or(settings.get("--rpc-url", "RPC_URL"))
+            .unwrap_or_else(|| DEFAULT_RPC_URL.to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+            .map(EndpointConfig::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if rpc_endpoints.is_empty() {
+            return Err("At least one RPC URL is required".to_string());
+        }
+        // The PubSub endpoint defaults to the first RPC endpoint's
+        let rpc_ws_url = match settings.get("--rpc-ws-url", "RPC_WS_URL") {
+            Some(url) => url,
+            None => ws_url_for(&rpc_endpoints[0].url).ok_or_else(|| {
+                format!(
+                    "Cannot derive RPC_WS_URL from {}; set it explicitly",
+                    rpc_endpoints[0].url
+                )
+            })?,
+        };
+        let geyser_url = settings
+            .get("--geyser-url", "GEYSER_URL")
+            .filter(|u| !u.is_empty())
+            .map(|u| check_geyser_url("GEYSER_URL", u))
+            .transpose()?;
+        let geyser_x_token = settings
+            .get("--geyser-x-token", "GEYSER_X_TOKEN")
+            .filter(|t| !t.is_empty());
+        let failure_threshold = settings.parse(
+            "--rpc-failure-threshold",
+            "RPC_FAILURE_THRESHOLD",
+            DEFAULT_FAILURE_THRESHOLD,
+            |&n| n > 0,
+        )?;
+        let cooldown_secs = settings.parse(
+            "--rpc-cooldown-secs",
+            "RPC_COOLDOWN_SECS",
+            DEFAULT_COOLDOWN_SECS,
+            |_| true,
+        )?;
+        let pool_size = settings.parse(
+            "--rpc-pool-size",
+            "RPC_POOL_SIZE",
+            DEFAULT_POOL_SIZE,
+            |_| true,
+        )?;
+        let idle_timeout_secs = settings.parse(
+            "--rpc-idle-timeout-secs",
+            "RPC_IDLE_TIMEOUT_SECS",
+            DEFAULT_IDLE_TIMEOUT_SECS,
+            |_| true,
+        )?;
+        let connect_timeout_secs = settings.parse(
+            "--rpc-connect-timeout-secs",
+            "RPC_CONNECT_TIMEOUT_SECS",
+            DEFAULT_CONNECT_TIMEOUT_SECS,
+            |&n| n > 0,
+        )?;
+        let read_timeout_secs = settings.parse(
+            "--rpc-read-timeout-secs",
+            "RPC_READ_TIMEOUT_SECS",
+            DEFAULT_READ_TIMEOUT_SECS,
+            |&n| n > 0,
+        )?;
+        // Each `name:value`
+        let mut rpc_headers: Vec<Header> = Vec::new();
+        for header in settings
+            .get("--rpc-headers", "RPC_HEADERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+        {
+            let header = Header::parse(header)?;
+            if rpc_headers
+                .iter()
+                .any(|h| h.name.eq_ignore_ascii_case(&header.name))
+            {
+                return Err(format!("Duplicate RPC header {}", header.name));
+            }
+            rpc_headers.push(header);
+        }
+        // Like other HTTP clients, fall back to the standard proxy variables
+        let rpc_proxy = settings
+            .get("--rpc-proxy", "RPC_PROXY")
+            .or_else(|| {
+                [
+                    "HTTPS_PROXY",
+                    "https_proxy",
+                    "HTTP_PROXY",
+                    "http_proxy",
+                    "ALL_PROXY",
+                    "all_proxy",
+                ]
+                .iter()
+                .find_map(|var| env::var(var).ok())
+            })
+            .filter(|p| !p.is_empty());
+        // Parsed once here to reject a bad one before any pool is built
+        if let Some(proxy) = &rpc_proxy {
+            Proxy::parse(proxy)?;
+        }
+        let rpc_no_proxy: Vec<String> = settings
+            .get("--rpc-no-proxy", "RPC_NO_PROXY")
+            .or_else(|| env::var("NO_PROXY").or_else(|_| env::var("no_proxy")).ok())
+            .unwrap_or_default()
+            .split(',')
+            .map(|h| h.trim().to_string())
+            .filter(|h| !h.is_empty())
+            .collect();
+        let health_check_interval_secs = settings.parse(
+            "--health-check-interval-secs",
+            "HEALTH_CHECK_INTERVAL_SECS",
+            DEFAULT_HEALTH_CHECK_INTERVAL_SECS,
+            |&n| n > 0,
+        )?;
+        // Every cluster's pool shares the settings above
+        let adaptive_sampling =
+            settings.parse("--adaptive-sampling", "ADAPTIVE_SAMPLING", true, |_| true)?;
+        let rpc_pool = |endpoints: Vec<EndpointConfig>| -> Result<RpcPool, String> {
+            let rpc = RpcPool::new(
+                endpoints,
+                failure_threshold,
+                Duration::from_secs(cooldown_secs),
+            )
+            .with_connection_pool(pool_size, Duration::from_secs(idle_timeout_secs))
+            .with_timeouts(
+                Duration::from_secs(connect_timeout_secs),
+                Duration::from_secs(read_timeout_secs),
+            )
+            .with_headers(rpc_headers.clone())
+            .with_adaptive_sampling(adaptive_sampling);
+            match &rpc_proxy {
+                Some(proxy) => rpc.with_proxy(Proxy::parse(proxy)?, rpc_no_proxy.clone()),
+                None => Ok(rpc),
+            }
+        };
+        let rpc = rpc_pool(rpc_endpoints)?;
+        // RPC_HEADERS are for the configured provider, so they aren't sent to these
+        let mut rpc_allowlist: Vec<Arc<RpcPool>> = Vec::new();
+        for url in settings
+            .get("--rpc-url-allowlist", "RPC_URL_ALLOWLIST")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+        {
+            let url = normalize_rpc_url(url)
+                .ok_or_else(|| format!("Invalid RPC_URL_ALLOWLIST entry: {}", url))?;
+            if !rpc_allowlist.iter().any(|p| p.urls().eq([url.as_str()])) {
+                let pool = rpc_pool(vec![EndpointConfig::new(&url)])?.with_headers(Vec::new());
+                rpc_allowlist.push(Arc::new(pool));
+            }
+        }
+
+        // TRACKED_PROGRAMS (comma-separated) supersedes the single-program TRACKED_PROGRAM
+        let tracked_programs = parse_programs(
+            &settings
+                .get("--tracked-programs", "TRACKED_PROGRAMS")
+                .or(settings.get("--tracked-program", "TRACKED_PROGRAM"))
+                .unwrap_or_else(|| JUPITER_AGGREGATOR_V6.to_string()),
+        )?;
+
+        // CLUSTERS (comma-separated) names further clusters, each set up with settings
+        // prefixed by its name: `DEVNET_RPC_URLS` and so on
+        let cluster = settings
+            .get("--cluster", "CLUSTER")
+            .unwrap_or_else(|| DEFAULT_CLUSTER.to_string());
+        if !is_valid_cluster_name(&cluster) {
+            return Err(format!("Invalid CLUSTER: {}", cluster));
+        }
+        let mut clusters: Vec<Cluster> = Vec::new();
+        for name in settings
+            .get("--clusters", "CLUSTERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+        {
+            if !is_valid_cluster_name(name) {
+                return Err(format!("Invalid cluster name: {}", name));
+            }
+            if name == cluster || clusters.iter().any(|c| c.name == name) {
+                return Err(format!("Duplicate cluster {}", name));
+            }
+            let var = name.to_uppercase().replace('-', "_");
+            let setting = |suffix: &str| {
+                let flag = format!("--{}-{}", name, suffix.to_lowercase().replace('_', "-"));
+                settings.get(&flag, &format!("{}_{}", var, suffix))
+            };
+            let endpoints = setting("RPC_URLS")
+                .or_else(|| setting("RPC_URL"))
+                .ok_or_else(|| format!("{}_RPC_URLS is required for cluster {}", var, name))?
+                .split(',')
+                .map(str::trim)
+                .filter(|u| !u.is_empty())
+                .map(EndpointConfig::parse)
+                .collect::<Result<Vec<_>, _>>()?;
+            if endpoints.is_empty() {
+                return Err(format!(
+                    "At least one RPC URL is required for cluster {}",
+                    name
+                ));
+            }
+            let rpc_ws_url = match setting("RPC_WS_URL") {
+                Some(url) => url,
+                None => ws_url_for(&endpoints[0].url).ok_or_else(|| {
+                    format!(
+                        "Cannot derive {}_RPC_WS_URL from {}; set it explicitly",
+                        var, endpoints[0].url
+                    )
+                })?,
+            };
+            let tracked_programs = match setting("TRACKED_PROGRAMS") {
+                Some(programs) => parse_programs(&programs)?,
+                None => tracked_programs.clone(),
+            };
+            clusters.push(Cluster {
+                name: name.to_string(),
+                rpc: Arc::new(rpc_pool(endpoints)?),
+                rpc_ws_url,
+                geyser_url: setting("GEYSER_URL")
+                    .filter(|u| !u.is_empty())
+                    .map(|u| check_geyser_url(&format!("{}_GEYSER_URL", var), u))
+                    .transpose()?,
+                geyser_x_token: setting("GEYSER_X_TOKEN").filter(|t| !t.is_empty()),
+                tracked_programs,
+            });
+        }
+
+        let refresh_interval_secs = settings.parse(
+            "--refresh-interval-secs",
+            "REFRESH_INTERVAL_SECS",
+            DEFAULT_REFRESH_INTERVAL_SECS,
+            |&n| n > 0,
+        )?;
+        let refresh_timeout_secs = settings.parse(
+            "--refresh-timeout-secs",
+            "REFRESH_TIMEOUT_SECS",
+            DEFAULT_REFRESH_TIMEOUT_SECS,
+            |&n| n > 0,
+        )?;
+        let max_staleness_secs = settings.parse(
+            "--max-staleness-secs",
+            "MAX_STALENESS_SECS",
+            DEFAULT_MAX_STALENESS_SECS,
+            |_| true,
+        )?;
+        let ready_max_age_secs = settings.parse(
+            "--ready-max-age-secs",
+            "READY_MAX_AGE_SECS",
+            DEFAULT_READY_MAX_AGE_SECS,
+            |&n| n > 0,
+        )?;
+        let sample_size =
+            settings.parse("--sample-size", "SAMPLE_SIZE", DEFAULT_SAMPLE_SIZE, |&n| {
+                n > 0
+            })?;
+        let min_sample_fraction = settings.parse(
+            "--min-sample-fraction",
+            "MIN_SAMPLE_FRACTION",
+            DEFAULT_MIN_SAMPLE_FRACTION,
+            |&f| (0.0..=1.0).contains(&f),
+        )?;
+        let max_retries =
+            settings.parse("--max-retries", "MAX_RETRIES", DEFAULT_MAX_RETRIES, |&n| {
+                n > 0
+            })?;
+        let batch_size =
+            settings.parse("--batch-size", "BATCH_SIZE", DEFAULT_BATCH_SIZE, |&n| {
+                (1..=1000).contains(&n)
+            })?;
+        let batch_concurrency = settings.parse(
+            "--batch-concurrency",
+            "BATCH_CONCURRENCY",
+            DEFAULT_BATCH_CONCURRENCY,
+            |&n| n > 0,
+        )?;
+        let max_response_bytes = settings.parse(
+            "--max-response-bytes",
+            "MAX_RESPONSE_BYTES",
+            DEFAULT_MAX_RESPONSE_BYTES,
+            |&n| n > 0,
+        )?;
+        let transaction_encoding = settings.parse(
+            "--transaction-encoding",
+            "TRANSACTION_ENCODING",
+            RpcEncoding::Json,
+            |_| true,
+        )?;
+        let max_transaction_version = match settings
+            .get("--max-transaction-version", "MAX_TRANSACTION_VERSION")
+            .as_deref()
+        {
+            None | Some("0") => Some(0),
+            Some("legacy") => None,
+            Some(v) => {
+                return Err(format!(
+                    "Invalid MAX_TRANSACTION_VERSION: {} (expected 0 or legacy)",
+                    v
+                ));
+            }
+        };
+        let fee_source_name = settings
+            .get("--fee-source", "FEE_SOURCE")
+            .unwrap_or_else(|| "transactions".to_string());
+        let fee_source = fee_source_by_name(&fee_source_name)
+            .ok_or_else(|| format!("Invalid FEE_SOURCE: {}", fee_source_name))?;
+        if fee_source_name == "geyser" {
+            if geyser_url.is_none() {
+                return Err("GEYSER_URL is required with FEE_SOURCE=geyser".to_string());
+            }
+            if let Some(cluster) = clusters.iter().find(|c| c.geyser_url.is_none()) {
+                return Err(format!(
+                    "{}_GEYSER_URL is required for cluster {} with FEE_SOURCE=geyser",
+                    cluster.var_prefix(),
+                    cluster.name
+                ));
+            }
+        }
+        let commitment =
+            settings.parse("--commitment", "COMMITMENT", Commitment::Confirmed, |_| {
+                true
+            })?;
+        let fallback_scoped =
+            settings.parse("--fallback-scoped", "FALLBACK_SCOPED", true, |_| true)?;
+        let block_scan_slots = settings.parse(
+            "--block-scan-slots",
+            "BLOCK_SCAN_SLOTS",
+            DEFAULT_BLOCK_SCAN_SLOTS,
+            |&n| n > 0,
+        )?;
+        let block_scan_network =
+            settings.parse("--block-scan-network", "BLOCK_SCAN_NETWORK", false, |_| {
+                true
+            })?;
+        let block_filters = BlockFilters {
+            votes: settings.parse("--block-skip-votes", "BLOCK_SKIP_VOTES", true, |_| true)?,
+            transfers: settings.parse(
+                "--block-skip-transfers",
+                "BLOCK_SKIP_TRANSFERS",
+                true,
+                |_| true,
+            )?,
+            no_compute_budget: settings.parse(
+                "--block-skip-no-compute-budget",
+                "BLOCK_SKIP_NO_COMPUTE_BUDGET",
+                true,
+                |_| true,
+            )?,
+        };
+        let max_sample_age = settings
+            .get("--max-sample-age", "MAX_SAMPLE_AGE")
+            .map(|v| {
+                v.parse::<SampleAge>()
+                    .map_err(|_| format!("Invalid MAX_SAMPLE_AGE: {}", v))
+            })
+            .transpose()?;
+        let min_compute_units = settings.parse(
+            "--min-compute-units",
+            "MIN_COMPUTE_UNITS",
+            DEFAULT_MIN_COMPUTE_UNITS,
+            |_| true,
+        )?;
+        let max_fee_clamp =
+            settings.parse("--max-fee-clamp", "MAX_FEE_CLAMP", MAX_PRIORITY_FEE, |&n| {
+                n > 0
+            })?;
+        let min_fee_floor = settings.parse("--min-fee-floor", "MIN_FEE_FLOOR", 0, |&n| {
+            n <= max_fee_clamp
+        })?;
+        // Overrides saved by `PUT /admin/limits` win over the settings
+        let limits_path = settings
+            .get("--limits-path", "LIMITS_PATH")
+            .filter(|p| !p.is_empty());
+        let (min_fee_floor, max_fee_clamp) = match &limits_path {
+            Some(path) => limits::read(path)?
+                .apply(min_fee_floor, max_fee_clamp)
+                .map_err(|e| format!("Invalid fee limits in {}: {}", path, e))?,
+            None => (min_fee_floor, max_fee_clamp),
+        };
+        let include_failed =
+            settings.parse("--include-failed", "INCLUDE_FAILED", true, |_| true)?;
+        let aggregation = settings.parse(
+            "--aggregation",
+            "AGGREGATION",
+            Aggregation::Percentile,
+            |_| true,
+        )?;
+        let ewma_alpha =
+            settings.parse("--ewma-alpha", "EWMA_ALPHA", DEFAULT_EWMA_ALPHA, |&a| {
+                a > 0.0 && a <= 1.0
+            })?;
+        let trend_window = settings.parse(
+            "--trend-window",
+            "TREND_WINDOW",
+            DEFAULT_TREND_WINDOW,
+            |&n| n >= 2,
+        )?;
+        let history_path = settings
+            .get("--history-path", "HISTORY_PATH")
+            .filter(|p| !p.is_empty());
+        let db_path = settings.get("--db", "DB_PATH").filter(|p| !p.is_empty());
+        let database_url = settings
+            .get("--database-url", "DATABASE_URL")
+            .filter(|u| !u.is_empty());
+        if let Some(url) = &database_url
+            && !url::Url::parse(url)
+                .is_ok_and(|u| u.scheme() == "postgres" || u.scheme() == "postgresql")
+        {
+            return Err(format!(
+                "Invalid DATABASE_URL (expected postgres://...): {}",
+                crate::proxy::redact(url)
+            ));
+        }
+        let persisted: Vec<&str> = [
+            ("HISTORY_PATH", history_path.is_some()),
+            ("DB_PATH", db_path.is_some()),
+            ("DATABASE_URL", database_url.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        if persisted.len() > 1 {
+            return Err(format!(
+                "{} and {} can't both be set",
+                persisted[0], persisted[1]
+            ));
+        }
+        let db_retention = match settings.get("--db-retention", "DB_RETENTION") {
+            Some(v) => parse_duration(&v)
+                .filter(|d| !d.is_zero())
+                .ok_or_else(|| format!("Invalid DB_RETENTION: {}", v))?,
+            None => DEFAULT_DB_RETENTION,
+        };
+        let state_path = settings
+            .get("--state-path", "STATE_PATH")
+            .filter(|p| !p.is_empty());
+        let shutdown_timeout_secs = settings.parse(
+            "--shutdown-timeout-secs",
+            "SHUTDOWN_TIMEOUT_SECS",
+            DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            |_| true,
+        )?;
+        let log_format = settings.parse("--log-format", "LOG_FORMAT", LogFormat::Text, |_| true)?;
+        let access_log = settings.parse(
+            "--access-log",
+            "ACCESS_LOG",
+            AccessLogFormat::Common,
+            |_| true,
+        )?;
+        let rate_limit_rps = settings
+            .get("--rate-limit-rps", "RATE_LIMIT_RPS")
+            .filter(|v| !v.is_empty())
+            .map(|v| match v.parse::<f64>() {
+                Ok(n) if (MIN_RATE..=f64::MAX).contains(&n) => Ok(n),
+                _ => Err(format!(
+                    "Invalid RATE_LIMIT_RPS: {} (must be at least {})",
+                    v, MIN_RATE
+                )),
+            })
+            .transpose()?;
+        let rate_limit_burst = settings.parse(
+            "--rate-limit-burst",
+            "RATE_LIMIT_BURST",
+            DEFAULT_RATE_LIMIT_BURST,
+            |&n| n >= 1.0 && n.is_finite(),
+        )?;
+
+        // Each `key[;name=N][;quota=N][;admin]`
+        let mut api_keys: Vec<ApiKey> = Vec::new();
+        for key in settings
+            .get("--api-keys", "API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+        {
+            let key = ApiKey::parse(key)?;
+            if api_keys.iter().any(|k| k.key == key.key) {
+                return Err(format!("Duplicate API key {}", key.label()));
+            }
+            api_keys.push(key);
+        }
+
+        // Each `url[;secret=S][;thresholds=N|N...][;change=PCT][;percentile=P][;program=P]`
+        let webhooks: Vec<Webhook> = settings
+            .get("--webhooks", "WEBHOOKS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .map(Webhook::parse)
+            .collect::<Result<_, _>>()?;
+
+        let publish = settings
+            .get("--publish-url", "PUBLISH_URL")
+            .filter(|u| !u.is_empty())
+            .map(|u| PublishTarget::parse(&u))
+            .transpose()?;
+        let publish_topic = settings
+            .get("--publish-topic", "PUBLISH_TOPIC")
+            .unwrap_or_else(|| DEFAULT_PUBLISH_TOPIC.to_string());
+        if publish_topic.is_empty() || publish_topic.contains(char::is_whitespace) {
+            return Err(format!("Invalid PUBLISH_TOPIC: {}", publish_topic));
+        }
+
+        let kafka_brokers: Vec<String> = settings
+            .get("--kafka-brokers", "KAFKA_BROKERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|b| !b.is_empty())
+            .map(str::to_string)
+            .collect();
+        if let Some(b) = kafka_brokers.iter().find(|b| {
+            b.rsplit_once(':')
+                .is_none_or(|(_, port)| port.parse::<u16>().is_err())
+        }) {
+            return Err(format!("Invalid Kafka broker {}: expected host:port", b));
+        }
+        let kafka_topic = settings
+            .get("--kafka-topic", "KAFKA_TOPIC")
+            .unwrap_or_else(|| DEFAULT_KAFKA_TOPIC.to_string());
+        if kafka_topic.is_empty()
+            || kafka_topic.len() > 249
+            || !kafka_topic
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+        {
+            return Err(format!("Invalid KAFKA_TOPIC: {}", kafka_topic));
+        }
+
+        let shared_cache = settings
+            .get("--shared-cache-url", "SHARED_CACHE_URL")
+            .filter(|u| !u.is_empty())
+            .map(|u| SharedCacheUrl::parse(&u))
+            .transpose()?;
+        let shared_cache_prefix = settings
+            .get("--shared-cache-prefix", "SHARED_CACHE_PREFIX")
+            .unwrap_or_else(|| DEFAULT_SHARED_CACHE_PREFIX.to_string());
+        if shared_cache_prefix.is_empty() || shared_cache_prefix.contains(char::is_whitespace) {
+            return Err(format!(
+                "Invalid SHARED_CACHE_PREFIX: {}",
+                shared_cache_prefix
+            ));
+        }
+
+        let cors_origins: Vec<String> = settings
+            .get("--cors-origins", "CORS_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|o| o.trim().trim_end_matches('/').to_ascii_lowercase())
+            .filter(|o| !o.is_empty())
+            .collect();
+        if let Some(origin) = cors_origins.iter().find(|o| !is_valid_origin(o)) {
+            return Err(format!("Invalid CORS_ORIGINS entry: {}", origin));
+        }
+
+        settings.check_unknown_keys()?;
+
+        Ok(Config {
+            listen_addr,
+            listen_socket,
+            listen_socket_mode,
+            tls_cert_path,
+            tls_key_path,
+            grpc_addr,
+            rpc: Arc::new(rpc),
+            rpc_ws_url,
+            geyser_url,
+            geyser_x_token,
+            rpc_allowlist,
+            health_check_interval: Duration::from_secs(health_check_interval_secs),
+            tracked_programs,
+            cluster,
+            clusters,
+            refresh_interval: Duration::from_secs(refresh_interval_secs),
+            refresh_timeout: Duration::from_secs(refresh_timeout_secs),
+            max_staleness: Duration::from_secs(max_staleness_secs),
+            ready_max_age: Duration::from_secs(ready_max_age_secs),
+            sample_size,
+            min_sample_fraction,
+            max_retries,
+            batch_size,
+            batch_concurrency,
+            max_response_bytes,
+            transaction_encoding,
+            max_transaction_version,
+            fee_source,
+            commitment,
+            fallback_scoped,
+            block_scan_slots,
+            block_scan_network,
+            block_filters,
+            max_sample_age,
+            min_compute_units,
+            min_fee_floor,
+            max_fee_clamp,
+            limits_path,
+            include_failed,
+            aggregation,
+            ewma_alpha,
+            trend_window,
+            history_path,
+            db_path,
+            database_url,
+            db_retention,
+            state_path,
+            shutdown_timeout: Duration::from_secs(shutdown_timeout_secs),
+            log_format,
+            access_log,
+            rate_limit_rps,
+            rate_limit_burst,
+            api_keys,
+            cors_origins,
+            webhooks,
+            publish,
+            publish_topic,
+            kafka_brokers,
+            kafka_topic,
+            shared_cache,
+            shared_cache_prefix,
+        })
+    }
+
+    /// How the sources fetch transactions.
+    pub fn transaction_options(&self) -> TransactionOptions {
+        TransactionOptions {
+            commitment: self.commitment,
+            encoding: self.transaction_encoding,
+            max_supported_version: self.max_transaction_version,
+            max_response_len: self.max_response_bytes,
+        }
+    }
+
+    /// These settings, scaled by `rpc`'s sampling level while its endpoints rate limit
+    /// calls: fewer transactions or blocks sampled, in smaller batches.
+    pub fn throttled(&self) -> Config {
+        let level = self.rpc.sampling_level();
+        let scale = |n: usize| ((n as f64 * level).ceil() as usize).max(1);
+        Config {
+            sample_size: scale(self.sample_size),
+            batch_size: scale(self.batch_size),
+            block_scan_slots: scale(self.block_scan_slots as usize) as u64,
+            ..self.clone()
+        }
+    }
+
+    /// The refresh interval, lengthened by `rpc`'s sampling level likewise, though not
+    /// past half of `ready_max_age`, so that the estimates stay fresh enough to serve.
+    pub fn throttled_refresh_interval(&self) -> Duration {
+        let longest = self.refresh_interval.max(self.ready_max_age / 2);
+        self.refresh_interval
+            .div_f64(self.rpc.sampling_level())
+            .min(longest)
+    }
+
+    /// The settings the estimator for `cluster` runs with: these, read from its RPC
+    /// endpoints for its programs; see `with_rpc`.
+    pub fn for_cluster(&self, cluster: &Cluster) -> Config {
+        Config {
+            rpc_ws_url: cluster.rpc_ws_url.clone(),
+            geyser_url: cluster.geyser_url.clone(),
+            geyser_x_token: cluster.geyser_x_token.clone(),
+            tracked_programs: cluster.tracked_programs.clone(),
+            cluster: cluster.name.clone(),
+            ..self.with_rpc(cluster.rpc.clone())
+        }
+    }
+
+    /// These settings, read from `rpc` instead, with a fee source of its own, and
+    /// without the webhooks or further clusters, which concern the configured endpoints.
+    pub fn with_rpc(&self, rpc: Arc<RpcPool>) -> Config {
+        Config {
+            rpc,
+            clusters: Vec::new(),
+            fee_source: fee_source_by_name(fee_source_setting_name(self.fee_source.as_ref()))
+                .expect("the fee source's own name is valid"),
+            webhooks: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// The allowlisted endpoint `url` names, if it's allowlisted.
+    pub fn allowed_rpc(&self, url: &str) -> Option<&Arc<RpcPool>> {
+        let url = normalize_rpc_url(url)?;
+        self.rpc_allowlist
+            .iter()
+            .find(|p| p.urls().eq([url.as_str()]))
+    }
+
+    /// The further cluster named `name`, if configured.
+    pub fn cluster(&self, name: &str) -> Option<&Cluster> {
+        self.clusters.iter().find(|c| c.name == name)
+    }
+
+    /// The database history is recorded to, if any.
+    pub fn database(&self) -> Option<DatabaseTarget> {
+        match (&self.db_path, &self.database_url) {
+            (Some(path), _) => Some(DatabaseTarget::Sqlite(path.into())),
+            (None, Some(url)) => Some(DatabaseTarget::Postgres(url.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// Settings reloaded at runtime as `new`, keeping what can't change without a restart.
+    /// Errors name the first restart-only setting that differs. The fee source, with its
+    /// sampling state, carries over, as does the RPC pool unless its settings changed.
+    pub fn reloaded(&self, mut new: Config) -> Result<Config, String> {
+        let restart_only = [
+            ("listen_addr", self.listen_addr != new.listen_addr),
+            ("listen_socket", self.listen_socket != new.listen_socket),
+            (
+                "listen_socket_mode",
+                self.listen_socket_mode != new.listen_socket_mode,
+            ),
+            ("tls_cert_path", self.tls_cert_path != new.tls_cert_path),
+            ("tls_key_path", self.tls_key_path != new.tls_key_path),
+            ("grpc_addr", self.grpc_addr != new.grpc_addr),
+            (
+                "tracked_programs",
+                self.tracked_programs != new.tracked_programs,
+            ),
+            ("cluster", self.cluster != new.cluster),
+            ("clusters", !same_clusters(&self.clusters, &new.clusters)),
+            (
+                "fee_source",
+                fee_source_setting_name(self.fee_source.as_ref())
+                    != fee_source_setting_name(new.fee_source.as_ref()),
+            ),
+            ("commitment", self.commitment != new.commitment),
+            ("rpc_ws_url", self.rpc_ws_url != new.rpc_ws_url),
+            ("geyser_url", self.geyser_url != new.geyser_url),
+            ("geyser_x_token", self.geyser_x_token != new.geyser_x_token),
+            ("history_path", self.history_path != new.history_path),
+            ("db_path", self.db_path != new.db_path),
+            ("database_url", self.database_url != new.database_url),
+            ("db_retention", self.db_retention != new.db_retention),
+            ("log_format", self.log_format != new.log_format),
+            ("publish_url", self.publish != new.publish),
+            ("kafka_brokers", self.kafka_brokers != new.kafka_brokers),
+            ("kafka_topic", self.kafka_topic != new.kafka_topic),
+            ("shared_cache_url", self.shared_cache != new.shared_cache),
+            (
+                "shared_cache_prefix",
+                self.shared_cache_prefix != new.shared_cache_prefix,
+            ),
+        ];
+        if let Some((name, _)) = restart_only.iter().find(|(_, changed)| *changed) {
+            return Err(format!("{} can't change without a restart", name));
+        }
+        // The `logs` and `geyser` sources' subscriptions keep the headers (`logs` only)
+        // and proxy they opened with
+        let fee_source = fee_source_setting_name(self.fee_source.as_ref());
+        if matches!(fee_source, "logs" | "geyser") {
+            let subscription = [
+                (
+                    "rpc_headers",
+                    fee_source == "logs" && self.rpc.headers() != new.rpc.headers(),
+                ),
+                ("rpc_proxy", self.rpc.proxy() != new.rpc.proxy()),
+                ("rpc_no_proxy", self.rpc.no_proxy() != new.rpc.no_proxy()),
+            ];
+            if let Some((name, _)) = subscription.iter().find(|(_, changed)| *changed) {
+                return Err(format!(
+                    "{} can't change without a restart while FEE_SOURCE is {}",
+                    name, fee_source
+                ));
+            }
+        }
+        new.fee_source = self.fee_source.clone();
+        new.clusters = self.clusters.clone();
+        if endpoint_urls(&self.rpc) == endpoint_urls(&new.rpc)
+            && self.rpc.failure_threshold() == new.rpc.failure_threshold()
+            && self.rpc.cooldown() == new.rpc.cooldown()
+            && self.rpc.pool_size() == new.rpc.pool_size()
+            && self.rpc.idle_timeout() == new.rpc.idle_timeout()
+            && self.rpc.connect_timeout() == new.rpc.connect_timeout()
+            && self.rpc.read_timeout() == new.rpc.read_timeout()
+            && self.rpc.headers() == new.rpc.headers()
+            && self.rpc.proxy() == new.rpc.proxy()
+            && self.rpc.no_proxy() == new.rpc.no_proxy()
+            && self.rpc.adaptive_sampling() == new.rpc.adaptive_sampling()
+        {
+            new.rpc = self.rpc.clone();
+        }
+        Ok(new)
+    }
+}
+
+/// Formats the effective configuration as a config file that reproduces it, except for
+/// the API keys, webhooks, RPC headers and proxy, Geyser tokens and broker passwords.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let endpoints = endpoint_urls(&self.rpc);
+        writeln!(f, "listen_addr = {}", toml_string(&self.listen_addr))?;
+        match &self.listen_socket {
+            Some(path) => writeln!(f, "listen_socket = {}", toml_string(path))?,
+            None => writeln!(f, "# listen_socket is unset")?,
+        }
+        writeln!(
+            f,
+            "listen_socket_mode = \"{:03o}\"",
+            self.listen_socket_mode
+        )?;
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert), Some(key)) => {
+                writeln!(f, "tls_cert_path = {}", toml_string(cert))?;
+                writeln!(f, "tls_key_path = {}", toml_string(key))?;
+            }
+            _ => writeln!(f, "# tls_cert_path and tls_key_path are unset")?,
+        }
+        match &self.grpc_addr {
+            Some(addr) => writeln!(f, "grpc_addr = {}", toml_string(addr))?,
+            None => writeln!(f, "# grpc_addr is unset")?,
+        }
+        writeln!(f, "rpc_urls = {}", toml_array(&endpoints))?;
+        writeln!(f, "rpc_ws_url = {}", toml_string(&self.rpc_ws_url))?;
+        match &self.geyser_url {
+            Some(url) => writeln!(f, "geyser_url = {}", toml_string(url))?,
+            None => writeln!(f, "# geyser_url is unset")?,
+        }
+        if self.geyser_x_token.is_some() {
+            writeln!(f, "# geyser_x_token: not shown")?;
+        }
+        let allowlist: Vec<String> = self
+            .rpc_allowlist
+            .iter()
+            .flat_map(|p| p.urls().map(str::to_string))
+            .collect();
+        writeln!(f, "rpc_url_allowlist = {}", toml_array(&allowlist))?;
+        writeln!(
+            f,
+            "rpc_failure_threshold = {}",
+            self.rpc.failure_threshold()
+        )?;
+        writeln!(f, "rpc_cooldown_secs = {}", self.rpc.cooldown().as_secs())?;
+        writeln!(f, "rpc_pool_size = {}", self.rpc.pool_size())?;
+        writeln!(
+            f,
+            "rpc_idle_timeout_secs = {}",
+            self.rpc.idle_timeout().as_secs()
+        )?;
+        writeln!(
+            f,
+            "rpc_connect_timeout_secs = {}",
+            self.rpc.connect_timeout().as_secs()
+        )?;
+        writeln!(
+            f,
+            "rpc_read_timeout_secs = {}",
+            self.rpc.read_timeout().as_secs()
+        )?;
+        // Only the names, as the values are usually credentials
+        match self.rpc.headers() {
+            [] => writeln!(f, "# rpc_headers is unset")?,
+            headers => {
+                let names: Vec<&str> = headers.iter().map(|h| h.name.as_str()).collect();
+                writeln!(f, "# rpc_headers: {}, values not shown", names.join(", "))?
+            }
+        }
+        match self.rpc.proxy() {
+            Some(proxy) if proxy.has_credentials() => {
+                writeln!(f, "# rpc_proxy: {}, password not shown", proxy)?
+            }
+            Some(proxy) => writeln!(f, "rpc_proxy = {}", toml_string(&proxy.to_string()))?,
+            None => writeln!(f, "# rpc_proxy is unset")?,
+        }
+        writeln!(f, "rpc_no_proxy = {}", toml_array(self.rpc.no_proxy()))?;
+        writeln!(
+            f,
+            "health_check_interval_secs = {}",
+            self.health_check_interval.as_secs()
+        )?;
+        writeln!(
+            f,
+            "tracked_programs = {}",
+            toml_array(&self.tracked_programs)
+        )?;
+        writeln!(f, "cluster = {}", toml_string(&self.cluster))?;
+        let names: Vec<String> = self.clusters.iter().map(|c| c.name.clone()).collect();
+        writeln!(f, "clusters = {}", toml_array(&names))?;
+        for cluster in &self.clusters {
+            let prefix = cluster.var_prefix().to_lowercase();
+            let endpoints = endpoint_urls(&cluster.rpc);
+            writeln!(f, "{}_rpc_urls = {}", prefix, toml_array(&endpoints))?;
+            writeln!(
+                f,
+                "{}_rpc_ws_url = {}",
+                prefix,
+                toml_string(&cluster.rpc_ws_url)
+            )?;
+            if let Some(url) = &cluster.geyser_url {
+                writeln!(f, "{}_geyser_url = {}", prefix, toml_string(url))?;
+            }
+            if cluster.geyser_x_token.is_some() {
+                writeln!(f, "# {}_geyser_x_token: not shown", prefix)?;
+            }
+            writeln!(
+                f,
+                "{}_tracked_programs = {}",
+                prefix,
+                toml_array(&cluster.tracked_programs)
+            )?;
+        }
+        writeln!(
+            f,
+            "refresh_interval_secs = {}",
+            self.refresh_interval.as_secs()
+        )?;
+        writeln!(
+            f,
+            "refresh_timeout_secs = {}",
+            self.refresh_timeout.as_secs()
+        )?;
+        writeln!(f, "max_staleness_secs = {}", self.max_staleness.as_secs())?;
+        writeln!(f, "sample_size = {}", self.sample_size)?;
+        writeln!(f, "min_sample_fraction = {}", self.min_sample_fraction)?;
+        writeln!(f, "max_retries = {}", self.max_retries)?;
+        writeln!(f, "batch_size = {}", self.batch_size)?;
+        writeln!(f, "batch_concurrency = {}", self.batch_concurrency)?;
+        writeln!(f, "adaptive_sampling = {}", self.rpc.adaptive_sampling())?;
+        writeln!(f, "max_response_bytes = {}", self.max_response_bytes)?;
+        writeln!(
+            f,
+            "transaction_encoding = {}",
+            toml_string(self.transaction_encoding.as_str())
+        )?;
+        match self.max_transaction_version {
+            Some(version) => writeln!(f, "max_transaction_version = \"{}\"", version)?,
+            None => writeln!(f, "max_transaction_version = \"legacy\"")?,
+        }
+        writeln!(
+            f,
+            "fee_source = {}",
+            toml_string(fee_source_setting_name(self.fee_source.as_ref()))
+        )?;
+        writeln!(f, "commitment = {}", toml_string(self.commitment.as_str()))?;
+        writeln!(f, "fallback_scoped = {}", self.fallback_scoped)?;
+        writeln!(f, "block_scan_slots = {}", self.block_scan_slots)?;
+        writeln!(f, "block_scan_network = {}", self.block_scan_network)?;
+        writeln!(f, "block_skip_votes = {}", self.block_filters.votes)?;
+        writeln!(f, "block_skip_transfers = {}", self.block_filters.transfers)?;
+        writeln!(
+            f,
+            "block_skip_no_compute_budget = {}",
+            self.block_filters.no_compute_budget
+        )?;
+        match self.max_sample_age {
+            Some(SampleAge::Slots(n)) => writeln!(f, "max_sample_age = \"{}slots\"", n)?,
+            Some(SampleAge::Duration(d)) => writeln!(f, "max_sample_age = \"{}s\"", d.as_secs())?,
+            None => writeln!(f, "# max_sample_age is unset")?,
+        }
+        writeln!(f, "min_compute_units = {}", self.min_compute_units)?;
+        writeln!(f, "min_fee_floor = {}", self.min_fee_floor)?;
+        writeln!(f, "max_fee_clamp = {}", self.max_fee_clamp)?;
+        match &self.limits_path {
+            Some(path) => writeln!(f, "limits_path = {}", toml_string(path))?,
+            None => writeln!(f, "# limits_path is unset")?,
+        }
+        writeln!(f, "include_failed = {}", self.include_failed)?;
+        writeln!(
+            f,
+            "aggregation = {}",
+            toml_string(&self.aggregation.to_string())
+        )?;
+        writeln!(f, "ewma_alpha = {}", self.ewma_alpha)?;
+        writeln!(f, "trend_window = {}", self.trend_window)?;
+        match &self.history_path {
+            Some(path) => writeln!(f, "history_path = {}", toml_string(path))?,
+            None => writeln!(f, "# history_path is unset")?,
+        }
+        match &self.db_path {
+            Some(path) => writeln!(f, "db_path = {}", toml_string(path))?,
+            None => writeln!(f, "# db_path is unset")?,
+        }
+        match &self.database_url {
+            Some(url) if crate::proxy::redact(url) != *url => writeln!(
+                f,
+                "# database_url: {}, password not shown",
+                crate::proxy::redact(url)
+            )?,
+            Some(url) => writeln!(f, "database_url = {}", toml_string(url))?,
+            None => writeln!(f, "# database_url is unset")?,
+        }
+        writeln!(f, "db_retention = \"{}s\"", self.db_retention.as_secs())?;
+        match &self.state_path {
+            Some(path) => writeln!(f, "state_path = {}", toml_string(path))?,
+            None => writeln!(f, "# state_path is unset")?,
+        }
+        writeln!(
+            f,
+            "shutdown_timeout_secs = {}",
+            self.shutdown_timeout.as_secs()
+        )?;
+        writeln!(
+            f,
+            "log_format = {}",
+            toml_string(&self.log_format.to_string())
+        )?;
+        writeln!(
+            f,
+            "access_log = {}",
+            toml_string(&self.access_log.to_string())
+        )?;
+        match self.rate_limit_rps {
+            Some(rps) => writeln!(f, "rate_limit_rps = {}", rps)?,
+            None => writeln!(f, "# rate_limit_rps is unset")?,
+        }
+        writeln!(f, "rate_limit_burst = {}", self.rate_limit_burst)?;
+        // Not printed, so that the output can be shared
+        match self.api_keys.len() {
+            0 => writeln!(f, "# api_keys is unset")?,
+            n => writeln!(f, "# api_keys: {} keys, not shown", n)?,
+        }
+        writeln!(f, "cors_origins = {}", toml_array(&self.cors_origins))?;
+        match &self.publish {
+            Some(target) if target.has_credentials() => {
+                writeln!(f, "# publish_url: {}, password not shown", target)?
+            }
+            Some(target) => writeln!(f, "publish_url = {}", toml_string(&target.to_string()))?,
+            None => writeln!(f, "# publish_url is unset")?,
+        }
+        writeln!(f, "publish_topic = {}", toml_string(&self.publish_topic))?;
+        writeln!(f, "kafka_brokers = {}", toml_array(&self.kafka_brokers))?;
+        writeln!(f, "kafka_topic = {}", toml_string(&self.kafka_topic))?;
+        match &self.shared_cache {
+            Some(url) if url.has_credentials() => {
+                writeln!(f, "# shared_cache_url: {}, password not shown", url)?
+            }
+            Some(url) => writeln!(f, "shared_cache_url = {}", toml_string(&url.to_string()))?,
+            None => writeln!(f, "# shared_cache_url is unset")?,
+        }
+        writeln!(
+            f,
+            "shared_cache_prefix = {}",
+            toml_string(&self.shared_cache_prefix)
+        )?;
+        // Not printed either, as they may carry secrets
+        match self.webhooks.len() {
+            0 => writeln!(f, "# webhooks is unset"),
+            n => writeln!(f, "# webhooks: {} webhooks, not shown", n),
+        }
+    }
+}
+
+/// A TOML basic string; JSON's escaping is a subset of TOML's.
+fn toml_string(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
+
+fn toml_array(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|s| toml_string(s)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// `url`, the value of `setting`, if it's an `http://` (h2c) or `https://` URL.
+fn check_geyser_url(setting: &str, url: String) -> Result<String, String> {
+    match url::Url::parse(&url).as_ref().map(url::Url::scheme) {
+        Ok("http" | "https") => Ok(url),
+        _ => Err(format!(
+            "Invalid {}: {} (expected http:// or https://)",
+            setting, url
+        )),
+    }
+}
+
+/// The PubSub WebSocket URL conventionally served alongside the JSON-RPC endpoint
+/// `rpc_url`: `ws(s)://` on the same host, one port up if a port is given.
+pub fn ws_url_for(rpc_url: &str) -> Option<String> {
+    let mut url = url::Url::parse(rpc_url).ok()?;
+    let scheme = match url.scheme() {
+        "http" => "ws",
+        "https" => "wss",
+        _ => return None,
+    };
+    if let Some(port) = url.port() {
+        url.set_port(Some(port.checked_add(1)?)).ok()?;
+    }
+    url.set_scheme(scheme).ok()?;
+    Some(url.to_string())
+}
+
+/// `url` as written by `url::Url`, so that equivalent spellings compare equal, or `None`
+/// unless it's an HTTP(S) URL.
+fn normalize_rpc_url(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+/// Where settings are read from: CLI flags, then environment variables, then the
+/// config file.
+struct Settings {
+    file: HashMap<String, String>, // keyed by environment variable name
+    file_path: Option<String>,
+    read: RefCell<HashSet<String>>, // variables looked up so far
+}
+
+impl Settings {
+    fn load() -> Result<Settings, String> {
+        let file_path = flag_or_env("--config", "CONFIG_FILE").filter(|p| !p.is_empty());
+        let file = match &file_path {
+            Some(path) => config_file::read(path)?,
+            None => HashMap::new(),
+        };
+        Ok(Settings {
+            file,
+            file_path,
+            read: RefCell::default(),
+        })
+    }
+
+    /// Look up a setting by its flag, then its `var` environment variable, then `var` in
+    /// lowercase in the config file.
+    fn get(&self, flag: &str, var: &str) -> Option<String> {
+        self.read.borrow_mut().insert(var.to_string());
+        flag_or_env(flag, var).or_else(|| self.file.get(var).cloned())
+    }
+
+    /// Parse a setting with `FromStr`, rejecting unparseable values and those failing `valid`.
+    fn parse<T: FromStr>(
+        &self,
+        flag: &str,
+        var: &str,
+        default: T,
+        valid: impl Fn(&T) -> bool,
+    ) -> Result<T, String> {
+        match self.get(flag, var) {
+            Some(v) => match v.parse::<T>() {
+                Ok(n) if valid(&n) => Ok(n),
+                _ => Err(format!("Invalid {}: {}", var, v)),
+            },
+            None => Ok(default),
+        }
+    }
+
+    /// Reject config file keys that no setting was read from, most likely typos.
+    fn check_unknown_keys(&self) -> Result<(), String> {
+        let read = self.read.borrow();
+        let mut unknown: Vec<String> = self
+            .file
+            .keys()
+            .filter(|k| !read.contains(*k))
+            .map(|k| k.to_lowercase())
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort();
+        Err(format!(
+            "Unknown setting in {}: {}",
+            self.file_path.as_deref().unwrap_or("config file"),
+            unknown.join(", ")
+        ))
+    }
+}
+
+/// Look up `--flag value` / `--flag=value` on the command line, then the `var` env variable.
+pub fn flag_or_env(flag: &str, var: &str) -> Option<String> {
+    flag_value(flag).or_else(|| env::var(var).ok())
+}
+
+/// Look up `--flag value` / `--flag=value` on the command line.
+pub fn flag_value(flag: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(v) = arg
+            .strip_prefix(flag)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(v.to_string());
+        }
+    }
+    None
+}
+
+/// Parse a duration such as `90s`, `15m`, `6h` or `7d`; a bare number is seconds.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    n.parse::<u64>()
+        .ok()?
+        .checked_mul(secs)
+        .map(Duration::from_secs)
+}
+
+/// Whether `s` is `*` or a browser origin: `scheme://host[:port]`, without a path.
+fn is_valid_origin(s: &str) -> bool {
+    s == "*"
+        || url::Url::parse(s)
+            .is_ok_and(|url| url.has_host() && url.origin().ascii_serialization() == s)
+}
+
+/// Parse a comma-separated list of program addresses, dropping repeats.
+fn parse_programs(programs: &str) -> Result<Vec<String>, String> {
+    let mut tracked_programs: Vec<String> = Vec::new();
+    for program in programs.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        if !is_valid_address(program) {
+            return Err(format!("Invalid tracked program: {}", program));
+        }
+        if !tracked_programs.iter().any(|p| p == program) {
+            tracked_programs.push(program.to_string());
+        }
+    }
+    if tracked_programs.is_empty() {
+        return Err("At least one tracked program is required".to_string());
+    }
+    Ok(tracked_programs)
+}
+
+/// Whether `s` can name a cluster: lowercase letters, digits and dashes, and not the
+/// start of another path.
+fn is_valid_cluster_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !RESERVED_CLUSTER_NAMES.contains(&s)
+}
+
+/// `pool`'s endpoints as configured, e.g. `url;weight=2`.
+fn endpoint_urls(pool: &RpcPool) -> Vec<String> {
+    pool.endpoint_configs()
+        .iter()
+        .map(|e| e.to_string())
+        .collect()
+}
+
+/// Whether `a` and `b` are the same clusters, read from the same endpoints for the
+/// same programs.
+fn same_clusters(a: &[Cluster], b: &[Cluster]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.name == b.name
+                && endpoint_urls(&a.rpc) == endpoint_urls(&b.rpc)
+                && a.rpc_ws_url == b.rpc_ws_url
+                && a.geyser_url == b.geyser_url
+                && a.geyser_x_token == b.geyser_x_token
+                && a.tracked_programs == b.tracked_programs
+        })
+}
+
+/// Whether `s` looks like a base58-encoded 32-byte Solana address.
+pub fn is_valid_address(s: &str) -> bool {
+    (32..=44).contains(&s.len())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() && !matches!(c, '0' | 'O' | 'I' | 'l'))
+}
@@ -0,0 +1,314 @@
+//! Reader for `config.toml`: the subset of TOML a flat settings file needs. Each key is
+//! a setting's environment variable name in lowercase; values are strings, integers,
+//! floats, booleans, or arrays of those (which stand for comma-separated lists, so their
+//! items can't contain commas).
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Read and parse the settings file at `path`, keyed by environment variable name.
+pub fn read(path: &str) -> Result<HashMap<String, String>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+    parse(&text).map_err(|e| format!("{}:{}", path, e))
+}
+
+/// Parse settings from TOML text; errors are prefixed with their line number.
+pub fn parse(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let mut settings = HashMap::new();
+    loop {
+        parser.skip_blank_lines();
+        match parser.chars.peek() {
+            None => return Ok(settings),
+            Some('[') => return Err(parser.error("tables are not supported; use top-level keys")),
+            Some(_) => {}
+        }
+        let key = parser.key()?;
+        parser.skip_spaces();
+        if parser.chars.next() != Some('=') {
+            return Err(parser.error(&format!("expected '=' after {}", key)));
+        }
+        parser.skip_spaces();
+        let value = parser.value()?;
+        if settings.insert(key.to_uppercase(), value).is_some() {
+            return Err(parser.error(&format!("duplicate key {}", key)));
+        }
+        parser.end_of_line()?;
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize, // for error messages
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{}: {}", self.line, message)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|c| matches!(c, ' ' | '\t')).is_some() {}
+    }
+
+    fn skip_comment(&mut self) {
+        if self.chars.next_if_eq(&'#').is_some() {
+            while self.chars.next_if(|&c| c != '\n').is_some() {}
+        }
+    }
+
+    /// Skip whitespace, comments and newlines, as between keys and inside arrays.
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.chars.peek() {
+                Some('\n') => self.line += 1,
+                Some('\r') => {}
+                _ => return,
+            }
+            self.chars.next();
+        }
+    }
+
+    /// Expect nothing but a comment before the next newline.
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        self.skip_comment();
+        self.chars.next_if_eq(&'\r');
+        match self.chars.next() {
+            None => Ok(()),
+            Some('\n') => {
+                self.line += 1;
+                Ok(())
+            }
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        let mut key = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+        {
+            key.push(c);
+        }
+        if key.is_empty() {
+            return Err(self.error("expected a key"));
+        }
+        Ok(key)
+    }
+
+    /// A value as the setting's string form: arrays become comma-separated lists.
+    fn value(&mut self) -> Result<String, String> {
+        if self.chars.next_if_eq(&'[').is_none() {
+            return self.scalar();
+        }
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.chars.next_if_eq(&']').is_some() {
+                return Ok(items.join(","));
+            }
+            if self.chars.peek() == Some(&'[') {
+                return Err(self.error("nested arrays are not supported"));
+            }
+            let item = self.scalar()?;
+            if item.contains(',') {
+                return Err(self.error("array items can't contain ','"));
+            }
+            items.push(item);
+            self.skip_blank_lines();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(']') => return Ok(items.join(",")),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<String, String> {
+        match self.chars.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => self.bare_value(),
+        }
+    }
+
+    /// A `"..."` string, with escapes.
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.chars.next();
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some(u @ ('u' | 'U')) => {
+                            let len = if u == 'u' { 4 } else { 8 };
+                            let hex: String = self.chars.by_ref().take(len).collect();
+                            Some(hex)
+                                .filter(|h| {
+                                    h.len() == len && h.chars().all(|c| c.is_ascii_hexdigit())
+                                })
+                                .and_then(|h| u32::from_str_radix(&h, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?
+                        }
+                        _ => return Err(self.error("invalid escape in string")),
+                    };
+                    s.push(c);
+                }
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// A `'...'` string, taken verbatim.
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.chars.next();
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('\'') => return Ok(s),
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// A boolean or a number; underscores between digits are dropped.
+    fn bare_value(&mut self) -> Result<String, String> {
+        let mut token = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '+' | '-'))
+        {
+            token.push(c);
+        }
+        if token == "true" || token == "false" {
+            return Ok(token);
+        }
+        let number: String = token.chars().filter(|&c| c != '_').collect();
+        let is_number = number
+            .trim_start_matches(['+', '-'])
+            .starts_with(|c: char| c.is_ascii_digit())
+            && number.parse::<f64>().is_ok();
+        if !is_number {
+            return Err(self.error("expected a string, number, boolean or array (quote strings)"));
+        }
+        Ok(number.trim_start_matches('+').to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn values_become_setting_strings() {
+        let text = "\
+# Settings
+listen_addr = \"0.0.0.0:43278\"  # trailing comment
+rpc_urls = [
+  \"https://a.example.com;weight=80\", # first
+  'https://b.example.com',
+]
+refresh_interval_secs = 5
+max_fee_clamp = +500_000
+percentile = 0.75
+adaptive_sampling = false
+empty = []\r
+";
+        assert_eq!(
+            parse(text).unwrap(),
+            settings(&[
+                ("LISTEN_ADDR", "0.0.0.0:43278"),
+                (
+                    "RPC_URLS",
+                    "https://a.example.com;weight=80,https://b.example.com"
+                ),
+                ("REFRESH_INTERVAL_SECS", "5"),
+                ("MAX_FEE_CLAMP", "500000"),
+                ("PERCENTILE", "0.75"),
+                ("ADAPTIVE_SAMPLING", "false"),
+                ("EMPTY", ""),
+            ])
+        );
+        assert_eq!(parse("").unwrap(), HashMap::new());
+    }
+
+    #[test]
+    fn basic_strings_are_unescaped() {
+        let value = |s: &str| parse(&format!("k = {}", s)).map(|mut m| m.remove("K").unwrap());
+        assert_eq!(value(r#""a\"b\\c\nd\te\rf""#).unwrap(), "a\"b\\c\nd\te\rf");
+        assert_eq!(value(r#""\u00e9\U0001F600""#).unwrap(), "\u{e9}\u{1f600}");
+        assert_eq!(value(r#"'C:\path\n'"#).unwrap(), r"C:\path\n");
+        for s in [
+            r#""\x41""#,
+            r#""\u00e""#,
+            r#""\u+0e9""#,
+            r#""\ud800""#,
+            r#""\U00110000""#,
+        ] {
+            assert!(value(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        for (text, error) in [
+            ("a = 1\n\nb = 'x", "3: unterminated string"),
+            ("a = \"x\ny\"", "1: unterminated string"),
+            ("a = 1\nb = 2\na = 3", "3: duplicate key a"),
+            ("A = 1\na = 2", "2: duplicate key a"),
+            (
+                "a = 1\n[server]\nb = 2",
+                "2: tables are not supported; use top-level keys",
+            ),
+            ("# x\na 1", "2: expected '=' after a"),
+            ("= 1", "1: expected a key"),
+            ("a = 1 2", "1: unexpected '2'"),
+            (
+                "a = yes",
+                "1: expected a string, number, boolean or array (quote strings)",
+            ),
+            ("a = [1, [2]]", "1: nested arrays are not supported"),
+            ("a = [\n  1\n  2\n]", "3: expected ',' or ']' in array"),
+            ("a = \"\\q\"", "1: invalid escape in string"),
+            ("a = \"\\u12\"", "1: invalid unicode escape"),
+        ] {
+            assert_eq!(parse(text).unwrap_err(), error, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn array_items_cant_contain_commas() {
+        assert_eq!(
+            parse("a = [\"a,b\"]").unwrap_err(),
+            "1: array items can't contain ','"
+        );
+        assert_eq!(parse("a = ['a', 'b']").unwrap(), settings(&[("A", "a,b")]));
+        // A single string may still be a list itself
+        assert_eq!(parse("a = \"a,b\"").unwrap(), settings(&[("A", "a,b")]));
+    }
+}
@@ -0,0 +1,79 @@
+//! A coarse reading of how congested the network is, from the fees transactions are
+//! paying and how quickly the cluster is producing slots.
+
+use crate::rpc::PerformanceSample;
+use serde::Serialize;
+
+/// Median fees (micro-lamports per CU) at or above which congestion is normal, high and
+/// extreme.
+pub const NORMAL_FEE: u64 = 10_000;
+pub const HIGH_FEE: u64 = 100_000;
+pub const EXTREME_FEE: u64 = 1_000_000;
+/// Mean slot time above which the cluster is falling behind its 400ms target, which
+/// raises the level one step.
+pub const SLOW_SLOT_MS: f64 = 600.0;
+/// How many performance samples (about a minute each) are averaged.
+pub const PERFORMANCE_SAMPLES: usize = 5;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum CongestionLevel {
+    Low,
+    Normal,
+    High,
+    Extreme,
+}
+
+impl CongestionLevel {
+    /// The level for a median fee of `median_fee`, a step higher if slots are taking
+    /// longer than `SLOW_SLOT_MS`.
+    pub fn classify(median_fee: u64, performance: Option<&Performance>) -> CongestionLevel {
+        let level = match median_fee {
+            f if f >= EXTREME_FEE => CongestionLevel::Extreme,
+            f if f >= HIGH_FEE => CongestionLevel::High,
+            f if f >= NORMAL_FEE => CongestionLevel::Normal,
+            _ => CongestionLevel::Low,
+        };
+        match performance {
+            Some(p) if p.slot_time_ms > SLOW_SLOT_MS => level.raised(),
+            _ => level,
+        }
+    }
+
+    fn raised(self) -> CongestionLevel {
+        match self {
+            CongestionLevel::Low => CongestionLevel::Normal,
+            CongestionLevel::Normal => CongestionLevel::High,
+            CongestionLevel::High | CongestionLevel::Extreme => CongestionLevel::Extreme,
+        }
+    }
+}
+
+/// Throughput averaged over recent performance samples.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Performance {
+    pub tps: f64,
+    pub non_vote_tps: Option<f64>, // if every sample reports it
+    pub slot_time_ms: f64,
+    pub samples: usize,
+}
+
+impl Performance {
+    /// The averages over `samples`, or `None` if they cover no time or no slots.
+    pub fn from_samples(samples: &[PerformanceSample]) -> Option<Performance> {
+        let secs: u64 = samples.iter().map(|s| s.sample_period_secs).sum();
+        let slots: u64 = samples.iter().map(|s| s.num_slots).sum();
+        if secs == 0 || slots == 0 {
+            return None;
+        }
+        let transactions: u64 = samples.iter().map(|s| s.num_transactions).sum();
+        let non_vote: Option<u64> = samples.iter().map(|s| s.num_non_vote_transactions).sum();
+        Some(Performance {
+            tps: transactions as f64 / secs as f64,
+            non_vote_tps: non_vote.map(|n| n as f64 / secs as f64),
+            slot_time_ms: secs as f64 * 1000.0 / slots as f64,
+            samples: samples.len(),
+        })
+    }
+}
@@ -0,0 +1,306 @@
+//! Every refresh recorded to a SQLite or PostgreSQL database, which backs `/history`
+//! when configured. Unlike the in-memory history it survives restarts, and it's kept for
+//! as long as the retention window says, pruned as it goes. Both databases get the same
+//! schema and migrations, and the same SQL.
+
+use crate::history::HistoryPoint;
+use crate::{postgres, sqlite};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+pub const DEFAULT_DB_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often refreshes older than the retention window are deleted.
+const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+/// The PostgreSQL advisory lock held while migrating.
+const MIGRATION_LOCK_ID: u64 = 0x6976_7970_6669; // "ivypfi"
+/// Schema changes, in order. A database's version is the number it has had applied.
+const MIGRATIONS: [&str; 2] = [
+    "CREATE TABLE refreshes (
+        program TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        p25 BIGINT NOT NULL,
+        p50 BIGINT NOT NULL,
+        p75 BIGINT NOT NULL,
+        p90 BIGINT NOT NULL,
+        sample_size BIGINT NOT NULL,
+        min_slot BIGINT,
+        max_slot BIGINT,
+        source TEXT NOT NULL
+    )",
+    "CREATE INDEX refreshes_program_timestamp ON refreshes (program, timestamp)",
+];
+
+/// Where the history database is: a SQLite file, or a PostgreSQL server's
+/// `postgres://` URL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DatabaseTarget {
+    Sqlite(PathBuf),
+    Postgres(String),
+}
+
+/// Shows the URL without its password.
+impl fmt::Display for DatabaseTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatabaseTarget::Sqlite(path) => path.display().fmt(f),
+            DatabaseTarget::Postgres(url) => f.write_str(&crate::proxy::redact(url)),
+        }
+    }
+}
+
+/// A value bound to a statement or read from a row.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl Value {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Integer(n) => Some(*n),
+            Value::Real(x) => Some(*x as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Value {
+        Value::Integer(n as i64)
+    }
+}
+
+impl From<Option<u64>> for Value {
+    fn from(n: Option<u64>) -> Value {
+        n.map_or(Value::Null, Value::from)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+}
+
+/// A connection to either database.
+enum Connection {
+    Sqlite(sqlite::Connection),
+    Postgres(postgres::Connection),
+}
+
+impl Connection {
+    fn execute(&self, sql: &str, params: &[Value]) -> io::Result<()> {
+        match self {
+            Connection::Sqlite(conn) => conn.execute(sql, params),
+            Connection::Postgres(conn) => conn.execute(sql, params),
+        }
+    }
+
+    fn query(&self, sql: &str, params: &[Value]) -> io::Result<Vec<Vec<Value>>> {
+        match self {
+            Connection::Sqlite(conn) => conn.query(sql, params),
+            Connection::Postgres(conn) => conn.query(sql, params),
+        }
+    }
+
+    /// Begin a transaction in which no other connection can migrate the schema, so that
+    /// replicas starting together don't apply a migration twice.
+    fn begin_migration(&self) -> io::Result<()> {
+        match self {
+            Connection::Sqlite(conn) => conn.execute("BEGIN IMMEDIATE", &[]),
+            Connection::Postgres(conn) => {
+                conn.execute("BEGIN", &[])?;
+                conn.execute(
+                    "SELECT pg_advisory_xact_lock($1)",
+                    &[MIGRATION_LOCK_ID.into()],
+                )
+            }
+        }
+    }
+}
+
+/// The history database.
+pub struct Database {
+    conn: Mutex<Connection>,
+    retention: Duration,
+    pruned_at: AtomicU64, // unix seconds
+}
+
+impl Database {
+    /// Open the database at `target`, creating it or bringing its schema up to date as
+    /// needed, and prune it.
+    pub fn open(target: &DatabaseTarget, retention: Duration) -> io::Result<Database> {
+        let conn = match target {
+            DatabaseTarget::Sqlite(path) => Connection::Sqlite(sqlite::Connection::open(path)?),
+            DatabaseTarget::Postgres(url) => Connection::Postgres(postgres::Connection::open(url)?),
+        };
+        migrate(&conn)?;
+        let db = Database {
+            conn: Mutex::new(conn),
+            retention,
+            pruned_at: AtomicU64::new(0),
+        };
+        db.prune(crate::estimator::unix_now())?;
+        Ok(db)
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Record a refresh, pruning old ones every `PRUNE_INTERVAL_SECS`.
+    pub fn record(&self, point: &HistoryPoint) -> io::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO refreshes
+                (program, timestamp, p25, p50, p75, p90, sample_size, min_slot, max_slot, source)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            &[
+                point.program.as_str().into(),
+                point.timestamp.into(),
+                point.p25.into(),
+                point.p50.into(),
+                point.p75.into(),
+                point.p90.into(),
+                (point.sample_size as u64).into(),
+                point.min_slot.into(),
+                point.max_slot.into(),
+                point.source.as_deref().unwrap_or("unknown").into(),
+            ],
+        )?;
+        if point.timestamp >= self.pruned_at.load(Ordering::Relaxed) + PRUNE_INTERVAL_SECS {
+            self.prune(point.timestamp)?;
+        }
+        Ok(())
+    }
+
+    /// `program`'s refreshes from `since` onwards, averaged into buckets of `step`
+    /// seconds starting at `since`, as `History::series` does.
+    pub fn series(&self, program: &str, since: u64, step: u64) -> io::Result<Vec<HistoryPoint>> {
+        let rows = self.conn.lock().unwrap().query(
+            "SELECT (timestamp - $1) / $2 AS bucket,
+                    AVG(p25), AVG(p50), AVG(p75), AVG(p90), SUM(sample_size),
+                    MIN(min_slot), MAX(max_slot),
+                    CASE WHEN COUNT(DISTINCT source) = 1 THEN MIN(source) ELSE 'mixed' END
+             FROM refreshes
+             WHERE program = $3 AND timestamp >= $1
+             GROUP BY bucket
+             ORDER BY bucket",
+            &[since.into(), step.into(), program.into()],
+        )?;
+        let int = |v: &Value| v.as_i64().unwrap_or(0).max(0) as u64;
+        Ok(rows
+            .iter()
+            .map(|row| HistoryPoint {
+                program: program.to_string(),
+                timestamp: since + int(&row[0]) * step,
+                p25: int(&row[1]),
+                p50: int(&row[2]),
+                p75: int(&row[3]),
+                p90: int(&row[4]),
+                sample_size: int(&row[5]) as usize,
+                min_slot: row[6].as_i64().map(|s| s as u64),
+                max_slot: row[7].as_i64().map(|s| s as u64),
+                source: row[8].as_str().map(str::to_string),
+            })
+            .collect())
+    }
+
+    /// The refreshes from `from` up to `to`, of `program` or of every program, in the
+    /// order they were recorded, as `History::range` returns them.
+    pub fn range(
+        &self,
+        program: Option<&str>,
+        from: u64,
+        to: u64,
+    ) -> io::Result<Vec<HistoryPoint>> {
+        let columns = "SELECT program, timestamp, p25, p50, p75, p90, sample_size, min_slot, \
+                       max_slot, source FROM refreshes WHERE timestamp >= $1 AND timestamp < $2";
+        let conn = self.conn.lock().unwrap();
+        let rows = match program {
+            Some(program) => conn.query(
+                &format!("{} AND program = $3 ORDER BY timestamp", columns),
+                &[from.into(), to.into(), program.into()],
+            )?,
+            None => conn.query(
+                &format!("{} ORDER BY timestamp, program", columns),
+                &[from.into(), to.into()],
+            )?,
+        };
+        let int = |v: &Value| v.as_i64().unwrap_or(0).max(0) as u64;
+        Ok(rows
+            .iter()
+            .map(|row| HistoryPoint {
+                program: row[0].as_str().unwrap_or_default().to_string(),
+                timestamp: int(&row[1]),
+                p25: int(&row[2]),
+                p50: int(&row[3]),
+                p75: int(&row[4]),
+                p90: int(&row[5]),
+                sample_size: int(&row[6]) as usize,
+                min_slot: row[7].as_i64().map(|s| s as u64),
+                max_slot: row[8].as_i64().map(|s| s as u64),
+                source: row[9].as_str().map(str::to_string),
+            })
+            .collect())
+    }
+
+    /// Delete refreshes older than the retention window as of `now`.
+    fn prune(&self, now: u64) -> io::Result<()> {
+        let cutoff = now.saturating_sub(self.retention.as_secs());
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM refreshes WHERE timestamp < $1",
+            &[cutoff.into()],
+        )?;
+        self.pruned_at.store(now, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Apply the migrations `conn` hasn't had yet, together in one transaction.
+fn migrate(conn: &Connection) -> io::Result<()> {
+    conn.begin_migration()?;
+    let applied = (|| {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT NOT NULL)",
+            &[],
+        )?;
+        let rows = conn.query(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            &[],
+        )?;
+        let version = rows[0][0].as_i64().unwrap_or(0) as usize;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute(migration, &[])
+                .and_then(|()| {
+                    conn.execute(
+                        "INSERT INTO schema_migrations (version) VALUES ($1)",
+                        &[(i as u64 + 1).into()],
+                    )
+                })
+                .map_err(|e| io::Error::other(format!("migration {} failed: {}", i + 1, e)))?;
+        }
+        Ok(())
+    })();
+    match applied {
+        Ok(()) => conn.execute("COMMIT", &[]),
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", &[]);
+            Err(e)
+        }
+    }
+}
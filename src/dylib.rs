@@ -0,0 +1,58 @@
+//! Loads C libraries at runtime rather than linking them, so that optional backends
+//! such as the history database don't make every build depend on their libraries.
+
+use std::ffi::{CString, c_void};
+
+/// The first of `names` that loads, kept loaded for the life of the process.
+pub(crate) fn open(names: &[&str]) -> Result<*mut c_void, String> {
+    for name in names {
+        let c_name = CString::new(*name).unwrap();
+        // SAFETY: `c_name` is a valid C string
+        let handle = unsafe { libc::dlopen(c_name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if !handle.is_null() {
+            return Ok(handle);
+        }
+    }
+    Err(format!("couldn't load {}", names.join(" or ")))
+}
+
+/// The address of `name` in `handle`, or an error if it's missing.
+pub(crate) fn symbol(handle: *mut c_void, name: &str) -> Result<*mut c_void, String> {
+    let c_name = CString::new(name).unwrap();
+    // SAFETY: `handle` came from `dlopen` and `c_name` is a valid C string
+    let sym = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+    match sym.is_null() {
+        true => Err(format!("{} is missing from the library", name)),
+        false => Ok(sym),
+    }
+}
+
+/// Declares a struct of function pointers and a `load` that looks each one up by its
+/// field name in a library from `dylib::open`.
+macro_rules! dylib_api {
+    ($api:ident { $($name:ident: fn($($arg:ty),*) -> $ret:ty,)* }) => {
+        #[allow(non_snake_case)]
+        struct $api {
+            $($name: unsafe extern "C" fn($($arg),*) -> $ret,)*
+        }
+
+        impl $api {
+            fn load(handle: *mut std::ffi::c_void) -> Result<$api, String> {
+                Ok($api {
+                    $($name: {
+                        let sym = crate::dylib::symbol(handle, stringify!($name))?;
+                        // SAFETY: the symbol is the C function declared above
+                        unsafe {
+                            std::mem::transmute::<
+                                *mut std::ffi::c_void,
+                                unsafe extern "C" fn($($arg),*) -> $ret,
+                            >(sym)
+                        }
+                    },)*
+                })
+            }
+        }
+    };
+}
+
+pub(crate) use dylib_api;
@@ -0,0 +1,243 @@
+//! Why an estimate couldn't be made: what went wrong talking to the RPC endpoint, and
+//! the machine-readable code that tells clients whether trying again may help.
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// The kind of a failure to estimate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    RpcUnavailable, // no RPC endpoint answered, or none answered sensibly
+    BatchTooLarge,  // the RPC endpoint rejected a getTransaction batch as too big
+    EmptySample,    // nothing was left to estimate from
+    Timeout,        // the refresh or an RPC call ran out of time
+    RateLimited,    // the RPC endpoint throttled us
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::RpcUnavailable => "RPC_UNAVAILABLE",
+            ErrorCode::BatchTooLarge => "BATCH_TOO_LARGE",
+            ErrorCode::EmptySample => "EMPTY_SAMPLE",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+        }
+    }
+
+    /// Whether the same request may succeed later. Every failure to estimate is
+    /// transient; the codes differ in what clients may want to log or alert on.
+    pub fn retryable(self) -> bool {
+        true
+    }
+
+    /// The code for `e`, an error from sampling or estimating: its own if it has one,
+    /// otherwise read off the HTTP error behind it. Anything else is taken to mean the
+    /// RPC endpoint isn't usable.
+    pub fn of(e: &(dyn Error + 'static)) -> ErrorCode {
+        if let Some(e) = e.downcast_ref::<CodedError>() {
+            return e.code;
+        }
+        if let Some(e) = e.downcast_ref::<EstimatorError>() {
+            return e.code();
+        }
+        match e.downcast_ref::<ureq::Error>() {
+            Some(ureq::Error::Status(429, _)) => ErrorCode::RateLimited,
+            Some(ureq::Error::Transport(t)) if is_timeout(t) => ErrorCode::Timeout,
+            _ => ErrorCode::RpcUnavailable,
+        }
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorCode, String> {
+        [
+            ErrorCode::RpcUnavailable,
+            ErrorCode::BatchTooLarge,
+            ErrorCode::EmptySample,
+            ErrorCode::Timeout,
+            ErrorCode::RateLimited,
+        ]
+        .into_iter()
+        .find(|code| code.as_str() == s)
+        .ok_or_else(|| format!("unknown error code: {}", s))
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether a transport error, or any error behind it, is an I/O timeout.
+pub fn is_timeout(e: &(dyn Error + 'static)) -> bool {
+    std::iter::successors(Some(e), |&e| e.source()).any(|e| {
+        e.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            )
+        })
+    })
+}
+
+/// A failed RPC call.
+#[derive(Debug)]
+pub enum EstimatorError {
+    /// The endpoint couldn't be reached, or didn't answer.
+    Transport(Box<ureq::Transport>),
+    /// The response broke off, or ran over its size limit.
+    Io(io::Error),
+    /// The endpoint answered with an HTTP error.
+    Status { status: u16, body: String },
+    /// The endpoint answered with a JSON-RPC error.
+    Rpc {
+        method: &'static str,
+        code: i64,
+        message: String,
+    },
+    /// The response isn't the JSON expected.
+    Parse(serde_json::Error),
+    /// The response parsed, but doesn't answer the request.
+    Validation(String),
+    /// The endpoint answered a batch with nothing, as some do when it's too big.
+    BatchTooLarge,
+}
+
+impl EstimatorError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            EstimatorError::Transport(t) if is_timeout(&**t) => ErrorCode::Timeout,
+            EstimatorError::Io(e) if is_timeout(e) => ErrorCode::Timeout,
+            EstimatorError::Status { status: 429, .. } => ErrorCode::RateLimited,
+            EstimatorError::BatchTooLarge => ErrorCode::BatchTooLarge,
+            _ => ErrorCode::RpcUnavailable,
+        }
+    }
+
+    /// Whether the same call may succeed if made again: transport failures, throttling,
+    /// server errors and the node-side JSON-RPC errors (such as a node that's behind)
+    /// may pass; malformed requests and responses won't.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            EstimatorError::Transport(_) | EstimatorError::Io(_) => true,
+            EstimatorError::Status { status, .. } => *status == 429 || *status >= 500,
+            EstimatorError::Rpc { code, .. } => (-32099..=-32000).contains(code) || *code == -32603,
+            EstimatorError::Parse(_) | EstimatorError::Validation(_) => false,
+            EstimatorError::BatchTooLarge => true,
+        }
+    }
+}
+
+impl fmt::Display for EstimatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EstimatorError::Transport(t) => t.fmt(f),
+            EstimatorError::Io(e) => e.fmt(f),
+            EstimatorError::Status { status, body } => write!(f, "got status {}: {}", status, body),
+            EstimatorError::Rpc {
+                method,
+                code,
+                message,
+            } => write!(f, "{} error (code {}): {}", method, code, message),
+            EstimatorError::Parse(e) => write!(f, "invalid response: {}", e),
+            EstimatorError::Validation(message) => f.write_str(message),
+            EstimatorError::BatchTooLarge => {
+                f.write_str("batch size too large for destination RPC, try again!")
+            }
+        }
+    }
+}
+
+impl Error for EstimatorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EstimatorError::Transport(t) => Some(&**t),
+            EstimatorError::Io(e) => Some(e),
+            EstimatorError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ureq::Error> for EstimatorError {
+    fn from(e: ureq::Error) -> EstimatorError {
+        match e {
+            ureq::Error::Status(status, resp) => EstimatorError::Status {
+                status,
+                body: resp.into_string().unwrap_or_default(),
+            },
+            ureq::Error::Transport(t) => EstimatorError::Transport(Box::new(t)),
+        }
+    }
+}
+
+/// Reading the body fails with an I/O error, which wraps the JSON error if the body
+/// was read but didn't parse.
+impl From<io::Error> for EstimatorError {
+    fn from(e: io::Error) -> EstimatorError {
+        if e.kind() == io::ErrorKind::InvalidData
+            && e.get_ref()
+                .is_some_and(|inner| inner.is::<serde_json::Error>())
+        {
+            let inner = e.into_inner().unwrap().downcast::<serde_json::Error>();
+            return EstimatorError::Parse(*inner.unwrap());
+        }
+        EstimatorError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for EstimatorError {
+    fn from(e: serde_json::Error) -> EstimatorError {
+        match e.is_io() {
+            true => EstimatorError::Io(e.into()),
+            false => EstimatorError::Parse(e),
+        }
+    }
+}
+
+/// An error message with its code, for failures that can't be told apart by type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CodedError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> CodedError {
+        CodedError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// `e` with its code, as `ErrorCode::of` reads it.
+    pub fn from_error(e: &(dyn Error + 'static)) -> CodedError {
+        CodedError::new(ErrorCode::of(e), e.to_string())
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for CodedError {}
+
+/// As `{"code", "message", "retryable"}`.
+impl Serialize for CodedError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("CodedError", 3)?;
+        s.serialize_field("code", self.code.as_str())?;
+        s.serialize_field("message", &self.message)?;
+        s.serialize_field("retryable", &self.code.retryable())?;
+        s.end()
+    }
+}
@@ -0,0 +1,789 @@
+use crate::aggregation::{Aggregation, weighted_percentile};
+use crate::config::{Config, parse_duration};
+use crate::error::{CodedError, ErrorCode};
+use crate::explain::{Explanation, Stage};
+use crate::rpc::get_slot;
+use crate::rpc_pool::Deadline;
+use crate::smoothing::Smoothed;
+use crate::sources::{FeeSource, RecentPrioritizationFees, Sample};
+use crate::telemetry::{self, SpanKind};
+use crate::trend::{FeeTrend, Trend};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Default ceiling estimates are clamped to.
+pub const MAX_PRIORITY_FEE: u64 = 999_999;
+/// First tertile, matching the service's historical behavior.
+pub const DEFAULT_PERCENTILE: f64 = 100.0 / 3.0;
+/// Percentiles of `PriorityFeeLevels`' fields, in declaration order.
+pub const LEVEL_PERCENTILES: [f64; 6] = [0.0, 25.0, 50.0, 75.0, 95.0, 100.0];
+/// Percentile of sampled transactions' compute units that the recommended compute unit
+/// limit covers, and the headroom added on top.
+pub const COMPUTE_UNIT_LIMIT_PERCENTILE: f64 = 90.0;
+pub const COMPUTE_UNIT_LIMIT_MARGIN: f64 = 0.1;
+/// The most compute units a transaction may request.
+pub const MAX_COMPUTE_UNIT_LIMIT: u64 = 1_400_000;
+pub const LAMPORTS_PER_SOL: u64 = 1_000_000_000;
+/// How many times more a sample in a transaction's local fee market counts towards its
+/// estimate than one outside it.
+pub const LOCAL_MARKET_WEIGHT: f64 = 10.0;
+
+/// A sample of recent priority fees, along with when and how it was taken.
+#[derive(Clone)]
+pub struct Estimate {
+    pub samples: Arc<Vec<Sample>>,
+    pub selected: Arc<Vec<Sample>>, // the samples fees are read from, sorted by price
+    pub include_failed: bool,       // whether failed transactions' samples are selected
+    pub last_updated: u64,          // unix seconds
+    pub source: &'static str,       // `FeeSource::name` of the source that produced `samples`
+    pub smoothed: Option<Arc<Smoothed>>, // EWMA across refreshes, if tracked
+    pub trend: Option<Arc<Trend>>,  // fees over the latest refreshes, if tracked
+    pub min_fee: u64,               // fees read off the sample are clamped to
+    pub max_fee: u64,               // [min_fee, max_fee]
+    pub stale: bool,                // served on after later refreshes failed
+    pub low_confidence: bool,       // fewer samples than `Config::min_sample_fraction` calls for
+    pub explanation: Option<Arc<Explanation>>, // how the refresh went, if made here
+}
+
+impl Estimate {
+    pub fn new(
+        samples: Vec<Sample>,
+        include_failed: bool,
+        last_updated: u64,
+        source: &'static str,
+    ) -> Estimate {
+        Estimate {
+            selected: Arc::new(select(&samples, include_failed)),
+            samples: Arc::new(samples),
+            include_failed,
+            last_updated,
+            source,
+            smoothed: None,
+            trend: None,
+            min_fee: 0,
+            max_fee: MAX_PRIORITY_FEE,
+            stale: false,
+            low_confidence: false,
+            explanation: None,
+        }
+    }
+
+    /// The same estimate with failed transactions selected or not.
+    pub fn with_include_failed(&self, include_failed: bool) -> Estimate {
+        if include_failed == self.include_failed {
+            return self.clone();
+        }
+        Estimate {
+            selected: Arc::new(select(&self.samples, include_failed)),
+            include_failed,
+            ..self.clone()
+        }
+    }
+
+    /// The fee at percentile `p` (0-100), clamped at [min_fee, max_fee].
+    pub fn fee_at(&self, p: f64) -> u64 {
+        self.fee(p, Aggregation::Percentile)
+    }
+
+    /// The fee at percentile `p` (0-100) under `aggregation`, clamped at
+    /// [min_fee, max_fee].
+    pub fn fee(&self, p: f64, aggregation: Aggregation) -> u64 {
+        self.clamp(aggregation.fee_at(&self.selected, p))
+    }
+
+    /// An approximate 95% confidence interval around `fee(p, aggregation)`, clamped
+    /// likewise.
+    pub fn fee_interval(&self, p: f64, aggregation: Aggregation) -> (u64, u64) {
+        let (lo, hi) = aggregation.interval_at(&self.selected, p);
+        (self.clamp(lo), self.clamp(hi))
+    }
+
+    /// Fee levels at fixed percentiles, mirroring Helius' getPriorityFeeEstimate.
+    pub fn levels(&self) -> PriorityFeeLevels {
+        self.levels_with(Aggregation::Percentile)
+    }
+
+    pub fn levels_with(&self, aggregation: Aggregation) -> PriorityFeeLevels {
+        PriorityFeeLevels::from_fn(|i| self.level(LEVEL_PERCENTILES[i], aggregation))
+    }
+
+    /// The fee level at percentile `p` under `aggregation`.
+    pub fn level(&self, p: f64, aggregation: Aggregation) -> u64 {
+        self.clamp(aggregation.level_at(&self.selected, p))
+    }
+
+    /// A compute unit limit that covers `COMPUTE_UNIT_LIMIT_PERCENTILE` of the sampled
+    /// transactions with `COMPUTE_UNIT_LIMIT_MARGIN` to spare, or `None` if the source
+    /// doesn't report compute units.
+    pub fn compute_unit_limit(&self) -> Option<u64> {
+        let mut units: Vec<u64> = self
+            .selected
+            .iter()
+            .filter_map(|s| s.compute_units)
+            .collect();
+        if units.is_empty() {
+            return None;
+        }
+        units.sort_unstable();
+        Some(compute_unit_limit_for(nearest_rank(
+            &units,
+            COMPUTE_UNIT_LIMIT_PERCENTILE,
+        )))
+    }
+
+    /// The Jito tip in lamports at percentile `p` of the sampled transactions that
+    /// tipped, or `None` if none did.
+    pub fn jito_tip(&self, p: f64) -> Option<u64> {
+        let tips = self.jito_tips();
+        (!tips.is_empty()).then(|| nearest_rank(&tips, p))
+    }
+
+    /// Jito tip levels at the same percentiles as `levels`, or `None` if no sampled
+    /// transaction tipped.
+    pub fn jito_tip_levels(&self) -> Option<PriorityFeeLevels> {
+        let tips = self.jito_tips();
+        (!tips.is_empty())
+            .then(|| PriorityFeeLevels::from_fn(|i| nearest_rank(&tips, LEVEL_PERCENTILES[i])))
+    }
+
+    /// A log-scaled histogram of the selected samples' fees, unclamped, with their
+    /// summary statistics; `None` if there are none.
+    pub fn distribution(&self) -> Option<Distribution> {
+        let fees = &self.selected;
+        let (min, max) = (fees.first()?.price, fees.last()?.price);
+        let n = fees.len() as f64;
+        let mean = fees.iter().map(|s| s.price as f64).sum::<f64>() / n;
+        let variance = fees
+            .iter()
+            .map(|s| (s.price as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let mut buckets: Vec<Bucket> = vec![];
+        let mut lower = 0;
+        for upper in bucket_edges() {
+            if lower > max || upper <= lower {
+                break;
+            }
+            // `fees` is sorted by price, so counting is two binary searches
+            let count = fees.partition_point(|s| s.price < upper)
+                - fees.partition_point(|s| s.price < lower);
+            if count > 0 || !buckets.is_empty() {
+                buckets.push(Bucket {
+                    min: lower,
+                    max: upper,
+                    count,
+                });
+            }
+            lower = upper;
+        }
+        Some(Distribution {
+            buckets,
+            min,
+            max,
+            mean,
+            stddev: variance.sqrt(),
+            sample_size: fees.len(),
+        })
+    }
+
+    /// The selected samples' Jito tips, sorted.
+    pub fn jito_tips(&self) -> Vec<u64> {
+        let mut tips: Vec<u64> = self.selected.iter().filter_map(|s| s.jito_tip).collect();
+        tips.sort_unstable();
+        tips
+    }
+
+    /// The fee at percentile `p` for a transaction write-locking `accounts`: samples that
+    /// also write-locked any of them (its local fee market) weigh `LOCAL_MARKET_WEIGHT`
+    /// times as much as the rest. Returns the fee and the number of such samples.
+    pub fn local_fee(&self, p: f64, accounts: &[String]) -> (u64, usize) {
+        let accounts: HashSet<&str> = accounts.iter().map(String::as_str).collect();
+        let is_local = |s: &Sample| {
+            s.writable_accounts
+                .iter()
+                .any(|a| accounts.contains(a.as_str()))
+        };
+        let local_samples = self.selected.iter().filter(|s| is_local(s)).count();
+        let fee = weighted_percentile(&self.selected, p, |s| {
+            if is_local(s) {
+                LOCAL_MARKET_WEIGHT
+            } else {
+                1.0
+            }
+        });
+        (self.clamp(fee), local_samples)
+    }
+
+    /// The same estimate with fees clamped to [min_fee, max_fee].
+    pub fn with_fee_bounds(self, min_fee: u64, max_fee: u64) -> Estimate {
+        Estimate {
+            min_fee,
+            max_fee,
+            ..self
+        }
+    }
+
+    /// The same estimate, marked stale or not.
+    pub fn with_stale(self, stale: bool) -> Estimate {
+        Estimate { stale, ..self }
+    }
+
+    /// The same estimate, flagged as taken from too few samples or not.
+    pub fn with_low_confidence(self, low_confidence: bool) -> Estimate {
+        Estimate {
+            low_confidence,
+            ..self
+        }
+    }
+
+    fn clamp(&self, fee: u64) -> u64 {
+        fee.clamp(self.min_fee, self.max_fee)
+    }
+
+    /// The lowest and highest slots of the selected samples, if the source reports slots.
+    pub fn slot_range(&self) -> Option<(u64, u64)> {
+        let slots = self.selected.iter().filter_map(|s| s.slot);
+        Some((slots.clone().min()?, slots.max()?))
+    }
+
+    /// The smoothed fee at percentile `p`, if one was tracked for `aggregation` and this
+    /// estimate's failed-transaction selection.
+    pub fn smoothed_fee(&self, p: f64, aggregation: Aggregation) -> Option<u64> {
+        self.smoothed_for(aggregation).map(|s| s.fee_at(p))
+    }
+
+    pub fn smoothed_levels(&self, aggregation: Aggregation) -> Option<PriorityFeeLevels> {
+        self.smoothed_for(aggregation).map(|s| s.levels())
+    }
+
+    /// Which way the fee at percentile `p` is heading and its forecast, clamped like
+    /// fees, if a trend over at least two refreshes was tracked for `aggregation` and
+    /// this estimate's failed-transaction selection.
+    pub fn fee_trend(&self, p: f64, aggregation: Aggregation) -> Option<FeeTrend> {
+        let trend = self
+            .trend
+            .as_deref()
+            .filter(|t| t.aggregation == aggregation && t.include_failed == self.include_failed)?;
+        let mut fee_trend = trend.fee_trend(p)?;
+        for fee in &mut fee_trend.forecast {
+            *fee = self.clamp(*fee);
+        }
+        Some(fee_trend)
+    }
+
+    fn smoothed_for(&self, aggregation: Aggregation) -> Option<&Smoothed> {
+        self.smoothed
+            .as_deref()
+            .filter(|s| s.aggregation == aggregation && s.include_failed == self.include_failed)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PriorityFeeLevels {
+    pub min: u64,
+    pub low: u64,
+    pub medium: u64,
+    pub high: u64,
+    pub very_high: u64,
+    pub unsafe_max: u64,
+}
+
+impl PriorityFeeLevels {
+    /// Build levels from the fee for each index into `LEVEL_PERCENTILES`.
+    pub fn from_fn(level: impl Fn(usize) -> u64) -> PriorityFeeLevels {
+        PriorityFeeLevels {
+            min: level(0),
+            low: level(1),
+            medium: level(2),
+            high: level(3),
+            very_high: level(4),
+            unsafe_max: level(5),
+        }
+    }
+}
+
+/// How the selected samples' fees are spread, in micro-lamports per CU.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Distribution {
+    pub buckets: Vec<Bucket>, // from the lowest to the highest non-empty bucket
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stddev: f64, // of the population
+    pub sample_size: usize,
+}
+
+/// The number of fees in `[min, max)`.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Bucket {
+    pub min: u64,
+    pub max: u64,
+    pub count: usize,
+}
+
+/// The upper edges of log-scaled buckets, 1, 2, 5, 10, 20, 50 and so on, so fees of 0
+/// get a bucket of their own. They saturate at `u64::MAX`.
+fn bucket_edges() -> impl Iterator<Item = u64> {
+    (0..).flat_map(|d| [1u64, 2, 5].map(|m| m.saturating_mul(10u64.saturating_pow(d))))
+}
+
+/// Samples recent priority fees for the configured programs.
+///
+/// ```no_run
+/// use ivy_priority_fee::{Config, PriorityFeeEstimator};
+///
+/// let estimator = PriorityFeeEstimator::new(Config::default());
+/// let estimate = estimator.estimate().unwrap();
+/// println!("median fee: {}", estimate.fee_at(50.0));
+/// ```
+pub struct PriorityFeeEstimator {
+    config: RwLock<Arc<Config>>, // replaced wholesale by `set_config`
+    smoothed: Mutex<HashMap<String, Arc<Smoothed>>>, // latest EWMA by program
+    trends: Mutex<HashMap<String, Arc<Trend>>>, // latest refreshes by program
+}
+
+impl PriorityFeeEstimator {
+    pub fn new(config: Config) -> PriorityFeeEstimator {
+        PriorityFeeEstimator {
+            config: RwLock::new(Arc::new(config)),
+            smoothed: Mutex::new(HashMap::new()),
+            trends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The current settings. Estimates already under way keep the settings they started
+    /// with if `set_config` replaces them.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replace the settings, e.g. after reloading them; see `Config::reloaded`.
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    /// Estimate for the primary (first) tracked program; an error if none is tracked.
+    pub fn estimate(&self) -> Result<Estimate, Box<dyn std::error::Error>> {
+        let config = self.config();
+        let program = config
+            .tracked_programs
+            .first()
+            .ok_or("no tracked programs to estimate for")?;
+        self.estimate_program(program)
+    }
+
+    /// Sample `program` with the configured source, falling back to
+    /// getRecentPrioritizationFees if that fails, sampling less while the RPC endpoints
+    /// rate limit calls (see `Config::throttled`). Each successful estimate is also
+    /// tracked; see `track`.
+    pub fn estimate_program(&self, program: &str) -> Result<Estimate, Box<dyn std::error::Error>> {
+        let config = &self.config().throttled();
+        let _deadline = Deadline::after(config.refresh_timeout).enter();
+        let started = Instant::now();
+        let mut trail = Explanation::default();
+        let span = telemetry::span("sample", SpanKind::Internal);
+        span.set("fee_source", config.fee_source.name());
+        let sampled = sample_filtered(config, &*config.fee_source, program, &mut trail);
+        if let Err(e) = &sampled {
+            span.set_error(&e.to_string());
+        }
+        drop(span);
+        let (samples, source, target) = match sampled {
+            Ok(fees) => {
+                let target = config.fee_source.target_samples(config);
+                (fees, config.fee_source.name(), target)
+            }
+            Err(e) if config.fee_source.name() != RecentPrioritizationFees.name() => {
+                log::warn!(
+                    "{} failed for {}: {}, falling back to getRecentPrioritizationFees",
+                    config.fee_source.name(),
+                    program,
+                    e
+                );
+                trail.fallback_reason = Some(e.to_string());
+                let span = telemetry::span("sample", SpanKind::Internal);
+                span.set("fee_source", RecentPrioritizationFees.name());
+                span.set("fallback", true);
+                match sample_filtered(config, &RecentPrioritizationFees, program, &mut trail)
+                    .inspect_err(|e| span.set_error(&e.to_string()))
+                {
+                    Ok(fees) => (fees, RecentPrioritizationFees.name(), None),
+                    Err(fallback_err) => {
+                        // Reported as the configured source's failure
+                        let message = format!("{} (fallback: {})", e, fallback_err);
+                        return Err(CodedError::new(ErrorCode::of(&*e), message).into());
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        let span = telemetry::span("aggregate", SpanKind::Internal);
+        span.set("samples", samples.len());
+        let low_confidence = target.is_some_and(|target| {
+            (samples.len() as f64) < config.min_sample_fraction * target as f64
+        });
+        if low_confidence {
+            log::warn!(
+                "Only {} of {} transactions priced; flagging the estimate as low-confidence",
+                samples.len(),
+                target.unwrap_or_default()
+            );
+        }
+        let sampled = samples.len();
+        let selecting = Instant::now();
+        let mut estimate = Estimate::new(samples, config.include_failed, unix_now(), source)
+            .with_fee_bounds(config.min_fee_floor, config.max_fee_clamp)
+            .with_low_confidence(low_confidence);
+        trail.stages.push(Stage {
+            name: "select",
+            source,
+            samples_in: Some(sampled),
+            samples_out: Some(estimate.selected.len()),
+            duration_ms: selecting.elapsed().as_secs_f64() * 1000.0,
+            error: None,
+        });
+        trail.target_samples = target;
+        trail.duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+        estimate.explanation = Some(Arc::new(trail));
+        Ok(self.track(program, estimate))
+    }
+
+    /// Fold `estimate` into `program`'s EWMA and trend, and return it carrying them as
+    /// `smoothed` and `trend`. `estimate_program` does this with each estimate it makes;
+    /// estimates made elsewhere, such as by another replica, can be tracked likewise.
+    pub fn track(&self, program: &str, mut estimate: Estimate) -> Estimate {
+        let config = &*self.config();
+        let mut smoothed = self.smoothed.lock().unwrap();
+        let updated = Arc::new(Smoothed::update(
+            smoothed.get(program).map(|s| &**s),
+            &estimate,
+            config.aggregation,
+            config.ewma_alpha,
+        ));
+        smoothed.insert(program.to_string(), updated.clone());
+        estimate.smoothed = Some(updated);
+        drop(smoothed);
+        let mut trends = self.trends.lock().unwrap();
+        let updated = Arc::new(Trend::update(
+            trends.get(program).map(|t| &**t),
+            &estimate,
+            config.aggregation,
+            config.trend_window,
+        ));
+        trends.insert(program.to_string(), updated.clone());
+        estimate.trend = Some(updated);
+        estimate
+    }
+}
+
+/// Sample `program` with `source`, then drop the samples `min_compute_units` and
+/// `max_sample_age` rule out, recording each stage in `trail`.
+fn sample_filtered(
+    config: &Config,
+    source: &dyn FeeSource,
+    program: &str,
+    trail: &mut Explanation,
+) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+    let name = source.name();
+    let samples = trail.stage("sample", name, None, || source.sample(config, program))?;
+    let samples = trail.stage("minComputeUnits", name, Some(samples.len()), || {
+        with_min_compute_units(config, samples)
+    })?;
+    trail.stage("maxSampleAge", name, Some(samples.len()), || {
+        within_max_age(config, samples)
+    })
+}
+
+/// `samples` without those of transactions that consumed fewer than `min_compute_units`,
+/// whose prices, if derived from their fees, are mostly rounding. Samples without
+/// compute units are kept; it's an error if every sample is dropped.
+fn with_min_compute_units(
+    config: &Config,
+    samples: Vec<Sample>,
+) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+    if config.min_compute_units == 0 || samples.is_empty() {
+        return Ok(samples);
+    }
+    let kept: Vec<Sample> = samples
+        .into_iter()
+        .filter(|s| {
+            s.compute_units
+                .is_none_or(|cu| cu >= config.min_compute_units)
+        })
+        .collect();
+    if kept.is_empty() {
+        let message = format!(
+            "no samples of at least {} compute units",
+            config.min_compute_units
+        );
+        return Err(CodedError::new(ErrorCode::EmptySample, message).into());
+    }
+    Ok(kept)
+}
+
+/// `samples` without those older than `max_sample_age`. Samples of unknown age are
+/// kept; it's an error if there are no samples, or every sample is too old.
+fn within_max_age(
+    config: &Config,
+    samples: Vec<Sample>,
+) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+    if samples.is_empty() {
+        return Err(CodedError::new(ErrorCode::EmptySample, "no priority fees sampled").into());
+    }
+    let Some(max_age) = config.max_sample_age else {
+        return Ok(samples);
+    };
+    let kept: Vec<Sample> = match max_age {
+        SampleAge::Slots(n) => {
+            let tip = config
+                .rpc
+                .call(|agent, url| get_slot(agent, url, config.commitment))?;
+            let oldest = tip.saturating_sub(n);
+            samples
+                .into_iter()
+                .filter(|s| s.slot.is_none_or(|slot| slot >= oldest))
+                .collect()
+        }
+        SampleAge::Duration(d) => {
+            let oldest = unix_now().saturating_sub(d.as_secs());
+            samples
+                .into_iter()
+                .filter(|s| s.block_time.is_none_or(|t| t >= oldest))
+                .collect()
+        }
+    };
+    if kept.is_empty() {
+        let message = format!("no samples from the last {}", max_age);
+        return Err(CodedError::new(ErrorCode::EmptySample, message).into());
+    }
+    Ok(kept)
+}
+
+/// How old a sample may be to count towards an estimate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleAge {
+    Slots(u64),         // behind the current slot
+    Duration(Duration), // behind the wall clock, by block time
+}
+
+/// Parses `<n>slots` or a duration such as `90s` or `2m`.
+impl FromStr for SampleAge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SampleAge, String> {
+        let age = match s.strip_suffix("slots") {
+            Some(n) => n.parse().ok().map(SampleAge::Slots),
+            None => parse_duration(s).map(SampleAge::Duration),
+        };
+        match age {
+            Some(SampleAge::Slots(0)) | None => Err(format!("invalid sample age: {}", s)),
+            Some(SampleAge::Duration(d)) if d.is_zero() => {
+                Err(format!("invalid sample age: {}", s))
+            }
+            Some(age) => Ok(age),
+        }
+    }
+}
+
+impl fmt::Display for SampleAge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleAge::Slots(n) => write!(f, "{} slots", n),
+            SampleAge::Duration(d) => write!(f, "{}s", d.as_secs()),
+        }
+    }
+}
+
+/// The samples fees are read from, sorted by price so that any percentile can be read
+/// off at request time.
+fn select(samples: &[Sample], include_failed: bool) -> Vec<Sample> {
+    let mut selected: Vec<Sample> = samples
+        .iter()
+        .filter(|s| include_failed || !s.failed)
+        .cloned()
+        .collect();
+    selected.sort_unstable_by_key(|s| s.price);
+    selected
+}
+
+/// The value at percentile `p` (0-100) of `sorted`, which must not be empty.
+fn nearest_rank(sorted: &[u64], p: f64) -> u64 {
+    let idx = (sorted.len() as f64 * p / 100.0) as usize;
+    sorted[idx.min(sorted.len() - 1)]
+}
+
+/// A compute unit limit for a transaction consuming `units`, with
+/// `COMPUTE_UNIT_LIMIT_MARGIN` to spare.
+pub fn compute_unit_limit_for(units: u64) -> u64 {
+    let limit = units as f64 * (1.0 + COMPUTE_UNIT_LIMIT_MARGIN);
+    (limit.ceil() as u64).min(MAX_COMPUTE_UNIT_LIMIT)
+}
+
+/// The priority fee in lamports for `compute_unit_limit` units at `price`
+/// micro-lamports each, rounded up as the runtime does.
+pub fn priority_fee_lamports(price: u64, compute_unit_limit: u64) -> u64 {
+    (price as u128 * compute_unit_limit as u128).div_ceil(1_000_000) as u64
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(price: u64) -> Sample {
+        Sample {
+            signature: None,
+            price,
+            fee_lamports: None,
+            failed: false,
+            compute_units: None,
+            slot: None,
+            block_time: None,
+            jito_tip: None,
+            writable_accounts: Arc::from(Vec::new()),
+            recent_blockhash: None,
+        }
+    }
+
+    fn estimate_of(prices: &[u64], last_updated: u64) -> Estimate {
+        let samples = prices.iter().map(|&p| sample(p)).collect();
+        Estimate::new(samples, false, last_updated, "test")
+    }
+
+    #[test]
+    fn percentiles_read_the_nearest_rank() {
+        let estimate = estimate_of(&[40, 10, 30, 20], 0);
+        assert_eq!(estimate.fee_at(0.0), 10);
+        assert_eq!(estimate.fee_at(25.0), 20);
+        assert_eq!(estimate.fee_at(50.0), 30);
+        assert_eq!(estimate.fee_at(74.9), 30);
+        assert_eq!(estimate.fee_at(100.0), 40);
+        let levels = estimate.levels();
+        assert_eq!((levels.min, levels.medium, levels.unsafe_max), (10, 30, 40));
+    }
+
+    #[test]
+    fn failed_transactions_are_selected_on_request() {
+        let mut samples = vec![sample(10), sample(20)];
+        samples[1].failed = true;
+        let estimate = Estimate::new(samples, false, 0, "test");
+        assert_eq!(estimate.fee_at(100.0), 10);
+        assert_eq!(estimate.with_include_failed(true).fee_at(100.0), 20);
+    }
+
+    #[test]
+    fn fees_are_clamped_to_the_bounds() {
+        let estimate = estimate_of(&[5, 500, 5_000], 0).with_fee_bounds(100, 1_000);
+        assert_eq!(estimate.fee_at(0.0), 100);
+        assert_eq!(estimate.fee_at(50.0), 500);
+        assert_eq!(estimate.fee_at(100.0), 1_000);
+        assert_eq!(
+            estimate.fee_interval(50.0, Aggregation::Percentile).1,
+            1_000
+        );
+        assert_eq!(estimate.levels().unsafe_max, 1_000);
+        assert_eq!(estimate_of(&[u64::MAX], 0).fee_at(50.0), MAX_PRIORITY_FEE);
+    }
+
+    #[test]
+    fn samples_older_than_max_sample_age_are_dropped() {
+        let config = Config {
+            max_sample_age: Some(SampleAge::Duration(Duration::from_secs(60))),
+            ..Config::default()
+        };
+        let now = unix_now();
+        let mut samples = vec![sample(1), sample(2), sample(3)];
+        samples[0].block_time = Some(now - 600);
+        samples[1].block_time = Some(now - 10);
+        let kept = within_max_age(&config, samples).unwrap();
+        let prices: Vec<u64> = kept.iter().map(|s| s.price).collect();
+        assert_eq!(prices, [2, 3]); // the third's age is unknown
+
+        let mut stale = sample(1);
+        stale.block_time = Some(now - 600);
+        let e = within_max_age(&config, vec![stale]).unwrap_err();
+        assert_eq!(ErrorCode::of(&*e), ErrorCode::EmptySample);
+        assert_eq!(e.to_string(), "no samples from the last 60s");
+        assert!(within_max_age(&Config::default(), vec![]).is_err());
+    }
+
+    #[test]
+    fn sample_ages_parse() {
+        assert_eq!("150slots".parse(), Ok(SampleAge::Slots(150)));
+        assert_eq!(
+            "2m".parse(),
+            Ok(SampleAge::Duration(Duration::from_secs(120)))
+        );
+        assert!("0slots".parse::<SampleAge>().is_err());
+        assert!("0s".parse::<SampleAge>().is_err());
+    }
+
+    #[test]
+    fn tracking_smooths_fees_across_refreshes() {
+        let estimator = PriorityFeeEstimator::new(Config {
+            ewma_alpha: 0.5,
+            ..Config::default()
+        });
+        let first = estimator.track("p", estimate_of(&[100, 200], 0));
+        assert_eq!(first.smoothed_fee(0.0, Aggregation::Percentile), Some(100));
+        let second = estimator.track("p", estimate_of(&[300, 400], 60));
+        // Halfway between 100 and 300, then between 200 and 400
+        assert_eq!(second.smoothed_fee(0.0, Aggregation::Percentile), Some(200));
+        assert_eq!(
+            second.smoothed_fee(100.0, Aggregation::Percentile),
+            Some(300)
+        );
+        // Interpolated between the whole percentiles either side of the 50th boundary
+        assert_eq!(
+            second.smoothed_fee(49.5, Aggregation::Percentile),
+            Some(250)
+        );
+        // Programs are smoothed separately
+        let other = estimator.track("q", estimate_of(&[1_000], 60));
+        assert_eq!(
+            other.smoothed_fee(0.0, Aggregation::Percentile),
+            Some(1_000)
+        );
+    }
+
+    #[test]
+    fn tracking_fits_a_trend() {
+        let estimator = PriorityFeeEstimator::new(Config::default());
+        let first = estimator.track("p", estimate_of(&[100], 0));
+        assert!(first.fee_trend(50.0, Aggregation::Percentile).is_none());
+        estimator.track("p", estimate_of(&[200], 60));
+        let third = estimator.track("p", estimate_of(&[300], 120));
+        let trend = third.fee_trend(50.0, Aggregation::Percentile).unwrap();
+        assert_eq!(trend.direction, crate::trend::Direction::Rising);
+        assert!((trend.slope_per_minute - 100.0).abs() < 1e-9);
+        assert_eq!(trend.forecast, [400, 500, 600, 700, 800]);
+
+        // Forecasts are clamped like fees
+        let clamped = estimator.track("p", estimate_of(&[400], 180).with_fee_bounds(0, 450));
+        let trend = clamped.fee_trend(50.0, Aggregation::Percentile).unwrap();
+        assert!(trend.forecast.iter().all(|&fee| fee <= 450));
+    }
+
+    #[test]
+    fn estimating_without_tracked_programs_fails() {
+        let estimator = PriorityFeeEstimator::new(Config {
+            tracked_programs: vec![],
+            ..Config::default()
+        });
+        let e = estimator.estimate().err().unwrap();
+        assert_eq!(e.to_string(), "no tracked programs to estimate for");
+    }
+}
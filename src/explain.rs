@@ -0,0 +1,51 @@
+//! A record of how an estimate was made, stage by stage, for `GET /explain`.
+
+use crate::sources::Sample;
+use serde::Serialize;
+use std::error::Error;
+use std::time::Instant;
+
+/// The stages a refresh went through, in order, including any that failed.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Explanation {
+    pub stages: Vec<Stage>,
+    pub fallback_reason: Option<String>, // why the configured source was given up on
+    pub target_samples: Option<usize>,   // how many samples the source set out to take
+    pub duration_ms: f64,                // the whole refresh
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Stage {
+    pub name: &'static str,
+    pub source: &'static str,
+    pub samples_in: Option<usize>, // `None` for sampling, which starts from nothing
+    pub samples_out: Option<usize>, // `None` if the stage failed
+    pub duration_ms: f64,
+    pub error: Option<String>,
+}
+
+impl Explanation {
+    /// Run `stage` on `samples_in` samples from `source`, recording how many it left,
+    /// how long it took and how it failed, if it did.
+    pub fn stage(
+        &mut self,
+        name: &'static str,
+        source: &'static str,
+        samples_in: Option<usize>,
+        stage: impl FnOnce() -> Result<Vec<Sample>, Box<dyn Error>>,
+    ) -> Result<Vec<Sample>, Box<dyn Error>> {
+        let started = Instant::now();
+        let result = stage();
+        self.stages.push(Stage {
+            name,
+            source,
+            samples_in,
+            samples_out: result.as_ref().ok().map(Vec::len),
+            duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        result
+    }
+}
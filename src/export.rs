@@ -0,0 +1,135 @@
+//! History written out as CSV or Parquet, for `GET /history/export`.
+
+use crate::history::HistoryPoint;
+use crate::logging::rfc3339;
+use crate::parquet::{self, Column, Values};
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The formats history can be exported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ExportFormat, String> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!("unknown format {} (expected csv or parquet)", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    /// `points` in this format.
+    pub fn write(self, points: &[HistoryPoint]) -> Vec<u8> {
+        match self {
+            ExportFormat::Csv => csv(points).into_bytes(),
+            ExportFormat::Parquet => parquet(points),
+        }
+    }
+}
+
+const COLUMNS: [&str; 10] = [
+    "program",
+    "timestamp",
+    "p25",
+    "p50",
+    "p75",
+    "p90",
+    "sample_size",
+    "min_slot",
+    "max_slot",
+    "source",
+];
+
+/// A header row, then a row per point. Timestamps are RFC 3339, and missing values empty.
+fn csv(points: &[HistoryPoint]) -> String {
+    let mut out = COLUMNS.join(",") + "\n";
+    let optional = |n: Option<u64>| n.map(|n| n.to_string()).unwrap_or_default();
+    for p in points {
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&p.program),
+            rfc3339(Duration::from_secs(p.timestamp)),
+            p.p25,
+            p.p50,
+            p.p75,
+            p.p90,
+            p.sample_size,
+            optional(p.min_slot),
+            optional(p.max_slot),
+            csv_field(p.source.as_deref().unwrap_or_default()),
+        );
+    }
+    out
+}
+
+/// `s`, quoted if it has anything a CSV reader would split on.
+fn csv_field(s: &str) -> String {
+    match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    }
+}
+
+/// The same columns as `csv`, with timestamps in milliseconds.
+fn parquet(points: &[HistoryPoint]) -> Vec<u8> {
+    let int = |f: fn(&HistoryPoint) -> u64| {
+        Values::Int64(points.iter().map(|p| Some(f(p) as i64)).collect())
+    };
+    let slot = |f: fn(&HistoryPoint) -> Option<u64>| {
+        Values::Int64(points.iter().map(|p| f(p).map(|s| s as i64)).collect())
+    };
+    let values = [
+        Values::String(points.iter().map(|p| Some(p.program.clone())).collect()),
+        Values::TimestampMillis(
+            points
+                .iter()
+                .map(|p| Some(p.timestamp as i64 * 1000))
+                .collect(),
+        ),
+        int(|p| p.p25),
+        int(|p| p.p50),
+        int(|p| p.p75),
+        int(|p| p.p90),
+        int(|p| p.sample_size as u64),
+        slot(|p| p.min_slot),
+        slot(|p| p.max_slot),
+        Values::String(points.iter().map(|p| p.source.clone()).collect()),
+    ];
+    let columns: Vec<Column> = COLUMNS
+        .into_iter()
+        .zip(values)
+        .map(|(name, values)| Column {
+            name,
+            nullable: matches!(name, "min_slot" | "max_slot" | "source"),
+            values,
+        })
+        .collect();
+    parquet::write(
+        &columns,
+        concat!("ivy-priority-fee version ", env!("CARGO_PKG_VERSION")),
+    )
+}
@@ -0,0 +1,410 @@
+//! The parts of Yellowstone's Geyser gRPC API the `geyser` source uses: a `Subscribe`
+//! request for one program's transactions, and the transactions and pings in the
+//! updates streamed back. Transactions arrive with their status meta, so they're priced
+//! as getTransaction's are, without another call.
+
+use crate::compute_budget::bs58_encode;
+use crate::estimator::unix_now;
+use crate::protobuf::{self, Value, Writer};
+use crate::rpc::{
+    Commitment, CompiledInstruction, InstructionData, LoadedAddresses, Message, MessageHeader,
+    Transaction, TransactionMeta, priority_fee,
+};
+use crate::sources::Sample;
+use crate::transaction::base64_encode;
+
+/// The bidirectional streaming call updates are subscribed to with.
+pub const SUBSCRIBE_METHOD: &str = "/geyser.Geyser/Subscribe";
+
+/// What a `SubscribeUpdate` carries, as far as the source is concerned.
+#[derive(Debug)]
+pub enum Update {
+    Transaction(Option<Sample>), // `None` if it consumed no compute units
+    Ping,                        // the server's keepalive, answered with `ping_request`
+    Other,
+}
+
+/// A `SubscribeRequest` for the non-vote transactions mentioning `program`, failed ones
+/// included, once they reach `commitment`.
+pub fn subscribe_request(program: &str, commitment: Commitment) -> Vec<u8> {
+    let mut m = Writer::default();
+    // `transactions`, a map from filter name to SubscribeRequestFilterTransactions
+    m.message(3, |entry| {
+        entry.string(1, program);
+        entry.message(2, |filter| {
+            filter.bool(1, false); // vote
+            filter.string(3, program); // account_include
+        });
+    });
+    m.uint64(6, commitment_level(commitment));
+    m.buf
+}
+
+/// A `SubscribeRequest` that only pings, keeping proxies from closing a quiet stream.
+pub fn ping_request(id: u64) -> Vec<u8> {
+    let mut m = Writer::default();
+    m.message(9, |ping| ping.uint64(1, id));
+    m.buf
+}
+
+/// Geyser's `CommitmentLevel`.
+fn commitment_level(commitment: Commitment) -> u64 {
+    match commitment {
+        Commitment::Processed => 0,
+        Commitment::Confirmed => 1,
+        Commitment::Finalized => 2,
+    }
+}
+
+/// Read a `SubscribeUpdate`.
+pub fn parse_update(message: &[u8]) -> Result<Update, String> {
+    let mut update = Update::Other;
+    read(message, |number, value| {
+        match number {
+            4 => update = Update::Transaction(transaction_update(bytes(value)?)?),
+            6 => update = Update::Ping,
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(update)
+}
+
+/// Price a `SubscribeUpdateTransaction`. Its block time isn't given, so the time it was
+/// received stands in, which at any commitment is within seconds of it.
+fn transaction_update(message: &[u8]) -> Result<Option<Sample>, String> {
+    let (mut info, mut slot) = (None, None);
+    read(message, |number, value| {
+        match number {
+            1 => info = Some(bytes(value)?),
+            2 => slot = Some(varint(value)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    let (mut transaction, mut meta) = (None, None);
+    read(
+        info.ok_or("transaction update without a transaction")?,
+        |number, value| {
+            match number {
+                3 => transaction = Some(parse_transaction(bytes(value)?)?),
+                4 => meta = Some(parse_meta(bytes(value)?)?),
+                _ => {}
+            }
+            Ok(())
+        },
+    )?;
+    let (Some(transaction), Some(meta)) = (transaction, meta) else {
+        return Err("transaction update without a transaction or its meta".to_string());
+    };
+    Ok(priority_fee(&meta, &transaction).map(|sample| Sample {
+        slot,
+        block_time: Some(unix_now()),
+        ..sample
+    }))
+}
+
+/// Read a `solana.storage.ConfirmedBlock.Transaction`.
+fn parse_transaction(message: &[u8]) -> Result<Transaction, String> {
+    let (mut signature, mut parsed) = (None, None);
+    read(message, |number, value| {
+        match number {
+            1 if signature.is_none() => signature = Some(bs58_encode(bytes(value)?)),
+            2 => parsed = Some(parse_message(bytes(value)?)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(Transaction {
+        signature,
+        message: parsed.ok_or("transaction without a message")?,
+    })
+}
+
+fn parse_message(message: &[u8]) -> Result<Message, String> {
+    let mut parsed = Message {
+        header: MessageHeader {
+            num_required_signatures: 0,
+            num_readonly_signed_accounts: 0,
+            num_readonly_unsigned_accounts: 0,
+        },
+        account_keys: Vec::new(),
+        recent_blockhash: String::new(),
+        instructions: Vec::new(),
+    };
+    read(message, |number, value| {
+        match number {
+            1 => {
+                let header = &mut parsed.header;
+                read(bytes(value)?, |number, value| {
+                    match number {
+                        1 => header.num_required_signatures = varint(value)?,
+                        2 => header.num_readonly_signed_accounts = varint(value)?,
+                        3 => header.num_readonly_unsigned_accounts = varint(value)?,
+                        _ => {}
+                    }
+                    Ok(())
+                })?;
+            }
+            2 => parsed.account_keys.push(bs58_encode(bytes(value)?)),
+            3 => parsed.recent_blockhash = bs58_encode(bytes(value)?),
+            4 => parsed.instructions.push(parse_instruction(bytes(value)?)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(parsed)
+}
+
+fn parse_instruction(message: &[u8]) -> Result<CompiledInstruction, String> {
+    let mut ix = CompiledInstruction {
+        program_id_index: 0,
+        accounts: Vec::new(),
+        data: InstructionData::Bytes(Vec::new()),
+    };
+    read(message, |number, value| {
+        match number {
+            1 => ix.program_id_index = varint(value)? as usize,
+            2 => ix.accounts = bytes(value)?.iter().map(|&i| i as usize).collect(),
+            3 => ix.data = InstructionData::Bytes(bytes(value)?.to_vec()),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(ix)
+}
+
+/// Read a `solana.storage.ConfirmedBlock.TransactionStatusMeta`. Its error is kept
+/// only to mark the transaction failed, so it's left encoded.
+fn parse_meta(message: &[u8]) -> Result<TransactionMeta, String> {
+    let mut meta = TransactionMeta {
+        fee: 0,
+        err: None,
+        compute_units_consumed: None,
+        loaded_addresses: None,
+    };
+    let mut loaded = LoadedAddresses::default();
+    read(message, |number, value| {
+        match number {
+            1 => meta.err = Some(base64_encode(bytes(value)?).into()),
+            2 => meta.fee = varint(value)?,
+            12 => loaded.writable.push(bs58_encode(bytes(value)?)),
+            13 => loaded.readonly.push(bs58_encode(bytes(value)?)),
+            16 => meta.compute_units_consumed = Some(varint(value)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    meta.loaded_addresses = Some(loaded);
+    Ok(meta)
+}
+
+/// Pass each of `message`'s fields to `visit`.
+fn read<'a>(
+    message: &'a [u8],
+    mut visit: impl FnMut(u32, Value<'a>) -> Result<(), String>,
+) -> Result<(), String> {
+    for field in protobuf::fields(message) {
+        let (number, value) = field?;
+        visit(number, value).map_err(|e| format!("field {}: {}", number, e))?;
+    }
+    Ok(())
+}
+
+fn bytes(value: Value<'_>) -> Result<&[u8], String> {
+    match value {
+        Value::Bytes(b) => Ok(b),
+        _ => Err("expected a length-delimited value".to_string()),
+    }
+}
+
+fn varint(value: Value<'_>) -> Result<u64, String> {
+    match value {
+        Value::Varint(n) => Ok(n),
+        _ => Err("expected a varint".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute_budget::{COMPUTE_BUDGET_PROGRAM, bs58_decode};
+    use crate::grpc::{self, Call, ClientCall, Code, Responses, Status};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const PROGRAM: &str = "JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4";
+
+    /// A `SubscribeUpdate` carrying a transaction that set a price of 12,345
+    /// micro-lamports per CU, and failed if `failed`.
+    fn transaction_update_message(failed: bool) -> Vec<u8> {
+        let mut price = vec![3];
+        price.extend_from_slice(&12_345u64.to_le_bytes());
+        let mut limit = vec![2];
+        limit.extend_from_slice(&200_000u32.to_le_bytes());
+        let mut m = Writer::default();
+        m.message(4, |update| {
+            update.message(1, |info| {
+                info.bytes(1, &[1; 64]);
+                info.message(3, |tx| {
+                    tx.bytes(1, &[1; 64]);
+                    tx.message(2, |message| {
+                        message.message(1, |header| {
+                            header.uint64(1, 1);
+                            header.uint64(2, 0);
+                            header.uint64(3, 2);
+                        });
+                        message.bytes(2, &[2; 32]);
+                        message.bytes(2, &bs58_decode(COMPUTE_BUDGET_PROGRAM).unwrap());
+                        message.bytes(2, &bs58_decode(PROGRAM).unwrap());
+                        message.bytes(3, &[3; 32]);
+                        message.message(4, |ix| {
+                            ix.uint64(1, 1);
+                            ix.bytes(3, &price);
+                        });
+                        message.message(4, |ix| {
+                            ix.uint64(1, 1);
+                            ix.bytes(3, &limit);
+                        });
+                        message.message(4, |ix| {
+                            ix.uint64(1, 2);
+                            ix.bytes(2, &[0]);
+                        });
+                    });
+                });
+                info.message(4, |meta| {
+                    if failed {
+                        meta.message(1, |err| err.bytes(1, &[8, 0, 0, 0]));
+                    }
+                    meta.uint64(2, 7_500);
+                    meta.bytes(12, &[4; 32]);
+                    meta.uint64(16, 50_000);
+                });
+            });
+            update.uint64(2, 777);
+        });
+        m.buf
+    }
+
+    #[test]
+    fn subscribe_requests_filter_on_the_program() {
+        let request = subscribe_request("abc", Commitment::Confirmed);
+        assert_eq!(
+            request,
+            [
+                0x1a, 14, // transactions, 14 bytes
+                0x0a, 3, b'a', b'b', b'c', // the filter's name
+                0x12, 7, // the filter, 7 bytes
+                0x08, 0, // vote: false
+                0x1a, 3, b'a', b'b', b'c', // account_include
+                0x30, 1, // commitment: CONFIRMED
+            ]
+        );
+        assert_eq!(ping_request(5), [0x4a, 2, 0x08, 5]);
+    }
+
+    #[test]
+    fn transactions_are_priced_from_their_compute_budget() {
+        let Update::Transaction(Some(sample)) =
+            parse_update(&transaction_update_message(false)).unwrap()
+        else {
+            panic!("expected a priced transaction");
+        };
+        assert_eq!(sample.price, 12_345);
+        assert_eq!(sample.compute_units, Some(50_000));
+        assert_eq!(sample.fee_lamports, Some(7_500));
+        assert_eq!(sample.slot, Some(777));
+        assert!(!sample.failed);
+        assert_eq!(
+            sample.signature.as_deref(),
+            Some(bs58_encode(&[1; 64]).as_str())
+        );
+        assert_eq!(
+            sample.writable_accounts.to_vec(),
+            [bs58_encode(&[2; 32]), bs58_encode(&[4; 32])]
+        );
+        assert_eq!(
+            sample.recent_blockhash.as_deref(),
+            Some(bs58_encode(&[3; 32]).as_str())
+        );
+
+        let Update::Transaction(Some(failed)) =
+            parse_update(&transaction_update_message(true)).unwrap()
+        else {
+            panic!("expected a priced transaction");
+        };
+        assert!(failed.failed);
+    }
+
+    #[test]
+    fn other_updates_are_told_apart() {
+        let mut ping = Writer::default();
+        ping.message(6, |_| {});
+        assert!(matches!(parse_update(&ping.buf), Ok(Update::Ping)));
+        let mut slot = Writer::default();
+        slot.message(3, |s| s.uint64(1, 1));
+        assert!(matches!(parse_update(&slot.buf), Ok(Update::Other)));
+        let mut truncated = transaction_update_message(false);
+        truncated.truncate(40);
+        assert!(parse_update(&truncated).is_err());
+    }
+
+    /// Streams a ping and a transaction to subscribers of `PROGRAM`.
+    struct FakeGeyser;
+
+    impl grpc::Service for FakeGeyser {
+        fn call(&self, call: &Call, responses: &mut Responses) -> Result<(), Status> {
+            if call.method != SUBSCRIBE_METHOD {
+                return Err(Status::new(Code::Unimplemented, "unknown method"));
+            }
+            if call.metadata("x-token") != Some("secret") {
+                return Err(Status::new(Code::Unauthenticated, "bad x-token"));
+            }
+            if call.message != subscribe_request(PROGRAM, Commitment::Processed) {
+                return Err(Status::new(Code::InvalidArgument, "unexpected filter"));
+            }
+            let mut ping = Writer::default();
+            ping.message(6, |_| {});
+            let send = |responses: &mut Responses, message: &[u8]| {
+                responses
+                    .send(message)
+                    .map_err(|e| Status::new(Code::Internal, e.to_string()))
+            };
+            send(responses, &ping.buf)?;
+            send(responses, &transaction_update_message(false))
+        }
+    }
+
+    #[test]
+    fn subscribes_over_grpc() {
+        let addr = grpc::listen("127.0.0.1:0", Arc::new(FakeGeyser)).unwrap();
+        let url = format!("http://{}", addr);
+        let subscribe = |token: &str| {
+            let mut call = ClientCall::open(
+                &url,
+                SUBSCRIBE_METHOD,
+                &[("x-token", token)],
+                None,
+                Duration::from_secs(5),
+            )
+            .unwrap();
+            call.send(&subscribe_request(PROGRAM, Commitment::Processed))
+                .unwrap();
+            call.close_send().unwrap();
+            call
+        };
+
+        let mut call = subscribe("secret");
+        let ping = call.receive().unwrap().unwrap();
+        assert!(matches!(parse_update(&ping), Ok(Update::Ping)));
+        let transaction = call.receive().unwrap().unwrap();
+        assert!(matches!(
+            parse_update(&transaction),
+            Ok(Update::Transaction(Some(sample))) if sample.price == 12_345
+        ));
+        assert!(call.receive().unwrap().is_none());
+
+        let e = subscribe("wrong").receive().unwrap_err();
+        assert_eq!(e.to_string(), "gRPC status 16: bad x-token");
+    }
+}
@@ -0,0 +1,1002 @@
+//! Just enough GraphQL to answer queries: a parser for query documents, and an executor
+//! that resolves their selections against objects the caller implements. Variables,
+//! aliases, fragments and the `@skip` and `@include` directives are supported;
+//! mutations, subscriptions and introspection aren't.
+
+use serde_json::{Map, Value, json};
+use std::collections::{HashMap, HashSet};
+
+/// A field's arguments, with variables substituted.
+pub type Arguments = Map<String, Value>;
+
+/// An object of the schema, which resolves its own fields.
+pub trait Object {
+    /// The object's type name, for `__typename` and fragments' type conditions.
+    fn typename(&self) -> &'static str;
+
+    /// The value of the field `name` given `args`, or why it has none.
+    fn field(&self, name: &str, args: &Arguments) -> Result<Output<'_>, String>;
+}
+
+/// A field's value.
+pub enum Output<'a> {
+    Value(Value),                 // a scalar, a list of scalars, or null
+    Map(&'static str, Value),     // an object of the named type whose fields are its keys
+    Object(Box<dyn Object + 'a>), // an object resolving its fields itself
+    List(Vec<Output<'a>>),
+}
+
+/// Run the operation `operation_name` of `query` (or its only operation) against
+/// `root`, returning the response: `{"data": ...}`, with `"errors"` if any field
+/// failed. A document that can't be run at all is an error.
+pub fn execute(
+    query: &str,
+    operation_name: Option<&str>,
+    variables: &Map<String, Value>,
+    root: &dyn Object,
+) -> Result<Value, String> {
+    let document = Parser::new(query).document()?;
+    let mut operations = document
+        .operations
+        .iter()
+        .filter(|op| operation_name.is_none_or(|name| op.name.as_deref() == Some(name)));
+    let operation = match (operations.next(), operations.next()) {
+        (Some(op), None) => op,
+        (None, _) => {
+            return Err(match operation_name {
+                Some(name) => format!("no operation named {}", name),
+                None => "the document has no operations".to_string(),
+            });
+        }
+        (Some(_), Some(_)) => {
+            return Err(
+                "operationName is required for a document with several operations".to_string(),
+            );
+        }
+    };
+    if operation.kind != "query" {
+        return Err(format!("{} operations aren't supported", operation.kind));
+    }
+    let mut values = variables.clone();
+    for (name, default) in &operation.variables {
+        if !values.contains_key(name)
+            && let Some(default) = default
+        {
+            values.insert(name.clone(), default.clone());
+        }
+    }
+    let mut executor = Executor {
+        fragments: document
+            .fragments
+            .iter()
+            .map(|f| (f.name.as_str(), f))
+            .collect(),
+        variables: &values,
+        errors: Vec::new(),
+    };
+    let data = executor.select(root, &operation.selections, &mut Vec::new())?;
+    let mut response = json!({ "data": data });
+    if !executor.errors.is_empty() {
+        response["errors"] = Value::Array(executor.errors);
+    }
+    Ok(response)
+}
+
+// --------------------------- Execution ---------------------------
+
+struct Executor<'a> {
+    fragments: HashMap<&'a str, &'a Fragment>,
+    variables: &'a Map<String, Value>,
+    errors: Vec<Value>, // field errors, with their paths
+}
+
+impl<'a> Executor<'a> {
+    /// The fields of `selections` on `object`, keyed by alias or name.
+    fn select(
+        &mut self,
+        object: &dyn Object,
+        selections: &[Selection],
+        path: &mut Vec<Value>,
+    ) -> Result<Value, String> {
+        let mut fields: Vec<(&str, Vec<&Field>)> = Vec::new();
+        self.collect(
+            object.typename(),
+            selections,
+            &mut fields,
+            &mut HashSet::new(),
+        )?;
+        let mut result = Map::new();
+        for (key, group) in fields {
+            let field = group[0];
+            // A field selected more than once has the sub-selections of each
+            let selections: Vec<Selection> = group
+                .iter()
+                .flat_map(|f| f.selections.iter().cloned())
+                .collect();
+            path.push(json!(key));
+            let value = match field.name.as_str() {
+                "__typename" => json!(object.typename()),
+                name => match self.arguments(field) {
+                    Ok(args) => match object.field(name, &args) {
+                        Ok(output) => self.complete(output, field, &selections, path)?,
+                        Err(message) => self.error(message, path),
+                    },
+                    Err(message) => self.error(message, path),
+                },
+            };
+            path.pop();
+            result.insert(key.to_string(), value);
+        }
+        Ok(Value::Object(result))
+    }
+
+    /// Gather the fields `selections` select on an object of type `typename`, expanding
+    /// fragments and skipping fields excluded by directives.
+    fn collect<'s>(
+        &self,
+        typename: &str,
+        selections: &'s [Selection],
+        fields: &mut Vec<(&'s str, Vec<&'s Field>)>,
+        spreading: &mut HashSet<&'s str>, // fragments being expanded, to catch cycles
+    ) -> Result<(), String>
+    where
+        'a: 's,
+    {
+        for selection in selections {
+            let (directives, type_condition, nested) = match selection {
+                Selection::Field(field) => (&field.directives, None, None),
+                Selection::Spread(name, directives) => {
+                    let fragment = self
+                        .fragments
+                        .get(name.as_str())
+                        .ok_or_else(|| format!("unknown fragment {}", name))?;
+                    if !spreading.insert(name) {
+                        return Err(format!("fragment {} spreads itself", name));
+                    }
+                    (
+                        directives,
+                        Some(&fragment.type_condition),
+                        Some(&fragment.selections),
+                    )
+                }
+                Selection::Inline(type_condition, directives, selections) => {
+                    (directives, type_condition.as_ref(), Some(selections))
+                }
+            };
+            if !self.included(directives)? {
+                if let Selection::Spread(name, _) = selection {
+                    spreading.remove(name.as_str());
+                }
+                continue;
+            }
+            match (selection, nested) {
+                (Selection::Field(field), _) => {
+                    let key = field.alias.as_deref().unwrap_or(&field.name);
+                    match fields.iter_mut().find(|(k, _)| *k == key) {
+                        Some((_, group)) => group.push(field),
+                        None => fields.push((key, vec![field])),
+                    }
+                }
+                (_, Some(nested)) => {
+                    if type_condition.is_none_or(|t| t == typename) {
+                        self.collect(typename, nested, fields, spreading)?;
+                    }
+                    if let Selection::Spread(name, _) = selection {
+                        spreading.remove(name.as_str());
+                    }
+                }
+                (_, None) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `@skip` and `@include` directives let a selection through.
+    fn included(&self, directives: &[Directive]) -> Result<bool, String> {
+        for directive in directives {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .map(|(_, value)| value.resolve(self.variables))
+                .transpose()?;
+            let condition = match condition {
+                Some(Some(Value::Bool(b))) => b,
+                _ => return Err(format!("@{} needs a Boolean argument if", directive.name)),
+            };
+            match directive.name.as_str() {
+                "skip" if condition => return Ok(false),
+                "include" if !condition => return Ok(false),
+                "skip" | "include" => {}
+                other => return Err(format!("unknown directive @{}", other)),
+            }
+        }
+        Ok(true)
+    }
+
+    fn arguments(&self, field: &Field) -> Result<Arguments, String> {
+        let mut args = Map::new();
+        for (name, value) in &field.arguments {
+            if let Some(value) = value.resolve(self.variables)? {
+                args.insert(name.clone(), value);
+            }
+        }
+        Ok(args)
+    }
+
+    /// `output` as JSON, with its objects' fields selected by `selections`.
+    fn complete(
+        &mut self,
+        output: Output<'_>,
+        field: &Field,
+        selections: &[Selection],
+        path: &mut Vec<Value>,
+    ) -> Result<Value, String> {
+        let object: Box<dyn Object + '_> = match output {
+            Output::Value(value) if selections.is_empty() => return Ok(value),
+            Output::Value(Value::Null) => return Ok(Value::Null),
+            Output::Value(_) => {
+                let message = format!("field {} has no subfields to select", field.name);
+                return Ok(self.error(message, path));
+            }
+            Output::List(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for (i, item) in items.into_iter().enumerate() {
+                    path.push(json!(i));
+                    values.push(self.complete(item, field, selections, path)?);
+                    path.pop();
+                }
+                return Ok(Value::Array(values));
+            }
+            Output::Map(typename, value) => Box::new(MapObject { typename, value }),
+            Output::Object(object) => object,
+        };
+        if selections.is_empty() {
+            let message = format!(
+                "field {} of type {} needs a selection of subfields",
+                field.name,
+                object.typename()
+            );
+            return Ok(self.error(message, path));
+        }
+        self.select(object.as_ref(), selections, path)
+    }
+
+    /// Record a field error at `path`, returning the field's value: null.
+    fn error(&mut self, message: String, path: &[Value]) -> Value {
+        self.errors
+            .push(json!({ "message": message, "path": path }));
+        Value::Null
+    }
+}
+
+/// An object whose fields are the keys of a JSON object.
+struct MapObject {
+    typename: &'static str,
+    value: Value,
+}
+
+impl Object for MapObject {
+    fn typename(&self) -> &'static str {
+        self.typename
+    }
+
+    fn field(&self, name: &str, _: &Arguments) -> Result<Output<'_>, String> {
+        match self.value.get(name) {
+            Some(value) => Ok(Output::Value(value.clone())),
+            None => Err(format!("type {} has no field {}", self.typename, name)),
+        }
+    }
+}
+
+// --------------------------- Parsing ---------------------------
+
+struct Document {
+    operations: Vec<Operation>,
+    fragments: Vec<Fragment>,
+}
+
+struct Operation {
+    kind: String, // query, mutation or subscription
+    name: Option<String>,
+    variables: Vec<(String, Option<Value>)>, // with their defaults
+    selections: Vec<Selection>,
+}
+
+struct Fragment {
+    name: String,
+    type_condition: String,
+    selections: Vec<Selection>,
+}
+
+#[derive(Clone)]
+enum Selection {
+    Field(Field),
+    Spread(String, Vec<Directive>), // `...Name`
+    Inline(Option<String>, Vec<Directive>, Vec<Selection>), // `... on Type { }`
+}
+
+#[derive(Clone)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    arguments: Vec<(String, Literal)>,
+    directives: Vec<Directive>,
+    selections: Vec<Selection>,
+}
+
+#[derive(Clone)]
+struct Directive {
+    name: String,
+    arguments: Vec<(String, Literal)>,
+}
+
+/// A value as written in a query, which may refer to variables.
+#[derive(Clone)]
+enum Literal {
+    Variable(String),
+    Const(Value),
+    List(Vec<Literal>),
+    Object(Vec<(String, Literal)>),
+}
+
+impl Literal {
+    /// The value, or `None` for a variable that wasn't given.
+    fn resolve(&self, variables: &Map<String, Value>) -> Result<Option<Value>, String> {
+        Ok(Some(match self {
+            Literal::Variable(name) => return Ok(variables.get(name).cloned()),
+            Literal::Const(value) => value.clone(),
+            Literal::List(items) => Value::Array(
+                items
+                    .iter()
+                    .map(|item| Ok(item.resolve(variables)?.unwrap_or(Value::Null)))
+                    .collect::<Result<_, String>>()?,
+            ),
+            Literal::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .filter_map(|(name, value)| match value.resolve(variables) {
+                        Ok(Some(value)) => Some(Ok((name.clone(), value))),
+                        Ok(None) => None,
+                        Err(e) => Some(Err(e)),
+                    })
+                    .collect::<Result<_, String>>()?,
+            ),
+        }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Punctuator(char), // one of ! $ & ( ) : = @ [ ] { | }, or '.' for `...`
+    Name(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    peeked: Option<Token>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Parser<'a> {
+        Parser {
+            input,
+            pos: 0,
+            peeked: None,
+        }
+    }
+
+    fn document(&mut self) -> Result<Document, String> {
+        let mut document = Document {
+            operations: Vec::new(),
+            fragments: Vec::new(),
+        };
+        while self.peek()?.is_some() {
+            match self.peek()? {
+                Some(Token::Punctuator('{')) => document.operations.push(Operation {
+                    kind: "query".to_string(),
+                    name: None,
+                    variables: Vec::new(),
+                    selections: self.selection_set()?,
+                }),
+                Some(Token::Name(name)) if name == "fragment" => {
+                    self.next()?;
+                    let name = self.name()?;
+                    self.keyword("on")?;
+                    let type_condition = self.name()?;
+                    self.directives()?;
+                    document.fragments.push(Fragment {
+                        name,
+                        type_condition,
+                        selections: self.selection_set()?,
+                    });
+                }
+                Some(Token::Name(_)) => {
+                    let kind = self.name()?;
+                    if !matches!(kind.as_str(), "query" | "mutation" | "subscription") {
+                        return Err(format!("unexpected {}", kind));
+                    }
+                    let name = match self.peek()? {
+                        Some(Token::Name(_)) => Some(self.name()?),
+                        _ => None,
+                    };
+                    let variables = self.variable_definitions()?;
+                    self.directives()?;
+                    document.operations.push(Operation {
+                        kind,
+                        name,
+                        variables,
+                        selections: self.selection_set()?,
+                    });
+                }
+                Some(token) => return Err(format!("unexpected {}", describe(&token))),
+                None => unreachable!(),
+            }
+        }
+        Ok(document)
+    }
+
+    /// `($name: Type = default, ...)`, if present. Types aren't checked.
+    fn variable_definitions(&mut self) -> Result<Vec<(String, Option<Value>)>, String> {
+        let mut variables = Vec::new();
+        if !self.eat('(')? {
+            return Ok(variables);
+        }
+        while !self.eat(')')? {
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            self.skip_type()?;
+            let default = match self.eat('=')? {
+                true => match self.value(true)? {
+                    Literal::Const(value) => Some(value),
+                    literal => literal.resolve(&Map::new())?,
+                },
+                false => None,
+            };
+            self.directives()?;
+            variables.push((name, default));
+        }
+        Ok(variables)
+    }
+
+    fn skip_type(&mut self) -> Result<(), String> {
+        if self.eat('[')? {
+            self.skip_type()?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!')?;
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        self.expect('{')?;
+        let mut selections = Vec::new();
+        while !self.eat('}')? {
+            if self.eat('.')? {
+                let type_condition = match self.peek()? {
+                    Some(Token::Name(name)) if name == "on" => {
+                        self.next()?;
+                        Some(self.name()?)
+                    }
+                    Some(Token::Name(_)) => {
+                        let name = self.name()?;
+                        selections.push(Selection::Spread(name, self.directives()?));
+                        continue;
+                    }
+                    _ => None,
+                };
+                let directives = self.directives()?;
+                selections.push(Selection::Inline(
+                    type_condition,
+                    directives,
+                    self.selection_set()?,
+                ));
+                continue;
+            }
+            let mut name = self.name()?;
+            let mut alias = None;
+            if self.eat(':')? {
+                alias = Some(name);
+                name = self.name()?;
+            }
+            let arguments = self.arguments()?;
+            let directives = self.directives()?;
+            let selections_of_field = match self.peek()? {
+                Some(Token::Punctuator('{')) => self.selection_set()?,
+                _ => Vec::new(),
+            };
+            selections.push(Selection::Field(Field {
+                alias,
+                name,
+                arguments,
+                directives,
+                selections: selections_of_field,
+            }));
+        }
+        Ok(selections)
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(String, Literal)>, String> {
+        let mut arguments = Vec::new();
+        if self.eat('(')? {
+            while !self.eat(')')? {
+                let name = self.name()?;
+                self.expect(':')?;
+                arguments.push((name, self.value(false)?));
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn directives(&mut self) -> Result<Vec<Directive>, String> {
+        let mut directives = Vec::new();
+        while self.eat('@')? {
+            directives.push(Directive {
+                name: self.name()?,
+                arguments: self.arguments()?,
+            });
+        }
+        Ok(directives)
+    }
+
+    /// A value; variables aren't allowed in `constant` ones, such as defaults.
+    fn value(&mut self, constant: bool) -> Result<Literal, String> {
+        Ok(match self.next()? {
+            Some(Token::Punctuator('$')) if !constant => Literal::Variable(self.name()?),
+            Some(Token::Punctuator('[')) => {
+                let mut items = Vec::new();
+                while !self.eat(']')? {
+                    items.push(self.value(constant)?);
+                }
+                Literal::List(items)
+            }
+            Some(Token::Punctuator('{')) => {
+                let mut fields = Vec::new();
+                while !self.eat('}')? {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value(constant)?));
+                }
+                Literal::Object(fields)
+            }
+            Some(Token::Int(n)) => Literal::Const(json!(n)),
+            Some(Token::Float(x)) => Literal::Const(json!(x)),
+            Some(Token::String(s)) => Literal::Const(json!(s)),
+            Some(Token::Name(name)) => Literal::Const(match name.as_str() {
+                "true" => json!(true),
+                "false" => json!(false),
+                "null" => Value::Null,
+                _ => json!(name), // an enum value
+            }),
+            Some(token) => return Err(format!("expected a value, found {}", describe(&token))),
+            None => return Err("expected a value, found the end of the document".to_string()),
+        })
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Some(Token::Name(name)) => Ok(name),
+            Some(token) => Err(format!("expected a name, found {}", describe(&token))),
+            None => Err("expected a name, found the end of the document".to_string()),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.name()? {
+            name if name == keyword => Ok(()),
+            name => Err(format!("expected {}, found {}", keyword, name)),
+        }
+    }
+
+    fn expect(&mut self, punctuator: char) -> Result<(), String> {
+        match self.eat(punctuator)? {
+            true => Ok(()),
+            false => Err(match self.peek()? {
+                Some(token) => format!("expected {}, found {}", punctuator, describe(&token)),
+                None => format!("expected {}, found the end of the document", punctuator),
+            }),
+        }
+    }
+
+    /// Consume `punctuator` if it's next.
+    fn eat(&mut self, punctuator: char) -> Result<bool, String> {
+        if self.peek()? == Some(Token::Punctuator(punctuator)) {
+            self.next()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn peek(&mut self) -> Result<Option<Token>, String> {
+        if self.peeked.is_none() {
+            self.peeked = self.lex()?;
+        }
+        Ok(self.peeked.clone())
+    }
+
+    fn next(&mut self) -> Result<Option<Token>, String> {
+        match self.peeked.take() {
+            Some(token) => Ok(Some(token)),
+            None => self.lex(),
+        }
+    }
+
+    fn lex(&mut self) -> Result<Option<Token>, String> {
+        // Whitespace, commas and comments are insignificant
+        loop {
+            let rest = &self.input[self.pos..];
+            match rest.chars().next() {
+                Some(c @ (' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}')) => self.pos += c.len_utf8(),
+                Some('#') => self.pos += rest.find(['\n', '\r']).unwrap_or(rest.len()),
+                _ => break,
+            }
+        }
+        let rest = &self.input[self.pos..];
+        let Some(c) = rest.chars().next() else {
+            return Ok(None);
+        };
+        let (token, len) = match c {
+            '.' if rest.starts_with("...") => (Token::Punctuator('.'), 3),
+            '!' | '$' | '&' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '|' | '}' => {
+                (Token::Punctuator(c), 1)
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (Token::Name(rest[..len].to_string()), len)
+            }
+            '-' | '0'..='9' => {
+                let len = rest[1..]
+                    .find(|c: char| {
+                        !(c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+                    })
+                    .map_or(rest.len(), |i| i + 1);
+                let number = &rest[..len];
+                let token = match number.contains(['.', 'e', 'E']) {
+                    true => number.parse().map(Token::Float).ok(),
+                    false => number.parse().map(Token::Int).ok(),
+                };
+                (
+                    token.ok_or_else(|| format!("invalid number {}", number))?,
+                    len,
+                )
+            }
+            '"' if rest.starts_with(r#"""""#) => {
+                let end = rest[3..]
+                    .find(r#"""""#)
+                    .ok_or("unterminated block string")?;
+                (Token::String(block_string(&rest[3..3 + end])), end + 6)
+            }
+            '"' => {
+                let (s, len) = string(rest)?;
+                (Token::String(s), len)
+            }
+            _ => return Err(format!("unexpected character {:?}", c)),
+        };
+        self.pos += len;
+        Ok(Some(token))
+    }
+}
+
+/// A quoted string at the start of `rest`, and its length there.
+fn string(rest: &str) -> Result<(String, usize), String> {
+    let mut out = String::new();
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, i + 1)),
+            '\n' | '\r' => break,
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('/') => out.push('/'),
+                Some('b') => out.push('\u{8}'),
+                Some('f') => out.push('\u{c}'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid escape \\u{}", hex))?;
+                    out.push(c);
+                }
+                other => return Err(format!("invalid escape \\{}", other.unwrap_or(' '))),
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+/// A block string's value: its lines without their common indentation, or leading and
+/// trailing blank lines.
+fn block_string(raw: &str) -> String {
+    let lines: Vec<&str> = raw.lines().collect();
+    let indent = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => line,
+            _ => line.get(indent..).unwrap_or(""),
+        })
+        .collect();
+    let start = lines
+        .iter()
+        .position(|l| !l.trim().is_empty())
+        .unwrap_or(lines.len());
+    let end = lines
+        .iter()
+        .rposition(|l| !l.trim().is_empty())
+        .map_or(start, |i| i + 1);
+    lines[start..end].join("\n").replace(r#"\""""#, r#"""""#)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Punctuator('.') => "...".to_string(),
+        Token::Punctuator(c) => c.to_string(),
+        Token::Name(name) => name.clone(),
+        Token::Int(n) => n.to_string(),
+        Token::Float(x) => x.to_string(),
+        Token::String(s) => format!("{:?}", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A schema of `Shop { name, echo(value), items: [Item], featured: Item, fail,
+    /// shop: Shop }`, where `Item { id, name }` is a map.
+    struct Shop;
+
+    impl Object for Shop {
+        fn typename(&self) -> &'static str {
+            "Shop"
+        }
+
+        fn field(&self, name: &str, args: &Arguments) -> Result<Output<'_>, String> {
+            let item = |id: u64, name: &str| Output::Map("Item", json!({ "id": id, "name": name }));
+            match name {
+                "name" => Ok(Output::Value(json!("corner shop"))),
+                "echo" => Ok(Output::Value(
+                    args.get("value").cloned().unwrap_or_default(),
+                )),
+                "items" => Ok(Output::List(vec![item(1, "apple"), item(2, "pear")])),
+                "featured" => Ok(item(3, "plum")),
+                "fail" => Err("out of stock".to_string()),
+                "shop" => Ok(Output::Object(Box::new(Shop))),
+                _ => Err(format!("type Shop has no field {}", name)),
+            }
+        }
+    }
+
+    fn run(query: &str, variables: Value) -> Result<Value, String> {
+        let Value::Object(variables) = variables else {
+            panic!("variables must be an object");
+        };
+        execute(query, None, &variables, &Shop)
+    }
+
+    fn data(query: &str) -> Value {
+        let response = run(query, json!({})).unwrap();
+        assert_eq!(response.get("errors"), None, "{}", response);
+        response["data"].clone()
+    }
+
+    #[test]
+    fn fields_are_selected_by_name_or_alias() {
+        assert_eq!(
+            data("{ name title: name __typename featured { id } shop { shop { name } } }"),
+            json!({
+                "name": "corner shop",
+                "title": "corner shop",
+                "__typename": "Shop",
+                "featured": { "id": 3 },
+                "shop": { "shop": { "name": "corner shop" } },
+            })
+        );
+        assert_eq!(
+            data("query { items { name } }"),
+            json!({ "items": [{ "name": "apple" }, { "name": "pear" }] })
+        );
+        // A field selected twice has the subfields of both
+        assert_eq!(
+            data("{ featured { id } featured { name } }"),
+            json!({ "featured": { "id": 3, "name": "plum" } })
+        );
+    }
+
+    #[test]
+    fn arguments_take_literals() {
+        let echo = |value: &str| data(&format!("{{ echo(value: {}) }}", value))["echo"].clone();
+        assert_eq!(echo("-12"), json!(-12));
+        assert_eq!(echo("2.5e1"), json!(25.0));
+        assert_eq!(echo("true"), json!(true));
+        assert_eq!(echo("null"), json!(null));
+        assert_eq!(echo("CHEAPEST"), json!("CHEAPEST"));
+        assert_eq!(echo(r#""a\"bé\n""#), json!("a\"bé\n"));
+        assert_eq!(
+            echo("\"\"\"\n    first\n      second\n\"\"\""),
+            json!("first\n  second")
+        );
+        assert_eq!(
+            echo(r#"[1, "two", { three: [3] }]"#),
+            json!([1, "two", { "three": [3] }])
+        );
+    }
+
+    #[test]
+    fn arguments_take_variables() {
+        let query = r#"
+            query Echo($given: Int, $list: [String!]! = ["x"], $absent: String) {
+                given: echo(value: $given)
+                defaulted: echo(value: { list: $list, absent: $absent })
+                absent: echo(value: $absent)
+            }
+        "#;
+        assert_eq!(
+            run(query, json!({ "given": 7 })).unwrap(),
+            json!({
+                "data": {
+                    "given": 7,
+                    "defaulted": { "list": ["x"] },
+                    "absent": null,
+                },
+            })
+        );
+        let response = run(query, json!({ "given": 7, "list": ["y", "z"] })).unwrap();
+        assert_eq!(response["data"]["defaulted"], json!({ "list": ["y", "z"] }));
+    }
+
+    #[test]
+    fn fragments_apply_to_their_type() {
+        let query = r#"
+            { ...ShopFields featured { ...ItemFields ... on Shop { name } } }
+            fragment ShopFields on Shop { name ... { title: name } }
+            fragment ItemFields on Item { id __typename }
+        "#;
+        assert_eq!(
+            data(query),
+            json!({
+                "name": "corner shop",
+                "title": "corner shop",
+                "featured": { "id": 3, "__typename": "Item" },
+            })
+        );
+    }
+
+    #[test]
+    fn directives_skip_and_include_fields() {
+        let query = r#"
+            query ($on: Boolean!) {
+                a: name @include(if: $on)
+                b: name @skip(if: $on)
+                c: name @include(if: true) @skip(if: false)
+                ... @skip(if: $on) { d: name }
+            }
+        "#;
+        let response = run(query, json!({ "on": true })).unwrap();
+        assert_eq!(
+            response["data"],
+            json!({ "a": "corner shop", "c": "corner shop" })
+        );
+        let response = run(query, json!({ "on": false })).unwrap();
+        assert_eq!(
+            response["data"],
+            json!({ "b": "corner shop", "c": "corner shop", "d": "corner shop" })
+        );
+    }
+
+    #[test]
+    fn field_errors_are_null_with_their_path() {
+        let response = run(
+            "{ name fail items { id color } featured name { x } shop }",
+            json!({}),
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            json!({
+                "data": {
+                    "name": null,
+                    "fail": null,
+                    "items": [{ "id": 1, "color": null }, { "id": 2, "color": null }],
+                    "featured": null,
+                    "shop": null,
+                },
+                "errors": [
+                    { "message": "field name has no subfields to select", "path": ["name"] },
+                    { "message": "out of stock", "path": ["fail"] },
+                    { "message": "type Item has no field color", "path": ["items", 0, "color"] },
+                    { "message": "type Item has no field color", "path": ["items", 1, "color"] },
+                    {
+                        "message": "field featured of type Item needs a selection of subfields",
+                        "path": ["featured"],
+                    },
+                    {
+                        "message": "field shop of type Shop needs a selection of subfields",
+                        "path": ["shop"],
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn operations_are_chosen_by_name() {
+        let query = "query A { a: name } query B { b: name }";
+        let select = |name| execute(query, name, &Map::new(), &Shop);
+        assert_eq!(
+            select(Some("B")).unwrap(),
+            json!({ "data": { "b": "corner shop" } })
+        );
+        assert_eq!(select(Some("C")).unwrap_err(), "no operation named C");
+        assert_eq!(
+            select(None).unwrap_err(),
+            "operationName is required for a document with several operations"
+        );
+        assert_eq!(
+            run("fragment F on Shop { name }", json!({})).unwrap_err(),
+            "the document has no operations"
+        );
+        assert_eq!(
+            run("mutation { name }", json!({})).unwrap_err(),
+            "mutation operations aren't supported"
+        );
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        let error = |query: &str| run(query, json!({})).unwrap_err();
+        assert_eq!(
+            error("{ name"),
+            "expected a name, found the end of the document"
+        );
+        assert_eq!(error("{ echo(value: ) }"), "expected a value, found )");
+        assert_eq!(
+            error("{ echo(value: $x) }  query ($x: Int = $y) { name }"),
+            "expected a value, found $"
+        );
+        assert_eq!(error(r#"{ "name" }"#), r#"expected a name, found "name""#);
+        assert_eq!(error("{ echo(value 1) }"), "expected :, found 1");
+        assert_eq!(error("{ echo(value: 1.2.3) }"), "invalid number 1.2.3");
+        assert_eq!(error(r#"{ echo(value: "open) }"#), "unterminated string");
+        assert_eq!(error(r#"{ echo(value: "\q") }"#), "invalid escape \\q");
+        assert_eq!(
+            error(r#"{ echo(value: """open) }"#),
+            "unterminated block string"
+        );
+        assert_eq!(error("{ name % }"), "unexpected character '%'");
+        assert_eq!(error("shop { name }"), "unexpected shop");
+        assert_eq!(error("fragment F Shop { name }"), "expected on, found Shop");
+        assert_eq!(error("{ ...Missing }"), "unknown fragment Missing");
+        assert_eq!(
+            error("{ ...F } fragment F on Shop { shop { ...F } ...F }"),
+            "fragment F spreads itself"
+        );
+        assert_eq!(error("{ name @skip }"), "@skip needs a Boolean argument if");
+        assert_eq!(
+            error("{ name @defer(if: true) }"),
+            "unknown directive @defer"
+        );
+    }
+}
@@ -0,0 +1,1282 @@
+//! A gRPC server: HTTP/2 over plain TCP (h2c), speaking just enough of the protocol for
+//! unary and server-streaming calls. Each call is handled on a thread of its own, which
+//! writes its responses straight to the connection. Also a client for one streaming call
+//! per connection, over h2c or TLS, for the `geyser` source.
+
+use crate::hpack::{self, Headers};
+use crate::proxy::Proxy;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Largest frame either side sends until the other allows larger.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// Calls in progress at once on a connection; more are refused.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// Largest request message accepted.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+/// How long a write may block on a client that isn't reading.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+// HTTP/2 error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+/// A gRPC status code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    InvalidArgument = 3,
+    NotFound = 5,
+    ResourceExhausted = 8,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    Unauthenticated = 16,
+}
+
+/// Why a call failed, as sent to the client in its trailers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub code: Code,
+    pub message: String,
+}
+
+impl Status {
+    pub fn new(code: Code, message: impl Into<String>) -> Status {
+        Status {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// A call's request: its method, e.g. `/package.Service/Method`, metadata, and message.
+pub struct Call {
+    pub method: String,
+    pub metadata: Headers,
+    pub message: Vec<u8>,
+    pub peer: SocketAddr,
+}
+
+impl Call {
+    /// The value of the metadata entry `name`, which must be lowercase.
+    pub fn metadata(&self, name: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Handles calls, from any number of threads at once.
+pub trait Service: Send + Sync + 'static {
+    /// Handle `call`, sending its response messages to `responses`. Streaming calls
+    /// should return once sending fails, as the client has gone away.
+    fn call(&self, call: &Call, responses: &mut Responses) -> Result<(), Status>;
+}
+
+/// Listen on `addr`, serving `service` from background threads. Returns the address
+/// listened on.
+pub fn listen(addr: &str, service: Arc<dyn Service>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let service = service.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(stream, service) {
+                    log::debug!("gRPC connection ended: {}", e);
+                }
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+// --------------------------- Connections ---------------------------
+
+/// What a connection's call threads share with the thread reading from it.
+struct Connection {
+    writer: Mutex<TcpStream>, // each frame is written whole while this is held
+    state: Mutex<SendState>,
+    window_opened: Condvar, // signalled when send windows grow, or streams end
+    peer: SocketAddr,
+}
+
+/// Flow control for sending, and which streams are still open.
+struct SendState {
+    window: i64,                       // the connection's send window
+    initial_window: i64,               // new streams' send windows, as the client set it
+    max_frame_size: usize,             // as the client set it
+    streams: HashMap<u32, OpenStream>, // by stream ID, from request until response ends
+    closed: bool,                      // the connection has gone
+}
+
+struct OpenStream {
+    window: i64,
+    cancelled: bool, // reset by the client
+}
+
+impl Connection {
+    fn write_frame(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        let result = self.writer.lock().unwrap().write_all(&frame);
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    /// Send a header block, split into CONTINUATION frames if it doesn't fit in one.
+    fn write_headers(
+        &self,
+        stream: u32,
+        headers: &[(&str, &str)],
+        end_stream: bool,
+    ) -> io::Result<()> {
+        let block = hpack::encode(headers);
+        let max_frame_size = self.state.lock().unwrap().max_frame_size;
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, stream, chunk)?;
+            (kind, flags) = (CONTINUATION, 0);
+        }
+        if block.is_empty() {
+            self.write_frame(kind, flags | END_HEADERS, stream, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Send `data` on `stream` as the client's windows allow, waiting for them to open.
+    fn write_data(&self, stream: u32, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            let len = loop {
+                let Some(open) = state.streams.get(&stream).filter(|s| !s.cancelled) else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "call cancelled",
+                    ));
+                };
+                if state.closed {
+                    return Err(io::ErrorKind::ConnectionAborted.into());
+                }
+                let window = state.window.min(open.window);
+                if window > 0 {
+                    break (window as usize).min(state.max_frame_size).min(data.len());
+                }
+                state = self.window_opened.wait(state).unwrap();
+            };
+            state.window -= len as i64;
+            if let Some(open) = state.streams.get_mut(&stream) {
+                open.window -= len as i64;
+            }
+            drop(state);
+            let (chunk, rest) = data.split_at(len);
+            self.write_frame(DATA, 0, stream, chunk)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn is_cancelled(&self, stream: u32) -> bool {
+        let state = self.state.lock().unwrap();
+        state.closed || state.streams.get(&stream).is_none_or(|s| s.cancelled)
+    }
+
+    /// Forget `stream`, whose response has ended.
+    fn end_stream(&self, stream: u32) {
+        self.state.lock().unwrap().streams.remove(&stream);
+    }
+
+    /// Mark the connection gone, failing its calls' writes.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.window_opened.notify_all();
+    }
+
+    /// Tell the client the connection is ending because of a protocol error, returning
+    /// that error.
+    fn go_away(&self, last_stream: u32, code: u32, message: &str) -> io::Error {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(message.as_bytes());
+        let _ = self.write_frame(GOAWAY, 0, 0, &payload);
+        io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+    }
+}
+
+/// A request being received: its headers, and its body so far.
+struct Incoming {
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+/// A header block split across frames, until its END_HEADERS.
+struct PartialHeaders {
+    stream: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// Read frames from the client until it disconnects, starting a thread for each call.
+fn serve_connection(stream: TcpStream, service: Arc<dyn Service>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let conn = Arc::new(Connection {
+        writer: Mutex::new(stream.try_clone()?),
+        state: Mutex::new(SendState {
+            window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            streams: HashMap::new(),
+            closed: false,
+        }),
+        window_opened: Condvar::new(),
+        peer: stream.peer_addr()?,
+    });
+    let result = read_frames(&stream, &conn, &service);
+    conn.close();
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+fn read_frames(
+    mut stream: &TcpStream,
+    conn: &Arc<Connection>,
+    service: &Arc<dyn Service>,
+) -> io::Result<()> {
+    let mut preface = [0; PREFACE.len()];
+    stream.read_exact(&mut preface)?;
+    if preface != PREFACE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an HTTP/2 connection",
+        ));
+    }
+    let mut settings = SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+    settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+    conn.write_frame(SETTINGS, 0, 0, &settings)?;
+
+    let mut decoder = hpack::Decoder::default();
+    let mut incoming: HashMap<u32, Incoming> = HashMap::new();
+    let mut partial: Option<PartialHeaders> = None;
+    let mut last_stream = 0;
+    loop {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let (kind, flags) = (header[3], header[4]);
+        let id = u32::from_be_bytes(header[5..9].try_into().unwrap()) & 0x7fff_ffff;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(conn.go_away(last_stream, FRAME_SIZE_ERROR, "frame too large"));
+        }
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload)?;
+        if partial.is_some() && kind != CONTINUATION {
+            return Err(conn.go_away(last_stream, PROTOCOL_ERROR, "expected CONTINUATION"));
+        }
+
+        match kind {
+            HEADERS | CONTINUATION => {
+                let block = match kind {
+                    HEADERS => {
+                        if id == 0 {
+                            return Err(conn.go_away(
+                                last_stream,
+                                PROTOCOL_ERROR,
+                                "HEADERS on stream 0",
+                            ));
+                        }
+                        let mut fragment = unpad(&payload, flags).ok_or_else(|| {
+                            conn.go_away(last_stream, PROTOCOL_ERROR, "bad padding")
+                        })?;
+                        if flags & PRIORITY != 0 {
+                            fragment = fragment.get(5..).ok_or_else(|| {
+                                conn.go_away(last_stream, FRAME_SIZE_ERROR, "HEADERS too short")
+                            })?;
+                        }
+                        PartialHeaders {
+                            stream: id,
+                            end_stream: flags & END_STREAM != 0,
+                            block: fragment.to_vec(),
+                        }
+                    }
+                    _ => match partial.take() {
+                        Some(mut p) if p.stream == id => {
+                            p.block.extend_from_slice(&payload);
+                            p
+                        }
+                        _ => {
+                            return Err(conn.go_away(
+                                last_stream,
+                                PROTOCOL_ERROR,
+                                "unexpected CONTINUATION",
+                            ));
+                        }
+                    },
+                };
+                if block.block.len() > MAX_MESSAGE_LEN {
+                    return Err(conn.go_away(
+                        last_stream,
+                        PROTOCOL_ERROR,
+                        "header block too large",
+                    ));
+                }
+                if flags & END_HEADERS == 0 {
+                    partial = Some(block);
+                    continue;
+                }
+                // Every block must be decoded, to keep the decoder's table in step
+                let headers = decoder
+                    .decode(&block.block)
+                    .map_err(|e| conn.go_away(last_stream, COMPRESSION_ERROR, &e))?;
+                let id = block.stream;
+                if incoming.contains_key(&id) {
+                    // Trailers, which end the request
+                    if !block.end_stream {
+                        return Err(conn.go_away(
+                            last_stream,
+                            PROTOCOL_ERROR,
+                            "trailers without END_STREAM",
+                        ));
+                    }
+                    let request = incoming.remove(&id).unwrap();
+                    dispatch(conn, service, id, request);
+                    continue;
+                }
+                if id % 2 == 0 || id <= last_stream {
+                    return Err(conn.go_away(last_stream, PROTOCOL_ERROR, "invalid stream ID"));
+                }
+                last_stream = id;
+                {
+                    let mut state = conn.state.lock().unwrap();
+                    if state.streams.len() >= MAX_CONCURRENT_STREAMS {
+                        drop(state);
+                        reset(conn, id, REFUSED_STREAM)?;
+                        continue;
+                    }
+                    let window = state.initial_window;
+                    state.streams.insert(
+                        id,
+                        OpenStream {
+                            window,
+                            cancelled: false,
+                        },
+                    );
+                }
+                let request = Incoming {
+                    headers,
+                    body: Vec::new(),
+                };
+                match block.end_stream {
+                    true => dispatch(conn, service, id, request),
+                    false => {
+                        incoming.insert(id, request);
+                    }
+                }
+            }
+            DATA => {
+                if id == 0 {
+                    return Err(conn.go_away(last_stream, PROTOCOL_ERROR, "DATA on stream 0"));
+                }
+                // Padding counts against the window too, so replenish it all
+                if len > 0 {
+                    window_update(conn, 0, len)?;
+                }
+                let data = unpad(&payload, flags)
+                    .ok_or_else(|| conn.go_away(last_stream, PROTOCOL_ERROR, "bad padding"))?;
+                let Some(request) = incoming.get_mut(&id) else {
+                    if id > last_stream {
+                        return Err(conn.go_away(
+                            last_stream,
+                            PROTOCOL_ERROR,
+                            "DATA on an idle stream",
+                        ));
+                    }
+                    continue; // the stream was reset, or the request already complete
+                };
+                if request.body.len() + data.len() > MAX_MESSAGE_LEN + 5 {
+                    incoming.remove(&id);
+                    let status = Status::new(Code::ResourceExhausted, "request too large");
+                    finish(conn, id, false, Err(status));
+                    // Stop the client sending the rest
+                    reset(conn, id, NO_ERROR)?;
+                    continue;
+                }
+                request.body.extend_from_slice(data);
+                if flags & END_STREAM != 0 {
+                    let request = incoming.remove(&id).unwrap();
+                    dispatch(conn, service, id, request);
+                } else if len > 0 {
+                    window_update(conn, id, len)?;
+                }
+            }
+            RST_STREAM => {
+                incoming.remove(&id);
+                if let Some(open) = conn.state.lock().unwrap().streams.get_mut(&id) {
+                    open.cancelled = true;
+                }
+                conn.window_opened.notify_all();
+            }
+            SETTINGS if flags & ACK != 0 => {}
+            SETTINGS => {
+                if id != 0 || !len.is_multiple_of(6) {
+                    return Err(conn.go_away(last_stream, FRAME_SIZE_ERROR, "malformed SETTINGS"));
+                }
+                for setting in payload.chunks(6) {
+                    let value = u32::from_be_bytes(setting[2..6].try_into().unwrap());
+                    match u16::from_be_bytes([setting[0], setting[1]]) {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            if value as i64 > MAX_WINDOW {
+                                return Err(conn.go_away(
+                                    last_stream,
+                                    FLOW_CONTROL_ERROR,
+                                    "window too large",
+                                ));
+                            }
+                            let mut state = conn.state.lock().unwrap();
+                            let delta = value as i64 - state.initial_window;
+                            state.initial_window = value as i64;
+                            for open in state.streams.values_mut() {
+                                open.window += delta;
+                            }
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => {
+                            if !(DEFAULT_MAX_FRAME_SIZE as u32..1 << 24).contains(&value) {
+                                return Err(conn.go_away(
+                                    last_stream,
+                                    PROTOCOL_ERROR,
+                                    "invalid frame size",
+                                ));
+                            }
+                            conn.state.lock().unwrap().max_frame_size = value as usize;
+                        }
+                        _ => {}
+                    }
+                }
+                conn.window_opened.notify_all();
+                conn.write_frame(SETTINGS, ACK, 0, &[])?;
+            }
+            PING if flags & ACK == 0 => {
+                if len != 8 {
+                    return Err(conn.go_away(
+                        last_stream,
+                        FRAME_SIZE_ERROR,
+                        "PING must be 8 bytes",
+                    ));
+                }
+                conn.write_frame(PING, ACK, 0, &payload)?;
+            }
+            WINDOW_UPDATE => {
+                if len != 4 {
+                    return Err(conn.go_away(
+                        last_stream,
+                        FRAME_SIZE_ERROR,
+                        "WINDOW_UPDATE must be 4 bytes",
+                    ));
+                }
+                let increment =
+                    (u32::from_be_bytes(payload[..4].try_into().unwrap()) & 0x7fff_ffff) as i64;
+                let mut state = conn.state.lock().unwrap();
+                let window = match id {
+                    0 => Some(&mut state.window),
+                    _ => state.streams.get_mut(&id).map(|s| &mut s.window),
+                };
+                if let Some(window) = window {
+                    *window += increment;
+                    if *window > MAX_WINDOW {
+                        drop(state);
+                        return Err(conn.go_away(
+                            last_stream,
+                            FLOW_CONTROL_ERROR,
+                            "window overflow",
+                        ));
+                    }
+                }
+                drop(state);
+                conn.window_opened.notify_all();
+            }
+            PUSH_PROMISE => {
+                return Err(conn.go_away(last_stream, PROTOCOL_ERROR, "clients can't push"));
+            }
+            GOAWAY => {
+                // Calls in progress finish; the client closes the connection after
+            }
+            _ => {} // PRIORITY, PING acks, and unknown frame types are ignored
+        }
+    }
+}
+
+/// A DATA or HEADERS frame's payload without its padding, or `None` if malformed.
+fn unpad(payload: &[u8], flags: u8) -> Option<&[u8]> {
+    if flags & PADDED == 0 {
+        return Some(payload);
+    }
+    let (&pad, rest) = payload.split_first()?;
+    rest.get(..rest.len().checked_sub(pad as usize)?)
+}
+
+fn window_update(conn: &Connection, stream: u32, increment: usize) -> io::Result<()> {
+    conn.write_frame(WINDOW_UPDATE, 0, stream, &(increment as u32).to_be_bytes())
+}
+
+fn reset(conn: &Connection, stream: u32, code: u32) -> io::Result<()> {
+    conn.end_stream(stream);
+    conn.write_frame(RST_STREAM, 0, stream, &code.to_be_bytes())
+}
+
+// --------------------------- Calls ---------------------------
+
+/// Where a call sends its response messages.
+pub struct Responses {
+    conn: Arc<Connection>,
+    stream: u32,
+    headers_sent: bool,
+}
+
+impl Responses {
+    /// Send a response message. Fails once the call is cancelled or the client is gone.
+    pub fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        if !self.headers_sent {
+            self.conn
+                .write_headers(self.stream, &response_headers(), false)?;
+            self.headers_sent = true;
+        }
+        let mut frame = Vec::with_capacity(5 + message.len());
+        frame.push(0); // not compressed
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend_from_slice(message);
+        self.conn.write_data(self.stream, &frame)
+    }
+
+    /// Whether the client has cancelled the call, or gone away.
+    pub fn is_cancelled(&self) -> bool {
+        self.conn.is_cancelled(self.stream)
+    }
+}
+
+fn response_headers() -> [(&'static str, &'static str); 3] {
+    [
+        (":status", "200"),
+        ("content-type", "application/grpc"),
+        ("grpc-accept-encoding", "identity"),
+    ]
+}
+
+/// Handle the complete request `request` on a thread of its own.
+fn dispatch(conn: &Arc<Connection>, service: &Arc<dyn Service>, stream: u32, request: Incoming) {
+    let (conn, service) = (conn.clone(), service.clone());
+    thread::spawn(move || {
+        let header = |name: &str| {
+            request
+                .headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        if header(":method") != Some("POST") {
+            let _ = conn.write_headers(stream, &[(":status", "405")], true);
+            conn.end_stream(stream);
+            return;
+        }
+        if !header("content-type").is_some_and(|t| t.starts_with("application/grpc")) {
+            let _ = conn.write_headers(stream, &[(":status", "415")], true);
+            conn.end_stream(stream);
+            return;
+        }
+        let call = match message(&request.body) {
+            Ok(message) => Ok(Call {
+                method: header(":path").unwrap_or_default().to_string(),
+                metadata: request
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| !name.starts_with(':'))
+                    .cloned()
+                    .collect(),
+                message,
+                peer: conn.peer,
+            }),
+            Err(status) => Err(status),
+        };
+        let mut responses = Responses {
+            conn: conn.clone(),
+            stream,
+            headers_sent: false,
+        };
+        let status = call.and_then(|call| service.call(&call, &mut responses));
+        finish(&conn, stream, responses.headers_sent, status);
+    });
+}
+
+/// The single message of a request body.
+fn message(body: &[u8]) -> Result<Vec<u8>, Status> {
+    let invalid = || {
+        Status::new(
+            Code::InvalidArgument,
+            "expected exactly one request message",
+        )
+    };
+    if body.len() < 5 {
+        return Err(invalid());
+    }
+    if body[0] != 0 {
+        return Err(Status::new(
+            Code::Unimplemented,
+            "compressed messages aren't supported",
+        ));
+    }
+    let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(Status::new(Code::ResourceExhausted, "request too large"));
+    }
+    if body.len() != 5 + len {
+        return Err(invalid());
+    }
+    Ok(body[5..].to_vec())
+}
+
+/// End a call with `status`: in trailers after its responses, or in the response
+/// headers if none were sent.
+fn finish(conn: &Connection, stream: u32, headers_sent: bool, status: Result<(), Status>) {
+    if conn.is_cancelled(stream) {
+        conn.end_stream(stream);
+        return;
+    }
+    let (code, message) = match &status {
+        Ok(()) => ("0".to_string(), String::new()),
+        Err(status) => (
+            (status.code as u32).to_string(),
+            percent_encode(&status.message),
+        ),
+    };
+    let mut trailers = vec![("grpc-status", code.as_str())];
+    if !message.is_empty() {
+        trailers.push(("grpc-message", message.as_str()));
+    }
+    if !headers_sent {
+        trailers.splice(0..0, response_headers());
+    }
+    let _ = conn.write_headers(stream, &trailers, true);
+    conn.end_stream(stream);
+}
+
+/// `message` as gRPC sends status messages: percent-encoded, except for printable ASCII.
+fn percent_encode(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+// --------------------------- Client ---------------------------
+
+/// The client's receive window, for the connection and its call alike, so a busy stream
+/// isn't held up waiting for window updates.
+const CLIENT_WINDOW: i64 = 1 << 20;
+/// Largest response message accepted.
+const MAX_RESPONSE_LEN: usize = 16 << 20;
+/// The one stream a client connection carries.
+const CALL_STREAM: u32 = 1;
+
+trait ClientStream: Read + Write + Send {}
+impl<T: Read + Write + Send> ClientStream for T {}
+
+/// A streaming call to a gRPC server, on a connection of its own: request messages are
+/// sent with `send`, and response messages read with `receive`, on the same thread.
+pub struct ClientCall {
+    stream: Box<dyn ClientStream>,
+    decoder: hpack::Decoder,
+    window: i64,                       // the connection's send window
+    stream_window: i64,                // the call's send window
+    initial_window: i64,               // new streams' send windows, as the server set it
+    max_frame_size: usize,             // as the server set it
+    received: Vec<u8>,                 // response bytes not yet returned as messages
+    headers: Option<PartialHeaders>,   // a header block until its END_HEADERS
+    responding: bool,                  // the response headers have arrived
+    ended: Option<Result<(), String>>, // the call's status, once the server ends it
+}
+
+impl ClientCall {
+    /// Call `method`, e.g. `/package.Service/Method`, on the server at `url`, `http://`
+    /// for h2c or `https://` for TLS, through `proxy` if given, sending `metadata`. Reads
+    /// fail with a timeout error once the server has been silent for `idle_timeout`.
+    pub fn open(
+        url: &str,
+        method: &str,
+        metadata: &[(&str, &str)],
+        proxy: Option<&Proxy>,
+        idle_timeout: Duration,
+    ) -> Result<ClientCall, Box<dyn std::error::Error>> {
+        let url = url::Url::parse(url)?;
+        let host = url.host_str().ok_or("gRPC URL has no host")?.to_string();
+        let port = url.port_or_known_default().ok_or("gRPC URL has no port")?;
+        let tcp = match proxy {
+            Some(proxy) => proxy.tunnel(&host, port)?,
+            None => {
+                let addr = (host.as_str(), port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| format!("could not resolve {}", host))?;
+                TcpStream::connect_timeout(&addr, idle_timeout)?
+            }
+        };
+        tcp.set_read_timeout(Some(idle_timeout))?;
+        tcp.set_nodelay(true)?;
+        let stream: Box<dyn ClientStream> = match url.scheme() {
+            "http" => Box::new(tcp),
+            "https" => {
+                let name = rustls::pki_types::ServerName::try_from(host.clone())?;
+                let conn = rustls::ClientConnection::new(client_tls_config(), name)?;
+                Box::new(rustls::StreamOwned::new(conn, tcp))
+            }
+            other => return Err(format!("unsupported gRPC scheme: {}", other).into()),
+        };
+        let mut call = ClientCall {
+            stream,
+            decoder: hpack::Decoder::default(),
+            window: DEFAULT_WINDOW,
+            stream_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            received: Vec::new(),
+            headers: None,
+            responding: false,
+            ended: None,
+        };
+
+        let mut settings = SETTINGS_ENABLE_PUSH.to_be_bytes().to_vec();
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        settings.extend_from_slice(&SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes());
+        settings.extend_from_slice(&(CLIENT_WINDOW as u32).to_be_bytes());
+        call.stream.write_all(PREFACE)?;
+        call.write_frame(SETTINGS, 0, 0, &settings)?;
+        let increment = (CLIENT_WINDOW - DEFAULT_WINDOW) as u32;
+        call.write_frame(WINDOW_UPDATE, 0, 0, &increment.to_be_bytes())?;
+
+        let authority = match url.port() {
+            Some(p) => format!("{}:{}", host, p),
+            None => host,
+        };
+        let mut headers = vec![
+            (":method", "POST"),
+            (":scheme", url.scheme()),
+            (":path", method),
+            (":authority", authority.as_str()),
+            ("content-type", "application/grpc"),
+            ("te", "trailers"),
+        ];
+        headers.extend_from_slice(metadata);
+        // The server's SETTINGS haven't been read yet, so the block is split at the
+        // default frame size
+        let block = hpack::encode(&headers);
+        let mut chunks = block.chunks(DEFAULT_MAX_FRAME_SIZE).peekable();
+        let mut kind = HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_none() {
+                END_HEADERS
+            } else {
+                0
+            };
+            call.write_frame(kind, flags, CALL_STREAM, chunk)?;
+            kind = CONTINUATION;
+        }
+        call.stream.flush()?;
+        Ok(call)
+    }
+
+    /// Send a request message, waiting for the server's windows to open if need be.
+    pub fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut data = Vec::with_capacity(5 + message.len());
+        data.push(0); // not compressed
+        data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        data.extend_from_slice(message);
+        let mut data = data.as_slice();
+        while !data.is_empty() {
+            if let Some(status) = &self.ended {
+                return Err(ended_error(status).into());
+            }
+            let window = self.window.min(self.stream_window);
+            if window <= 0 {
+                self.read_frame()?;
+                continue;
+            }
+            let len = (window as usize).min(self.max_frame_size).min(data.len());
+            let (chunk, rest) = data.split_at(len);
+            self.write_frame(DATA, 0, CALL_STREAM, chunk)?;
+            self.window -= len as i64;
+            self.stream_window -= len as i64;
+            data = rest;
+        }
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// End the request, for calls whose server waits for the whole of it.
+    pub fn close_send(&mut self) -> io::Result<()> {
+        self.write_frame(DATA, END_STREAM, CALL_STREAM, &[])?;
+        self.stream.flush()
+    }
+
+    /// The next response message, or `None` once the call has ended with an OK status.
+    pub fn receive(&mut self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        loop {
+            if self.received.len() >= 5 {
+                if self.received[0] != 0 {
+                    return Err("compressed messages aren't supported".into());
+                }
+                let len = u32::from_be_bytes(self.received[1..5].try_into().unwrap()) as usize;
+                if len > MAX_RESPONSE_LEN {
+                    return Err("response message too large".into());
+                }
+                if self.received.len() >= 5 + len {
+                    let message = self.received[5..5 + len].to_vec();
+                    self.received.drain(..5 + len);
+                    return Ok(Some(message));
+                }
+            }
+            match &self.ended {
+                Some(Ok(())) if self.received.is_empty() => return Ok(None),
+                Some(Ok(())) => return Err("call ended inside a message".into()),
+                Some(status) => return Err(ended_error(status).into()),
+                None => self.read_frame()?,
+            }
+        }
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)
+    }
+
+    /// Read and act on one frame from the server.
+    fn read_frame(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut header = [0; 9];
+        self.stream.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let (kind, flags) = (header[3], header[4]);
+        let id = u32::from_be_bytes(header[5..9].try_into().unwrap()) & 0x7fff_ffff;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err("frame too large".into());
+        }
+        let mut payload = vec![0; len];
+        self.stream.read_exact(&mut payload)?;
+        if self.headers.is_some() && kind != CONTINUATION {
+            return Err("expected CONTINUATION".into());
+        }
+
+        match kind {
+            DATA if id == CALL_STREAM => {
+                // Padding counts against the window too, so replenish it all
+                if len > 0 {
+                    let increment = (len as u32).to_be_bytes();
+                    self.write_frame(WINDOW_UPDATE, 0, 0, &increment)?;
+                    self.write_frame(WINDOW_UPDATE, 0, CALL_STREAM, &increment)?;
+                    self.stream.flush()?;
+                }
+                let data = unpad(&payload, flags).ok_or("bad padding")?;
+                if self.received.len() + data.len() > MAX_RESPONSE_LEN + 5 {
+                    return Err("response message too large".into());
+                }
+                self.received.extend_from_slice(data);
+                if flags & END_STREAM != 0 {
+                    self.ended = Some(Err("call ended without a status".to_string()));
+                }
+            }
+            HEADERS | CONTINUATION => {
+                let mut block = match kind {
+                    HEADERS => {
+                        let mut fragment = unpad(&payload, flags).ok_or("bad padding")?;
+                        if flags & PRIORITY != 0 {
+                            fragment = fragment.get(5..).ok_or("HEADERS too short")?;
+                        }
+                        PartialHeaders {
+                            stream: id,
+                            end_stream: flags & END_STREAM != 0,
+                            block: fragment.to_vec(),
+                        }
+                    }
+                    _ => match self.headers.take() {
+                        Some(mut p) if p.stream == id => {
+                            p.block.extend_from_slice(&payload);
+                            p
+                        }
+                        _ => return Err("unexpected CONTINUATION".into()),
+                    },
+                };
+                if block.block.len() > MAX_MESSAGE_LEN {
+                    return Err("header block too large".into());
+                }
+                if flags & END_HEADERS == 0 {
+                    self.headers = Some(block);
+                    return Ok(());
+                }
+                // Every block must be decoded, to keep the decoder's table in step
+                let headers = self.decoder.decode(&std::mem::take(&mut block.block))?;
+                if block.stream == CALL_STREAM {
+                    self.on_headers(&headers, block.end_stream);
+                }
+            }
+            RST_STREAM if id == CALL_STREAM => {
+                let code = payload
+                    .get(..4)
+                    .map(|c| u32::from_be_bytes(c.try_into().unwrap()));
+                let message = format!("call reset by the server (code {})", code.unwrap_or(0));
+                self.ended = Some(Err(message));
+            }
+            SETTINGS if flags & ACK != 0 => {}
+            SETTINGS => {
+                if id != 0 || !len.is_multiple_of(6) {
+                    return Err("malformed SETTINGS".into());
+                }
+                for setting in payload.chunks(6) {
+                    let value = u32::from_be_bytes(setting[2..6].try_into().unwrap());
+                    match u16::from_be_bytes([setting[0], setting[1]]) {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            if value as i64 > MAX_WINDOW {
+                                return Err("window too large".into());
+                            }
+                            // The call's window moves with it; the connection's doesn't
+                            self.stream_window += value as i64 - self.initial_window;
+                            self.initial_window = value as i64;
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => {
+                            if !(DEFAULT_MAX_FRAME_SIZE as u32..1 << 24).contains(&value) {
+                                return Err("invalid frame size".into());
+                            }
+                            self.max_frame_size = value as usize;
+                        }
+                        _ => {}
+                    }
+                }
+                self.write_frame(SETTINGS, ACK, 0, &[])?;
+                self.stream.flush()?;
+            }
+            PING if flags & ACK == 0 => {
+                if len != 8 {
+                    return Err("PING must be 8 bytes".into());
+                }
+                self.write_frame(PING, ACK, 0, &payload)?;
+                self.stream.flush()?;
+            }
+            WINDOW_UPDATE => {
+                if len != 4 {
+                    return Err("WINDOW_UPDATE must be 4 bytes".into());
+                }
+                let increment =
+                    (u32::from_be_bytes(payload[..4].try_into().unwrap()) & 0x7fff_ffff) as i64;
+                match id {
+                    0 => self.window += increment,
+                    CALL_STREAM => self.stream_window += increment,
+                    _ => {}
+                }
+                if self.window > MAX_WINDOW || self.stream_window > MAX_WINDOW {
+                    return Err("window overflow".into());
+                }
+            }
+            PUSH_PROMISE => return Err("server push wasn't enabled".into()),
+            GOAWAY => {
+                let last_stream = payload
+                    .get(..4)
+                    .map(|s| u32::from_be_bytes(s.try_into().unwrap()) & 0x7fff_ffff);
+                let code = payload
+                    .get(4..8)
+                    .map(|c| u32::from_be_bytes(c.try_into().unwrap()));
+                // The call carries on if the server lets calls in progress finish
+                if last_stream.unwrap_or(0) < CALL_STREAM || code != Some(NO_ERROR) {
+                    let message = String::from_utf8_lossy(payload.get(8..).unwrap_or_default());
+                    return Err(format!(
+                        "server went away (code {}): {}",
+                        code.unwrap_or(0),
+                        message
+                    )
+                    .into());
+                }
+            }
+            _ => {} // PRIORITY, PING acks, other streams and unknown frame types are ignored
+        }
+        Ok(())
+    }
+
+    /// Act on the call's response headers or trailers.
+    fn on_headers(&mut self, headers: &Headers, end_stream: bool) {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        if !self.responding {
+            self.responding = true;
+            if let Some(status) = header(":status").filter(|&s| s != "200") {
+                self.ended = Some(Err(format!("HTTP status {}", status)));
+                return;
+            }
+        }
+        // Trailers, or the response headers of a call that failed outright
+        if let Some(code) = header("grpc-status") {
+            self.ended = Some(match code {
+                "0" => Ok(()),
+                _ => Err(format!(
+                    "gRPC status {}: {}",
+                    code,
+                    percent_decode(header("grpc-message").unwrap_or_default())
+                )),
+            });
+        } else if end_stream {
+            self.ended = Some(Err("call ended without a status".to_string()));
+        }
+    }
+}
+
+/// Why `send` or `receive` can't go on once the call has ended with `status`.
+fn ended_error(status: &Result<(), String>) -> String {
+    match status {
+        Ok(()) => "call already ended".to_string(),
+        Err(e) => e.clone(),
+    }
+}
+
+/// A status message as gRPC sends them; see `percent_encode`.
+fn percent_decode(message: &str) -> String {
+    let mut out = Vec::with_capacity(message.len());
+    let mut bytes = message.bytes();
+    while let Some(byte) = bytes.next() {
+        let escaped = (byte == b'%')
+            .then(|| bytes.clone().take(2).collect::<Vec<u8>>())
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok());
+        match escaped {
+            Some(decoded) => {
+                out.push(decoded);
+                bytes.nth(1);
+            }
+            None => out.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// TLS settings for `https://` calls: those `wss://` uses, offering HTTP/2.
+fn client_tls_config() -> Arc<rustls::ClientConfig> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let mut config = (*crate::pubsub::tls_config()).clone();
+            config.alpn_protocols = vec![b"h2".to_vec()];
+            Arc::new(config)
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers each call with its request message, then that message reversed.
+    struct Echo;
+
+    impl Service for Echo {
+        fn call(&self, call: &Call, responses: &mut Responses) -> Result<(), Status> {
+            if call.method != "/test.Echo/Echo" {
+                return Err(Status::new(Code::Unimplemented, "no such method: 100%"));
+            }
+            let internal = |e: io::Error| Status::new(Code::Internal, e.to_string());
+            responses.send(&call.message).map_err(internal)?;
+            let reversed: Vec<u8> = call.message.iter().rev().copied().collect();
+            responses.send(&reversed).map_err(internal)
+        }
+    }
+
+    fn echo(url: &str, method: &str, message: &[u8]) -> ClientCall {
+        let mut call = ClientCall::open(url, method, &[], None, Duration::from_secs(5)).unwrap();
+        call.send(message).unwrap();
+        call.close_send().unwrap();
+        call
+    }
+
+    #[test]
+    fn client_calls_round_trip() {
+        let url = format!("http://{}", listen("127.0.0.1:0", Arc::new(Echo)).unwrap());
+        let mut call = echo(&url, "/test.Echo/Echo", b"hello");
+        assert_eq!(call.receive().unwrap().as_deref(), Some(&b"hello"[..]));
+        assert_eq!(call.receive().unwrap().as_deref(), Some(&b"olleh"[..]));
+        assert_eq!(call.receive().unwrap(), None);
+
+        // Larger than a frame each way, and than the server's initial window
+        let large: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let mut call = echo(&url, "/test.Echo/Echo", &large);
+        assert_eq!(call.receive().unwrap(), Some(large.clone()));
+        assert_eq!(call.receive().unwrap().map(|m| m.len()), Some(large.len()));
+        assert_eq!(call.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn client_calls_report_the_status() {
+        let url = format!("http://{}", listen("127.0.0.1:0", Arc::new(Echo)).unwrap());
+        let e = echo(&url, "/test.Echo/Nope", b"").receive().unwrap_err();
+        assert_eq!(e.to_string(), "gRPC status 12: no such method: 100%");
+    }
+
+    #[test]
+    fn status_messages_are_percent_encoded() {
+        let message = "100% done\n, café";
+        assert_eq!(percent_encode(message), "100%25 done%0A, caf%C3%A9");
+        assert_eq!(percent_decode(&percent_encode(message)), message);
+        assert_eq!(percent_decode("50%"), "50%");
+    }
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// The next frame from `stream`, as (type, flags, stream ID, payload).
+    fn read_raw_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).unwrap();
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        let id = u32::from_be_bytes(header[5..9].try_into().unwrap());
+        (header[3], header[4], id, payload)
+    }
+
+    #[test]
+    fn calls_are_served_from_raw_frames() {
+        let addr = listen("127.0.0.1:0", Arc::new(Echo)).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let headers = hpack::encode(&[
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", "/test.Echo/Echo"),
+            (":authority", "localhost"),
+            ("content-type", "application/grpc"),
+        ]);
+        // The request message, length-prefixed, in a DATA frame with 3 bytes of padding
+        let data = [&[3, 0, 0, 0, 0, 5][..], b"hello", &[0; 3]].concat();
+        let request = [
+            PREFACE,
+            &frame(SETTINGS, 0, 0, &[]),
+            &frame(HEADERS, END_HEADERS, 1, &headers),
+            &frame(DATA, PADDED | END_STREAM, 1, &data),
+        ]
+        .concat();
+        stream.write_all(&request).unwrap();
+
+        let settings = read_raw_frame(&mut stream);
+        assert_eq!(settings, (SETTINGS, 0, 0, vec![0, 3, 0, 0, 0, 100]));
+        let mut decoder = hpack::Decoder::default();
+        let (mut headers, mut messages) = (Vec::new(), Vec::new());
+        loop {
+            let (kind, flags, id, payload) = read_raw_frame(&mut stream);
+            match kind {
+                HEADERS => {
+                    assert_eq!((id, flags & END_HEADERS), (1, END_HEADERS));
+                    headers.push(decoder.decode(&payload).unwrap());
+                    if flags & END_STREAM != 0 {
+                        break;
+                    }
+                }
+                DATA => {
+                    assert_eq!(id, 1);
+                    messages.push(payload);
+                }
+                _ => {} // the ack of our SETTINGS, and window updates
+            }
+        }
+        let field = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            headers,
+            [
+                vec![
+                    field(":status", "200"),
+                    field("content-type", "application/grpc"),
+                    field("grpc-accept-encoding", "identity"),
+                ],
+                vec![field("grpc-status", "0")],
+            ]
+        );
+        assert_eq!(
+            messages,
+            [b"\x00\x00\x00\x00\x05hello", b"\x00\x00\x00\x00\x05olleh"]
+        );
+    }
+
+    #[test]
+    fn request_bodies_hold_one_length_prefixed_message() {
+        assert_eq!(message(b"\x00\x00\x00\x00\x02hi"), Ok(b"hi".to_vec()));
+        assert_eq!(message(b"\x00\x00\x00\x00\x00"), Ok(Vec::new()));
+        let code = |body: &[u8]| message(body).unwrap_err().code;
+        assert_eq!(code(b""), Code::InvalidArgument);
+        assert_eq!(code(b"\x00\x00\x00\x00\x03hi"), Code::InvalidArgument);
+        assert_eq!(code(b"\x00\x00\x00\x00\x01hi"), Code::InvalidArgument); // trailing bytes
+        assert_eq!(code(b"\x01\x00\x00\x00\x02hi"), Code::Unimplemented);
+        assert_eq!(code(b"\x00\x00\x10\x00\x01"), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn padding_is_stripped() {
+        assert_eq!(unpad(b"abc", 0), Some(&b"abc"[..]));
+        assert_eq!(unpad(b"\x02abc", PADDED), Some(&b"a"[..]));
+        assert_eq!(unpad(b"\x00abc", PADDED), Some(&b"abc"[..]));
+        assert_eq!(unpad(b"\x04abc", PADDED), None);
+        assert_eq!(unpad(b"", PADDED), None);
+    }
+}
@@ -0,0 +1,224 @@
+//! A time series of past estimates, kept in memory and optionally appended to a file
+//! of JSON lines so it survives restarts, or recorded to a database instead.
+
+use crate::database::{Database, DatabaseTarget};
+use crate::estimator::Estimate;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How far back history is kept and can be queried.
+pub const MAX_HISTORY_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Percentiles of one program's estimate at one refresh.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPoint {
+    pub program: String,
+    pub timestamp: u64, // unix seconds
+    pub p25: u64,
+    pub p50: u64,
+    pub p75: u64,
+    pub p90: u64,
+    pub sample_size: usize,
+    pub min_slot: Option<u64>,
+    pub max_slot: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>, // `FeeSource::name`, or "mixed" if a bucket's points differ
+}
+
+impl HistoryPoint {
+    pub fn new(program: &str, e: &Estimate) -> HistoryPoint {
+        HistoryPoint {
+            program: program.to_string(),
+            timestamp: e.last_updated,
+            p25: e.fee_at(25.0),
+            p50: e.fee_at(50.0),
+            p75: e.fee_at(75.0),
+            p90: e.fee_at(90.0),
+            sample_size: e.selected.len(),
+            min_slot: e.slot_range().map(|(min, _)| min),
+            max_slot: e.slot_range().map(|(_, max)| max),
+            source: Some(e.source.to_string()),
+        }
+    }
+}
+
+/// Past estimates of every program, oldest first.
+pub struct History {
+    points: Mutex<Vec<HistoryPoint>>,
+    file: Option<Mutex<File>>,
+    db: Option<Database>, // takes the place of `points` and `file`
+}
+
+impl History {
+    /// A history kept in memory only.
+    pub fn in_memory() -> History {
+        History {
+            points: Mutex::new(Vec::new()),
+            file: None,
+            db: None,
+        }
+    }
+
+    /// A history kept in memory only, starting with `points`, such as those saved on a
+    /// previous shutdown.
+    pub fn in_memory_from(mut points: Vec<HistoryPoint>) -> History {
+        points.sort_by_key(|p| p.timestamp);
+        History {
+            points: Mutex::new(points),
+            file: None,
+            db: None,
+        }
+    }
+
+    /// A history persisted to `path`, loading the points already there.
+    pub fn open(path: &str) -> Result<History, Box<dyn std::error::Error>> {
+        let mut points = Vec::new();
+        if let Ok(f) = File::open(path) {
+            for (i, line) in BufReader::new(f).lines().enumerate() {
+                match serde_json::from_str::<HistoryPoint>(&line?) {
+                    Ok(point) => points.push(point),
+                    Err(e) => {
+                        log::warn!("skipping invalid history line {} in {}: {}", i + 1, path, e)
+                    }
+                }
+            }
+        }
+        points.sort_by_key(|p| p.timestamp);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(History {
+            points: Mutex::new(points),
+            file: Some(Mutex::new(file)),
+            db: None,
+        })
+    }
+
+    /// A history recorded to the database at `target`, keeping `retention` of it.
+    pub fn open_database(target: &DatabaseTarget, retention: Duration) -> io::Result<History> {
+        Ok(History {
+            points: Mutex::new(Vec::new()),
+            file: None,
+            db: Some(Database::open(target, retention)?),
+        })
+    }
+
+    /// Record a point, dropping those older than `MAX_HISTORY_AGE_SECS`, or the
+    /// database's retention window.
+    pub fn record(&self, point: HistoryPoint) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.record(&point) {
+                log::error!("failed to record history: {}", e);
+            }
+            return;
+        }
+        if let Some(file) = &self.file {
+            let line = serde_json::to_string(&point).unwrap();
+            if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                log::error!("failed to write history: {}", e);
+            }
+        }
+        let mut points = self.points.lock().unwrap();
+        let cutoff = point.timestamp.saturating_sub(MAX_HISTORY_AGE_SECS);
+        let stale = points.partition_point(|p| p.timestamp < cutoff);
+        points.drain(..stale);
+        points.push(point);
+    }
+
+    /// Whether points are persisted as they're recorded.
+    pub fn is_persisted(&self) -> bool {
+        self.file.is_some() || self.db.is_some()
+    }
+
+    /// How far back points are kept and can be queried, in seconds.
+    pub fn max_age_secs(&self) -> u64 {
+        match &self.db {
+            Some(db) => db.retention().as_secs(),
+            None => MAX_HISTORY_AGE_SECS,
+        }
+    }
+
+    /// Every point, oldest first.
+    pub fn points(&self) -> Vec<HistoryPoint> {
+        self.points.lock().unwrap().clone()
+    }
+
+    /// Make sure every recorded point has reached the disk.
+    pub fn flush(&self) -> std::io::Result<()> {
+        match &self.file {
+            Some(file) => file.lock().unwrap().sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// The points from `from` up to but not including `to`, of `program` or of every
+    /// program, oldest first.
+    pub fn range(
+        &self,
+        program: Option<&str>,
+        from: u64,
+        to: u64,
+    ) -> io::Result<Vec<HistoryPoint>> {
+        if let Some(db) = &self.db {
+            return db.range(program, from, to);
+        }
+        let points = self.points.lock().unwrap();
+        let start = points.partition_point(|p| p.timestamp < from);
+        let end = points.partition_point(|p| p.timestamp < to);
+        Ok(points[start..end.max(start)]
+            .iter()
+            .filter(|p| program.is_none_or(|program| p.program == program))
+            .cloned()
+            .collect())
+    }
+
+    /// `program`'s points from `since` onwards, averaged into buckets of `step` seconds
+    /// starting at `since`. Buckets without points are omitted.
+    pub fn series(&self, program: &str, since: u64, step: u64) -> io::Result<Vec<HistoryPoint>> {
+        if let Some(db) = &self.db {
+            return db.series(program, since, step);
+        }
+        let points = self.points.lock().unwrap();
+        let start = points.partition_point(|p| p.timestamp < since);
+        let mut series: Vec<HistoryPoint> = Vec::new();
+        let mut bucket: Vec<&HistoryPoint> = Vec::new();
+        let mut bucket_start = since;
+        for point in points[start..].iter().filter(|p| p.program == program) {
+            let point_bucket = since + (point.timestamp - since) / step * step;
+            if point_bucket != bucket_start && !bucket.is_empty() {
+                series.push(average(program, bucket_start, &bucket));
+                bucket.clear();
+            }
+            bucket_start = point_bucket;
+            bucket.push(point);
+        }
+        if !bucket.is_empty() {
+            series.push(average(program, bucket_start, &bucket));
+        }
+        Ok(series)
+    }
+}
+
+/// The mean percentiles of `points`, with their total sample size and slot range.
+fn average(program: &str, timestamp: u64, points: &[&HistoryPoint]) -> HistoryPoint {
+    let mean = |f: fn(&HistoryPoint) -> u64| {
+        (points.iter().map(|p| f(p) as u128).sum::<u128>() / points.len() as u128) as u64
+    };
+    HistoryPoint {
+        program: program.to_string(),
+        timestamp,
+        p25: mean(|p| p.p25),
+        p50: mean(|p| p.p50),
+        p75: mean(|p| p.p75),
+        p90: mean(|p| p.p90),
+        sample_size: points.iter().map(|p| p.sample_size).sum(),
+        min_slot: points.iter().filter_map(|p| p.min_slot).min(),
+        max_slot: points.iter().filter_map(|p| p.max_slot).max(),
+        source: match points.iter().all(|p| p.source == points[0].source) {
+            true => points[0].source.clone(),
+            false => Some("mixed".to_string()),
+        },
+    }
+}
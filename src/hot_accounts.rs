@@ -0,0 +1,45 @@
+//! Which accounts sampled transactions contend for. Fee markets are per writable
+//! account, so the accounts many senders write-lock at once are the ones driving fees up.
+
+use crate::sources::Sample;
+use std::collections::{HashMap, HashSet};
+
+/// An account write-locked by sampled transactions, with those transactions.
+pub struct HotAccount {
+    pub account: String,
+    pub payers: usize,        // distinct fee payers among them
+    pub samples: Vec<Sample>, // the transactions that write-locked it
+}
+
+/// The accounts write-locked by at least `min_samples` of `samples`, most contended
+/// first: by how many distinct fee payers wrote to them, then by how many
+/// transactions. A transaction's first writable account is its fee payer, so a busy
+/// bot's own wallet ranks below a pool its transactions compete for with others'.
+/// Samples that aren't of a transaction, such as getRecentPrioritizationFees', are
+/// skipped.
+pub fn hot_accounts(samples: &[Sample], min_samples: usize) -> Vec<HotAccount> {
+    let mut by_account: HashMap<&str, (HashSet<&str>, Vec<Sample>)> = HashMap::new();
+    for s in samples {
+        let (Some(_), Some(payer)) = (&s.signature, s.writable_accounts.first()) else {
+            continue;
+        };
+        for account in s.writable_accounts.iter() {
+            let (payers, samples) = by_account.entry(account).or_default();
+            payers.insert(payer);
+            samples.push(s.clone());
+        }
+    }
+    let mut hot: Vec<HotAccount> = by_account
+        .into_iter()
+        .filter(|(_, (_, samples))| samples.len() >= min_samples)
+        .map(|(account, (payers, samples))| HotAccount {
+            account: account.to_string(),
+            payers: payers.len(),
+            samples,
+        })
+        .collect();
+    hot.sort_by(|a, b| {
+        (b.payers, b.samples.len(), &a.account).cmp(&(a.payers, a.samples.len(), &b.account))
+    });
+    hot
+}
@@ -0,0 +1,570 @@
+//! HPACK, HTTP/2's header compression (RFC 7541), for the gRPC server. Requests' headers
+//! are decoded in full, Huffman coding and dynamic table included; responses' are
+//! encoded without either, which every decoder accepts.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// The dynamic table size HTTP/2 starts with, and which the gRPC server never raises.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+/// What each table entry costs on top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// A header block's fields, in order.
+pub type Headers = Vec<(String, String)>;
+
+/// The state a connection's header blocks are decoded with, which each block may change,
+/// so every block must be decoded, and in the order they were sent.
+pub struct Decoder {
+    table: VecDeque<(String, String)>, // most recently added first
+    size: usize,                       // of `table`'s entries, with their overhead
+    max_size: usize,                   // as last set by the encoder
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// The fields of the header block `block`.
+    pub fn decode(&mut self, block: &[u8]) -> Result<Headers, String> {
+        let mut headers = Vec::new();
+        let mut input = block;
+        while let Some(&first) = input.first() {
+            if first & 0x80 != 0 {
+                // Indexed field
+                let index = integer(&mut input, 7)?;
+                headers.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                // Literal added to the table
+                let field = self.literal(&mut input, 6)?;
+                self.insert(field.clone());
+                headers.push(field);
+            } else if first & 0x20 != 0 {
+                let max_size = integer(&mut input, 5)?;
+                if max_size > DEFAULT_TABLE_SIZE {
+                    return Err(format!("table size {} exceeds the limit", max_size));
+                }
+                self.max_size = max_size;
+                self.evict(0);
+            } else {
+                // Literal without indexing, or never indexed
+                headers.push(self.literal(&mut input, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    /// A literal field whose name is a table entry, or follows if its index is 0.
+    fn literal(&self, input: &mut &[u8], prefix: u8) -> Result<(String, String), String> {
+        let name = match integer(input, prefix)? {
+            0 => string(input)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, string(input)?))
+    }
+
+    /// The entry at `index` in the static table, followed by the dynamic one.
+    fn entry(&self, index: usize) -> Result<(String, String), String> {
+        match index {
+            0 => Err("index 0".to_string()),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self
+                .table
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| format!("index {} is past the end of the table", index)),
+        }
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // An entry larger than the table empties it, and isn't added
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Drop the oldest entries until `room` more would fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size
+            && let Some((name, value)) = self.table.pop_back()
+        {
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// `headers` as a header block, each field a literal that isn't added to the table.
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(name, value) in headers {
+        match STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value))
+        {
+            Some(i) => write_integer(&mut block, 0x80, 7, i + 1),
+            None => {
+                match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+                    Some(i) => write_integer(&mut block, 0, 4, i + 1),
+                    None => {
+                        block.push(0);
+                        write_string(&mut block, name);
+                    }
+                }
+                write_string(&mut block, value);
+            }
+        }
+    }
+    block
+}
+
+/// Read an integer with a `prefix`-bit prefix, the rest of whose first byte is flags.
+fn integer(input: &mut &[u8], prefix: u8) -> Result<usize, String> {
+    let truncated = || "truncated integer".to_string();
+    let (&first, rest) = input.split_first().ok_or_else(truncated)?;
+    *input = rest;
+    let max = (1 << prefix) - 1;
+    let mut value = (first & max) as usize;
+    if value < max as usize {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input.split_first().ok_or_else(truncated)?;
+        *input = rest;
+        if shift > 28 {
+            return Err("integer too large".to_string());
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn write_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        block.push(rest as u8 | 0x80);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+/// Read a string literal, Huffman-coded or not.
+fn string(input: &mut &[u8]) -> Result<String, String> {
+    let huffman = input.first().is_some_and(|b| b & 0x80 != 0);
+    let len = integer(input, 7)?;
+    if len > input.len() {
+        return Err("truncated string".to_string());
+    }
+    let (raw, rest) = input.split_at(len);
+    *input = rest;
+    let bytes = match huffman {
+        true => huffman_decode(raw)?,
+        false => raw.to_vec(),
+    };
+    String::from_utf8(bytes).map_err(|_| "header isn't UTF-8".to_string())
+}
+
+fn write_string(block: &mut Vec<u8>, s: &str) {
+    write_integer(block, 0, 7, s.len());
+    block.extend_from_slice(s.as_bytes());
+}
+
+/// A node's child with this bit set is a symbol; otherwise it's the index of a node.
+const LEAF: u16 = 0x8000;
+/// The end-of-string symbol, which mustn't appear in a string.
+const EOS_LEAF: u16 = LEAF | 256;
+
+/// The Huffman code as a binary tree: each node's children for a 0 and a 1 bit, with
+/// the root first. 0 is no child, as the root is no node's child.
+fn huffman_tree() -> &'static [[u16; 2]] {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = (code >> i) as usize & 1;
+                if i == 0 {
+                    tree[node][bit] = LEAF | symbol as u16;
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0; 2]);
+                        tree[node][bit] = (tree.len() - 1) as u16;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, String> {
+    let tree = huffman_tree();
+    let mut out = Vec::with_capacity(raw.len() * 8 / 5);
+    let mut node = 0;
+    // Bits read since the last symbol; the string must end in fewer than 8, all 1s
+    let (mut pending, mut all_ones) = (0, true);
+    for byte in raw {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            pending += 1;
+            all_ones &= bit == 1;
+            match tree[node][bit as usize] {
+                EOS_LEAF => return Err("EOS in a Huffman-coded string".to_string()),
+                child if child & LEAF != 0 => {
+                    out.push(child as u8);
+                    (node, pending, all_ones) = (0, 0, true);
+                }
+                child => node = child as usize,
+            }
+        }
+    }
+    if pending >= 8 || !all_ones {
+        return Err("invalid Huffman padding".to_string());
+    }
+    Ok(out)
+}
+
+/// The code and length in bits of each byte's Huffman code, then EOS's (RFC 7541
+/// appendix B).
+#[rustfmt::skip]
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28),
+    (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24),
+    (0x3ffffffc, 30), (0xfffffe9, 28), (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28),
+    (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28), (0xffffff4, 28),
+    (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10), (0xf9, 8),
+    (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6), (0x0, 5), (0x1, 5), (0x2, 5),
+    (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7),
+    (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7), (0x63, 7), (0x64, 7),
+    (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7),
+    (0x6d, 7), (0x6e, 7), (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6), (0x7ffd, 15),
+    (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6), (0x27, 6),
+    (0x6, 5), (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6),
+    (0x76, 7), (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7), (0x79, 7),
+    (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13),
+    (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22),
+    (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23),
+    (0xffffeb, 24), (0x7fffdf, 23), (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22),
+    (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22),
+    (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23),
+    (0x1fffde, 21), (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21),
+    (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23), (0x3fffe1, 22),
+    (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22),
+    (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22),
+    (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26),
+    (0x3ffffe4, 26), (0x7ffffde, 27), (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24),
+    (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24), (0x1fffe4, 21),
+    (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20),
+    (0x1fffe6, 21), (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24),
+    (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23), (0x3ffffeb, 26), (0x7ffffe6, 27),
+    (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27),
+    (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+/// RFC 7541 appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Headers {
+        fields
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Decode `block` with `decoder`, checking its fields and then the dynamic table's
+    /// entries, newest first, and size.
+    fn check(
+        decoder: &mut Decoder,
+        block: &str,
+        headers: &[(&str, &str)],
+        table: &[(&str, &str)],
+        size: usize,
+    ) {
+        assert_eq!(decoder.decode(&hex(block)).unwrap(), fields(headers));
+        assert_eq!(Vec::from(decoder.table.clone()), fields(table));
+        assert_eq!(decoder.size, size);
+    }
+
+    const DATE_21: &str = "Mon, 21 Oct 2013 20:13:21 GMT";
+    const DATE_22: &str = "Mon, 21 Oct 2013 20:13:22 GMT";
+    const LOCATION: &str = "https://www.example.com";
+    const COOKIE: &str = "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1";
+
+    /// The requests of RFC 7541 C.3 and C.4, whose blocks differ only in Huffman coding.
+    fn requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::default();
+        let first = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ];
+        let authority = (":authority", "www.example.com");
+        check(&mut decoder, blocks[0], &first, &[authority], 57);
+
+        let cache_control = ("cache-control", "no-cache");
+        let mut second = first.to_vec();
+        second.push(cache_control);
+        check(
+            &mut decoder,
+            blocks[1],
+            &second,
+            &[cache_control, authority],
+            110,
+        );
+
+        let custom = ("custom-key", "custom-value");
+        let third = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            authority,
+            custom,
+        ];
+        let table = [custom, cache_control, authority];
+        check(&mut decoder, blocks[2], &third, &table, 164);
+    }
+
+    /// The responses of RFC 7541 C.5 and C.6, which evict entries from a 256-byte table.
+    fn responses(blocks: [&str; 3]) {
+        let mut decoder = Decoder {
+            max_size: 256,
+            ..Default::default()
+        };
+        let first = [
+            (":status", "302"),
+            ("cache-control", "private"),
+            ("date", DATE_21),
+            ("location", LOCATION),
+        ];
+        let table: Vec<_> = first.iter().rev().copied().collect();
+        check(&mut decoder, blocks[0], &first, &table, 222);
+
+        let mut second = first;
+        second[0] = (":status", "307");
+        let table = [second[0], second[3], second[2], second[1]];
+        check(&mut decoder, blocks[1], &second, &table, 222);
+
+        let third = [
+            (":status", "200"),
+            ("cache-control", "private"),
+            ("date", DATE_22),
+            ("location", LOCATION),
+            ("content-encoding", "gzip"),
+            ("set-cookie", COOKIE),
+        ];
+        let table = [third[5], third[4], third[2]];
+        check(&mut decoder, blocks[2], &third, &table, 215);
+    }
+
+    #[test]
+    fn requests_decode_without_huffman_coding() {
+        requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn requests_decode_with_huffman_coding() {
+        requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    #[test]
+    fn responses_evict_without_huffman_coding() {
+        responses([
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230
+             3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65
+             7861 6d70 6c65 2e63 6f6d",
+            "4803 3330 37c1 c0bf",
+            "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220
+             474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157
+             454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076
+             6572 7369 6f6e 3d31",
+        ]);
+    }
+
+    #[test]
+    fn responses_evict_with_huffman_coding() {
+        responses([
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0
+             82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+            "4883 640e ffc1 c0bf",
+            "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b
+             d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27
+             0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+        ]);
+    }
+
+    #[test]
+    fn integers_use_their_prefix_then_continue() {
+        // RFC 7541 C.1
+        for (value, bytes) in [(10, &[0x0a][..]), (1337, &[0x1f, 0x9a, 0x0a])] {
+            let mut block = Vec::new();
+            write_integer(&mut block, 0, 5, value);
+            assert_eq!(block, bytes);
+            assert_eq!(integer(&mut &block[..], 5), Ok(value));
+        }
+        assert_eq!(
+            integer(&mut &[0x1f, 0x9a][..], 5),
+            Err("truncated integer".to_string())
+        );
+        let overlong = [0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(
+            integer(&mut &overlong[..], 5),
+            Err("integer too large".to_string())
+        );
+    }
+
+    #[test]
+    fn encoded_blocks_use_the_static_table() {
+        let block = encode(&[
+            (":status", "200"),
+            ("content-type", "application/grpc"),
+            ("grpc-status", "0"),
+        ]);
+        let mut expected = vec![0x88, 0x0f, 0x10, 0x10];
+        expected.extend_from_slice(b"application/grpc");
+        expected.extend_from_slice(b"\x00\x0bgrpc-status\x010");
+        assert_eq!(block, expected);
+
+        let mut decoder = Decoder::default();
+        let headers = [
+            (":status", "200"),
+            ("grpc-message", "x".repeat(200).leak() as &str),
+        ];
+        assert_eq!(decoder.decode(&encode(&headers)).unwrap(), fields(&headers));
+        assert!(decoder.table.is_empty());
+    }
+
+    #[test]
+    fn malformed_blocks_are_rejected() {
+        let error = |block: &[u8]| Decoder::default().decode(block).unwrap_err();
+        assert_eq!(error(&[0x80]), "index 0");
+        assert_eq!(error(&[0xbe]), "index 62 is past the end of the table");
+        assert_eq!(
+            error(&[0x3f, 0xe2, 0x1f]),
+            "table size 4097 exceeds the limit"
+        );
+        assert_eq!(error(&[0x04, 0x05, b'a']), "truncated string");
+        assert_eq!(error(&[0x04, 0x81, 0xff]), "invalid Huffman padding");
+        assert_eq!(error(&[0x04, 0x81, 0x00]), "invalid Huffman padding"); // not all 1s
+    }
+}
@@ -0,0 +1,62 @@
+//! Detection of Jito bundle tips: plain SOL transfers to one of Jito's tip accounts.
+
+use crate::rpc::{LoadedAddresses, Message};
+
+pub const SYSTEM_PROGRAM: &str = "11111111111111111111111111111111";
+
+/// The accounts Jito's block engine accepts tips at.
+pub const JITO_TIP_ACCOUNTS: [&str; 8] = [
+    "96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5",
+    "HFqU5x63VTqvQss8hp11i4wVV8bD44PvwucfZ2bU7gRe",
+    "Cw8CFyM9FkoMi7K7Crf6HNQqf4uEMzpKw6QNghXLvLkY",
+    "ADaUMid9yfUytqMBgopwjb2DTLSokTSzL1zt6iGPaS49",
+    "DfXygSm4jCyNCybVYYK6DwvWqjKee8pbDmJGcLWNDXjh",
+    "ADuUkR4vqLUMWXxW9gh6D6L8pMSawimctcNZ5pGwDcEt",
+    "DttWaMuVvTiduZRnguLF7jNxTgiMBZ1hyAumKUiL2KRL",
+    "3AVi9Tg9Uo68tJfuvoKvqKNWKc5wPdSSdeBnizKZ6jT",
+];
+
+const SYSTEM_TRANSFER: u32 = 2;
+
+/// The lamports a message fetched from the RPC transfers to Jito tip accounts, or `None` if it
+/// tips nothing. Transfers made by inner (CPI) instructions aren't seen.
+pub fn tip_lamports(message: &Message, loaded: Option<&LoadedAddresses>) -> Option<u64> {
+    // Instruction account indexes run through the static keys, then the keys loaded
+    // from lookup tables: writable first, then readonly
+    let keys: Vec<&String> = message
+        .account_keys
+        .iter()
+        .chain(
+            loaded
+                .into_iter()
+                .flat_map(|a| a.writable.iter().chain(&a.readonly)),
+        )
+        .collect();
+    let mut tip = None;
+    for ix in &message.instructions {
+        if keys.get(ix.program_id_index).map(|k| k.as_str()) != Some(SYSTEM_PROGRAM) {
+            continue;
+        }
+        let Some(&to) = ix.accounts.get(1) else {
+            continue;
+        };
+        if !keys
+            .get(to)
+            .is_some_and(|k| JITO_TIP_ACCOUNTS.contains(&k.as_str()))
+        {
+            continue;
+        }
+        if let Some(lamports) = ix.data.bytes().and_then(|d| transfer_lamports(&d)) {
+            tip = Some(tip.unwrap_or(0) + lamports);
+        }
+    }
+    tip
+}
+
+/// The amount of a System program Transfer instruction, or `None` for other instructions.
+pub fn transfer_lamports(data: &[u8]) -> Option<u64> {
+    if u32::from_le_bytes(data.get(0..4)?.try_into().ok()?) != SYSTEM_TRANSFER {
+        return None;
+    }
+    Some(u64::from_le_bytes(data.get(4..12)?.try_into().ok()?))
+}
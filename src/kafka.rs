@@ -0,0 +1,553 @@
+//! A minimal Kafka producer: one JSON record per refresh, keyed by program, sent to the
+//! partition leader with `acks=1`. Speaks just enough of the protocol to do that
+//! (Metadata v1 and Produce v3 with v2 record batches), over plain TCP.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_KAFKA_TOPIC: &str = "priority-fees";
+/// Records waiting to be sent; more are dropped while the cluster is unreachable.
+const MAX_QUEUED: usize = 1024;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the leader waits for the write before failing it, in milliseconds.
+const PRODUCE_TIMEOUT_MS: i32 = 5_000;
+const CLIENT_ID: &str = env!("CARGO_PKG_NAME");
+
+const PRODUCE: i16 = 0;
+const METADATA: i16 = 3;
+
+/// A handle to the background thread producing to one topic.
+pub struct KafkaProducer {
+    queue: mpsc::SyncSender<(String, String)>,
+}
+
+impl KafkaProducer {
+    /// Start producing to `topic`, finding partition leaders through `brokers`
+    /// (`host:port` each) on the first record and again whenever a send fails.
+    pub fn start(brokers: Vec<String>, topic: String) -> KafkaProducer {
+        let (queue, records) = mpsc::sync_channel(MAX_QUEUED);
+        thread::spawn(move || produce_loop(&brokers, &topic, &records));
+        KafkaProducer { queue }
+    }
+
+    /// Queue a record. Dropped with a warning if the queue is full.
+    pub fn send(&self, key: &str, value: String) {
+        if self.queue.try_send((key.to_string(), value)).is_err() {
+            log::warn!("Kafka queue full, dropping record for {}", key);
+        }
+    }
+}
+
+fn produce_loop(brokers: &[String], topic: &str, records: &mpsc::Receiver<(String, String)>) {
+    let mut cluster: Option<Cluster> = None;
+    while let Ok((key, value)) = records.recv() {
+        // Leadership may have moved since the metadata was read, so refresh it once
+        // before giving up on the record
+        for attempt in 0..2 {
+            let c = match &mut cluster {
+                Some(c) => c,
+                None => match Cluster::discover(brokers, topic) {
+                    Ok(c) => cluster.insert(c),
+                    Err(e) => {
+                        log::warn!("failed to read Kafka metadata for {}: {}", topic, e);
+                        break;
+                    }
+                },
+            };
+            match c.produce(topic, key.as_bytes(), value.as_bytes()) {
+                Ok(()) => break,
+                Err(e) => {
+                    cluster = None;
+                    if attempt == 1 {
+                        log::warn!("failed to produce to Kafka topic {}: {}", topic, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The topic's partition leaders, with connections to them opened as needed.
+struct Cluster {
+    brokers: HashMap<i32, String>, // node id to `host:port`
+    leaders: Vec<i32>,             // node id of each partition's leader, by partition
+    connections: HashMap<i32, Connection>,
+}
+
+impl Cluster {
+    /// Ask the first of `brokers` that answers for the topic's partitions and leaders.
+    fn discover(brokers: &[String], topic: &str) -> io::Result<Cluster> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no brokers configured");
+        for broker in brokers {
+            match Connection::open(broker).and_then(|mut conn| metadata(&mut conn, topic)) {
+                Ok(cluster) => return Ok(cluster),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn produce(&mut self, topic: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let partition = partition_for(key, self.leaders.len());
+        let leader = self.leaders[partition];
+        let conn = match self.connections.entry(leader) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let addr = self
+                    .brokers
+                    .get(&leader)
+                    .ok_or_else(|| io::Error::other(format!("no address for leader {}", leader)))?;
+                e.insert(Connection::open(addr)?)
+            }
+        };
+        let body = produce_request(topic, partition, key, value, unix_millis());
+        let mut resp = conn.request(PRODUCE, 3, &body)?;
+        for _ in 0..resp.i32()? {
+            resp.string()?;
+            for _ in 0..resp.i32()? {
+                resp.i32()?; // partition
+                let error_code = resp.i16()?;
+                resp.i64()?; // base offset
+                resp.i64()?; // log append time
+                if error_code != 0 {
+                    return Err(io::Error::other(format!(
+                        "partition {} rejected the record: error {}",
+                        partition, error_code
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the topic's metadata over `conn`.
+fn metadata(conn: &mut Connection, topic: &str) -> io::Result<Cluster> {
+    let mut body = Vec::new();
+    put_i32(&mut body, 1);
+    put_string(&mut body, topic);
+    let mut resp = conn.request(METADATA, 1, &body)?;
+
+    let mut brokers = HashMap::new();
+    for _ in 0..resp.i32()? {
+        let node_id = resp.i32()?;
+        let host = resp.string()?;
+        let port = resp.i32()?;
+        resp.nullable_string()?; // rack
+        brokers.insert(node_id, format!("{}:{}", host, port));
+    }
+    resp.i32()?; // controller id
+    let mut leaders = Vec::new();
+    for _ in 0..resp.i32()? {
+        let error_code = resp.i16()?;
+        let name = resp.string()?;
+        resp.i8()?; // is internal
+        let mut partitions = Vec::new();
+        for _ in 0..resp.i32()? {
+            resp.i16()?; // partition error code
+            let index = resp.i32()?;
+            let leader = resp.i32()?;
+            for _ in 0..2 {
+                // replicas, then in-sync replicas
+                for _ in 0..resp.i32()? {
+                    resp.i32()?;
+                }
+            }
+            partitions.push((index, leader));
+        }
+        if name != topic {
+            continue;
+        }
+        if error_code != 0 {
+            return Err(io::Error::other(format!(
+                "topic {} unavailable: error {}",
+                topic, error_code
+            )));
+        }
+        partitions.sort_unstable();
+        leaders = partitions.into_iter().map(|(_, leader)| leader).collect();
+    }
+    if leaders.is_empty() || leaders.contains(&-1) {
+        return Err(io::Error::other(format!(
+            "topic {} has no partitions with a leader yet",
+            topic
+        )));
+    }
+    Ok(Cluster {
+        brokers,
+        leaders,
+        connections: HashMap::new(),
+    })
+}
+
+struct Connection {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl Connection {
+    fn open(addr: &str) -> io::Result<Connection> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+        let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(Connection {
+            stream,
+            correlation_id: 0,
+        })
+    }
+
+    /// Send a request with a v1 header and read its response, past the header.
+    fn request(&mut self, api_key: i16, api_version: i16, body: &[u8]) -> io::Result<Reader> {
+        self.correlation_id += 1;
+        let mut message = Vec::new();
+        put_i16(&mut message, api_key);
+        put_i16(&mut message, api_version);
+        put_i32(&mut message, self.correlation_id);
+        put_string(&mut message, CLIENT_ID);
+        message.extend_from_slice(body);
+        let mut framed = (message.len() as i32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&message);
+        self.stream.write_all(&framed)?;
+
+        let mut size = [0u8; 4];
+        self.stream.read_exact(&mut size)?;
+        let mut resp = vec![0u8; i32::from_be_bytes(size).max(0) as usize];
+        self.stream.read_exact(&mut resp)?;
+        let mut reader = Reader(resp, 0);
+        if reader.i32()? != self.correlation_id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response out of order",
+            ));
+        }
+        Ok(reader)
+    }
+}
+
+/// The body of a Produce v3 request for one record to `partition` of `topic`.
+fn produce_request(
+    topic: &str,
+    partition: usize,
+    key: &[u8],
+    value: &[u8],
+    timestamp: i64,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_i16(&mut body, -1); // no transactional id
+    put_i16(&mut body, 1); // acks
+    put_i32(&mut body, PRODUCE_TIMEOUT_MS);
+    put_i32(&mut body, 1); // topics
+    put_string(&mut body, topic);
+    put_i32(&mut body, 1); // partitions
+    put_i32(&mut body, partition as i32);
+    let batch = record_batch(key, value, timestamp);
+    put_i32(&mut body, batch.len() as i32);
+    body.extend_from_slice(&batch);
+    body
+}
+
+/// A v2 record batch holding one record with `key` and `value`.
+fn record_batch(key: &[u8], value: &[u8], timestamp: i64) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.push(0); // attributes
+    put_varint(&mut record, 0); // timestamp delta
+    put_varint(&mut record, 0); // offset delta
+    put_varint(&mut record, key.len() as i64);
+    record.extend_from_slice(key);
+    put_varint(&mut record, value.len() as i64);
+    record.extend_from_slice(value);
+    put_varint(&mut record, 0); // headers
+
+    // Everything the CRC covers: from the attributes to the end
+    let mut tail = Vec::new();
+    put_i16(&mut tail, 0); // attributes: no compression, create time
+    put_i32(&mut tail, 0); // last offset delta
+    put_i64(&mut tail, timestamp);
+    put_i64(&mut tail, timestamp);
+    put_i64(&mut tail, -1); // producer id
+    put_i16(&mut tail, -1); // producer epoch
+    put_i32(&mut tail, -1); // base sequence
+    put_i32(&mut tail, 1); // records
+    put_varint(&mut tail, record.len() as i64);
+    tail.extend_from_slice(&record);
+
+    let mut batch = Vec::new();
+    put_i64(&mut batch, 0); // base offset
+    put_i32(&mut batch, (4 + 1 + 4 + tail.len()) as i32); // the rest of the batch
+    put_i32(&mut batch, -1); // partition leader epoch
+    batch.push(2); // magic
+    batch.extend_from_slice(&crc32c(&tail).to_be_bytes());
+    batch.extend_from_slice(&tail);
+    batch
+}
+
+/// The partition the Java client's default partitioner picks for `key`, so records for
+/// a program land where other producers would put them.
+fn partition_for(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+/// Kafka's variant of MurmurHash2.
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h: u32 = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate() {
+            h ^= (b as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/// CRC-32C (Castagnoli), as record batches are checksummed with.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+// --------------------------- Encoding ---------------------------
+
+fn put_i16(out: &mut Vec<u8>, v: i16) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_i32(out: &mut Vec<u8>, v: i32) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_i64(out: &mut Vec<u8>, v: i64) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    put_i16(out, s.len() as i16);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// A zigzag-encoded variable-length integer, as in record fields.
+fn put_varint(out: &mut Vec<u8>, v: i64) {
+    let mut z = ((v << 1) ^ (v >> 63)) as u64;
+    while z >= 0x80 {
+        out.push((z as u8 & 0x7f) | 0x80);
+        z >>= 7;
+    }
+    out.push(z as u8);
+}
+
+/// A response body, read front to back.
+struct Reader(Vec<u8>, usize);
+
+impl Reader {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let bytes = self
+            .0
+            .get(self.1..self.1 + N)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "response too short"))?;
+        self.1 += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn i8(&mut self) -> io::Result<i8> {
+        Ok(i8::from_be_bytes(self.take()?))
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.take()?))
+    }
+
+    fn nullable_string(&mut self) -> io::Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let end = self.1 + len as usize;
+        let bytes = self
+            .0
+            .get(self.1..end)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "response too short"))?;
+        self.1 = end;
+        Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        Ok(self.nullable_string()?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const TIMESTAMP: i64 = 1_700_000_000_000; // 0x0000018bcfe56800
+
+    #[test]
+    fn crc32c_matches_the_check_value() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+    }
+
+    #[test]
+    fn record_batches_are_checksummed_from_the_attributes() {
+        let batch = record_batch(b"key", b"value", TIMESTAMP);
+        assert_eq!(batch.len(), 76);
+        assert_eq!(&batch[16..17], [2]); // magic
+        assert_eq!(&batch[17..21], [0xdb, 0x5e, 0x9c, 0xdd]);
+        assert_eq!(crc32c(&batch[21..]), 0xdb5e_9cdd);
+    }
+
+    #[test]
+    fn produce_requests_encode_one_record_batch() {
+        let mut expected: Vec<u8> = vec![
+            0xff, 0xff, // no transactional id
+            0x00, 0x01, // acks
+            0x00, 0x00, 0x13, 0x88, // timeout: 5000 ms
+            0x00, 0x00, 0x00, 0x01, // one topic
+            0x00, 0x04, b'f', b'e', b'e', b's', //
+            0x00, 0x00, 0x00, 0x01, // one partition
+            0x00, 0x00, 0x00, 0x02, // partition 2
+            0x00, 0x00, 0x00, 0x4c, // record batch: 76 bytes
+        ];
+        expected.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // base offset
+            0x00, 0x00, 0x00, 0x40, // batch length
+            0xff, 0xff, 0xff, 0xff, // partition leader epoch
+            0x02, // magic
+            0xdb, 0x5e, 0x9c, 0xdd, // CRC-32C of the rest
+            0x00, 0x00, // attributes
+            0x00, 0x00, 0x00, 0x00, // last offset delta
+            0x00, 0x00, 0x01, 0x8b, 0xcf, 0xe5, 0x68, 0x00, // first timestamp
+            0x00, 0x00, 0x01, 0x8b, 0xcf, 0xe5, 0x68, 0x00, // max timestamp
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // producer id
+            0xff, 0xff, // producer epoch
+            0xff, 0xff, 0xff, 0xff, // base sequence
+            0x00, 0x00, 0x00, 0x01, // one record
+            0x1c, // record length 14, zigzagged
+            0x00, // attributes
+            0x00, // timestamp delta
+            0x00, // offset delta
+            0x06, b'k', b'e', b'y', // key
+            0x0a, b'v', b'a', b'l', b'u', b'e', // value
+            0x00, // no headers
+        ]);
+        assert_eq!(
+            produce_request("fees", 2, b"key", b"value", TIMESTAMP),
+            expected
+        );
+    }
+
+    #[test]
+    fn varints_are_zigzagged() {
+        let encode = |v: i64| {
+            let mut out = Vec::new();
+            put_varint(&mut out, v);
+            out
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(-1), [0x01]);
+        assert_eq!(encode(1), [0x02]);
+        assert_eq!(encode(63), [0x7e]);
+        assert_eq!(encode(64), [0x80, 0x01]);
+        assert_eq!(encode(-65), [0x81, 0x01]);
+        assert_eq!(encode(300), [0xd8, 0x04]);
+    }
+
+    #[test]
+    fn keys_are_partitioned_as_the_java_client_does() {
+        // From the Java client's own tests of `Utils.murmur2`
+        let cases: [(&[u8], i32); 6] = [
+            (b"21", -973932308),
+            (b"foobar", -790332482),
+            (b"a-little-bit-long-string", -985981536),
+            (b"a-little-bit-longer-string", -1486304829),
+            (
+                b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            (b"abc", 479470107),
+        ];
+        for (key, hash) in cases {
+            assert_eq!(murmur2(key) as i32, hash);
+        }
+        assert_eq!(
+            partition_for(b"foobar", 7),
+            (-790332482i32 & 0x7fff_ffff) as usize % 7
+        );
+    }
+
+    #[test]
+    fn requests_are_framed_with_a_v1_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut size = [0u8; 4];
+            stream.read_exact(&mut size).unwrap();
+            let mut request = vec![0u8; i32::from_be_bytes(size) as usize];
+            stream.read_exact(&mut request).unwrap();
+            // Size, correlation ID, then the response body
+            stream
+                .write_all(&[0, 0, 0, 6, 0, 0, 0, 1, 0xab, 0xcd])
+                .unwrap();
+            request
+        });
+
+        let mut conn = Connection::open(&addr).unwrap();
+        let mut resp = conn.request(PRODUCE, 3, b"body").unwrap();
+        assert_eq!(resp.i16().unwrap(), 0xabcdu16 as i16);
+        let mut expected = vec![
+            0x00, 0x00, // Produce
+            0x00, 0x03, // v3
+            0x00, 0x00, 0x00, 0x01, // correlation ID
+        ];
+        expected.extend_from_slice(&(CLIENT_ID.len() as i16).to_be_bytes());
+        expected.extend_from_slice(CLIENT_ID.as_bytes());
+        expected.extend_from_slice(b"body");
+        assert_eq!(broker.join().unwrap(), expected);
+    }
+}
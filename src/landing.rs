@@ -0,0 +1,119 @@
+//! How fast transactions land for the fees they pay. A transaction's latency is the
+//! slots between the block whose hash it was built on, which it must have been sent
+//! after, and the block it landed in; the fee to land within N slots is the lowest
+//! fee at which sampled transactions paying at least as much mostly did.
+
+use crate::config::Config;
+use crate::rpc::get_blockhashes;
+use crate::sources::Sample;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// How many slots a blockhash stays valid for, so how far before a transaction's slot
+/// its blockhash can be.
+pub const MAX_BLOCKHASH_AGE: u64 = 150;
+/// Most slots whose blockhashes are fetched in one batch.
+const BLOCKHASH_BATCH: usize = 100;
+/// Fewest transactions paying at least a fee for their latencies to decide it.
+pub const MIN_TARGET_SAMPLES: usize = 20;
+pub const DEFAULT_CONFIDENCE: f64 = 0.9;
+
+/// The blockhash of each recent slot, as fetched; `None` for skipped slots.
+#[derive(Default)]
+pub struct BlockhashSlots {
+    by_slot: Mutex<BTreeMap<u64, Option<String>>>,
+}
+
+impl BlockhashSlots {
+    /// Each of `samples`' latency in slots, where its slot and the slot of its
+    /// blockhash are both known, paired with its fee. Fetches the blockhashes of the
+    /// slots they could have been built on that haven't been fetched yet.
+    pub fn latencies(
+        &self,
+        config: &Config,
+        samples: &[Sample],
+    ) -> Result<Vec<(u64, u64)>, String> {
+        let slots = samples
+            .iter()
+            .filter(|s| s.recent_blockhash.is_some())
+            .filter_map(|s| s.slot);
+        let (Some(first), Some(last)) = (slots.clone().min(), slots.max()) else {
+            return Ok(Vec::new());
+        };
+        let first = first.saturating_sub(MAX_BLOCKHASH_AGE);
+        let missing: Vec<u64> = {
+            let by_slot = self.by_slot.lock().unwrap();
+            (first..=last)
+                .filter(|slot| !by_slot.contains_key(slot))
+                .collect()
+        };
+        for chunk in missing.chunks(BLOCKHASH_BATCH) {
+            let hashes = config
+                .rpc
+                .call(|agent, url| get_blockhashes(agent, url, chunk, config.commitment))
+                .map_err(|e| e.to_string())?;
+            self.by_slot.lock().unwrap().extend(hashes);
+        }
+
+        let mut by_slot = self.by_slot.lock().unwrap();
+        // Keep what the next request's window may still need
+        *by_slot = by_slot.split_off(&first);
+        let slot_of: HashMap<&str, u64> = by_slot
+            .iter()
+            .filter_map(|(&slot, hash)| Some((hash.as_deref()?, slot)))
+            .collect();
+        Ok(samples
+            .iter()
+            .filter_map(|s| {
+                let built_on = slot_of.get(s.recent_blockhash.as_deref()?)?;
+                Some((s.price, s.slot?.checked_sub(*built_on)?))
+            })
+            .collect())
+    }
+}
+
+/// The fee to land within some number of slots, and how the sample bears it out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetFee {
+    pub priority_fee: Option<u64>, // `None` if no fee lands fast often enough
+    pub landing_probability: Option<f64>, // share of those paying at least it that did
+    pub samples: usize,            // transactions paying at least it
+}
+
+/// The lowest fee at which at least `confidence` of the transactions in `latencies`
+/// (fee, latency) paying at least as much landed within `slots` slots, going by no
+/// fewer than `MIN_TARGET_SAMPLES` of them.
+pub fn fee_to_land(latencies: &[(u64, u64)], slots: u64, confidence: f64) -> TargetFee {
+    let mut by_fee = latencies.to_vec();
+    by_fee.sort_unstable_by(|a, b| b.cmp(a));
+    let mut best = TargetFee {
+        priority_fee: None,
+        landing_probability: None,
+        samples: 0,
+    };
+    let (mut paying, mut landed) = (0, 0);
+    for (i, &(fee, latency)) in by_fee.iter().enumerate() {
+        paying += 1;
+        landed += (latency <= slots) as usize;
+        // Only once every transaction paying exactly `fee` is counted
+        if by_fee.get(i + 1).is_some_and(|&(next, _)| next == fee) {
+            continue;
+        }
+        let probability = landed as f64 / paying as f64;
+        if paying >= MIN_TARGET_SAMPLES && probability >= confidence {
+            best = TargetFee {
+                priority_fee: Some(fee),
+                landing_probability: Some(probability),
+                samples: paying,
+            };
+        }
+    }
+    best
+}
+
+/// The median of `latencies`' latencies, if any.
+pub fn median_latency(latencies: &[(u64, u64)]) -> Option<u64> {
+    let mut slots: Vec<u64> = latencies.iter().map(|&(_, latency)| latency).collect();
+    slots.sort_unstable();
+    slots.get(slots.len() / 2).copied()
+}
@@ -0,0 +1,61 @@
+//! Priority fee estimation for Solana, by sampling the fees recent transactions paid.
+//!
+//! The `ivy-priority-fee` binary serves these estimates over HTTP; other Rust services
+//! can embed a [`PriorityFeeEstimator`] directly instead.
+
+// The OpenAPI document's `json!` literals nest deeper than the default allows
+#![recursion_limit = "256"]
+
+pub mod aggregation;
+pub mod api_keys;
+pub mod compute_budget;
+pub mod config;
+pub mod config_file;
+pub mod congestion;
+pub mod database;
+mod dylib;
+pub mod error;
+pub mod estimator;
+pub mod explain;
+pub mod export;
+pub mod geyser;
+pub mod graphql;
+pub mod grpc;
+pub mod history;
+pub mod hot_accounts;
+pub mod hpack;
+pub mod jito;
+pub mod kafka;
+pub mod landing;
+pub mod limits;
+pub mod logging;
+pub mod lookup_tables;
+pub mod msgpack;
+pub mod openapi;
+pub mod parquet;
+pub mod postgres;
+pub mod protobuf;
+pub mod proxy;
+pub mod publish;
+pub mod pubsub;
+pub mod rate_limit;
+pub mod redis;
+pub mod rpc;
+pub mod rpc_pool;
+pub mod shared_cache;
+pub mod smoothing;
+pub mod snapshot;
+pub mod sources;
+pub mod sqlite;
+pub mod telemetry;
+pub mod tls;
+pub mod transaction;
+pub mod trend;
+pub mod unix_socket;
+pub mod webhooks;
+
+pub use aggregation::Aggregation;
+pub use config::Config;
+pub use estimator::{Estimate, PriorityFeeEstimator, PriorityFeeLevels};
+pub use rpc_pool::{EndpointConfig, RpcPool};
+pub use sources::FeeSource;
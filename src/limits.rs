@@ -0,0 +1,64 @@
+//! Fee limit overrides set at runtime, which take precedence over the configured
+//! MIN_FEE_FLOOR and MAX_FEE_CLAMP and are optionally kept in a JSON file so they
+//! survive reloads and restarts.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+
+/// Overrides of the configured fee limits, in micro-lamports per CU.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FeeLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_fee_floor: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fee_clamp: Option<u64>,
+}
+
+impl FeeLimits {
+    /// These overrides on top of `other`'s.
+    pub fn or(self, other: FeeLimits) -> FeeLimits {
+        FeeLimits {
+            min_fee_floor: self.min_fee_floor.or(other.min_fee_floor),
+            max_fee_clamp: self.max_fee_clamp.or(other.max_fee_clamp),
+        }
+    }
+
+    /// The floor and clamp after overriding `floor` and `clamp`, if the clamp is
+    /// positive and the floor doesn't exceed it.
+    pub fn apply(&self, floor: u64, clamp: u64) -> Result<(u64, u64), String> {
+        let floor = self.min_fee_floor.unwrap_or(floor);
+        let clamp = self.max_fee_clamp.unwrap_or(clamp);
+        if clamp == 0 {
+            return Err("maxFeeClamp must be positive".to_string());
+        }
+        if floor > clamp {
+            return Err(format!(
+                "minFeeFloor ({}) must not exceed maxFeeClamp ({})",
+                floor, clamp
+            ));
+        }
+        Ok((floor, clamp))
+    }
+}
+
+/// Read the overrides saved at `path`; none if there's no file yet.
+pub fn read(path: &str) -> Result<FeeLimits, String> {
+    match fs::read_to_string(path) {
+        Ok(s) => {
+            serde_json::from_str(&s).map_err(|e| format!("Invalid fee limits in {}: {}", path, e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(FeeLimits::default()),
+        Err(e) => Err(format!("Failed to read fee limits from {}: {}", path, e)),
+    }
+}
+
+/// Save `limits` to `path`, replacing the file at once so a crash can't leave it torn.
+pub fn write(path: &str, limits: &FeeLimits) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(serde_json::to_string(limits)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,355 @@
+//! The binary's logger: leveled records on stderr, as text or JSON lines, filtered by
+//! `RUST_LOG`. Records carry the fields of the spans open on their thread, such as the
+//! program a refresh is for. The access log goes to stdout.
+//!
+//! The library logs through the `log` facade, so embedders can install any logger.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How records are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text, // `2026-01-01T00:00:00Z INFO target: message key=value`
+    Json, // one object per line
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Install the logger, with levels from `RUST_LOG` (`info` if unset): a default level
+/// and/or `target=level` directives, e.g. `warn,ivy_priority_fee::sources=debug`.
+pub fn init(format: LogFormat) -> Result<(), log::SetLoggerError> {
+    let filter = Filter::parse(&std::env::var("RUST_LOG").unwrap_or_default());
+    log::set_max_level(filter.max_level());
+    log::set_boxed_logger(Box::new(Logger { format, filter }))
+}
+
+// --------------------------- Spans ---------------------------
+
+thread_local! {
+    static SPANS: RefCell<Vec<Vec<(String, String)>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Fields added to every record logged on this thread until the span is dropped.
+pub struct Span {
+    _not_send: PhantomData<*const ()>, // must be dropped on the thread that opened it
+}
+
+/// Open a span with `fields`.
+pub fn span(fields: &[(&str, &dyn fmt::Display)]) -> Span {
+    let fields = fields
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    SPANS.with(|spans| spans.borrow_mut().push(fields));
+    Span {
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        SPANS.with(|spans| spans.borrow_mut().pop());
+    }
+}
+
+/// Set `key` on this thread's innermost span, if one is open.
+pub fn record(key: &str, value: &dyn fmt::Display) {
+    SPANS.with(|spans| {
+        if let Some(fields) = spans.borrow_mut().last_mut() {
+            let value = value.to_string();
+            match fields.iter_mut().find(|(k, _)| k == key) {
+                Some(field) => field.1 = value,
+                None => fields.push((key.to_string(), value)),
+            }
+        }
+    });
+}
+
+fn span_fields() -> Vec<(String, String)> {
+    SPANS.with(|spans| spans.borrow().iter().flatten().cloned().collect())
+}
+
+// --------------------------- Logger ---------------------------
+
+/// Levels by target: the longest matching target prefix wins.
+struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// Parse `RUST_LOG`, skipping directives it can't make sense of. A bare target
+    /// enables everything from it.
+    fn parse(spec: &str) -> Filter {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Ok(level) = level.parse() {
+                        filter.targets.push((target.to_string(), level));
+                    }
+                }
+                None => match directive.parse() {
+                    Ok(level) => filter.default = level,
+                    Err(_) => filter
+                        .targets
+                        .push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        filter
+            .targets
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        filter
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(t, _)| target.starts_with(t.as_str()))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    filter: Filter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = rfc3339_now();
+        let fields = span_fields();
+        let line = match self.format {
+            LogFormat::Text => {
+                let mut line = format!(
+                    "{} {:<5} {}: {}",
+                    timestamp,
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+                for (k, v) in &fields {
+                    line.push_str(&format!(" {}={}", k, v));
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut object = json!({
+                    "timestamp": timestamp,
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                for (k, v) in fields {
+                    object[k] = v.parse::<u64>().map_or_else(|_| json!(v), |n| json!(n));
+                }
+                object.to_string()
+            }
+        };
+        // Nowhere to report a failure to write to stderr
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+// --------------------------- Access log ---------------------------
+
+/// How the binary writes its access log, one line per request on stdout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    Common, // Common Log Format, followed by the latency and request ID
+    Json,
+    Off,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AccessLogFormat, String> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "json" => Ok(AccessLogFormat::Json),
+            "off" => Ok(AccessLogFormat::Off),
+            _ => Err(format!("unknown access log format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessLogFormat::Common => write!(f, "common"),
+            AccessLogFormat::Json => write!(f, "json"),
+            AccessLogFormat::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// One served request.
+pub struct AccessRecord<'a> {
+    pub client_ip: IpAddr,
+    pub method: &'a str,
+    pub url: &'a str, // path and query string
+    pub status: u16,
+    pub latency: Duration, // until the response was ready, not fully sent
+    pub request_id: &'a str,
+}
+
+impl AccessLogFormat {
+    pub fn write(&self, r: &AccessRecord) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = match self {
+            AccessLogFormat::Off => return,
+            AccessLogFormat::Common => {
+                let (year, month, day, h, m, sec) = civil_time(now.as_secs());
+                format!(
+                    "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{} {} HTTP/1.1\" {} - {}ms {}",
+                    r.client_ip,
+                    day,
+                    MONTHS[month as usize - 1],
+                    year,
+                    h,
+                    m,
+                    sec,
+                    r.method,
+                    r.url,
+                    r.status,
+                    r.latency.as_millis(),
+                    r.request_id
+                )
+            }
+            AccessLogFormat::Json => json!({
+                "timestamp": rfc3339(now),
+                "clientIp": r.client_ip.to_string(),
+                "method": r.method,
+                "url": r.url,
+                "status": r.status,
+                "latencyMs": r.latency.as_secs_f64() * 1000.0,
+                "requestId": r.request_id,
+            })
+            .to_string(),
+        };
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+}
+
+/// A request ID unique to this process: random, then sequential.
+pub fn new_request_id() -> String {
+    static PREFIX: OnceLock<u64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let prefix = PREFIX.get_or_init(|| RandomState::new().build_hasher().finish());
+    format!(
+        "{:016x}{:08x}",
+        prefix,
+        NEXT.fetch_add(1, Ordering::Relaxed) as u32
+    )
+}
+
+// --------------------------- Time ---------------------------
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The current UTC time as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+fn rfc3339_now() -> String {
+    rfc3339(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+/// `since_epoch` as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+pub fn rfc3339(since_epoch: Duration) -> String {
+    let (year, month, day, h, m, s) = civil_time(since_epoch.as_secs());
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        h,
+        m,
+        s,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The UTC date of a unix timestamp as `YYYY-MM-DD`.
+pub fn utc_date(secs: u64) -> String {
+    let (year, month, day, _, _, _) = civil_time(secs);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// UTC date and time of a unix timestamp.
+fn civil_time(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    (year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// The Gregorian date `days` days after 1970-01-01 (Howard Hinnant's algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,120 @@
+//! Address lookup tables, fetched with getAccountInfo and cached, to find the accounts a
+//! v0 transaction loads through them. Sampled transactions come with theirs resolved in
+//! `meta.loadedAddresses`; transactions posted for an estimate don't.
+
+use crate::compute_budget::bs58_encode;
+use crate::config::Config;
+use crate::rpc::{LoadedAddresses, get_account_info};
+use crate::transaction::ParsedTransaction;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Bytes before a lookup table's addresses: its state type and metadata.
+const LOOKUP_TABLE_META_SIZE: usize = 56;
+/// Most tables kept; the cache starts over once it's full.
+pub const MAX_CACHED_TABLES: usize = 10_000;
+
+/// Why a transaction's lookups couldn't be resolved.
+#[derive(Debug)]
+pub enum LookupError {
+    Rpc(Box<dyn Error>), // a table couldn't be fetched
+    Invalid(String),     // a table doesn't exist or lacks an index the transaction uses
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LookupError::Rpc(e) => write!(f, "getAccountInfo failed: {}", e),
+            LookupError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+/// The addresses of lookup tables fetched so far, by table address.
+#[derive(Default)]
+pub struct LookupTables {
+    by_address: Mutex<HashMap<String, Arc<[String]>>>,
+}
+
+impl LookupTables {
+    /// The accounts `tx` loads from lookup tables. A table is fetched the first time
+    /// it's used, and again if the transaction uses an index past the end of the copy
+    /// cached, as tables can be extended.
+    pub fn resolve(
+        &self,
+        config: &Config,
+        tx: &ParsedTransaction,
+    ) -> Result<LoadedAddresses, LookupError> {
+        let mut loaded = LoadedAddresses::default();
+        for lookup in &tx.address_table_lookups {
+            let indexes = lookup
+                .writable_indexes
+                .iter()
+                .chain(&lookup.readonly_indexes);
+            let Some(&last) = indexes.max() else {
+                continue;
+            };
+            let addresses = self.table(config, &lookup.account_key, last as usize)?;
+            let address = |&i: &u8| {
+                addresses.get(i as usize).cloned().ok_or_else(|| {
+                    let message = format!(
+                        "lookup table {} has no address at index {}",
+                        lookup.account_key, i
+                    );
+                    LookupError::Invalid(message)
+                })
+            };
+            for i in &lookup.writable_indexes {
+                loaded.writable.push(address(i)?);
+            }
+            for i in &lookup.readonly_indexes {
+                loaded.readonly.push(address(i)?);
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// The addresses of the table at `address`, fetching it unless the cached copy
+    /// reaches `index`.
+    fn table(
+        &self,
+        config: &Config,
+        address: &str,
+        index: usize,
+    ) -> Result<Arc<[String]>, LookupError> {
+        if let Some(table) = self.by_address.lock().unwrap().get(address)
+            && index < table.len()
+        {
+            return Ok(table.clone());
+        }
+        let data = config
+            .rpc
+            .call(|agent, url| get_account_info(agent, url, address, config.commitment))
+            .map_err(LookupError::Rpc)?
+            .ok_or_else(|| LookupError::Invalid(format!("lookup table {} not found", address)))?;
+        let table: Arc<[String]> = lookup_table_addresses(&data)
+            .map_err(|e| LookupError::Invalid(format!("{}: {}", address, e)))?
+            .into();
+        let mut by_address = self.by_address.lock().unwrap();
+        if by_address.len() >= MAX_CACHED_TABLES {
+            by_address.clear();
+        }
+        by_address.insert(address.to_string(), table.clone());
+        Ok(table)
+    }
+}
+
+/// The addresses stored in the data of an address lookup table account.
+pub fn lookup_table_addresses(data: &[u8]) -> Result<Vec<String>, String> {
+    // A u32 state type comes first, 1 for an initialized table
+    if data.len() < LOOKUP_TABLE_META_SIZE || data[..4] != [1, 0, 0, 0] {
+        return Err("not an address lookup table".to_string());
+    }
+    let addresses = &data[LOOKUP_TABLE_META_SIZE..];
+    if !addresses.len().is_multiple_of(32) {
+        return Err("address lookup table is truncated".to_string());
+    }
+    Ok(addresses.chunks_exact(32).map(bs58_encode).collect())
+}
@@ -1,240 +1,4350 @@
+use ivy_priority_fee::api_keys::{self, ApiKey, Usage};
+use ivy_priority_fee::config::{self, Cluster, is_valid_address, parse_duration};
+use ivy_priority_fee::congestion::{CongestionLevel, PERFORMANCE_SAMPLES, Performance};
+use ivy_priority_fee::error::{CodedError, ErrorCode};
+use ivy_priority_fee::estimator::{
+    DEFAULT_PERCENTILE, LAMPORTS_PER_SOL, MAX_COMPUTE_UNIT_LIMIT, MAX_PRIORITY_FEE,
+    PriorityFeeLevels, compute_unit_limit_for, priority_fee_lamports, unix_now,
+};
+use ivy_priority_fee::export::ExportFormat;
+use ivy_priority_fee::graphql::{self, Arguments, Output};
+use ivy_priority_fee::grpc::{self, Code, Status};
+use ivy_priority_fee::history::{History, HistoryPoint};
+use ivy_priority_fee::hot_accounts::hot_accounts;
+use ivy_priority_fee::kafka::KafkaProducer;
+use ivy_priority_fee::landing::{
+    BlockhashSlots, DEFAULT_CONFIDENCE, MAX_BLOCKHASH_AGE, fee_to_land, median_latency,
+};
+use ivy_priority_fee::limits::{self, FeeLimits};
+use ivy_priority_fee::logging::{self, AccessLogFormat, AccessRecord};
+use ivy_priority_fee::lookup_tables::{LookupError, LookupTables};
+use ivy_priority_fee::msgpack;
+use ivy_priority_fee::openapi;
+use ivy_priority_fee::protobuf::{self, Writer};
+use ivy_priority_fee::publish::Publisher;
+use ivy_priority_fee::rate_limit::RateLimiter;
+use ivy_priority_fee::rpc::{
+    Commitment, LAMPORTS_PER_SIGNATURE, get_recent_performance_samples, simulate_transaction,
+};
+use ivy_priority_fee::rpc_pool::RpcPool;
+use ivy_priority_fee::shared_cache::SharedCache;
+use ivy_priority_fee::snapshot::{SavedEstimate, SavedState};
+use ivy_priority_fee::sources::{
+    FEE_SOURCE_NAMES, FeeSource, RecentPrioritizationFees, Sample, fee_source_setting_name,
+};
+use ivy_priority_fee::telemetry::{self, SpanContext, SpanKind};
+use ivy_priority_fee::tls::{ReloadingCert, TlsFrontend};
+use ivy_priority_fee::transaction::{ParsedTransaction, TransactionEncoding};
+use ivy_priority_fee::trend::FeeTrend;
+use ivy_priority_fee::unix_socket;
+use ivy_priority_fee::webhooks::{Webhook, Webhooks};
+use ivy_priority_fee::{Aggregation, Config, Estimate, PriorityFeeEstimator};
+use rouille::websocket::{self, Message, Websocket};
 use rouille::{Response, router};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::json;
-use std::env;
-use std::io::Read;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
-const LISTEN_URL: &str = "127.0.0.1:43278";
-const DEFAULT_RPC_URL: &str = "https://api.mainnet-beta.solana.com";
-const JUPITER_AGGREGATOR_V6: &str = "JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4";
-const MAX_RESPONSE_LEN: u64 = 100_000_000;
-const MAX_RETRIES: usize = 10;
-const MAX_PRIORITY_FEE: u64 = 999_999;
+/// How often `/stream` and `/ws` send something to keep idle connections open.
+const STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+type SharedEstimate = Arc<RwLock<Latest>>;
+
+/// Results of a program's refreshes. When a refresh fails, the last good estimate is
+/// still served, marked stale, until it's older than `Config::max_staleness`.
+#[derive(Default)]
+struct Latest {
+    estimate: Option<Estimate>, // from the latest successful refresh
+    error: Option<CodedError>,  // of the latest refresh, if it failed
+    stale_until: u64,           // unix seconds; a stale `estimate` is served until then
+    restored: bool,             // `estimate` was saved before a restart, and not refreshed since
+}
+
+impl Latest {
+    fn record(&mut self, result: Result<Estimate, CodedError>, max_staleness: Duration) {
+        match result {
+            Ok(e) => {
+                self.estimate = Some(e);
+                self.error = None;
+            }
+            Err(err) => self.error = Some(err),
+        }
+        self.restored = false;
+        if let Some(e) = &self.estimate {
+            self.stale_until = e.last_updated.saturating_add(max_staleness.as_secs());
+        }
+    }
+
+    /// The estimate to serve, or why there's none; `None` until the first refresh
+    /// completes, unless an estimate was restored. That's served as stale meanwhile.
+    fn served(&self) -> Option<Result<Estimate, CodedError>> {
+        match (&self.estimate, &self.error) {
+            (Some(e), None) if self.restored => (unix_now() <= self.stale_until).then(|| {
+                Ok(Estimate {
+                    stale: true,
+                    ..e.clone()
+                })
+            }),
+            (Some(e), None) => Some(Ok(e.clone())),
+            (Some(e), Some(_)) if unix_now() <= self.stale_until => Some(Ok(Estimate {
+                stale: true,
+                ..e.clone()
+            })),
+            (Some(e), Some(err)) => Some(Err(CodedError::new(
+                err.code,
+                format!(
+                    "{} (last good estimate is {}s old)",
+                    err,
+                    unix_now().saturating_sub(e.last_updated)
+                ),
+            ))),
+            (None, Some(err)) => Some(Err(err.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Latest estimate for every tracked program, keyed by program address.
+/// The set of keys is fixed at startup; each program's refresh loop owns its entry.
+struct EstimateCache {
+    programs: Vec<String>, // in configured order; the first one backs `GET /`
+    by_program: HashMap<String, SharedEstimate>,
+    updates: Updates,
+    webhooks: Webhooks, // registered at runtime, and told of each refresh
+    publisher: Option<Publisher>, // pushes each refresh to PUBLISH_URL
+    kafka: Option<KafkaProducer>, // produces a record of each refresh to KAFKA_TOPIC
+    shared: Option<SharedCache>, // shares refreshes with other replicas
+}
+
+impl EstimateCache {
+    fn new(
+        programs: &[String],
+        publisher: Option<Publisher>,
+        kafka: Option<KafkaProducer>,
+        shared: Option<SharedCache>,
+    ) -> EstimateCache {
+        EstimateCache {
+            programs: programs.to_vec(),
+            by_program: programs
+                .iter()
+                .map(|p| (p.clone(), Arc::default()))
+                .collect(),
+            updates: Updates::default(),
+            webhooks: Webhooks::default(),
+            publisher,
+            kafka,
+            shared,
+        }
+    }
+
+    /// Store the result of a refresh of `program` and wake its streaming clients.
+    fn publish(
+        &self,
+        program: &str,
+        result: Result<Estimate, CodedError>,
+        max_staleness: Duration,
+    ) {
+        self.by_program[program]
+            .write()
+            .unwrap()
+            .record(result, max_staleness);
+        self.updates.publish(program);
+    }
+
+    /// Serve `estimate`, saved before a restart, as stale until `program`'s first refresh.
+    fn restore(&self, program: &str, estimate: Estimate, max_staleness: Duration) {
+        let mut latest = self.by_program[program].write().unwrap();
+        latest.stale_until = estimate
+            .last_updated
+            .saturating_add(max_staleness.as_secs());
+        latest.estimate = Some(estimate);
+        latest.restored = true;
+        drop(latest);
+        self.updates.publish(program);
+    }
+
+    /// Every program's latest estimate and, unless `history` persists itself, its
+    /// points, to be restored after a restart.
+    fn saved_state(&self, history: &History) -> SavedState {
+        let estimates = self
+            .by_program
+            .iter()
+            .filter_map(|(program, latest)| {
+                let latest = latest.read().unwrap();
+                let e = latest.estimate.as_ref()?;
+                Some((program.clone(), SavedEstimate::new(e)))
+            })
+            .collect();
+        SavedState {
+            saved_at: unix_now(),
+            estimates,
+            history: match history.is_persisted() {
+                true => Vec::new(),
+                false => history.points(),
+            },
+        }
+    }
+
+    /// Clamp every cached estimate to [min_fee, max_fee] from now on.
+    fn set_fee_bounds(&self, min_fee: u64, max_fee: u64) {
+        for latest in self.by_program.values() {
+            let mut latest = latest.write().unwrap();
+            latest.estimate = latest
+                .estimate
+                .take()
+                .map(|e| e.with_fee_bounds(min_fee, max_fee));
+        }
+        for program in &self.programs {
+            self.updates.publish(program);
+        }
+    }
+
+    fn get(&self, program: &str) -> Option<&SharedEstimate> {
+        self.by_program.get(program)
+    }
+
+    fn primary(&self) -> &SharedEstimate {
+        &self.by_program[&self.programs[0]]
+    }
+
+    /// Every program's estimate as served, if each has one.
+    fn served_all(&self) -> Option<Vec<Estimate>> {
+        self.programs
+            .iter()
+            .map(|p| self.by_program[p].read().unwrap().served()?.ok())
+            .collect()
+    }
+
+    /// Merge every program's latest successful sample into one estimate.
+    fn aggregate(&self) -> Option<Estimate> {
+        self.merge(&self.programs)
+    }
+
+    /// An estimate from the sampled transactions of any tracked program that
+    /// write-locked `account`, or `None` if there are fewer than `MIN_ACCOUNT_SAMPLES`.
+    fn account_estimate(&self, account: &str) -> Option<Estimate> {
+        let e = self.aggregate()?;
+        let samples: Vec<Sample> = e
+            .samples
+            .iter()
+            .filter(|s| s.writable_accounts.iter().any(|a| a == account))
+            .cloned()
+            .collect();
+        if samples.len() < MIN_ACCOUNT_SAMPLES {
+            return None;
+        }
+        Some(
+            Estimate::new(samples, e.include_failed, e.last_updated, e.source)
+                .with_fee_bounds(e.min_fee, e.max_fee)
+                .with_low_confidence(e.low_confidence),
+        )
+    }
+
+    /// Merge the samples of the tracked programs among `keys`, or of every tracked
+    /// program if none of them appear. Returns the programs merged, too.
+    fn merge_for_keys(&self, keys: &[String]) -> Option<(Vec<String>, Estimate)> {
+        let mut programs: Vec<String> = self
+            .programs
+            .iter()
+            .filter(|p| keys.contains(p))
+            .cloned()
+            .collect();
+        if programs.is_empty() {
+            programs = self.programs.clone();
+        }
+        let e = self.merge(&programs)?;
+        Some((programs, e))
+    }
+
+    /// Merge the latest successful samples of `programs` into one estimate.
+    /// `last_updated` is that of the stalest contributing program, and `source` is
+    /// "mixed" if the programs' samples came from different sources.
+    fn merge(&self, programs: &[String]) -> Option<Estimate> {
+        let mut samples = Vec::new();
+        let mut include_failed = true;
+        let mut last_updated = u64::MAX;
+        let mut source = None;
+        let mut fee_bounds = (0, MAX_PRIORITY_FEE);
+        let mut stale = false;
+        let mut low_confidence = false;
+        for program in programs {
+            if let Some(Ok(e)) = self.by_program[program].read().unwrap().served() {
+                stale |= e.stale;
+                low_confidence |= e.low_confidence;
+                samples.extend_from_slice(&e.samples);
+                include_failed = e.include_failed;
+                fee_bounds = (e.min_fee, e.max_fee);
+                last_updated = last_updated.min(e.last_updated);
+                source = match source {
+                    None => Some(e.source),
+                    Some(s) if s == e.source => Some(s),
+                    Some(_) => Some("mixed"),
+                };
+            }
+        }
+        if last_updated == u64::MAX {
+            return None;
+        }
+        Some(
+            Estimate::new(
+                samples,
+                include_failed,
+                last_updated,
+                source.unwrap_or("mixed"),
+            )
+            .with_fee_bounds(fee_bounds.0, fee_bounds.1)
+            .with_stale(stale)
+            .with_low_confidence(low_confidence),
+        )
+    }
+}
+
+/// A further cluster's estimator and the latest estimates it made. Its refreshes are
+/// neither published, recorded to disk nor shared with other replicas.
+struct ClusterEstimates {
+    name: String,
+    estimator: Arc<PriorityFeeEstimator>,
+    cache: Arc<EstimateCache>,
+}
+
+impl ClusterEstimates {
+    fn new(config: &Config, cluster: &Cluster) -> ClusterEstimates {
+        let config = config.for_cluster(cluster);
+        ClusterEstimates {
+            name: cluster.name.clone(),
+            cache: Arc::new(EstimateCache::new(
+                &config.tracked_programs,
+                None,
+                None,
+                None,
+            )),
+            estimator: Arc::new(PriorityFeeEstimator::new(config)),
+        }
+    }
+}
 
 fn main() {
-    let rpc_url = env::var("RPC_URL").unwrap_or_else(|_| DEFAULT_RPC_URL.to_string());
-
-    eprintln!("Starting ivy-priority-fee on http://{}", LISTEN_URL);
-    eprintln!("RPC: {}", rpc_url);
-
-    rouille::start_server(LISTEN_URL, move |request| {
-        let rpc_url = rpc_url.clone();
-
-        router!(request,
-            (GET) (/) => {
-                match get_reasonable_priority_fee(&rpc_url) {
-                    Ok(fee) => Response::json(&json!({ "reasonablePriorityFee": fee })),
-                    Err(err) => {
-                        Response::from_data("application/json", json!({
-                            "error": err.to_string()
-                        }).to_string()).with_status_code(500)
+    let command = match Command::from_args() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    match command {
+        Command::Help => print!("{}", USAGE),
+        Command::Serve => {
+            // Before any thread is spawned, so that every thread inherits the mask
+            let signals = block_signals(&[libc::SIGHUP, libc::SIGTERM, libc::SIGINT]);
+            serve(init(), signals);
+        }
+        Command::Query => query(&init()),
+        Command::History => dump_history(&init()),
+        Command::EstimateTx(transaction) => estimate_tx(&init(), transaction),
+    }
+}
+
+/// Load the config, printing it and exiting instead with `--check-config`, and set up
+/// logging and tracing.
+fn init() -> Config {
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if std::env::args().any(|a| a == "--check-config") {
+        print!("{}", config);
+        std::process::exit(0);
+    }
+    logging::init(config.log_format).expect("logger is only installed once");
+    match telemetry::init() {
+        Ok(Some(endpoint)) => log::info!("Exporting traces to {}", endpoint),
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    config
+}
+
+/// Run the server until `SIGTERM` or `SIGINT`, which `signals` are blocked for.
+fn serve(config: Config, signals: libc::sigset_t) {
+    log::info!("RPC: {}", config.rpc.urls().collect::<Vec<_>>().join(", "));
+    if let Some(proxy) = config.rpc.proxy() {
+        log::info!("RPC proxy: {}", proxy);
+    }
+    log::info!("Tracked programs: {}", config.tracked_programs.join(", "));
+    for cluster in &config.clusters {
+        log::info!(
+            "Cluster {}: RPC: {}; tracked programs: {}",
+            cluster.name,
+            cluster.rpc.urls().collect::<Vec<_>>().join(", "),
+            cluster.tracked_programs.join(", ")
+        );
+    }
+    log::info!("Fee source: {}", config.fee_source.name());
+    log::info!("Refresh interval: {}s", config.refresh_interval.as_secs());
+
+    let mut saved = load_saved_state(&config);
+    let history = Arc::new(open_history(&config, &mut saved));
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => match ReloadingCert::load(cert_path, key_path) {
+            Ok(cert) => {
+                let cert = Arc::new(cert);
+                cert.watch();
+                Some(Arc::new(TlsFrontend::new(cert)))
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    let publisher = config.publish.clone().map(|target| {
+        log::info!("Publishing estimates to {}", target);
+        Publisher::start(target)
+    });
+    let kafka = (!config.kafka_brokers.is_empty()).then(|| {
+        log::info!(
+            "Producing estimates to Kafka topic {} via {}",
+            config.kafka_topic,
+            config.kafka_brokers.join(", ")
+        );
+        KafkaProducer::start(config.kafka_brokers.clone(), config.kafka_topic.clone())
+    });
+    let shared = config.shared_cache.clone().map(|url| {
+        log::info!("Sharing estimates with other replicas through {}", url);
+        SharedCache::new(url, &config.shared_cache_prefix)
+    });
+    let cache = Arc::new(EstimateCache::new(
+        &config.tracked_programs,
+        publisher,
+        kafka,
+        shared,
+    ));
+    if let Some(saved) = saved {
+        log::info!(
+            "Serving estimates saved {}s ago until the first refreshes",
+            unix_now().saturating_sub(saved.saved_at)
+        );
+        for (program, e) in saved.estimates {
+            if cache.get(&program).is_some() {
+                let e = e
+                    .into_estimate()
+                    .with_include_failed(config.include_failed)
+                    .with_fee_bounds(config.min_fee_floor, config.max_fee_clamp);
+                cache.restore(&program, e, config.max_staleness);
+            }
+        }
+    }
+    let clusters: Arc<Vec<ClusterEstimates>> = Arc::new(
+        config
+            .clusters
+            .iter()
+            .map(|cluster| ClusterEstimates::new(&config, cluster))
+            .collect(),
+    );
+    let estimator = Arc::new(PriorityFeeEstimator::new(config));
+    // Probe whichever RPC pools are current, as a reload may replace them
+    let probed = estimator.clone();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(probed.config().health_check_interval);
+            probed.config().rpc.probe();
+            for cluster in &probed.config().clusters {
+                cluster.rpc.probe();
+            }
+        }
+    });
+    let shutdown = Arc::new(Shutdown::default());
+    // Set with `PUT /admin/limits`, and applied again on each reload
+    let fee_limits = Arc::new(Mutex::new(FeeLimits::default()));
+    let (reloaded, reloaded_clusters, reloaded_limits, stopping) = (
+        estimator.clone(),
+        clusters.clone(),
+        fee_limits.clone(),
+        shutdown.clone(),
+    );
+    thread::spawn(move || {
+        loop {
+            match wait_for_signal(&signals) {
+                libc::SIGHUP => {
+                    let _ = reload_config(&reloaded, &reloaded_clusters, &reloaded_limits);
+                }
+                // A second Ctrl-C skips the drain. Repeated SIGTERMs don't, as they're
+                // commonly sent to both the process and its group.
+                libc::SIGINT if stopping.is_requested() => std::process::exit(1),
+                _ => stopping.request(),
+            }
+        }
+    });
+    spawn_refresh_loops(&estimator, &cache, &history, &shutdown);
+    for cluster in clusters.iter() {
+        let history = Arc::new(History::in_memory());
+        spawn_refresh_loops(&cluster.estimator, &cluster.cache, &history, &shutdown);
+    }
+    let (drained_estimator, drained_cache, drained_history) =
+        (estimator.clone(), cache.clone(), history.clone());
+
+    let account_fees = AccountFees::default();
+    let blockhashes = BlockhashSlots::default();
+    let lookup_tables = LookupTables::default();
+    let rpc_overrides = RpcOverrides::default();
+    let comparison = Comparison::default();
+    let rate_limiter = Arc::new(RateLimiter::default());
+    let usage = Arc::new(Usage::default());
+    let grpc_service = GrpcService {
+        cache: cache.clone(),
+        estimator: estimator.clone(),
+        rate_limiter: rate_limiter.clone(),
+        usage: usage.clone(),
+    };
+    let listen_addr = estimator.config().listen_addr.clone();
+    // With TLS, or without a TCP listener, the HTTP server only takes connections
+    // relayed from the TLS frontend or the Unix socket
+    let http_addr = if tls.is_some() || listen_addr.is_empty() {
+        "127.0.0.1:0"
+    } else {
+        listen_addr.as_str()
+    };
+    let frontend = tls.clone();
+    let stopping = shutdown.clone();
+    let server = rouille::Server::new(http_addr, move |request| {
+        let config = estimator.config();
+        let aggregation = config.aggregation;
+        let client = frontend
+            .as_ref()
+            .and_then(|f| f.client_addr(request.remote_addr()))
+            .unwrap_or(*request.remote_addr());
+        serve_logged(request, client.ip(), config.access_log, || {
+            with_cors(request, &config.cors_origins, || {
+                with_compression(request, || {
+                    with_format(request, || {
+                        with_api_version(request, |request| {
+                            if let Some(rps) = config.rate_limit_rps
+                                && !is_probe(&request.url())
+                                && let Err(wait) =
+                                    rate_limiter.check(client.ip(), rps, config.rate_limit_burst)
+                            {
+                                return too_many_requests(wait);
+                            }
+                            try_or_respond!(check_access(request, &config.api_keys, &usage));
+                            if let Some(url) = request.header("X-Rpc-Url") {
+                                return rpc_override_response(
+                                    request,
+                                    &config,
+                                    &rpc_overrides,
+                                    url,
+                                );
+                            }
+                            // router! can't match a path with a dot in it
+                            if request.method() == "GET" && request.url() == "/openapi.json" {
+                                return Response::json(&openapi::document());
+                            }
+                            router!(request,
+                                (GET) (/) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let format = ResponseFormat::of(request);
+                                    with_estimate(request, &config, cache.primary(), |e| fee_response(e, &query, format))
+                                },
+                                (GET) (/levels) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    with_estimate(request, &config, cache.primary(), |e| Response::json(&levels_json(e, &query)))
+                                },
+                                (POST) (/) => {
+                                    json_rpc(request, &cache, aggregation)
+                                },
+                                (POST) (/estimate) => {
+                                    let body: TransactionEstimateRequest = try_or_respond!(read_json(request));
+                                    transaction_estimate(body, &cache, &lookup_tables, &config)
+                                },
+                                (POST) (/simulate-estimate) => {
+                                    let body: TransactionEstimateRequest = try_or_respond!(read_json(request));
+                                    let commitment = try_or_respond!(commitment_param(request, config.commitment));
+                                    simulate_estimate(body, commitment, &cache, &lookup_tables, &estimator)
+                                },
+                                (GET) (/fee) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    all_fees_response(request, &config, &cache, &query)
+                                },
+                                (GET) (/fee/{program: String}) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    match cache.get(&program) {
+                                        Some(estimate) => {
+                                            let format = ResponseFormat::of(request);
+                                            with_estimate(request, &config, estimate, |e| fee_response(e, &query, format))
+                                        }
+                                        None => error_response(404, &format!("program {} is not tracked", program)),
+                                    }
+                                },
+                                (GET) (/jito-tip) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    jito_tip(request, &cache, &query)
+                                },
+                                (GET) (/distribution) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    let Some(estimate) = cache.get(&program) else {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    };
+                                    with_estimate(request, &config, estimate, |e| distribution_response(&query.apply(e), &program))
+                                },
+                                (GET) (/hot-accounts) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let limit = match request.get_param("limit").map(|v| v.parse::<usize>()) {
+                                        Some(Ok(limit)) if limit > 0 => limit,
+                                        Some(_) => return error_response(400, "limit must be a positive whole number"),
+                                        None => DEFAULT_HOT_ACCOUNTS,
+                                    };
+                                    hot_accounts_response(request, &cache, &query, limit)
+                                },
+                                (GET) (/explain) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    let Some(estimate) = cache.get(&program) else {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    };
+                                    with_estimate(request, &config, estimate, |e| explain_response(e, &program, &query, &config))
+                                },
+                                (GET) (/compare) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    if cache.get(&program).is_none() {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    }
+                                    compare_response(&config, &comparison, &program, &query)
+                                },
+                                (GET) (/samples) => {
+                                    let limit = match request.get_param("limit").map(|v| v.parse::<usize>()) {
+                                        Some(Ok(limit)) if limit > 0 => limit,
+                                        Some(_) => return error_response(400, "limit must be a positive whole number"),
+                                        None => DEFAULT_SAMPLES_LIMIT,
+                                    };
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    let Some(estimate) = cache.get(&program) else {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    };
+                                    with_estimate(request, &config, estimate, |e| samples_response(e, &program, limit))
+                                },
+                                (GET) (/congestion) => {
+                                    congestion(request, &cache, &estimator)
+                                },
+                                (GET) (/target) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let target = try_or_respond!(Target::parse(request));
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    let Some(estimate) = cache.get(&program) else {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    };
+                                    with_estimate(request, &config, estimate, |e| {
+                                        target_response(&query.apply(e), &program, &target, &blockhashes, &config)
+                                    })
+                                },
+                                (GET) (/account/{account: String}/fee) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    if !is_valid_address(&account) {
+                                        return error_response(400, &format!("invalid account: {}", account));
+                                    }
+                                    let source = request.get_param("source");
+                                    let e = match source.as_deref() {
+                                        None => cache
+                                            .account_estimate(&account)
+                                            .map_or_else(|| account_fees.get(&estimator, &account), Ok),
+                                        Some("samples") => cache
+                                            .account_estimate(&account)
+                                            .ok_or_else(|| format!("too few sampled transactions write to {}", account)),
+                                        Some("recent") => account_fees.get(&estimator, &account),
+                                        Some(other) => {
+                                            return error_response(400, &format!("unknown source: {}", other));
+                                        }
+                                    };
+                                    match e {
+                                        Ok(e) => {
+                                            let mut body = fee_json(&e, &query);
+                                            body["account"] = json!(account);
+                                            body["samples"] = json!(query.apply(&e).selected.len());
+                                            Response::json(&body)
+                                        }
+                                        Err(err) => error_response(503, &err),
+                                    }
+                                },
+                                (GET) (/stream) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    if cache.get(&program).is_none() {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    }
+                                    event_stream(EventStream {
+                                        cache: cache.clone(),
+                                        program,
+                                        query,
+                                    })
+                                },
+                                (GET) (/wait) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    if cache.get(&program).is_none() {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    }
+                                    let wait = try_or_respond!(LongPoll::parse(request));
+                                    let format = ResponseFormat::of(request);
+                                    long_poll(&cache, &program, &query, wait, format)
+                                },
+                                (GET) (/ws) => {
+                                    let (response, websocket) = match websocket::start(request, None::<&str>) {
+                                        Ok(r) => r,
+                                        Err(e) => return error_response(400, &e.to_string()),
+                                    };
+                                    let cache = cache.clone();
+                                    thread::spawn(move || {
+                                        if let Ok(ws) = websocket.recv() {
+                                            serve_subscription(ws, &cache, aggregation);
+                                        }
+                                    });
+                                    response
+                                },
+                                (GET) (/history) => {
+                                    let program = request
+                                        .get_param("program")
+                                        .unwrap_or_else(|| cache.programs[0].clone());
+                                    if cache.get(&program).is_none() {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    }
+                                    let window = try_or_respond!(parse_duration_param(request, "window", "1h"));
+                                    let step = try_or_respond!(parse_duration_param(request, "step", "1m"));
+                                    if window > history.max_age_secs() {
+                                        let message = format!("window must be at most {}", duration_label(history.max_age_secs()));
+                                        return error_response(400, &message);
+                                    }
+                                    if step == 0 {
+                                        return error_response(400, "step must be positive");
+                                    }
+                                    let since = unix_now().saturating_sub(window);
+                                    Response::json(&json!({
+                                        "program": program,
+                                        "window": window,
+                                        "step": step,
+                                        "points": try_or_respond!(history.series(&program, since, step).map_err(|e| {
+                                            log::error!("failed to read history: {}", e);
+                                            error_response(500, "failed to read history")
+                                        }))
+                                    }))
+                                },
+                                (GET) (/graphql) => {
+                                    let body = try_or_respond!(GraphqlRequest::from_params(request));
+                                    graphql_response(body, &cache, &history, aggregation)
+                                },
+                                (POST) (/graphql) => {
+                                    let body: GraphqlRequest = try_or_respond!(read_json(request));
+                                    graphql_response(body, &cache, &history, aggregation)
+                                },
+                                (GET) (/graphql/schema) => {
+                                    Response::text(GRAPHQL_SCHEMA)
+                                },
+                                (GET) (/history/export) => {
+                                    let format = try_or_respond!(request
+                                        .get_param("format")
+                                        .unwrap_or_else(|| "csv".to_string())
+                                        .parse::<ExportFormat>()
+                                        .map_err(|e| error_response(400, &e)));
+                                    let program = request.get_param("program");
+                                    if let Some(program) = &program && cache.get(program).is_none() {
+                                        return error_response(404, &format!("program {} is not tracked", program));
+                                    }
+                                    let now = unix_now();
+                                    let from = try_or_respond!(parse_timestamp_param(request, "from"))
+                                        .unwrap_or_else(|| now.saturating_sub(history.max_age_secs()));
+                                    let to = try_or_respond!(parse_timestamp_param(request, "to")).unwrap_or(now + 1);
+                                    if from >= to {
+                                        return error_response(400, "from must be before to");
+                                    }
+                                    let points = try_or_respond!(history.range(program.as_deref(), from, to).map_err(|e| {
+                                        log::error!("failed to read history: {}", e);
+                                        error_response(500, "failed to read history")
+                                    }));
+                                    let filename = format!("history-{}-{}.{}", from, to, format.extension());
+                                    Response::from_data(format.content_type(), format.write(&points)).with_additional_header(
+                                        "Content-Disposition",
+                                        format!("attachment; filename=\"{}\"", filename),
+                                    )
+                                },
+                                (GET) (/health) => {
+                                    Response::text("ok")
+                                },
+                                (GET) (/livez) => {
+                                    Response::text("ok")
+                                },
+                                (GET) (/readyz) => {
+                                    readiness(&cache, config.ready_max_age, stopping.is_requested())
+                                },
+                                (GET) (/version) => {
+                                    Response::json(&version(&config))
+                                },
+                                (GET) (/admin/usage) => {
+                                    usage_response(&usage, &config.api_keys)
+                                },
+                                (POST) (/admin/refresh) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    let programs = match request.get_param("program") {
+                                        Some(p) if cache.get(&p).is_none() => {
+                                            return error_response(404, &format!("program {} is not tracked", p));
+                                        }
+                                        Some(p) => vec![p],
+                                        None => cache.programs.clone(),
+                                    };
+                                    forced_refresh(&estimator, &programs, &query, &cache, &history, &stopping)
+                                },
+                                (GET) (/admin/limits) => {
+                                    limits_response(&config)
+                                },
+                                (PUT) (/admin/limits) => {
+                                    let update: FeeLimits = try_or_respond!(read_json(request));
+                                    set_fee_limits(update, &fee_limits, &estimator, &cache, &clusters)
+                                },
+                                (GET) (/admin/webhooks) => {
+                                    webhooks_response(&config, &cache.webhooks)
+                                },
+                                (POST) (/admin/webhooks) => {
+                                    let webhook: Webhook = try_or_respond!(read_json(request));
+                                    if let Err(e) = webhook.validate() {
+                                        return error_response(400, &e);
+                                    }
+                                    let id = cache.webhooks.register(webhook.clone());
+                                    Response::json(&webhook_json(id, &webhook)).with_status_code(201)
+                                },
+                                (DELETE) (/admin/webhooks/{id: u64}) => {
+                                    match cache.webhooks.unregister(id) {
+                                        true => Response::empty_204(),
+                                        false => error_response(404, &format!("no webhook {}", id)),
+                                    }
+                                },
+                                (POST) (/admin/reload) => {
+                                    match reload_config(&estimator, &clusters, &fee_limits) {
+                                        Ok(()) => Response::json(&json!({ "reloaded": true })),
+                                        Err(e) => error_response(400, &e),
+                                    }
+                                },
+                                // After every other route, which a cluster's name can't shadow
+                                (GET) (/{cluster: String}/fee) => {
+                                    let query = try_or_respond!(FeeQuery::parse(request, aggregation));
+                                    if cluster == config.cluster {
+                                        return all_fees_response(request, &config, &cache, &query);
+                                    }
+                                    match clusters.iter().find(|c| c.name == cluster) {
+                                        Some(c) => all_fees_response(request, &config, &c.cache, &query),
+                                        None => error_response(404, &format!("cluster {} is not configured", cluster)),
+                                    }
+                                },
+                                _ => Response::empty_404()
+                            )
+                        })
+                    })
+                })
+            })
+        })
+    });
+    let server = match server {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Failed to listen on {}: {}", listen_addr, e);
+            std::process::exit(1);
+        }
+    };
+    // Relays connect to the server over loopback, even if it listens on all interfaces
+    let mut http_addr = server.server_addr();
+    if http_addr.ip().is_unspecified() {
+        http_addr.set_ip(match http_addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    match &tls {
+        _ if listen_addr.is_empty() => {}
+        Some(tls) => match tls.listen(&listen_addr, http_addr) {
+            Ok(addr) => log::info!("Listening on https://{}", addr),
+            Err(e) => {
+                log::error!("Failed to listen on {}: {}", listen_addr, e);
+                std::process::exit(1);
+            }
+        },
+        None => log::info!("Listening on http://{}", server.server_addr()),
+    }
+    let socket = drained_estimator.config().listen_socket.clone();
+    if let Some(path) = &socket {
+        let mode = drained_estimator.config().listen_socket_mode;
+        if let Err(e) = unix_socket::listen(path, mode, http_addr) {
+            log::error!("Failed to listen on {}: {}", path, e);
+            std::process::exit(1);
+        }
+        log::info!("Listening on unix:{}", path);
+    }
+    if let Some(addr) = &drained_estimator.config().grpc_addr {
+        match grpc::listen(addr, Arc::new(grpc_service)) {
+            Ok(addr) => log::info!("Serving gRPC on {}", addr),
+            Err(e) => {
+                log::error!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    while !shutdown.is_requested() {
+        server.poll_timeout(Duration::from_millis(100));
+    }
+
+    // Stop accepting connections, end streams, and give in-flight requests and
+    // refreshes until the deadline to finish
+    let timeout = drained_estimator.config().shutdown_timeout;
+    log::info!("Shutting down; waiting up to {}s", timeout.as_secs());
+    let deadline = Instant::now() + timeout;
+    drained_cache.updates.close();
+    server.poll_timeout(Duration::from_millis(100));
+    let (drained, requests_done) = mpsc::channel();
+    thread::spawn(move || {
+        server.join();
+        let _ = drained.send(());
+    });
+    if requests_done
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .is_err()
+    {
+        log::warn!("Gave up waiting for in-flight requests");
+    }
+    if !shutdown.wait_for_refreshes(deadline) {
+        log::warn!("Gave up waiting for refreshes");
+    }
+    if let Some(shared) = &drained_cache.shared
+        && let Err(e) = shared.release()
+    {
+        log::warn!("failed to release the leader lock: {}", e);
+    }
+    if let Some(path) = &drained_estimator.config().state_path {
+        match drained_cache
+            .saved_state(&drained_history)
+            .save(Path::new(path))
+        {
+            Ok(()) => log::info!("Saved the latest estimates to {}", path),
+            Err(e) => log::error!("failed to save the latest estimates to {}: {}", path, e),
+        }
+    }
+    if let Err(e) = drained_history.flush() {
+        log::error!("failed to flush history: {}", e);
+    }
+    telemetry::flush(deadline);
+    if let Some(path) = &socket {
+        unix_socket::remove(path);
+    }
+    log::info!("Shut down");
+}
+
+// --------------------------- Commands ---------------------------
+
+const USAGE: &str = "\
+Usage: ivy-priority-fee [COMMAND] [--setting value ...]
+
+Commands:
+  serve                    Serve estimates over HTTP (the default)
+  query                    Refresh the estimates once and print them, as GET /fee does
+  history                  Print the stored history, as GET /history/export does
+  estimate-tx TRANSACTION  Refresh the estimates once and print one for a base64
+                           transaction, as POST /estimate does
+
+query and estimate-tx take --percentile; query also --aggregation, --cu and
+--include-failed, and estimate-tx --encoding. history takes --format (csv or parquet),
+--from, --to and --program.
+
+Settings are read from flags, the environment or --config, as listed in the README;
+--check-config prints them and exits.
+";
+
+/// What the binary was asked to do, by its first argument.
+enum Command {
+    Serve,
+    Query,
+    History,
+    EstimateTx(String), // the transaction
+    Help,
+}
+
+impl Command {
+    fn from_args() -> Result<Command, String> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if args.iter().any(|a| a == "--help" || a == "-h") {
+            return Ok(Command::Help);
+        }
+        match args.first().map(String::as_str) {
+            // Only settings, as before there were commands
+            None => Ok(Command::Serve),
+            Some(arg) if arg.starts_with('-') => Ok(Command::Serve),
+            Some("serve") => Ok(Command::Serve),
+            Some("query") => Ok(Command::Query),
+            Some("history") => Ok(Command::History),
+            Some("estimate-tx") => match args.get(1) {
+                Some(tx) if !tx.starts_with('-') => Ok(Command::EstimateTx(tx.clone())),
+                _ => Err("estimate-tx needs a transaction".to_string()),
+            },
+            Some("help") => Ok(Command::Help),
+            Some(other) => Err(format!("Unknown command {} (see --help)", other)),
+        }
+    }
+}
+
+/// The state saved at `STATE_PATH`, if it's set and something was.
+fn load_saved_state(config: &Config) -> Option<SavedState> {
+    config.state_path.as_deref().and_then(|path| {
+        SavedState::load(Path::new(path)).unwrap_or_else(|e| {
+            log::warn!("Failed to load the state saved in {}: {}", path, e);
+            None
+        })
+    })
+}
+
+/// The history `config` persists to, or one in memory holding any `saved` with the
+/// state, exiting if it can't be opened.
+fn open_history(config: &Config, saved: &mut Option<SavedState>) -> History {
+    match (&config.history_path, config.database()) {
+        (Some(path), _) => match History::open(path) {
+            Ok(h) => h,
+            Err(e) => {
+                log::error!("Failed to open history file {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(target)) => match History::open_database(&target, config.db_retention) {
+            Ok(h) => h,
+            Err(e) => {
+                log::error!("Failed to open history database {}: {}", target, e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => History::in_memory_from(
+            saved
+                .as_mut()
+                .map(|s| std::mem::take(&mut s.history))
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// `query`: print every tracked program's estimate.
+fn query(config: &Config) {
+    let cache = refresh_once(config);
+    let params = [
+        ("--percentile", "percentile"),
+        ("--include-failed", "includeFailed"),
+        ("--aggregation", "aggregation"),
+        ("--cu", "cu"),
+    ];
+    let mut url = url::form_urlencoded::Serializer::new("/fee?".to_string());
+    for (flag, param) in params {
+        if let Some(v) = config::flag_value(flag) {
+            url.append_pair(param, &v);
+        }
+    }
+    // Parsed as `GET /fee` would parse the same query
+    let request = rouille::Request::fake_http("GET", url.finish(), vec![], vec![]);
+    let response = match FeeQuery::parse(&request, config.aggregation) {
+        Ok(query) => fees_response(&cache, &query),
+        Err(response) => response,
+    };
+    print_response(response);
+    // So that scripts can tell when no program could be estimated
+    if cache.aggregate().is_none() {
+        std::process::exit(1);
+    }
+}
+
+/// `history`: write the stored history to stdout.
+fn dump_history(config: &Config) {
+    let history = open_history(config, &mut load_saved_state(config));
+    let fail = |message: &str| -> ! {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    };
+    if !history.is_persisted() && history.points().is_empty() {
+        fail("No history is stored; set HISTORY_PATH, DB_PATH, DATABASE_URL or STATE_PATH");
+    }
+    let format = config::flag_value("--format")
+        .unwrap_or_else(|| "csv".to_string())
+        .parse::<ExportFormat>()
+        .unwrap_or_else(|e| fail(&e));
+    let timestamp = |flag: &str| {
+        config::flag_value(flag).map(|v| {
+            v.parse::<u64>()
+                .unwrap_or_else(|_| fail(&format!("{} must be a unix timestamp in seconds", flag)))
+        })
+    };
+    let now = unix_now();
+    let from = timestamp("--from").unwrap_or_else(|| now.saturating_sub(history.max_age_secs()));
+    let to = timestamp("--to").unwrap_or(now + 1);
+    let program = config::flag_value("--program");
+    let points = history
+        .range(program.as_deref(), from, to)
+        .unwrap_or_else(|e| fail(&format!("Failed to read history: {}", e)));
+    let mut stdout = std::io::stdout().lock();
+    if let Err(e) = stdout
+        .write_all(&format.write(&points))
+        .and_then(|()| stdout.flush())
+    {
+        fail(&format!("Failed to write history: {}", e));
+    }
+}
+
+/// `estimate-tx`: print the estimate for `transaction`.
+fn estimate_tx(config: &Config, transaction: String) {
+    let percentile = match config::flag_value("--percentile").map(|v| v.parse::<f64>()) {
+        Some(Ok(p)) => Some(p),
+        Some(Err(_)) => {
+            eprintln!("--percentile must be a number between 0 and 100");
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let cache = refresh_once(config);
+    let body = TransactionEstimateRequest {
+        transaction,
+        encoding: config::flag_value("--encoding"),
+        percentile,
+        include_failed: None,
+    };
+    print_response(transaction_estimate(
+        body,
+        &cache,
+        &LookupTables::default(),
+        config,
+    ));
+}
+
+/// Refresh every tracked program once, with nothing recorded or published, for the
+/// one-shot commands.
+fn refresh_once(config: &Config) -> EstimateCache {
+    let cache = EstimateCache::new(&config.tracked_programs, None, None, None);
+    let estimator = PriorityFeeEstimator::new(config.clone());
+    let history = History::in_memory();
+    thread::scope(|s| {
+        for program in &config.tracked_programs {
+            s.spawn(|| refresh(&estimator, program, &cache, &history));
+        }
+    });
+    cache
+}
+
+/// Print a response's body, to stderr and exiting unsuccessfully if it's an error.
+fn print_response(response: Response) {
+    let failed = response.is_error();
+    let (mut reader, _) = response.data.into_reader_and_size();
+    let mut body = String::new();
+    let _ = reader.read_to_string(&mut body);
+    match failed {
+        true => {
+            eprintln!("{}", body);
+            std::process::exit(1);
+        }
+        false => println!("{}", body),
+    }
+}
+
+// --------------------------- HTTP helpers ---------------------------
+
+/// Longest client-supplied `X-Request-Id` that's passed through.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Serve `request` with `handler`, tagging the response and every log record the handler
+/// writes with a request ID, and writing the request to the access log. The ID is the
+/// client's `X-Request-Id` if it sent a reasonable one, or a new one. `client_ip` is the
+/// client's address, which differs from the connection's behind the TLS frontend.
+fn serve_logged(
+    request: &rouille::Request,
+    client_ip: IpAddr,
+    access_log: AccessLogFormat,
+    handler: impl FnOnce() -> Response,
+) -> Response {
+    let started = Instant::now();
+    let request_id = request
+        .header("X-Request-Id")
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map_or_else(logging::new_request_id, str::to_string);
+    let span = logging::span(&[("request_id", &request_id)]);
+    let parent = request
+        .header("traceparent")
+        .and_then(SpanContext::from_traceparent);
+    let trace = telemetry::span_with_parent(request.method(), SpanKind::Server, parent);
+    trace.set("http.request.method", request.method());
+    trace.set("url.path", request.url());
+    trace.set("client.address", client_ip.to_string());
+    trace.set("request_id", request_id.as_str());
+    let response = handler().with_unique_header("X-Request-Id", request_id.clone());
+    trace.set("http.response.status_code", response.status_code);
+    if response.status_code >= 500 {
+        trace.set_error(&format!("status {}", response.status_code));
+    }
+    drop(trace);
+    drop(span);
+    access_log.write(&AccessRecord {
+        client_ip,
+        method: request.method(),
+        url: &redact_api_key(request.raw_url()),
+        status: response.status_code,
+        latency: started.elapsed(),
+        request_id: &request_id,
+    });
+    response
+}
+
+/// Check that `request` may be served: probes always may; otherwise, with `keys`
+/// configured, see `authorize`. Without keys, every endpoint is open except the admin
+/// ones, which are refused, since there's no admin key to present for them.
+fn check_access(
+    request: &rouille::Request,
+    keys: &[ApiKey],
+    usage: &Usage,
+) -> Result<(), Response> {
+    let url = request.url();
+    if is_probe(&url) {
+        return Ok(());
+    }
+    if keys.is_empty() {
+        return match is_admin_endpoint(&url) {
+            true => Err(error_response(
+                403,
+                "this endpoint needs an admin API key; configure one in API_KEYS",
+            )),
+            false => Ok(()),
+        };
+    }
+    authorize(request, keys, usage)
+}
+
+/// Check that `request` carries one of `keys`, as `Authorization: Bearer <key>` or an
+/// `api-key` query parameter, that the key may call the endpoint, and that it has quota
+/// left for today, counting the request against it.
+fn authorize(request: &rouille::Request, keys: &[ApiKey], usage: &Usage) -> Result<(), Response> {
+    let key = request
+        .header("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|k| k.trim().to_string())
+        .or_else(|| request.get_param("api-key"))
+        .and_then(|presented| api_keys::find(keys, &presented))
+        .ok_or_else(|| {
+            error_response(401, "missing or invalid API key")
+                .with_unique_header("WWW-Authenticate", "Bearer")
+        })?;
+    if is_admin_endpoint(&request.url()) && !key.admin {
+        return Err(error_response(403, "this endpoint needs an admin API key"));
+    }
+    usage.record(key).map_err(|reset_in| {
+        error_response(429, "daily quota exceeded")
+            .with_unique_header("Retry-After", reset_in.to_string())
+    })?;
+    logging::record("api_key", &key.label());
+    Ok(())
+}
+
+/// Whether the endpoint at `url` needs an admin key: `/admin/*`; `/samples`, which gives
+/// away what the service watches; and `/compare`, which samples every RPC endpoint.
+fn is_admin_endpoint(url: &str) -> bool {
+    url.starts_with("/admin/") || url == "/samples" || url == "/compare"
+}
+
+/// `url` with the value of any `api-key` query parameter masked, for the access log.
+fn redact_api_key(url: &str) -> String {
+    let Some((path, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("api-key", _)) => "api-key=REDACTED",
+            _ => pair,
+        })
+        .collect();
+    format!("{}?{}", path, query.join("&"))
+}
+
+/// Unwrap a `Result<T, Response>`, returning the error response from the handler.
+macro_rules! try_or_respond {
+    ($e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(response) => return response,
+        }
+    };
+}
+use try_or_respond;
+
+/// The fee endpoints' query parameters, as given in a JSON body.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FeeParams {
+    program: Option<String>,
+    percentile: Option<f64>,
+    include_failed: Option<bool>,
+    aggregation: Option<String>,
+    cu: Option<u64>,
+}
+
+impl FeeParams {
+    /// The tracked program and query these select, defaulting to the primary program.
+    fn validate(
+        self,
+        cache: &EstimateCache,
+        aggregation: Aggregation,
+    ) -> Result<(String, FeeQuery), String> {
+        let program = self.program.unwrap_or_else(|| cache.programs[0].clone());
+        if cache.get(&program).is_none() {
+            return Err(format!("program {} is not tracked", program));
+        }
+        let percentile = self.percentile.unwrap_or(DEFAULT_PERCENTILE);
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err("percentile must be a number between 0 and 100".to_string());
+        }
+        let aggregation = match self.aggregation {
+            Some(a) => a.parse()?,
+            None => aggregation,
+        };
+        if let Some(cu) = self.cu {
+            check_compute_units(cu)?;
+        }
+        let query = FeeQuery {
+            percentile,
+            include_failed: self.include_failed,
+            aggregation,
+            compute_units: self.cu,
+        };
+        Ok((program, query))
+    }
+}
+
+/// Largest request body accepted.
+const MAX_REQUEST_BODY: u64 = 1 << 20;
+
+fn read_body(request: &rouille::Request) -> Result<String, String> {
+    let mut body = String::new();
+    if let Some(data) = request.data() {
+        data.take(MAX_REQUEST_BODY)
+            .read_to_string(&mut body)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(body)
+}
+
+/// Parse a JSON request body, responding with 400 if it's invalid.
+fn read_json<T: serde::de::DeserializeOwned>(request: &rouille::Request) -> Result<T, Response> {
+    read_body(request)
+        .and_then(|body| serde_json::from_str(&body).map_err(|e| e.to_string()))
+        .map_err(|e| error_response(400, &format!("invalid request body: {}", e)))
+}
+
+/// Query parameters shared by the fee endpoints.
+#[derive(Clone)]
+struct FeeQuery {
+    percentile: f64,
+    include_failed: Option<bool>, // overrides the configured INCLUDE_FAILED
+    aggregation: Aggregation,
+    compute_units: Option<u64>, // `cu`: a compute budget to price in lamports
+}
+
+impl FeeQuery {
+    fn parse(request: &rouille::Request, aggregation: Aggregation) -> Result<FeeQuery, Response> {
+        let percentile = match request.get_param("percentile") {
+            Some(v) => match v.parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => p,
+                _ => {
+                    return Err(error_response(
+                        400,
+                        "percentile must be a number between 0 and 100",
+                    ));
+                }
+            },
+            None => DEFAULT_PERCENTILE,
+        };
+        let include_failed = match request.get_param("includeFailed") {
+            Some(v) => match v.parse::<bool>() {
+                Ok(b) => Some(b),
+                _ => return Err(error_response(400, "includeFailed must be true or false")),
+            },
+            None => None,
+        };
+        let aggregation = match request.get_param("aggregation") {
+            Some(v) => v.parse().map_err(|e: String| error_response(400, &e))?,
+            None => aggregation,
+        };
+        let compute_units = match request.get_param("cu") {
+            Some(v) => {
+                let cu = v
+                    .parse::<u64>()
+                    .map_err(|_| error_response(400, CU_ERROR))?;
+                check_compute_units(cu).map_err(|e| error_response(400, &e))?;
+                Some(cu)
+            }
+            None => None,
+        };
+        Ok(FeeQuery {
+            percentile,
+            include_failed,
+            aggregation,
+            compute_units,
+        })
+    }
+
+    /// The estimate as selected by this query.
+    fn apply(&self, e: &Estimate) -> Estimate {
+        match self.include_failed {
+            Some(include_failed) => e.with_include_failed(include_failed),
+            None => e.clone(),
+        }
+    }
+
+    /// The fee this query asks for.
+    fn fee(&self, e: &Estimate) -> u64 {
+        self.apply(e).fee(self.percentile, self.aggregation)
+    }
+
+    /// An approximate 95% confidence interval around that fee.
+    fn fee_interval(&self, e: &Estimate) -> (u64, u64) {
+        self.apply(e)
+            .fee_interval(self.percentile, self.aggregation)
+    }
+
+    /// Which way that fee is heading, if a trend is tracked for this query's selection.
+    fn fee_trend(&self, e: &Estimate) -> Option<FeeTrend> {
+        self.apply(e).fee_trend(self.percentile, self.aggregation)
+    }
+
+    /// Its smoothed counterpart, if one is tracked for this query's selection.
+    fn smoothed_fee(&self, e: &Estimate) -> Option<u64> {
+        self.apply(e)
+            .smoothed_fee(self.percentile, self.aggregation)
+    }
+}
+
+const CU_ERROR: &str = "cu must be a whole number of compute units up to 1400000";
+
+fn check_compute_units(cu: u64) -> Result<(), String> {
+    if cu > MAX_COMPUTE_UNIT_LIMIT {
+        return Err(CU_ERROR.to_string());
+    }
+    Ok(())
+}
+
+/// Read a duration query parameter (e.g. `6h`), in seconds.
+fn parse_duration_param(
+    request: &rouille::Request,
+    name: &str,
+    default: &str,
+) -> Result<u64, Response> {
+    let v = request
+        .get_param(name)
+        .unwrap_or_else(|| default.to_string());
+    match parse_duration(&v) {
+        Some(d) => Ok(d.as_secs()),
+        None => Err(error_response(
+            400,
+            &format!("{} must be a duration such as 90s, 15m, 6h or 7d", name),
+        )),
+    }
+}
+
+/// Read the `commitment` query parameter, which overrides `COMMITMENT` for endpoints
+/// that call the RPC.
+fn commitment_param(
+    request: &rouille::Request,
+    default: Commitment,
+) -> Result<Commitment, Response> {
+    match request.get_param("commitment") {
+        Some(v) => v.parse().map_err(|_| {
+            error_response(400, "commitment must be processed, confirmed or finalized")
+        }),
+        None => Ok(default),
+    }
+}
+
+/// Read an optional unix timestamp query parameter, in seconds.
+fn parse_timestamp_param(request: &rouille::Request, name: &str) -> Result<Option<u64>, Response> {
+    request
+        .get_param(name)
+        .map(|v| v.parse())
+        .transpose()
+        .map_err(|_| {
+            error_response(
+                400,
+                &format!("{} must be a unix timestamp in seconds", name),
+            )
+        })
+}
+
+/// `secs` in the largest of days, hours, minutes or seconds that divides it, as
+/// `parse_duration_param` reads it.
+fn duration_label(secs: u64) -> String {
+    [(86_400, "d"), (3_600, "h"), (60, "m")]
+        .into_iter()
+        .find(|(unit, _)| secs > 0 && secs.is_multiple_of(*unit))
+        .map_or_else(
+            || format!("{}s", secs),
+            |(unit, suffix)| format!("{}{}", secs / unit, suffix),
+        )
+}
+
+/// The fee as JSON, or as just the number when `format` is text.
+fn fee_response(e: &Estimate, query: &FeeQuery, format: ResponseFormat) -> Response {
+    match format {
+        ResponseFormat::Text => Response::text(query.fee(e).to_string()),
+        _ => Response::json(&fee_json(e, query)),
+    }
+}
+
+fn levels_json(e: &Estimate, query: &FeeQuery) -> serde_json::Value {
+    let selected = query.apply(e);
+    let mut value = json!({
+        "priorityFeeLevels": selected.levels_with(query.aggregation),
+        "computeUnitLimit": selected.compute_unit_limit(),
+        "smoothedPriorityFeeLevels": selected.smoothed_levels(query.aggregation),
+        "minSlot": selected.slot_range().map(|(min, _)| min),
+        "maxSlot": selected.slot_range().map(|(_, max)| max),
+        "aggregation": query.aggregation.to_string(),
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    });
+    add_sample_metadata(&mut value, &selected);
+    value
+}
+
+fn fee_json(e: &Estimate, query: &FeeQuery) -> serde_json::Value {
+    let fee = query.fee(e);
+    let selected = query.apply(e);
+    let slot_range = selected.slot_range();
+    let mut value = json!({
+        "reasonablePriorityFee": fee,
+        "ci95": query.fee_interval(e),
+        "smoothedPriorityFee": query.smoothed_fee(e),
+        "computeUnitLimit": selected.compute_unit_limit(),
+        "percentile": query.percentile,
+        "aggregation": query.aggregation.to_string(),
+        "minSlot": slot_range.map(|(min, _)| min),
+        "maxSlot": slot_range.map(|(_, max)| max),
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    });
+    if let Some(cu) = query.compute_units {
+        let lamports = priority_fee_lamports(fee, cu);
+        value["computeUnits"] = json!(cu);
+        value["priorityFeeLamports"] = json!(lamports);
+        value["priorityFeeSol"] = json!(lamports as f64 / LAMPORTS_PER_SOL as f64);
+    }
+    add_trend(&mut value, query.fee_trend(e));
+    add_sample_metadata(&mut value, &selected);
+    value
+}
+
+/// Add which way the fee is heading and where that puts it each minute for the next
+/// few, keyed `1m` to `5m`; both `null` until a trend is tracked.
+fn add_trend(value: &mut serde_json::Value, trend: Option<FeeTrend>) {
+    value["trend"] = json!(trend.as_ref().map(|t| t.direction));
+    value["forecast"] = json!(trend.map(|t| t.forecast_by_minute()));
+}
+
+/// A refresh of `program` for analytics: fees at every fifth percentile and the 99th,
+/// fee levels and sample metadata.
+fn refresh_record(e: &Estimate, program: &str, config: &Config) -> serde_json::Value {
+    let percentiles: serde_json::Map<String, serde_json::Value> = (0..=20)
+        .map(|i| i as f64 * 5.0)
+        .chain([99.0])
+        .map(|p| (p.to_string(), json!(e.fee(p, config.aggregation))))
+        .collect();
+    let mut value = json!({
+        "program": program,
+        "percentiles": percentiles,
+        "priorityFeeLevels": e.levels_with(config.aggregation),
+        "smoothedPriorityFeeLevels": e.smoothed_levels(config.aggregation),
+        "computeUnitLimit": e.compute_unit_limit(),
+        "aggregation": config.aggregation.to_string(),
+        "source": e.source
+    });
+    add_sample_metadata(&mut value, e);
+    value
+}
+
+/// Add what clients need to judge an estimate by: whether refreshes have failed since,
+/// how many transactions it was read from and whether that's too few, the slots they
+/// landed in, and when it was computed.
+fn add_sample_metadata(value: &mut serde_json::Value, e: &Estimate) {
+    let slot_range = e.slot_range();
+    value["stale"] = json!(e.stale);
+    value["lowConfidence"] = json!(e.low_confidence);
+    value["sampleSize"] = json!(e.selected.len());
+    value["oldestSlot"] = json!(slot_range.map(|(oldest, _)| oldest));
+    value["newestSlot"] = json!(slot_range.map(|(_, newest)| newest));
+    value["computedAt"] = json!(e.last_updated);
+    value["ageSeconds"] = json!(unix_now().saturating_sub(e.last_updated));
+}
+
+/// `GET /fee` and `GET /<cluster>/fee`: `fees_response`, with a tag clients can
+/// revalidate it by once every program has an estimate.
+fn all_fees_response(
+    request: &rouille::Request,
+    config: &Config,
+    cache: &EstimateCache,
+    query: &FeeQuery,
+) -> Response {
+    match cache.served_all() {
+        Some(estimates) => cacheable(request, config, &estimates, || fees_response(cache, query)),
+        None => fees_response(cache, query),
+    }
+}
+
+/// Every tracked program's estimate, and the estimate of them all together, for
+/// `GET /fee` and the `query` command.
+fn fees_response(cache: &EstimateCache, query: &FeeQuery) -> Response {
+    let mut programs = serde_json::Map::new();
+    for program in &cache.programs {
+        let value = match cache.by_program[program].read().unwrap().served() {
+            Some(Ok(e)) => {
+                let mut value = json!({
+                    "reasonablePriorityFee": query.fee(&e),
+                    "ci95": query.fee_interval(&e),
+                    "smoothedPriorityFee": query.smoothed_fee(&e),
+                    "computeUnitLimit": query.apply(&e).compute_unit_limit(),
+                    "source": e.source,
+                    "lastUpdated": e.last_updated
+                });
+                add_trend(&mut value, query.fee_trend(&e));
+                add_sample_metadata(&mut value, &query.apply(&e));
+                value
+            }
+            Some(Err(err)) => json!({ "error": err }),
+            None => json!({ "error": error_json("UNAVAILABLE", NO_ESTIMATE_YET, true) }),
+        };
+        programs.insert(program.clone(), value);
+    }
+    let aggregate = match cache.aggregate() {
+        Some(e) => {
+            let mut value = json!({
+                "reasonablePriorityFee": query.fee(&e),
+                "ci95": query.fee_interval(&e),
+                "computeUnitLimit": query.apply(&e).compute_unit_limit(),
+                "source": e.source,
+                "lastUpdated": e.last_updated
+            });
+            add_sample_metadata(&mut value, &query.apply(&e));
+            value
+        }
+        None => json!({ "error": error_json("UNAVAILABLE", NO_ESTIMATE_YET, true) }),
+    };
+    Response::json(&json!({
+        "percentile": query.percentile,
+        "aggregation": query.aggregation.to_string(),
+        "programs": programs,
+        "aggregate": aggregate
+    }))
+}
+
+/// Respond from the cached estimate, or with the reason there isn't one.
+fn with_estimate(
+    request: &rouille::Request,
+    config: &Config,
+    estimate: &SharedEstimate,
+    f: impl FnOnce(&Estimate) -> Response,
+) -> Response {
+    match estimate.read().unwrap().served() {
+        Some(Ok(e)) => cacheable(request, config, std::slice::from_ref(&e), || f(&e)),
+        Some(Err(err)) => failure_response(&err),
+        None => error_response(503, NO_ESTIMATE_YET),
+    }
+}
+
+/// Respond with `handler`, computed from `estimates`, or with 304 if the client's
+/// `If-None-Match` shows it has the response already. Either way the response may be
+/// cached until the next refresh is due, and is tagged after when the estimates were
+/// refreshed, the fee limits they're clamped to and the format negotiated: the tag is
+/// weak, as `ageSeconds` and the like change in between.
+fn cacheable(
+    request: &rouille::Request,
+    config: &Config,
+    estimates: &[Estimate],
+    handler: impl FnOnce() -> Response,
+) -> Response {
+    let tag = estimates
+        .iter()
+        .map(|e| format!("{}{}", e.last_updated, if e.stale { "s" } else { "" }))
+        .collect::<Vec<_>>()
+        .join("-");
+    let etag = format!(
+        "W/\"{}-{}-{}-{}\"",
+        tag,
+        config.min_fee_floor,
+        config.max_fee_clamp,
+        ResponseFormat::of(request).name()
+    );
+    let next_refresh = estimates
+        .iter()
+        .map(|e| e.last_updated + config.refresh_interval.as_secs())
+        .min()
+        .unwrap_or_default();
+    // Shared caches mustn't serve one client's response to another without a key
+    let visibility = match config.api_keys.is_empty() {
+        true => "public",
+        false => "private",
+    };
+    let cache_control = format!(
+        "{}, max-age={}",
+        visibility,
+        next_refresh.saturating_sub(unix_now())
+    );
+    let matched = request.header("If-None-Match").is_some_and(|tags| {
+        tags.split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag.trim_start_matches("W/"))
+    });
+    let response = match matched {
+        true => Response {
+            status_code: 304,
+            headers: Vec::new(),
+            data: rouille::ResponseBody::empty(),
+            upgrade: None,
+        },
+        false => handler(),
+    };
+    response
+        .with_unique_header("ETag", etag)
+        .with_unique_header("Cache-Control", cache_control)
+}
+
+/// `program`'s estimate as served, or why there's none, for APIs without HTTP statuses.
+fn served_estimate(cache: &EstimateCache, program: &str) -> Result<Estimate, String> {
+    match cache.by_program[program].read().unwrap().served() {
+        Some(Ok(e)) => Ok(e),
+        Some(Err(err)) => Err(format!("{}: {}", err.code.as_str(), err)),
+        None => Err(NO_ESTIMATE_YET.to_string()),
+    }
+}
+
+/// An error response, `{"error": {"code", "message", "retryable"}}`, coded after its
+/// status. Failures to estimate have codes of their own; see `failure_response`.
+fn error_response(status: u16, message: &str) -> Response {
+    let (code, retryable) = match status {
+        400 => ("BAD_REQUEST", false),
+        401 => ("UNAUTHORIZED", false),
+        403 => ("FORBIDDEN", false),
+        404 => ("NOT_FOUND", false),
+        429 => ("RATE_LIMITED", true),
+        502 => ("RPC_UNAVAILABLE", true),
+        503 => ("UNAVAILABLE", true),
+        _ => ("INTERNAL", false),
+    };
+    let body = json!({ "error": error_json(code, message, retryable) });
+    Response::from_data("application/json", body.to_string()).with_status_code(status)
+}
+
+/// The `error` of an error response, or of an entry in a response that lists several.
+fn error_json(code: &str, message: &str, retryable: bool) -> serde_json::Value {
+    json!({ "code": code, "message": message, "retryable": retryable })
+}
+
+const NO_ESTIMATE_YET: &str = "no estimate available yet";
+
+/// An error response for a failure to estimate, with the status its code calls for.
+fn failure_response(err: &CodedError) -> Response {
+    let status = match err.code {
+        ErrorCode::RpcUnavailable | ErrorCode::BatchTooLarge => 502,
+        ErrorCode::Timeout => 504,
+        ErrorCode::EmptySample | ErrorCode::RateLimited => 503,
+    };
+    Response::from_data("application/json", json!({ "error": err }).to_string())
+        .with_status_code(status)
+}
+
+/// How long browsers may cache a preflight response.
+const CORS_MAX_AGE_SECS: u64 = 600;
+
+/// Serve `request` with `handler`, allowing the browser origins in `origins` (`*` for any)
+/// to read the response. Preflight requests are answered here, before authentication,
+/// as browsers send them without credentials.
+fn with_cors(
+    request: &rouille::Request,
+    origins: &[String],
+    handler: impl FnOnce() -> Response,
+) -> Response {
+    let allowed = request.header("Origin").filter(|origin| {
+        origins
+            .iter()
+            .any(|o| o == "*" || o.eq_ignore_ascii_case(origin))
+    });
+    let Some(origin) = allowed else {
+        return handler();
+    };
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        "*".to_string()
+    } else {
+        origin.to_string()
+    };
+    let response = match request.header("Access-Control-Request-Method") {
+        Some(_) if request.method() == "OPTIONS" => Response::empty_204()
+            .with_unique_header(
+                "Access-Control-Allow-Methods",
+                "GET, POST, PUT, DELETE, OPTIONS",
+            )
+            .with_unique_header(
+                "Access-Control-Allow-Headers",
+                "Authorization, Content-Type, X-Request-Id, X-Rpc-Url, traceparent",
+            )
+            .with_unique_header("Access-Control-Max-Age", CORS_MAX_AGE_SECS.to_string()),
+        _ => handler().with_unique_header(
+            "Access-Control-Expose-Headers",
+            "Retry-After, X-Request-Id, X-Rpc-Url",
+        ),
+    };
+    response
+        .with_unique_header("Access-Control-Allow-Origin", allow_origin)
+        .with_additional_header("Vary", "Origin")
+}
+
+/// What a client asked for responses in: `?format=`, or else the first of the types its
+/// `Accept` header lists that's one of these. JSON unless it asked otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResponseFormat {
+    Json,
+    Text,        // just the fee, from the fee endpoints; JSON elsewhere
+    MessagePack, // any JSON response re-encoded
+}
+
+impl ResponseFormat {
+    fn of(request: &rouille::Request) -> ResponseFormat {
+        // Other values, such as `/history/export`'s formats, aren't for responses in general
+        if let Some(format) = request.get_param("format") {
+            return match format.as_str() {
+                "text" => ResponseFormat::Text,
+                "msgpack" => ResponseFormat::MessagePack,
+                _ => ResponseFormat::Json,
+            };
+        }
+        request
+            .header("Accept")
+            .unwrap_or_default()
+            .split(',')
+            .find_map(
+                |media| match media.split(';').next().unwrap_or_default().trim() {
+                    "application/json" => Some(ResponseFormat::Json),
+                    "text/plain" => Some(ResponseFormat::Text),
+                    "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                        Some(ResponseFormat::MessagePack)
+                    }
+                    _ => None,
+                },
+            )
+            .unwrap_or(ResponseFormat::Json)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ResponseFormat::Json => "json",
+            ResponseFormat::Text => "text",
+            ResponseFormat::MessagePack => "msgpack",
+        }
+    }
+}
+
+fn is_json(response: &Response) -> bool {
+    response.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("Content-Type") && value.starts_with("application/json")
+    })
+}
+
+/// Re-encode `handler`'s JSON responses as MessagePack for clients that asked for it.
+fn with_format(request: &rouille::Request, handler: impl FnOnce() -> Response) -> Response {
+    let mut response = handler();
+    if !is_json(&response) {
+        return response;
+    }
+    response = response.with_additional_header("Vary", "Accept");
+    if ResponseFormat::of(request) != ResponseFormat::MessagePack {
+        return response;
+    }
+    let data = std::mem::replace(&mut response.data, rouille::ResponseBody::empty());
+    let (mut reader, _) = data.into_reader_and_size();
+    let mut body = Vec::new();
+    let value = reader
+        .read_to_end(&mut body)
+        .ok()
+        .and_then(|_| serde_json::from_slice(&body).ok());
+    match value {
+        Some(value) => {
+            response.data = rouille::ResponseBody::from_data(msgpack::to_vec(&value));
+            response.with_unique_header("Content-Type", "application/msgpack")
+        }
+        None => {
+            response.data = rouille::ResponseBody::from_data(body);
+            response
+        }
+    }
+}
+
+/// Responses smaller than this aren't worth compressing.
+const MIN_COMPRESSED_LEN: usize = 1024;
+
+/// The `Content-Encoding`s responses can be compressed with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContentEncoding {
+    Gzip,
+    Deflate, // zlib-wrapped, as HTTP's `deflate` means
+}
+
+impl ContentEncoding {
+    /// The encoding `request`'s `Accept-Encoding` gives the highest weight, preferring
+    /// gzip on a tie, or `None` if it accepts neither.
+    fn of(request: &rouille::Request) -> Option<ContentEncoding> {
+        let header = request.header("Accept-Encoding")?;
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for (name, q) in rouille::input::parse_priority_header(header) {
+            let encoding = match name.to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" | "*" => ContentEncoding::Gzip,
+                "deflate" => ContentEncoding::Deflate,
+                _ => continue,
+            };
+            let better = |(_, best_q): (ContentEncoding, f32)| {
+                q > best_q || (q == best_q && encoding == ContentEncoding::Gzip)
+            };
+            if q > 0.0 && best.is_none_or(better) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let level = flate2::Compression::default();
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compress `handler`'s response as `request`'s `Accept-Encoding` allows, if it's big
+/// enough to be worth it. Streamed responses, whose length isn't known, are left alone.
+fn with_compression(request: &rouille::Request, handler: impl FnOnce() -> Response) -> Response {
+    let mut response = handler();
+    let encoded = response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+    if encoded || response.upgrade.is_some() {
+        return response;
+    }
+    let data = std::mem::replace(&mut response.data, rouille::ResponseBody::empty());
+    let (mut reader, size) = data.into_reader_and_size();
+    let body = match size {
+        Some(size) if size >= MIN_COMPRESSED_LEN => {
+            let mut body = Vec::with_capacity(size);
+            reader.read_to_end(&mut body).map(|_| body)
+        }
+        _ => {
+            response.data = match size {
+                Some(size) => rouille::ResponseBody::from_reader_and_size(reader, size),
+                None => rouille::ResponseBody::from_reader(reader),
+            };
+            return response;
+        }
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            log::warn!("Failed to read response body: {}", e);
+            return error_response(500, "failed to read response body");
+        }
+    };
+    response = response.with_additional_header("Vary", "Accept-Encoding");
+    let compressed = ContentEncoding::of(request)
+        .and_then(|encoding| Some((encoding, encoding.compress(&body).ok()?)));
+    match compressed {
+        Some((encoding, compressed)) => {
+            response.data = rouille::ResponseBody::from_data(compressed);
+            response.with_unique_header("Content-Encoding", encoding.name())
+        }
+        None => {
+            response.data = rouille::ResponseBody::from_data(body);
+            response
+        }
+    }
+}
+
+/// The current version of the API, served under `/v1/`.
+const API_VERSION: &str = "v1";
+
+/// Serve `/v1/…` with `route` as if it were `/…`, wrapping JSON responses in the
+/// envelope, and anything else with `route` unchanged. JSON-RPC and GraphQL keep their
+/// own envelopes.
+fn with_api_version(
+    request: &rouille::Request,
+    route: impl FnOnce(&rouille::Request) -> Response,
+) -> Response {
+    let prefix = format!("/{}", API_VERSION);
+    let versioned = match request.url().strip_prefix(&prefix) {
+        Some(rest) if rest.starts_with('/') => request.remove_prefix(&prefix),
+        _ => None,
+    };
+    let Some(request) = versioned else {
+        return route(request);
+    };
+    let response = route(&request);
+    let own_envelope =
+        (request.method() == "POST" && request.url() == "/") || request.url() == "/graphql";
+    match own_envelope {
+        true => response,
+        false => envelope(response),
+    }
+}
+
+/// `response`, if JSON, as `{"data": ..., "meta": ..., "error": ...}`: the body as
+/// `data`, or an error's `error` as `error`, with the other null. New fields can then be
+/// added to `meta` or `data` without disturbing clients.
+fn envelope(mut response: Response) -> Response {
+    if !is_json(&response) {
+        return response;
+    }
+    let data = std::mem::replace(&mut response.data, rouille::ResponseBody::empty());
+    let (mut reader, _) = data.into_reader_and_size();
+    let mut body = Vec::new();
+    let value = reader
+        .read_to_end(&mut body)
+        .ok()
+        .and_then(|_| serde_json::from_slice::<serde_json::Value>(&body).ok());
+    let Some(mut value) = value else {
+        response.data = rouille::ResponseBody::from_data(body);
+        return response;
+    };
+    let meta = json!({ "apiVersion": API_VERSION });
+    let enveloped = match response.status_code >= 400 {
+        true => json!({ "data": null, "meta": meta, "error": value["error"].take() }),
+        false => json!({ "data": value, "meta": meta, "error": null }),
+    };
+    response.data = rouille::ResponseBody::from_data(enveloped.to_string());
+    response
+}
+
+/// 429, with `Retry-After` rounded up to whole seconds.
+fn too_many_requests(wait: Duration) -> Response {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    error_response(429, "rate limit exceeded")
+        .with_unique_header("Retry-After", retry_after.to_string())
+}
+
+fn usage_response(usage: &Usage, keys: &[ApiKey]) -> Response {
+    let keys: Vec<serde_json::Value> = usage
+        .report(keys)
+        .into_iter()
+        .map(|u| {
+            json!({
+                "name": u.key.label(),
+                "admin": u.key.admin,
+                "dailyQuota": u.key.daily_quota,
+                "today": u.today,
+                "remaining": u.remaining,
+                "days": u.days.iter().map(|(date, requests)| json!({
+                    "date": date,
+                    "requests": requests,
+                })).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    Response::json(&json!({ "keys": keys }))
+}
+
+// --------------------------- Transaction estimates ---------------------------
+
+/// Body of `POST /estimate` and `POST /simulate-estimate`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct TransactionEstimateRequest {
+    transaction: String,      // serialized transaction
+    encoding: Option<String>, // "base64" (default) or "base58"
+    percentile: Option<f64>,
+    include_failed: Option<bool>,
+}
+
+/// A posted transaction, decoded, with the estimate for the programs it invokes.
+struct TransactionContext {
+    tx: ParsedTransaction,
+    encoding: TransactionEncoding,
+    percentile: f64,
+    markets: Vec<String>,  // writable accounts other than the signers'
+    programs: Vec<String>, // tracked programs the estimate merges
+    estimate: Estimate,
+}
+
+impl TransactionContext {
+    fn new(
+        body: &TransactionEstimateRequest,
+        cache: &EstimateCache,
+        lookup_tables: &LookupTables,
+        config: &Config,
+    ) -> Result<TransactionContext, Response> {
+        let encoding = match body.encoding.as_deref() {
+            None | Some("base64") => TransactionEncoding::Base64,
+            Some("base58") => TransactionEncoding::Base58,
+            Some(other) => {
+                return Err(error_response(400, &format!("unknown encoding: {}", other)));
+            }
+        };
+        let percentile = body.percentile.unwrap_or(DEFAULT_PERCENTILE);
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(error_response(
+                400,
+                "percentile must be a number between 0 and 100",
+            ));
+        }
+        let tx = ParsedTransaction::decode(&body.transaction, encoding)
+            .map_err(|e| error_response(400, &e))?;
+        let loaded = lookup_tables.resolve(config, &tx).map_err(|e| match &e {
+            LookupError::Rpc(err) => {
+                failure_response(&CodedError::new(ErrorCode::of(&**err), e.to_string()))
+            }
+            LookupError::Invalid(message) => error_response(400, message),
+        })?;
+        // Signers' own accounts (the fee payer, typically) aren't contended fee markets
+        let markets: Vec<String> = tx
+            .account_keys
+            .iter()
+            .zip(&tx.writable)
+            .skip(tx.num_required_signatures as usize)
+            .filter(|(_, writable)| **writable)
+            .map(|(k, _)| k.clone())
+            .chain(loaded.writable)
+            .collect();
+        let Some((programs, estimate)) = cache.merge_for_keys(&tx.account_keys) else {
+            return Err(error_response(503, NO_ESTIMATE_YET));
+        };
+        let estimate = match body.include_failed {
+            Some(include_failed) => estimate.with_include_failed(include_failed),
+            None => estimate,
+        };
+        Ok(TransactionContext {
+            tx,
+            encoding,
+            percentile,
+            markets,
+            programs,
+            estimate,
+        })
+    }
+}
+
+/// Estimate a fee for a specific transaction, weighted toward recent transactions
+/// that write-locked the same accounts (its local fee markets).
+fn transaction_estimate(
+    body: TransactionEstimateRequest,
+    cache: &EstimateCache,
+    lookup_tables: &LookupTables,
+    config: &Config,
+) -> Response {
+    let ctx = try_or_respond!(TransactionContext::new(&body, cache, lookup_tables, config));
+    let e = &ctx.estimate;
+    let (fee, local_samples) = e.local_fee(ctx.percentile, &ctx.markets);
+    Response::json(&json!({
+        "reasonablePriorityFee": fee,
+        "computeUnitLimit": e.compute_unit_limit(),
+        "percentile": ctx.percentile,
+        "writableAccounts": ctx.markets,
+        "localSamples": local_samples,
+        "totalSamples": e.selected.len(),
+        "programs": ctx.programs,
+        "computeUnitPrice": ctx.tx.compute_budget().and_then(|b| b.unit_price),
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+/// Simulate a transaction to measure the compute units it consumes, and recommend a
+/// compute unit limit and price for it along with what they'd cost in total.
+fn simulate_estimate(
+    body: TransactionEstimateRequest,
+    commitment: Commitment,
+    cache: &EstimateCache,
+    lookup_tables: &LookupTables,
+    estimator: &PriorityFeeEstimator,
+) -> Response {
+    let config = estimator.config();
+    let ctx = try_or_respond!(TransactionContext::new(
+        &body,
+        cache,
+        lookup_tables,
+        &config
+    ));
+    let simulation = match config.rpc.call(|agent, url| {
+        simulate_transaction(agent, url, &body.transaction, ctx.encoding, commitment)
+    }) {
+        Ok(s) => s,
+        Err(e) => {
+            let message = format!("simulateTransaction failed: {}", e);
+            return failure_response(&CodedError::new(ErrorCode::of(&*e), message));
+        }
+    };
+    if let Some(err) = simulation.err {
+        return Response::json(&json!({
+            "error": error_json("SIMULATION_FAILED", "transaction failed in simulation", false),
+            "simulationError": err,
+            "logs": simulation.logs
+        }))
+        .with_status_code(422);
+    }
+    let Some(units_consumed) = simulation.units_consumed else {
+        return error_response(502, "simulateTransaction did not report unitsConsumed");
+    };
+
+    let e = &ctx.estimate;
+    let compute_unit_limit = compute_unit_limit_for(units_consumed);
+    let (price, local_samples) = e.local_fee(ctx.percentile, &ctx.markets);
+    let base_fee = LAMPORTS_PER_SIGNATURE * ctx.tx.num_required_signatures as u64;
+    let priority_fee = priority_fee_lamports(price, compute_unit_limit);
+    Response::json(&json!({
+        "computeUnitLimit": compute_unit_limit,
+        "computeUnitPrice": price,
+        "unitsConsumed": units_consumed,
+        "baseFeeLamports": base_fee,
+        "priorityFeeLamports": priority_fee,
+        "totalFeeLamports": base_fee + priority_fee,
+        "percentile": ctx.percentile,
+        "localSamples": local_samples,
+        "totalSamples": e.selected.len(),
+        "programs": ctx.programs,
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+// --------------------------- Account fee markets ---------------------------
+
+/// Fewest sampled transactions writing to an account that `/account/:pubkey/fee`
+/// estimates from before falling back to getRecentPrioritizationFees.
+const MIN_ACCOUNT_SAMPLES: usize = 10;
+
+/// getRecentPrioritizationFees estimates for individual accounts, each reused for one
+/// refresh interval.
+#[derive(Default)]
+struct AccountFees {
+    by_account: Mutex<HashMap<String, Estimate>>,
+}
+
+impl AccountFees {
+    fn get(&self, estimator: &PriorityFeeEstimator, account: &str) -> Result<Estimate, String> {
+        let config = estimator.config();
+        let ttl = config.refresh_interval.as_secs();
+        let fresh = |e: &Estimate| unix_now() < e.last_updated + ttl;
+        if let Some(e) = self
+            .by_account
+            .lock()
+            .unwrap()
+            .get(account)
+            .filter(|e| fresh(e))
+        {
+            return Ok(e.clone());
+        }
+        let samples = RecentPrioritizationFees::sample_accounts(&config, &[account.to_string()])
+            .map_err(|e| e.to_string())?;
+        let e = Estimate::new(
+            samples,
+            config.include_failed,
+            unix_now(),
+            RecentPrioritizationFees.name(),
+        )
+        .with_fee_bounds(config.min_fee_floor, config.max_fee_clamp);
+        let mut by_account = self.by_account.lock().unwrap();
+        by_account.retain(|_, e| fresh(e));
+        by_account.insert(account.to_string(), e.clone());
+        Ok(e)
+    }
+}
+
+// --------------------------- Hot accounts ---------------------------
+
+/// How many accounts `/hot-accounts` lists without a `limit`.
+const DEFAULT_HOT_ACCOUNTS: usize = 10;
+
+/// The accounts the sampled transactions of every tracked program (or just `?program=`)
+/// contend for most, with the fees paid to write-lock them.
+fn hot_accounts_response(
+    request: &rouille::Request,
+    cache: &EstimateCache,
+    query: &FeeQuery,
+    limit: usize,
+) -> Response {
+    let programs = match request.get_param("program") {
+        Some(program) if cache.get(&program).is_none() => {
+            return error_response(404, &format!("program {} is not tracked", program));
+        }
+        Some(program) => vec![program],
+        None => cache.programs.clone(),
+    };
+    let Some(e) = cache.merge(&programs) else {
+        return error_response(503, NO_ESTIMATE_YET);
+    };
+    let e = query.apply(&e);
+    let accounts: Vec<serde_json::Value> = hot_accounts(&e.selected, MIN_ACCOUNT_SAMPLES)
+        .into_iter()
+        .take(limit)
+        .map(|hot| {
+            let transactions = hot.samples.len();
+            let local = Estimate::new(hot.samples, e.include_failed, e.last_updated, e.source)
+                .with_fee_bounds(e.min_fee, e.max_fee);
+            json!({
+                "account": hot.account,
+                "transactions": transactions,
+                "payers": hot.payers,
+                "share": transactions as f64 / e.selected.len() as f64,
+                "reasonablePriorityFee": local.fee(query.percentile, query.aggregation),
+                "priorityFeeLevels": local.levels_with(query.aggregation)
+            })
+        })
+        .collect();
+    Response::json(&json!({
+        "accounts": accounts,
+        "percentile": query.percentile,
+        "aggregation": query.aggregation.to_string(),
+        "totalSamples": e.selected.len(),
+        "programs": programs,
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+// --------------------------- Jito tips ---------------------------
+
+/// Recommend a Jito bundle tip from the tips sampled transactions paid, across every
+/// tracked program or just `?program=`.
+fn jito_tip(request: &rouille::Request, cache: &EstimateCache, query: &FeeQuery) -> Response {
+    let programs = match request.get_param("program") {
+        Some(program) if cache.get(&program).is_none() => {
+            return error_response(404, &format!("program {} is not tracked", program));
+        }
+        Some(program) => vec![program],
+        None => cache.programs.clone(),
+    };
+    let Some(e) = cache.merge(&programs) else {
+        return error_response(503, NO_ESTIMATE_YET);
+    };
+    let e = query.apply(&e);
+    let tip = e.jito_tip(query.percentile);
+    Response::json(&json!({
+        "jitoTip": tip,
+        "jitoTipSol": tip.map(|t| t as f64 / LAMPORTS_PER_SOL as f64),
+        "jitoTipLevels": e.jito_tip_levels(),
+        "percentile": query.percentile,
+        "tippedSamples": e.jito_tips().len(),
+        "totalSamples": e.selected.len(),
+        "programs": programs,
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+// --------------------------- Distribution ---------------------------
+
+/// A histogram of the fees `e` was read from, with their summary statistics.
+fn distribution_response(e: &Estimate, program: &str) -> Response {
+    let Some(distribution) = e.distribution() else {
+        return error_response(503, "no priority fees sampled");
+    };
+    let mut value = json!(distribution);
+    value["program"] = json!(program);
+    value["source"] = json!(e.source);
+    value["lastUpdated"] = json!(e.last_updated);
+    Response::json(&value)
+}
+
+// --------------------------- Samples ---------------------------
+
+/// How many samples `/samples` lists without a `limit`.
+const DEFAULT_SAMPLES_LIMIT: usize = 100;
+
+/// The transactions `e` was estimated from, newest first, up to `limit` of them, for
+/// checking an estimate against the chain.
+fn samples_response(e: &Estimate, program: &str, limit: usize) -> Response {
+    let mut samples: Vec<&Sample> = e.samples.iter().collect();
+    samples.sort_by_key(|s| std::cmp::Reverse(s.slot));
+    samples.truncate(limit);
+    Response::json(&json!({
+        "samples": samples,
+        "sampleSize": e.samples.len(),
+        "selected": e.selected.len(),
+        "includeFailed": e.include_failed,
+        "program": program,
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+// --------------------------- Explain ---------------------------
+
+/// How `e` came to be and how `query`'s fee is read off it: the refresh's stages with
+/// their sample counts and timings, then the selection, aggregation and clamping.
+fn explain_response(e: &Estimate, program: &str, query: &FeeQuery, config: &Config) -> Response {
+    let selected = query.apply(e);
+    let raw_fee = query
+        .aggregation
+        .fee_at(&selected.selected, query.percentile);
+    let fee = query.fee(e);
+    let clamped = match raw_fee {
+        f if f < e.min_fee => Some("floor"),
+        f if f > e.max_fee => Some("ceiling"),
+        _ => None,
+    };
+    let trail = e.explanation.as_deref();
+    Response::json(&json!({
+        "program": program,
+        "configuredSource": config.fee_source.name(),
+        "source": e.source,
+        "fallbackReason": trail.and_then(|t| t.fallback_reason.as_ref()),
+        "stages": trail.map(|t| &t.stages),
+        "durationMs": trail.map(|t| t.duration_ms),
+        "targetSamples": trail.and_then(|t| t.target_samples),
+        "minSampleFraction": config.min_sample_fraction,
+        "lowConfidence": e.low_confidence,
+        "includeFailed": selected.include_failed,
+        "sampleSize": selected.samples.len(),
+        "selected": selected.selected.len(),
+        "aggregation": query.aggregation.to_string(),
+        "percentile": query.percentile,
+        "rawFee": raw_fee,
+        "minFee": e.min_fee,
+        "maxFee": e.max_fee,
+        "clamped": clamped,
+        "reasonablePriorityFee": fee,
+        "smoothedPriorityFee": query.smoothed_fee(e),
+        "stale": e.stale,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+// --------------------------- Congestion ---------------------------
+
+/// Classify network congestion from the median fee sampled across every tracked program
+/// (or just `?program=`) and the node's recent performance samples. If those can't be
+/// fetched, the fee alone decides.
+fn congestion(
+    request: &rouille::Request,
+    cache: &EstimateCache,
+    estimator: &PriorityFeeEstimator,
+) -> Response {
+    let programs = match request.get_param("program") {
+        Some(program) if cache.get(&program).is_none() => {
+            return error_response(404, &format!("program {} is not tracked", program));
+        }
+        Some(program) => vec![program],
+        None => cache.programs.clone(),
+    };
+    let Some(e) = cache.merge(&programs) else {
+        return error_response(503, NO_ESTIMATE_YET);
+    };
+    let performance = match estimator
+        .config()
+        .rpc
+        .call(|agent, url| get_recent_performance_samples(agent, url, PERFORMANCE_SAMPLES))
+    {
+        Ok(samples) => Performance::from_samples(&samples),
+        Err(err) => {
+            log::warn!("getRecentPerformanceSamples failed: {}", err);
+            None
+        }
+    };
+    let median_fee = e.fee_at(50.0);
+    Response::json(&json!({
+        "level": CongestionLevel::classify(median_fee, performance.as_ref()),
+        "medianFee": median_fee,
+        "priorityFeeLevels": e.levels(),
+        "performance": performance,
+        "programs": programs,
+        "sampleSize": e.selected.len(),
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+// --------------------------- Landing targets ---------------------------
+
+/// A `/target` request's `slots` and `confidence` parameters.
+struct Target {
+    slots: u64,
+    confidence: f64, // share of transactions paying the fee that must land in time
+}
+
+impl Target {
+    fn parse(request: &rouille::Request) -> Result<Target, Response> {
+        let slots = match request.get_param("slots").map(|v| v.parse::<u64>()) {
+            Some(Ok(slots)) if (1..=MAX_BLOCKHASH_AGE).contains(&slots) => slots,
+            _ => {
+                return Err(error_response(
+                    400,
+                    &format!(
+                        "slots must be a whole number from 1 to {}",
+                        MAX_BLOCKHASH_AGE
+                    ),
+                ));
+            }
+        };
+        let confidence = match request.get_param("confidence") {
+            Some(v) => match v.parse::<f64>() {
+                Ok(c) if c > 0.0 && c < 1.0 => c,
+                _ => return Err(error_response(400, "confidence must be between 0 and 1")),
+            },
+            None => DEFAULT_CONFIDENCE,
+        };
+        Ok(Target { slots, confidence })
+    }
+}
+
+/// The fee to land within `target.slots` slots, read off how many slots `e`'s sampled
+/// transactions took to land for what they paid.
+fn target_response(
+    e: &Estimate,
+    program: &str,
+    target: &Target,
+    blockhashes: &BlockhashSlots,
+    config: &Config,
+) -> Response {
+    let latencies = match blockhashes.latencies(config, &e.selected) {
+        Ok(latencies) => latencies,
+        Err(err) => return error_response(502, &err),
+    };
+    if latencies.is_empty() {
+        return error_response(
+            503,
+            &format!(
+                "the {} source doesn't report when transactions were built",
+                e.source
+            ),
+        );
+    }
+    let fee = fee_to_land(&latencies, target.slots, target.confidence);
+    Response::json(&json!({
+        "priorityFee": fee.priority_fee.map(|f| f.clamp(e.min_fee, e.max_fee)),
+        "slots": target.slots,
+        "confidence": target.confidence,
+        "landingProbability": fee.landing_probability,
+        "payingSamples": fee.samples,
+        "medianLatencySlots": median_latency(&latencies),
+        "sampleSize": latencies.len(),
+        "program": program,
+        "source": e.source,
+        "lastUpdated": e.last_updated
+    }))
+}
+
+// --------------------------- RPC overrides ---------------------------
+
+/// Estimates made on request against allowlisted endpoints picked with `X-Rpc-Url`, by
+/// endpoint. Each program is sampled at most once a refresh interval per endpoint, when
+/// a request asks for it; in between, requests are served what that sample gave.
+#[derive(Default)]
+struct RpcOverrides {
+    by_url: Mutex<HashMap<String, Arc<RpcOverride>>>,
+}
+
+struct RpcOverride {
+    estimator: PriorityFeeEstimator, // keeps the endpoint's fee source, smoothing and trends
+    cache: EstimateCache,
+    refreshed: Mutex<HashMap<String, Instant>>, // by program; held while one refreshes
+}
+
+impl RpcOverrides {
+    /// The estimates from `rpc`, with `program`'s refreshed first if it's due. `config`
+    /// is the settings they're made with, but for where they're read from.
+    fn refreshed(&self, config: &Config, rpc: &Arc<RpcPool>, program: &str) -> Arc<RpcOverride> {
+        let url = rpc.urls().next().unwrap_or_default().to_string();
+        let rpc_override = self
+            .by_url
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_insert_with(|| {
+                Arc::new(RpcOverride {
+                    estimator: PriorityFeeEstimator::new(config.with_rpc(rpc.clone())),
+                    cache: EstimateCache::new(&config.tracked_programs, None, None, None),
+                    refreshed: Mutex::default(),
+                })
+            })
+            .clone();
+        let mut refreshed = rpc_override.refreshed.lock().unwrap();
+        if refreshed
+            .get(program)
+            .is_none_or(|at| at.elapsed() >= config.refresh_interval)
+        {
+            // Pick up reloaded settings, keeping the sampling state
+            let fee_source = rpc_override.estimator.config().fee_source.clone();
+            rpc_override.estimator.set_config(Config {
+                fee_source,
+                ..config.with_rpc(rpc.clone())
+            });
+            let result = rpc_override
+                .estimator
+                .estimate_program(program)
+                .map_err(|e| CodedError::from_error(&*e));
+            if let Err(e) = &result {
+                log::warn!("estimate from {} failed for {}: {}", url, program, e);
+            }
+            rpc_override
+                .cache
+                .publish(program, result, config.max_staleness);
+            refreshed.insert(program.to_string(), Instant::now());
+        }
+        drop(refreshed);
+        rpc_override
+    }
+}
+
+/// Serve `request` with an estimate from the allowlisted endpoint `url` rather than the
+/// configured ones. Only `GET /`, `GET /levels` and `GET /fee/{program}` take one.
+fn rpc_override_response(
+    request: &rouille::Request,
+    config: &Config,
+    overrides: &RpcOverrides,
+    url: &str,
+) -> Response {
+    let Some(rpc) = config.allowed_rpc(url) else {
+        return error_response(403, &format!("X-Rpc-Url {} is not allowlisted", url));
+    };
+    let path = request.url();
+    let program = match (request.method(), path.as_str()) {
+        ("GET", "/" | "/levels") => config.tracked_programs[0].clone(),
+        ("GET", path) if path.starts_with("/fee/") => path["/fee/".len()..].to_string(),
+        _ => {
+            let message = "X-Rpc-Url is only taken by GET /, /levels and /fee/{program}";
+            return error_response(400, message);
+        }
+    };
+    if !config.tracked_programs.contains(&program) {
+        return error_response(404, &format!("program {} is not tracked", program));
+    }
+    let query = try_or_respond!(FeeQuery::parse(request, config.aggregation));
+    let format = ResponseFormat::of(request);
+    let rpc_override = overrides.refreshed(config, rpc, &program);
+    let estimate = &rpc_override.cache.by_program[&program];
+    let response = match path.as_str() {
+        "/levels" => with_estimate(request, config, estimate, |e| {
+            Response::json(&levels_json(e, &query))
+        }),
+        _ => with_estimate(request, config, estimate, |e| {
+            fee_response(e, &query, format)
+        }),
+    };
+    let url = rpc.urls().next().unwrap_or_default().to_string();
+    response
+        .with_unique_header("X-Rpc-Url", url)
+        .with_additional_header("Vary", "X-Rpc-Url")
+}
+
+// --------------------------- Provider comparison ---------------------------
+
+/// Estimates from each configured RPC endpoint on its own, for `GET /compare`, made on
+/// request as those from allowlisted endpoints are.
+#[derive(Default)]
+struct Comparison {
+    split: Mutex<Option<SplitPool>>,
+    estimates: RpcOverrides,
+}
+
+/// A pool split into one for each of its endpoints.
+struct SplitPool {
+    pool: Arc<RpcPool>,
+    parts: Vec<Arc<RpcPool>>,
+}
+
+impl Comparison {
+    /// Each of `config.rpc`'s endpoints as a pool of its own, split again whenever a
+    /// reload replaces it.
+    fn pools(&self, config: &Config) -> Vec<Arc<RpcPool>> {
+        let mut split = self.split.lock().unwrap();
+        match &*split {
+            Some(s) if Arc::ptr_eq(&s.pool, &config.rpc) => s.parts.clone(),
+            _ => {
+                let parts: Vec<Arc<RpcPool>> = config
+                    .rpc
+                    .endpoint_pools()
+                    .into_iter()
+                    .map(Arc::new)
+                    .collect();
+                *split = Some(SplitPool {
+                    pool: config.rpc.clone(),
+                    parts: parts.clone(),
+                });
+                parts
+            }
+        }
+    }
+}
+
+/// `program`'s estimate from each configured endpoint, sampled side by side, and how
+/// far they diverge: an endpoint whose newest sampled slot lags the others' is likely
+/// serving stale data.
+fn compare_response(
+    config: &Config,
+    comparison: &Comparison,
+    program: &str,
+    query: &FeeQuery,
+) -> Response {
+    let pools = comparison.pools(config);
+    let results: Vec<Option<Result<Estimate, CodedError>>> = thread::scope(|scope| {
+        let handles: Vec<_> = pools
+            .iter()
+            .map(|rpc| {
+                scope.spawn(move || {
+                    let estimates = comparison.estimates.refreshed(config, rpc, program);
+                    estimates.cache.by_program[program].read().unwrap().served()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let estimates: Vec<Estimate> = results.iter().flatten().flatten().cloned().collect();
+    let mut fees: Vec<u64> = estimates.iter().map(|e| query.fee(e)).collect();
+    fees.sort_unstable();
+    let median = fees.get(fees.len().saturating_sub(1) / 2).copied();
+    let newest = |e: &Estimate| e.slot_range().map(|(_, newest)| newest);
+    let newest_slot = estimates.iter().filter_map(newest).max();
+    let mut max_slot_lag = None;
+    let providers: Vec<serde_json::Value> = pools
+        .iter()
+        .zip(&results)
+        .map(|(rpc, result)| {
+            let url = rpc.urls().next().unwrap_or_default();
+            let e = match result {
+                Some(Ok(e)) => e,
+                Some(Err(err)) => return json!({ "url": url, "error": err }),
+                None => {
+                    let err = error_json("UNAVAILABLE", NO_ESTIMATE_YET, true);
+                    return json!({ "url": url, "error": err });
+                }
+            };
+            let fee = query.fee(e);
+            let slot_lag = newest_slot.zip(newest(e)).map(|(max, slot)| max - slot);
+            max_slot_lag = max_slot_lag.max(slot_lag);
+            json!({
+                "url": url,
+                "reasonablePriorityFee": fee,
+                "feeDeviation": median
+                    .filter(|&m| m > 0)
+                    .map(|m| (fee as f64 - m as f64) / m as f64),
+                "slotLag": slot_lag,
+                "newestSlot": newest(e),
+                "sampleSize": query.apply(e).selected.len(),
+                "source": e.source,
+                "stale": e.stale,
+                "lastUpdated": e.last_updated
+            })
+        })
+        .collect();
+    let spread = fees.first().zip(fees.last()).map(|(min, max)| max - min);
+    Response::json(&json!({
+        "program": program,
+        "percentile": query.percentile,
+        "aggregation": query.aggregation.to_string(),
+        "providers": providers,
+        "divergence": {
+            "feeSpread": spread,
+            "relativeFeeSpread": spread
+                .zip(median.filter(|&m| m > 0))
+                .map(|(spread, m)| spread as f64 / m as f64),
+            "maxSlotLag": max_slot_lag,
+            "failed": results.iter().filter(|r| !matches!(r, Some(Ok(_)))).count()
+        }
+    }))
+}
+
+// --------------------------- JSON-RPC ---------------------------
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const ESTIMATE_FAILED: i64 = -32001; // the latest refresh failed
+const ESTIMATE_UNAVAILABLE: i64 = -32002; // no refresh has completed yet
+
+/// Serve a JSON-RPC 2.0 request or batch posted to `/`, so Solana tooling that speaks
+/// JSON-RPC can use the service without a REST client.
+fn json_rpc(
+    request: &rouille::Request,
+    cache: &EstimateCache,
+    aggregation: Aggregation,
+) -> Response {
+    let payload = match read_body(request).and_then(|body| {
+        serde_json::from_str::<serde_json::Value>(&body).map_err(|e| e.to_string())
+    }) {
+        Ok(serde_json::Value::Array(batch)) if batch.is_empty() => {
+            rpc_error(json!(null), INVALID_REQUEST, "empty batch")
+        }
+        Ok(serde_json::Value::Array(batch)) => {
+            let responses: Vec<serde_json::Value> = batch
+                .into_iter()
+                .filter_map(|call| rpc_call(call, cache, aggregation))
+                .collect();
+            if responses.is_empty() {
+                return Response::empty_204(); // only notifications
+            }
+            json!(responses)
+        }
+        Ok(call) => match rpc_call(call, cache, aggregation) {
+            Some(response) => response,
+            None => return Response::empty_204(),
+        },
+        Err(e) => rpc_error(json!(null), PARSE_ERROR, &format!("parse error: {}", e)),
+    };
+    Response::json(&payload)
+}
+
+/// Answer one call, or `None` for a notification (a call without an `id`).
+fn rpc_call(
+    call: serde_json::Value,
+    cache: &EstimateCache,
+    aggregation: Aggregation,
+) -> Option<serde_json::Value> {
+    let id = call.get("id").cloned();
+    let method = call.get("method").and_then(|m| m.as_str());
+    let (method, id) = match (method, call.get("jsonrpc")) {
+        (Some(method), Some(v)) if v == "2.0" => (method, id?),
+        _ => {
+            return Some(rpc_error(
+                id.unwrap_or(json!(null)),
+                INVALID_REQUEST,
+                "invalid request",
+            ));
+        }
+    };
+    let params = call.get("params").cloned().unwrap_or(json!(null));
+    let result = match method {
+        "getReasonablePriorityFee" => with_rpc_estimate(params, cache, aggregation, |p, e, q| {
+            let mut value = fee_json(e, q);
+            value["program"] = json!(p);
+            value
+        }),
+        "getPriorityFeeLevels" => with_rpc_estimate(params, cache, aggregation, |p, e, q| {
+            let mut value = levels_json(e, q);
+            value["program"] = json!(p);
+            value
+        }),
+        "getPriorityFeeEstimate" => helius_estimate(params, cache, aggregation),
+        _ => Err((METHOD_NOT_FOUND, format!("method not found: {}", method))),
+    };
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err((code, message)) => rpc_error(id, code, &message),
+    })
+}
+
+/// Read `FeeParams` from positional (`[{...}]`) or named (`{...}`) params and answer
+/// from the selected program's cached estimate.
+fn with_rpc_estimate(
+    params: serde_json::Value,
+    cache: &EstimateCache,
+    aggregation: Aggregation,
+    f: impl FnOnce(&str, &Estimate, &FeeQuery) -> serde_json::Value,
+) -> Result<serde_json::Value, (i64, String)> {
+    let params = match params {
+        serde_json::Value::Null => json!({}),
+        serde_json::Value::Array(mut a) if a.len() <= 1 => a.pop().unwrap_or(json!({})),
+        p => p,
+    };
+    let (program, query) = serde_json::from_value::<FeeParams>(params)
+        .map_err(|e| e.to_string())
+        .and_then(|p| p.validate(cache, aggregation))
+        .map_err(|e| (INVALID_PARAMS, e))?;
+    match cache.by_program[&program].read().unwrap().served() {
+        Some(Ok(e)) => Ok(f(&program, &e, &query)),
+        Some(Err(err)) => Err((ESTIMATE_FAILED, err.message)),
+        None => Err((ESTIMATE_UNAVAILABLE, NO_ESTIMATE_YET.to_string())),
+    }
+}
+
+/// Helius' floor for `recommended` estimates, in micro-lamports per CU.
+const HELIUS_RECOMMENDED_MIN_FEE: u64 = 10_000;
+
+/// Params of Helius' `getPriorityFeeEstimate`. Options this service has no equivalent
+/// for (`lookbackSlots`, `includeVote`, ...) are accepted and ignored.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct HeliusEstimateParams {
+    transaction: Option<String>,
+    account_keys: Option<Vec<String>>,
+    #[serde(default)]
+    options: HeliusEstimateOptions,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct HeliusEstimateOptions {
+    priority_level: Option<String>,
+    #[serde(default)]
+    include_all_priority_fee_levels: bool,
+    transaction_encoding: Option<String>,
+    #[serde(default)]
+    recommended: bool,
+}
+
+/// A drop-in for Helius' `getPriorityFeeEstimate`, backed by this service's samples.
+/// The estimate is drawn from the tracked programs among the transaction's (or the
+/// given) account keys; see `EstimateCache::merge_for_keys`.
+fn helius_estimate(
+    params: serde_json::Value,
+    cache: &EstimateCache,
+    aggregation: Aggregation,
+) -> Result<serde_json::Value, (i64, String)> {
+    let params = match params {
+        serde_json::Value::Array(mut a) if a.len() == 1 => a.pop().unwrap(),
+        p => p,
+    };
+    let params: HeliusEstimateParams =
+        serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))?;
+    let invalid = |e: String| (INVALID_PARAMS, e);
+
+    let mut keys = params.account_keys.unwrap_or_default();
+    if let Some(tx) = &params.transaction {
+        let encoding = match params.options.transaction_encoding.as_deref() {
+            None | Some("Base58") => TransactionEncoding::Base58,
+            Some("Base64") => TransactionEncoding::Base64,
+            Some(other) => return Err(invalid(format!("unknown transactionEncoding: {}", other))),
+        };
+        keys.extend(
+            ParsedTransaction::decode(tx, encoding)
+                .map_err(invalid)?
+                .account_keys,
+        );
+    }
+    let (_, e) = cache
+        .merge_for_keys(&keys)
+        .ok_or((ESTIMATE_UNAVAILABLE, NO_ESTIMATE_YET.to_string()))?;
+    let levels = e.levels_with(aggregation);
+
+    if params.options.include_all_priority_fee_levels {
+        return Ok(json!({
+            "priorityFeeLevels": {
+                "min": levels.min as f64,
+                "low": levels.low as f64,
+                "medium": levels.medium as f64,
+                "high": levels.high as f64,
+                "veryHigh": levels.very_high as f64,
+                "unsafeMax": levels.unsafe_max as f64
+            }
+        }));
+    }
+    let fee = if params.options.recommended {
+        levels.medium.max(HELIUS_RECOMMENDED_MIN_FEE)
+    } else {
+        match params.options.priority_level.as_deref() {
+            Some("Min") => levels.min,
+            Some("Low") => levels.low,
+            None | Some("Medium") | Some("Default") => levels.medium,
+            Some("High") => levels.high,
+            Some("VeryHigh") => levels.very_high,
+            Some("UnsafeMax") => levels.unsafe_max,
+            Some(other) => return Err(invalid(format!("unknown priorityLevel: {}", other))),
+        }
+    };
+    Ok(json!({ "priorityFeeEstimate": fee as f64 }))
+}
+
+fn rpc_error(id: serde_json::Value, code: i64, message: &str) -> serde_json::Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id
+    })
+}
+
+// --------------------------- Streaming ---------------------------
+
+/// Counts how many times each program's estimate has been published, so streaming
+/// clients can wait for the next one.
+#[derive(Default)]
+struct Updates {
+    versions: Mutex<HashMap<String, u64>>,
+    changed: Condvar,
+    closed: AtomicBool, // set on shutdown, ending every stream
+}
+
+impl Updates {
+    fn publish(&self, program: &str) {
+        *self
+            .versions
+            .lock()
+            .unwrap()
+            .entry(program.to_string())
+            .or_default() += 1;
+        self.changed.notify_all();
+    }
+
+    /// How many times `program` has been published so far.
+    fn version(&self, program: &str) -> u64 {
+        self.versions
+            .lock()
+            .unwrap()
+            .get(program)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Wake every waiting stream for good.
+    fn close(&self) {
+        let _versions = self.versions.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        self.changed.notify_all();
+    }
+
+    /// Wait until `program` has been published more than `seen` times, or `timeout`
+    /// passes. Returns the number of publications so far, or `None` once closed.
+    fn wait(&self, program: &str, seen: u64, timeout: Duration) -> Option<u64> {
+        let version = |versions: &HashMap<String, u64>| versions.get(program).copied().unwrap_or(0);
+        let versions = self.versions.lock().unwrap();
+        let (versions, _) = self
+            .changed
+            .wait_timeout_while(versions, timeout, |v| {
+                version(v) <= seen && !self.closed.load(Ordering::Relaxed)
+            })
+            .unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(version(&versions))
+    }
+}
+
+/// A `/stream` client: receives a server-sent event with the program's fee every time
+/// it's refreshed.
+struct EventStream {
+    cache: Arc<EstimateCache>,
+    program: String,
+    query: FeeQuery,
+}
+
+/// Respond with a `text/event-stream` driven by `stream`. Events are written straight
+/// to the connection, which rouille only hands over for upgrades; a chunked body would
+/// be buffered until several events had accumulated.
+fn event_stream(stream: EventStream) -> Response {
+    Response {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".into(), "text/event-stream".into()),
+            ("Cache-Control".into(), "no-cache".into()),
+        ],
+        data: rouille::ResponseBody::empty(),
+        upgrade: Some(Box::new(stream)),
+    }
+}
+
+impl rouille::Upgrade for EventStream {
+    fn build(&mut self, mut socket: Box<dyn rouille::ReadWrite + Send>) {
+        let mut seen = 0;
+        loop {
+            let Some(version) = self
+                .cache
+                .updates
+                .wait(&self.program, seen, STREAM_KEEPALIVE)
+            else {
+                return;
+            };
+            let message = if version == seen {
+                ": keepalive\n\n".to_string()
+            } else {
+                seen = version;
+                match self.cache.by_program[&self.program]
+                    .read()
+                    .unwrap()
+                    .served()
+                {
+                    Some(Ok(e)) => {
+                        let mut event = fee_json(&e, &self.query);
+                        event["program"] = json!(self.program);
+                        format!("event: fee\ndata: {}\n\n", event)
+                    }
+                    Some(Err(err)) => {
+                        format!("event: error\ndata: {}\n\n", json!({ "error": err }))
                     }
+                    None => continue,
+                }
+            };
+            // The client has gone away
+            if socket
+                .write_all(message.as_bytes())
+                .and_then(|_| socket.flush())
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+/// The first message a `/ws` client sends: `{"subscribe": {"percentile": 75, "minChangePct": 10}}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubscribeMessage {
+    subscribe: Subscription,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Subscription {
+    program: Option<String>,
+    percentile: Option<f64>,
+    include_failed: Option<bool>,
+    aggregation: Option<String>,
+    cu: Option<u64>,
+    #[serde(default)]
+    min_change_pct: f64, // push only when the fee moves by at least this much
+}
+
+impl Subscription {
+    fn validate(
+        self,
+        cache: &EstimateCache,
+        aggregation: Aggregation,
+    ) -> Result<(String, FeeQuery, f64), String> {
+        if self.min_change_pct < 0.0 {
+            return Err("minChangePct must not be negative".to_string());
+        }
+        let params = FeeParams {
+            program: self.program,
+            percentile: self.percentile,
+            include_failed: self.include_failed,
+            aggregation: self.aggregation,
+            cu: self.cu,
+        };
+        let (program, query) = params.validate(cache, aggregation)?;
+        Ok((program, query, self.min_change_pct))
+    }
+}
+
+/// Serve a `/ws` client: wait for its subscription, then push the program's fee each
+/// time a refresh moves it by at least `minChangePct` percent since the last push.
+/// rouille's websockets can't be read while another thread writes, so the subscription
+/// is fixed once accepted; clients reconnect to change it.
+fn serve_subscription(mut ws: Websocket, cache: &EstimateCache, aggregation: Aggregation) {
+    let (program, query, min_change_pct) = loop {
+        let text = match ws.next() {
+            Some(Message::Text(text)) => text,
+            Some(Message::Binary(_)) => continue,
+            None => return, // closed
+        };
+        let subscription = serde_json::from_str::<SubscribeMessage>(&text)
+            .map_err(|e| format!("invalid subscribe message: {}", e))
+            .and_then(|m| m.subscribe.validate(cache, aggregation));
+        match subscription {
+            Ok(s) => break s,
+            Err(e) => {
+                let error = json!({ "error": error_json("BAD_REQUEST", &e, false) });
+                if ws.send_text(&error.to_string()).is_err() {
+                    return;
+                }
+            }
+        }
+    };
+    let ack = json!({
+        "subscribed": {
+            "program": program,
+            "percentile": query.percentile,
+            "aggregation": query.aggregation.to_string(),
+            "minChangePct": min_change_pct
+        }
+    });
+    if ws.send_text(&ack.to_string()).is_err() {
+        return;
+    }
+
+    let mut seen = 0;
+    let mut last_fee: Option<u64> = None;
+    let mut last_error: Option<CodedError> = None;
+    loop {
+        let Some(version) = cache.updates.wait(&program, seen, STREAM_KEEPALIVE) else {
+            return;
+        };
+        // rouille can't send pings, so idle connections get a keepalive message, which
+        // also notices clients that have gone away
+        if version == seen {
+            if ws.send_text(r#"{"keepalive":true}"#).is_err() {
+                return;
+            }
+            continue;
+        }
+        seen = version;
+        let message = match cache.by_program[&program].read().unwrap().served() {
+            Some(Ok(e)) => {
+                let fee = query.fee(&e);
+                if !moved(fee, last_fee, min_change_pct) {
+                    continue;
+                }
+                last_fee = Some(fee);
+                last_error = None;
+                let mut event = fee_json(&e, &query);
+                event["program"] = json!(program);
+                event
+            }
+            Some(Err(err)) if last_error.as_ref() != Some(&err) => {
+                last_error = Some(err.clone());
+                json!({ "error": err })
+            }
+            _ => continue,
+        };
+        if ws.send_text(&message.to_string()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Whether `fee` is at least `min_change_pct` percent away from `last`, or there's no
+/// `last` to compare it with.
+fn moved(fee: u64, last: Option<u64>, min_change_pct: f64) -> bool {
+    match last {
+        Some(last) => fee.abs_diff(last) as f64 * 100.0 >= min_change_pct * last as f64,
+        None => true,
+    }
+}
+
+/// Longest a `/wait` request may be held, in seconds.
+const MAX_WAIT_SECS: u64 = 300;
+const DEFAULT_WAIT_SECS: u64 = 60;
+
+/// A `/wait` request's `minChangePct`, `timeout` and `fee` parameters.
+struct LongPoll {
+    min_change_pct: f64,
+    timeout: Duration,
+    fee: Option<u64>, // the fee the client last saw, else the current one
+}
+
+impl LongPoll {
+    fn parse(request: &rouille::Request) -> Result<LongPoll, Response> {
+        let min_change_pct = match request.get_param("minChangePct") {
+            Some(v) => match v.parse::<f64>() {
+                Ok(pct) if pct >= 0.0 => pct,
+                _ => {
+                    return Err(error_response(
+                        400,
+                        "minChangePct must be a non-negative number",
+                    ));
                 }
             },
-            (GET) (/health) => {
-                Response::text("ok")
+            None => 0.0,
+        };
+        let timeout = match request.get_param("timeout") {
+            Some(v) => match v.parse::<u64>() {
+                Ok(secs) if (1..=MAX_WAIT_SECS).contains(&secs) => secs,
+                _ => {
+                    return Err(error_response(
+                        400,
+                        &format!(
+                            "timeout must be a whole number of seconds from 1 to {}",
+                            MAX_WAIT_SECS
+                        ),
+                    ));
+                }
             },
-            _ => Response::empty_404()
-        )
-    });
+            None => DEFAULT_WAIT_SECS,
+        };
+        let fee =
+            match request.get_param("fee") {
+                Some(v) => Some(v.parse::<u64>().map_err(|_| {
+                    error_response(400, "fee must be a whole number of microlamports")
+                })?),
+                None => None,
+            };
+        Ok(LongPoll {
+            min_change_pct,
+            timeout: Duration::from_secs(timeout),
+            fee,
+        })
+    }
 }
 
-fn get_reasonable_priority_fee(rpc_url: &str) -> Result<u64, Box<dyn std::error::Error>> {
-    // 1) Fetch last 1,000 confirmed Jupiter transactions' signatures
-    let signatures: Vec<String> = get_signatures_for_address(rpc_url, JUPITER_AGGREGATOR_V6, 1000)?;
-    if signatures.is_empty() {
-        return Ok(0);
+/// Hold a `/wait` request until a refresh moves `program`'s fee by `minChangePct`
+/// percent from the one the client last saw, or the timeout passes, then respond with
+/// the latest estimate and whether it moved.
+fn long_poll(
+    cache: &EstimateCache,
+    program: &str,
+    query: &FeeQuery,
+    wait: LongPoll,
+    format: ResponseFormat,
+) -> Response {
+    let deadline = Instant::now() + wait.timeout;
+    let mut seen = cache.updates.version(program);
+    let served = || cache.by_program[program].read().unwrap().served();
+    let last_fee = wait
+        .fee
+        .or_else(|| served().and_then(Result::ok).map(|e| query.fee(&e)));
+    let mut changed = false;
+    while !changed {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match cache.updates.wait(program, seen, remaining) {
+            Some(version) if version > seen => seen = version,
+            _ => break, // timed out, or shutting down
+        }
+        if let Some(Ok(e)) = served() {
+            changed = moved(query.fee(&e), last_fee, wait.min_change_pct);
+        }
     }
+    let response = match served() {
+        Some(Ok(e)) if format == ResponseFormat::Text => Response::text(query.fee(&e).to_string()),
+        Some(Ok(e)) => {
+            let mut body = fee_json(&e, query);
+            body["program"] = json!(program);
+            body["changed"] = json!(changed);
+            Response::json(&body)
+        }
+        Some(Err(err)) => failure_response(&err),
+        None => error_response(503, NO_ESTIMATE_YET),
+    };
+    response.with_unique_header("Cache-Control", "no-store")
+}
+
+// --------------------------- gRPC ---------------------------
+
+/// The prefix of the paths of the methods of `proto/priority_fee.proto`'s service.
+const GRPC_SERVICE: &str = "/ivy.priorityfee.v1.PriorityFees/";
+
+/// The `PriorityFees` service, served on `GRPC_ADDR` with the same rate limits and API
+/// keys as HTTP.
+struct GrpcService {
+    cache: Arc<EstimateCache>,
+    estimator: Arc<PriorityFeeEstimator>,
+    rate_limiter: Arc<RateLimiter>,
+    usage: Arc<Usage>,
+}
 
-    // 2) Call getTransaction for those signatures, and compute per-tx priority fees
-    let mut priority_fees: Vec<u64> = Vec::new();
-    let mut priority_fee_error: Option<Box<dyn std::error::Error>> = None;
-    for _ in 0..MAX_RETRIES {
-        match get_priority_fees_for_signatures(rpc_url, &signatures) {
-            Ok(v) => {
-                priority_fees = v;
-                break;
+impl grpc::Service for GrpcService {
+    fn call(&self, call: &grpc::Call, responses: &mut grpc::Responses) -> Result<(), Status> {
+        let config = self.estimator.config();
+        if let Some(rps) = config.rate_limit_rps
+            && let Err(wait) = self
+                .rate_limiter
+                .check(call.peer.ip(), rps, config.rate_limit_burst)
+        {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0);
+            let message = format!("rate limit exceeded; retry in {}s", retry_after);
+            return Err(Status::new(Code::ResourceExhausted, message));
+        }
+        if !config.api_keys.is_empty() {
+            authorize_call(call, &config.api_keys, &self.usage)?;
+        }
+        let method = call.method.strip_prefix(GRPC_SERVICE).unwrap_or_default();
+        if !matches!(method, "GetEstimate" | "GetLevels" | "SubscribeEstimates") {
+            let message = format!("unknown method {}", call.method);
+            return Err(Status::new(Code::Unimplemented, message));
+        }
+        let (program, query, min_change_pct) =
+            fee_request(&call.message, &self.cache, config.aggregation)?;
+        // A failed send means the client has gone, so there's no one to tell
+        match method {
+            "GetEstimate" => {
+                let e = served_estimate(&self.cache, &program)
+                    .map_err(|e| Status::new(Code::Unavailable, e))?;
+                let _ = responses.send(&estimate_message(&e, &program, &query));
+                Ok(())
+            }
+            "GetLevels" => {
+                let e = served_estimate(&self.cache, &program)
+                    .map_err(|e| Status::new(Code::Unavailable, e))?;
+                let _ = responses.send(&levels_message(&e, &program, &query));
+                Ok(())
             }
-            Err(e) => priority_fee_error = Some(e),
+            _ => subscribe_estimates(&self.cache, &program, &query, min_change_pct, responses),
         }
     }
-    if let Some(e) = priority_fee_error {
-        return Err(e);
-    }
+}
+
+/// `authorize` for a gRPC call, which presents its key as `authorization: Bearer <key>`
+/// metadata.
+fn authorize_call(call: &grpc::Call, keys: &[ApiKey], usage: &Usage) -> Result<(), Status> {
+    let key = call
+        .metadata("authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .and_then(|presented| api_keys::find(keys, presented.trim()))
+        .ok_or_else(|| Status::new(Code::Unauthenticated, "missing or invalid API key"))?;
+    usage.record(key).map_err(|reset_in| {
+        let message = format!("daily quota exceeded; resets in {}s", reset_in);
+        Status::new(Code::ResourceExhausted, message)
+    })
+}
 
-    // 3) Take 1st tertile, clamp at [0, MAX_PRIORITY_FEE]
-    if priority_fees.is_empty() {
-        return Ok(0);
+/// The program and query an `EstimateRequest` selects, and a `SubscribeRequest`'s
+/// `min_change_pct` too (0 for an `EstimateRequest`, which has no field 6).
+fn fee_request(
+    message: &[u8],
+    cache: &EstimateCache,
+    aggregation: Aggregation,
+) -> Result<(String, FeeQuery, f64), Status> {
+    let invalid = |message: String| Status::new(Code::InvalidArgument, message);
+    let string = |bytes: &[u8], name: &str| {
+        let s = std::str::from_utf8(bytes).map_err(|_| invalid(format!("{} isn't UTF-8", name)))?;
+        // proto3 can't tell an empty string from an unset one
+        Ok::<_, Status>((!s.is_empty()).then(|| s.to_string()))
+    };
+    let mut params = FeeParams::default();
+    let mut min_change_pct = 0.0;
+    for field in protobuf::fields(message) {
+        match field.map_err(|e| invalid(format!("malformed request: {}", e)))? {
+            (1, protobuf::Value::Bytes(b)) => params.program = string(b, "program")?,
+            (2, protobuf::Value::Fixed64(bits)) => params.percentile = Some(f64::from_bits(bits)),
+            (3, protobuf::Value::Varint(v)) => params.include_failed = Some(v != 0),
+            (4, protobuf::Value::Bytes(b)) => params.aggregation = string(b, "aggregation")?,
+            (5, protobuf::Value::Varint(v)) => params.cu = Some(v),
+            (6, protobuf::Value::Fixed64(bits)) => min_change_pct = f64::from_bits(bits),
+            (number @ 1..=6, _) => {
+                return Err(invalid(format!("field {} has the wrong wire type", number)));
+            }
+            _ => {} // unknown fields are skipped, as protobuf readers do
+        }
+    }
+    if let Some(program) = &params.program
+        && cache.get(program).is_none()
+    {
+        let message = format!("program {} is not tracked", program);
+        return Err(Status::new(Code::NotFound, message));
     }
-    priority_fees.sort_unstable();
-    let first_tertile = priority_fees[priority_fees.len() / 3];
-    Ok(first_tertile.min(MAX_PRIORITY_FEE))
+    if min_change_pct < 0.0 || min_change_pct.is_nan() {
+        return Err(invalid("min_change_pct must not be negative".to_string()));
+    }
+    let (program, query) = params.validate(cache, aggregation).map_err(invalid)?;
+    Ok((program, query, min_change_pct))
 }
 
-// --------------------------- JSON-RPC plumbing ---------------------------
+/// An `Estimate` message: what `fee_json` says of `program`'s estimate.
+fn estimate_message(e: &Estimate, program: &str, query: &FeeQuery) -> Vec<u8> {
+    let fee = query.fee(e);
+    let selected = query.apply(e);
+    let (ci95_low, ci95_high) = query.fee_interval(e);
+    let slot_range = selected.slot_range();
+    let mut m = Writer::default();
+    m.string(1, program);
+    m.uint64(2, fee);
+    m.uint64(3, ci95_low);
+    m.uint64(4, ci95_high);
+    m.optional(5, query.smoothed_fee(e), Writer::uint64);
+    m.optional(6, selected.compute_unit_limit(), Writer::uint64);
+    m.double(7, query.percentile);
+    m.string(8, &query.aggregation.to_string());
+    m.optional(9, slot_range.map(|(min, _)| min), Writer::uint64);
+    m.optional(10, slot_range.map(|(_, max)| max), Writer::uint64);
+    m.string(11, e.source);
+    m.uint64(12, e.last_updated);
+    m.bool(13, e.stale);
+    m.bool(14, e.low_confidence);
+    m.uint64(15, selected.selected.len() as u64);
+    m.string(16, query.fee_trend(e).map_or("", |t| t.direction.as_str()));
+    if let Some(cu) = query.compute_units {
+        m.uint64(17, cu);
+        m.uint64(18, priority_fee_lamports(fee, cu));
+    }
+    m.buf
+}
 
-#[derive(Serialize)]
-struct JsonRpcRequest {
-    jsonrpc: &'static str,
-    id: serde_json::Value, // allow numeric or string
-    method: &'static str,
-    params: serde_json::Value,
+/// A `Levels` message: what `levels_json` says of `program`'s estimate.
+fn levels_message(e: &Estimate, program: &str, query: &FeeQuery) -> Vec<u8> {
+    let fee_levels = |m: &mut Writer, levels: &PriorityFeeLevels| {
+        m.uint64(1, levels.min);
+        m.uint64(2, levels.low);
+        m.uint64(3, levels.medium);
+        m.uint64(4, levels.high);
+        m.uint64(5, levels.very_high);
+        m.uint64(6, levels.unsafe_max);
+    };
+    let selected = query.apply(e);
+    let slot_range = selected.slot_range();
+    let mut m = Writer::default();
+    m.string(1, program);
+    m.message(2, |m| {
+        fee_levels(m, &selected.levels_with(query.aggregation))
+    });
+    if let Some(levels) = selected.smoothed_levels(query.aggregation) {
+        m.message(3, |m| fee_levels(m, &levels));
+    }
+    m.optional(4, selected.compute_unit_limit(), Writer::uint64);
+    m.string(5, &query.aggregation.to_string());
+    m.optional(6, slot_range.map(|(min, _)| min), Writer::uint64);
+    m.optional(7, slot_range.map(|(_, max)| max), Writer::uint64);
+    m.string(8, e.source);
+    m.uint64(9, e.last_updated);
+    m.bool(10, e.stale);
+    m.bool(11, e.low_confidence);
+    m.uint64(12, selected.selected.len() as u64);
+    m.buf
 }
 
-#[derive(Deserialize, Debug)]
-struct JsonRpcError {
-    code: i64,
-    message: String,
+/// Stream `program`'s estimate each time a refresh moves its fee by at least
+/// `min_change_pct` percent, starting with the current one, as `/ws` does. Failed
+/// refreshes aren't sent, and a client that cancels is noticed within
+/// `STREAM_KEEPALIVE`.
+fn subscribe_estimates(
+    cache: &EstimateCache,
+    program: &str,
+    query: &FeeQuery,
+    min_change_pct: f64,
+    responses: &mut grpc::Responses,
+) -> Result<(), Status> {
+    let mut seen = 0;
+    let mut last_fee: Option<u64> = None;
+    loop {
+        let Some(version) = cache.updates.wait(program, seen, STREAM_KEEPALIVE) else {
+            return Err(Status::new(Code::Unavailable, "shutting down"));
+        };
+        if responses.is_cancelled() {
+            return Ok(());
+        }
+        if version == seen {
+            continue;
+        }
+        seen = version;
+        let Some(Ok(e)) = cache.by_program[program].read().unwrap().served() else {
+            continue;
+        };
+        let fee = query.fee(&e);
+        if !moved(fee, last_fee, min_change_pct) {
+            continue;
+        }
+        last_fee = Some(fee);
+        if responses
+            .send(&estimate_message(&e, program, query))
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
 }
 
+// --------------------------- GraphQL ---------------------------
+
+/// The schema `/graphql` answers, also served at `/graphql/schema`.
+const GRAPHQL_SCHEMA: &str = include_str!("../graphql/schema.graphql");
+
+/// The fields of `graphql/schema.graphql`'s `Estimate` read straight from `fee_json`.
+const ESTIMATE_FIELDS: [&str; 21] = [
+    "reasonablePriorityFee",
+    "ci95",
+    "smoothedPriorityFee",
+    "computeUnitLimit",
+    "percentile",
+    "aggregation",
+    "minSlot",
+    "maxSlot",
+    "source",
+    "lastUpdated",
+    "trend",
+    "computeUnits",
+    "priorityFeeLamports",
+    "priorityFeeSol",
+    "stale",
+    "lowConfidence",
+    "sampleSize",
+    "oldestSlot",
+    "newestSlot",
+    "computedAt",
+    "ageSeconds",
+];
+
+/// A GraphQL request, as a `POST /graphql` body.
 #[derive(Deserialize)]
-struct SingleResponse<T> {
-    #[serde(default)]
-    result: Option<T>,
-    #[serde(default)]
-    error: Option<JsonRpcError>,
+#[serde(rename_all = "camelCase")]
+struct GraphqlRequest {
+    query: String,
+    operation_name: Option<String>,
+    variables: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
-#[derive(Deserialize)]
-struct BatchItem<T> {
-    #[serde(default)]
-    result: Option<T>,
-    #[serde(default)]
-    error: Option<JsonRpcError>,
-    id: serde_json::Value,
+impl GraphqlRequest {
+    /// A request given as `GET /graphql` parameters, `variables` being JSON.
+    fn from_params(request: &rouille::Request) -> Result<GraphqlRequest, Response> {
+        let query = request
+            .get_param("query")
+            .ok_or_else(|| error_response(400, "query is required"))?;
+        let variables = match request.get_param("variables") {
+            Some(v) => Some(serde_json::from_str(&v).map_err(|e| {
+                error_response(400, &format!("variables must be a JSON object: {}", e))
+            })?),
+            None => None,
+        };
+        Ok(GraphqlRequest {
+            query,
+            operation_name: request.get_param("operationName"),
+            variables,
+        })
+    }
+}
+
+/// Run a GraphQL request against the cached estimates and history. Field errors are
+/// reported alongside the data, as GraphQL does; only a document that can't be run
+/// at all is a 400.
+fn graphql_response(
+    body: GraphqlRequest,
+    cache: &EstimateCache,
+    history: &History,
+    aggregation: Aggregation,
+) -> Response {
+    let root = QueryRoot {
+        cache,
+        history,
+        aggregation,
+    };
+    let variables = body.variables.unwrap_or_default();
+    match graphql::execute(
+        &body.query,
+        body.operation_name.as_deref(),
+        &variables,
+        &root,
+    ) {
+        Ok(response) => Response::json(&response),
+        Err(message) => {
+            Response::json(&json!({ "errors": [{ "message": message }] })).with_status_code(400)
+        }
+    }
 }
 
-// --------------------------- getSignaturesForAddress ---------------------------
+/// The schema's `Query` type.
+struct QueryRoot<'a> {
+    cache: &'a EstimateCache,
+    history: &'a History,
+    aggregation: Aggregation,
+}
 
-#[derive(Deserialize)]
-struct SignatureInfo {
-    signature: String,
-    // other fields available but not required here
+impl QueryRoot<'_> {
+    /// The estimate a field's arguments select, as `FeeParams` would in a JSON body.
+    fn params(&self, args: &Arguments) -> Result<FeeParams, String> {
+        serde_json::from_value(serde_json::Value::Object(args.clone())).map_err(|e| e.to_string())
+    }
 }
 
-fn get_signatures_for_address(
-    rpc_url: &str,
-    address: &str,
-    limit: usize,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let limit = limit.min(1000); // RPC max
-    let req = JsonRpcRequest {
-        jsonrpc: "2.0",
-        id: json!(1),
-        method: "getSignaturesForAddress",
-        params: json!([
-            address,
-            {
-                "commitment": "confirmed",
-                "limit": limit
+impl graphql::Object for QueryRoot<'_> {
+    fn typename(&self) -> &'static str {
+        "Query"
+    }
+
+    fn field(&self, name: &str, args: &Arguments) -> Result<Output<'_>, String> {
+        match name {
+            "programs" => Ok(Output::Value(json!(self.cache.programs))),
+            "estimate" => {
+                let (program, query) = self.params(args)?.validate(self.cache, self.aggregation)?;
+                let e = served_estimate(self.cache, &program)?;
+                Ok(EstimateObject::output(Some(program), e, query))
+            }
+            "estimates" => {
+                let params = self.params(args)?;
+                if params.program.is_some() {
+                    return Err("estimates has no program argument".to_string());
+                }
+                let (_, query) = params.validate(self.cache, self.aggregation)?;
+                let estimates = self.cache.programs.iter().map(|program| {
+                    match served_estimate(self.cache, program) {
+                        Ok(e) => EstimateObject::output(Some(program.clone()), e, query.clone()),
+                        Err(_) => Output::Value(serde_json::Value::Null),
+                    }
+                });
+                Ok(Output::List(estimates.collect()))
+            }
+            "aggregate" => {
+                let params = self.params(args)?;
+                if params.program.is_some() {
+                    return Err("aggregate has no program argument".to_string());
+                }
+                let (_, query) = params.validate(self.cache, self.aggregation)?;
+                let e = self
+                    .cache
+                    .aggregate()
+                    .ok_or_else(|| NO_ESTIMATE_YET.to_string())?;
+                Ok(EstimateObject::output(None, e, query))
+            }
+            "history" => {
+                let program = match args.get("program") {
+                    Some(serde_json::Value::String(p)) => p.clone(),
+                    Some(serde_json::Value::Null) | None => self.cache.programs[0].clone(),
+                    Some(_) => return Err("program must be a string".to_string()),
+                };
+                if self.cache.get(&program).is_none() {
+                    return Err(format!("program {} is not tracked", program));
+                }
+                // The schema's defaults, for arguments not given
+                let duration = |name: &str, default: &str| {
+                    let v = args.get(name).unwrap_or(&serde_json::Value::Null);
+                    v.as_str()
+                        .or(v.is_null().then_some(default))
+                        .and_then(parse_duration)
+                        .map(|d| d.as_secs())
+                        .ok_or_else(|| {
+                            format!("{} must be a duration such as 90s, 15m, 6h or 7d", name)
+                        })
+                };
+                let (window, step) = (duration("window", "1h")?, duration("step", "1m")?);
+                if window > self.history.max_age_secs() {
+                    let max = duration_label(self.history.max_age_secs());
+                    return Err(format!("window must be at most {}", max));
+                }
+                if step == 0 {
+                    return Err("step must be positive".to_string());
+                }
+                let since = unix_now().saturating_sub(window);
+                let points = self.history.series(&program, since, step).map_err(|e| {
+                    log::error!("failed to read history: {}", e);
+                    "failed to read history".to_string()
+                })?;
+                let points = points.iter().map(|point| {
+                    let mut value = json!(point);
+                    value["source"] = json!(point.source); // null rather than absent
+                    Output::Map("HistoryPoint", value)
+                });
+                Ok(Output::List(points.collect()))
+            }
+            _ => Err(format!("type Query has no field {}", name)),
+        }
+    }
+}
+
+/// The schema's `Estimate` type: a program's estimate, or the aggregate's, as `query`
+/// selects it.
+struct EstimateObject {
+    program: Option<String>, // `None` for the aggregate
+    estimate: Estimate,
+    query: FeeQuery,
+    json: serde_json::Value, // `fee_json`, which most fields are read from
+}
+
+impl EstimateObject {
+    fn output(program: Option<String>, estimate: Estimate, query: FeeQuery) -> Output<'static> {
+        let json = fee_json(&estimate, &query);
+        Output::Object(Box::new(EstimateObject {
+            program,
+            estimate,
+            query,
+            json,
+        }))
+    }
+}
+
+impl graphql::Object for EstimateObject {
+    fn typename(&self) -> &'static str {
+        "Estimate"
+    }
+
+    fn field(&self, name: &str, args: &Arguments) -> Result<Output<'_>, String> {
+        let selected = || self.query.apply(&self.estimate);
+        match name {
+            "program" => Ok(Output::Value(json!(self.program))),
+            "fee" => {
+                let percentile = args
+                    .get("percentile")
+                    .and_then(|p| p.as_f64())
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .ok_or("percentile must be a number between 0 and 100")?;
+                Ok(Output::Value(json!(
+                    selected().fee(percentile, self.query.aggregation)
+                )))
             }
-        ]),
+            "levels" => {
+                let levels = selected().levels_with(self.query.aggregation);
+                Ok(Output::Map("FeeLevels", json!(levels)))
+            }
+            "smoothedLevels" => Ok(match selected().smoothed_levels(self.query.aggregation) {
+                Some(levels) => Output::Map("FeeLevels", json!(levels)),
+                None => Output::Value(serde_json::Value::Null),
+            }),
+            "forecast" => Ok(Output::Value(match self.json["forecast"].as_object() {
+                Some(by_minute) => by_minute.values().cloned().collect(),
+                None => serde_json::Value::Null,
+            })),
+            _ if ESTIMATE_FIELDS.contains(&name) => Ok(Output::Value(self.json[name].clone())),
+            _ => Err(format!("type Estimate has no field {}", name)),
+        }
+    }
+}
+
+// --------------------------- Refresh ---------------------------
+
+/// Start a `refresh_loop` for each of the programs `estimator` tracks.
+fn spawn_refresh_loops(
+    estimator: &Arc<PriorityFeeEstimator>,
+    cache: &Arc<EstimateCache>,
+    history: &Arc<History>,
+    shutdown: &Arc<Shutdown>,
+) {
+    for program in &estimator.config().tracked_programs {
+        let estimator = estimator.clone();
+        let program = program.clone();
+        let cache = cache.clone();
+        let history = history.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || refresh_loop(&estimator, &program, &cache, &history, &shutdown));
+    }
+}
+
+/// Recompute the estimate every refresh interval, or less often while the RPC endpoints
+/// rate limit calls (see `Config::throttled_refresh_interval`), publishing each result
+/// to `cache` and recording each success in `history`, until shutdown. Each refresh is
+/// logged in a span with the program, sample size, duration and the RPC endpoint last
+/// used.
+///
+/// With a shared cache, only the replica holding the program's leader lock refreshes
+/// it; the others follow its refreshes instead. If the shared cache can't be reached,
+/// every replica refreshes for itself.
+fn refresh_loop(
+    estimator: &PriorityFeeEstimator,
+    program: &str,
+    cache: &EstimateCache,
+    history: &History,
+    shutdown: &Shutdown,
+) {
+    let mut followed = None; // `refreshed_at` of the leader's refresh served last
+    while let Some(refreshing) = shutdown.begin_refresh() {
+        let leading = match &cache.shared {
+            Some(shared) => shared
+                .acquire(program, leader_lease(&estimator.config()))
+                .unwrap_or_else(|e| {
+                    log::warn!(
+                        "shared cache at {} unavailable, refreshing locally: {}",
+                        shared.url(),
+                        e
+                    );
+                    true
+                }),
+            None => true,
+        };
+        if leading {
+            let _ = refresh(estimator, program, cache, history);
+        } else {
+            follow(estimator, program, cache, history, &mut followed);
+        }
+        drop(refreshing);
+        thread::sleep(estimator.config().throttled_refresh_interval());
+    }
+}
+
+/// Recompute `program`'s estimate once, publishing the result to `cache`, recording a
+/// success in `history`, calling the webhooks it concerns and pushing it to the
+/// message broker and Kafka, if configured.
+fn refresh(
+    estimator: &PriorityFeeEstimator,
+    program: &str,
+    cache: &EstimateCache,
+    history: &History,
+) -> Result<Estimate, CodedError> {
+    let span = logging::span(&[("program", &program)]);
+    let trace = telemetry::span("refresh", SpanKind::Internal);
+    trace.set("program", program);
+    let started = Instant::now();
+    let result = estimator.estimate_program(program);
+    logging::record("duration_ms", &started.elapsed().as_millis());
+    let result = match result {
+        Ok(e) => {
+            logging::record("samples", &e.samples.len());
+            logging::record("source", &e.source);
+            log::debug!("refresh complete");
+            history.record(HistoryPoint::new(program, &e));
+            let config = estimator.config();
+            cache
+                .webhooks
+                .notify(&config.webhooks, program, &e, config.aggregation);
+            if let Some(publisher) = &cache.publisher {
+                let query = FeeQuery {
+                    percentile: DEFAULT_PERCENTILE,
+                    include_failed: None,
+                    aggregation: config.aggregation,
+                    compute_units: None,
+                };
+                let mut message = fee_json(&e, &query);
+                message["program"] = json!(program);
+                let subject = format!("{}.{}", config.publish_topic, program);
+                publisher.publish(&subject, message.to_string());
+            }
+            if let Some(kafka) = &cache.kafka {
+                kafka.send(program, refresh_record(&e, program, &config).to_string());
+            }
+            Ok(e)
+        }
+        Err(e) => {
+            log::warn!("refresh failed: {}", e);
+            trace.set_error(&e.to_string());
+            Err(CodedError::from_error(&*e))
+        }
+    };
+    drop(trace);
+    let config = estimator.config();
+    if let Some(shared) = &cache.shared {
+        let ttl = config.max_staleness.max(leader_lease(&config));
+        if let Err(e) = shared.store(program, &result, ttl) {
+            log::warn!("failed to share the refresh: {}", e);
+        }
+    }
+    cache.publish(program, result.clone(), config.max_staleness);
+    drop(span);
+    result
+}
+
+/// Serve the leader's latest refresh of `program` from the shared cache, unless it was
+/// served already. It's clamped, smoothed and recorded in `history` as a refresh of this
+/// replica's would be; webhooks, the message broker and Kafka are left to the leader.
+fn follow(
+    estimator: &PriorityFeeEstimator,
+    program: &str,
+    cache: &EstimateCache,
+    history: &History,
+    followed: &mut Option<u64>,
+) {
+    let Some(shared) = &cache.shared else {
+        return;
+    };
+    let snapshot = match shared.load(program) {
+        Ok(Some(snapshot)) if *followed != Some(snapshot.refreshed_at) => snapshot,
+        Ok(_) => return,
+        Err(e) => {
+            log::warn!("failed to read {} from the shared cache: {}", program, e);
+            return;
+        }
     };
+    *followed = Some(snapshot.refreshed_at);
+    log::debug!("serving {}'s refresh of {}", snapshot.leader, program);
+    let config = estimator.config();
+    let result = snapshot.result.map(|e| {
+        let e = e
+            .with_include_failed(config.include_failed)
+            .with_fee_bounds(config.min_fee_floor, config.max_fee_clamp);
+        let e = estimator.track(program, e);
+        history.record(HistoryPoint::new(program, &e));
+        e
+    });
+    cache.publish(program, result, config.max_staleness);
+}
+
+/// How long the leader lock is held between refreshes: long enough to cover two
+/// refreshes, so that it's only lost if the leader stops refreshing.
+fn leader_lease(config: &Config) -> Duration {
+    (config.throttled_refresh_interval() + config.refresh_timeout) * 2
+}
 
-    let resp = ureq::post(rpc_url).send_json(&req)?;
-    if resp.status() != 200 {
-        return Err(format!("got status {}: {}", resp.status(), resp.into_string()?).into());
+/// Refresh `programs` now rather than at their next interval, as `POST /admin/refresh`
+/// does, and respond with the fresh estimates. Fails with 503 if any refresh did.
+fn forced_refresh(
+    estimator: &PriorityFeeEstimator,
+    programs: &[String],
+    query: &FeeQuery,
+    cache: &EstimateCache,
+    history: &History,
+    shutdown: &Shutdown,
+) -> Response {
+    let Some(_refreshing) = shutdown.begin_refresh() else {
+        return error_response(503, "shutting down");
+    };
+    let mut failed = false;
+    let mut results = serde_json::Map::new();
+    for program in programs {
+        log::info!("Refreshing {} on request", program);
+        let value = match refresh(estimator, program, cache, history) {
+            Ok(e) => fee_json(&e, query),
+            Err(err) => {
+                failed = true;
+                json!({ "error": err })
+            }
+        };
+        results.insert(program.clone(), value);
     }
-    let resp: SingleResponse<Vec<SignatureInfo>> = resp.into_json()?;
+    Response::json(&json!({ "programs": results })).with_status_code(if failed { 503 } else { 200 })
+}
+
+// --------------------------- Probes ---------------------------
+
+/// Whether `url` is a liveness or readiness probe, which skip rate limits and API keys.
+fn is_probe(url: &str) -> bool {
+    matches!(url, "/health" | "/livez" | "/readyz")
+}
 
-    if let Some(err) = resp.error {
-        Err(format!(
-            "getSignaturesForAddress error (code {}): {}",
-            err.code, err.message
-        ))?
+/// Ready once every tracked program has an estimate from a refresh at most `max_age`
+/// ago, and until shutdown begins. Lists each program's state either way.
+fn readiness(cache: &EstimateCache, max_age: Duration, shutting_down: bool) -> Response {
+    let now = unix_now();
+    let mut ready = !shutting_down;
+    let mut programs = serde_json::Map::new();
+    for program in &cache.programs {
+        let latest = cache.by_program[program].read().unwrap();
+        let age = latest
+            .estimate
+            .as_ref()
+            .map(|e| now.saturating_sub(e.last_updated));
+        let fresh = age.is_some_and(|age| age <= max_age.as_secs());
+        ready &= fresh;
+        let mut state = json!({ "ready": fresh, "ageSeconds": age, "error": latest.error });
+        if let Some(shared) = &cache.shared {
+            state["leader"] = json!(shared.is_leading(program));
+        }
+        programs.insert(program.clone(), state);
     }
+    let body = json!({ "ready": ready, "shuttingDown": shutting_down, "programs": programs });
+    Response::json(&body).with_status_code(if ready { 200 } else { 503 })
+}
 
-    let result = resp
-        .result
-        .ok_or("getSignaturesForAddress: missing result")?;
+// --------------------------- Version ---------------------------
 
-    Ok(result.into_iter().map(|s| s.signature).collect())
+/// The build and the optional features this process runs with.
+fn version(config: &Config) -> serde_json::Value {
+    let built_at: u64 = env!("BUILD_TIMESTAMP").parse().unwrap_or(0);
+    let commit = env!("GIT_COMMIT");
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "gitCommit": if commit.is_empty() { None } else { Some(commit) },
+        "builtAt": logging::rfc3339(Duration::from_secs(built_at)),
+        "apiVersions": [API_VERSION],
+        "features": {
+            "feeSources": FEE_SOURCE_NAMES,
+            "feeSource": fee_source_setting_name(config.fee_source.as_ref()),
+            "persistence": config.history_path.is_some() || config.database().is_some(),
+            "tls": config.tls_cert_path.is_some(),
+            "unixSocket": config.listen_socket.is_some(),
+            "grpc": config.grpc_addr.is_some(),
+            "apiKeys": !config.api_keys.is_empty(),
+            "rateLimit": config.rate_limit_rps.is_some(),
+        }
+    })
 }
 
-// --------------------------- getTransaction (batch) ---------------------------
+// --------------------------- Shutdown ---------------------------
 
-#[derive(Deserialize, Debug)]
-struct TransactionMeta {
-    fee: u64,
-    #[serde(rename = "computeUnitsConsumed")]
-    compute_units_consumed: Option<u64>,
+/// Whether a graceful shutdown has begun, and how many refreshes are still under way.
+#[derive(Default)]
+struct Shutdown {
+    requested: AtomicBool,
+    refreshing: Mutex<usize>,
+    refreshed: Condvar,
 }
 
-#[derive(Deserialize, Debug, Default)]
-struct TransactionResult {
-    meta: Option<TransactionMeta>,
+/// Marks a refresh as under way until dropped.
+struct Refreshing<'a>(&'a Shutdown);
+
+impl Drop for Refreshing<'_> {
+    fn drop(&mut self) {
+        *self.0.refreshing.lock().unwrap() -= 1;
+        self.0.refreshed.notify_all();
+    }
 }
 
-fn get_priority_fees_for_signatures(
-    rpc_url: &str,
-    signatures: &[String],
-) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
-    // Build a JSON-RPC batch
-    let mut batch: Vec<JsonRpcRequest> = Vec::with_capacity(signatures.len());
-    for (i, sig) in signatures.iter().enumerate() {
-        batch.push(JsonRpcRequest {
-            jsonrpc: "2.0",
-            id: json!(i as u64),
-            method: "getTransaction",
-            params: json!([
-                sig,
-                {
-                    "commitment": "confirmed",
-                    "encoding": "json",
-                    "maxSupportedTransactionVersion": 0
-                }
-            ]),
+impl Shutdown {
+    fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Mark a refresh as under way, or return `None` if shutdown has begun.
+    fn begin_refresh(&self) -> Option<Refreshing<'_>> {
+        let mut refreshing = self.refreshing.lock().unwrap();
+        if self.is_requested() {
+            return None;
+        }
+        *refreshing += 1;
+        Some(Refreshing(self))
+    }
+
+    /// Wait for the refreshes under way to finish, up to `deadline`; returns whether
+    /// they did.
+    fn wait_for_refreshes(&self, deadline: Instant) -> bool {
+        let refreshing = self.refreshing.lock().unwrap();
+        let (refreshing, _) = self
+            .refreshed
+            .wait_timeout_while(
+                refreshing,
+                deadline.saturating_duration_since(Instant::now()),
+                |n| *n > 0,
+            )
+            .unwrap();
+        *refreshing == 0
+    }
+}
+
+// --------------------------- Fee limits ---------------------------
+
+/// Override the fee floor and clamp without a restart, on every cluster, keeping the
+/// overrides in `fee_limits` for reloads and saving them to LIMITS_PATH if set. Cached
+/// estimates are clamped to the new limits straight away.
+fn set_fee_limits(
+    update: FeeLimits,
+    fee_limits: &Mutex<FeeLimits>,
+    estimator: &PriorityFeeEstimator,
+    cache: &EstimateCache,
+    clusters: &[ClusterEstimates],
+) -> Response {
+    // Held throughout, so concurrent updates are applied, saved and kept one at a time
+    let mut fee_limits = fee_limits.lock().unwrap();
+    let config = estimator.config();
+    let (min_fee, max_fee) = match update.apply(config.min_fee_floor, config.max_fee_clamp) {
+        Ok(bounds) => bounds,
+        Err(e) => return error_response(400, &e),
+    };
+    if let Some(path) = &config.limits_path {
+        let saved = limits::read(path).and_then(|saved| {
+            limits::write(path, &update.or(saved))
+                .map_err(|e| format!("Failed to save fee limits to {}: {}", path, e))
         });
+        if let Err(e) = saved {
+            log::error!("{}", e);
+            return error_response(500, &e);
+        }
     }
+    *fee_limits = update.or(*fee_limits);
+    let mut new = (*config).clone();
+    new.min_fee_floor = min_fee;
+    new.max_fee_clamp = max_fee;
+    estimator.set_config(new);
+    cache.set_fee_bounds(min_fee, max_fee);
+    // The clusters share the configured limits, so they share the overrides too
+    for c in clusters {
+        let mut new = (*c.estimator.config()).clone();
+        new.min_fee_floor = min_fee;
+        new.max_fee_clamp = max_fee;
+        c.estimator.set_config(new);
+        c.cache.set_fee_bounds(min_fee, max_fee);
+    }
+    log::info!("Fee limits set to [{}, {}]", min_fee, max_fee);
+    limits_response(&estimator.config())
+}
+
+fn limits_response(config: &Config) -> Response {
+    Response::json(&json!({
+        "minFeeFloor": config.min_fee_floor,
+        "maxFeeClamp": config.max_fee_clamp,
+        "persisted": config.limits_path.is_some()
+    }))
+}
+
+// --------------------------- Webhooks ---------------------------
+
+/// The configured webhooks and those registered at runtime, without their secrets.
+fn webhooks_response(config: &Config, webhooks: &Webhooks) -> Response {
+    Response::json(&json!({
+        "configured": config.webhooks,
+        "registered": webhooks
+            .registered()
+            .iter()
+            .map(|(id, w)| webhook_json(*id, w))
+            .collect::<Vec<_>>()
+    }))
+}
+
+fn webhook_json(id: u64, webhook: &Webhook) -> serde_json::Value {
+    let mut value = json!(webhook);
+    value["id"] = json!(id);
+    value["signed"] = json!(webhook.secret.is_some());
+    value
+}
+
+// --------------------------- Configuration reload ---------------------------
 
-    // Send the batch
-    let resp = ureq::post(rpc_url).send_json(&batch)?;
-    if resp.status() != 200 {
-        return Err(format!("got status {}: {}", resp.status(), resp.into_string()?).into());
-    }
-    let mut s = String::new();
-    resp.into_reader()
-        .take(MAX_RESPONSE_LEN)
-        .read_to_string(&mut s)?;
-    let responses: Vec<BatchItem<TransactionResult>> = serde_json::from_str(&s)?;
-    if responses.len() == 0 && signatures.len() != 0 {
-        return Err(format!("batch size too large for destination RPC, try again!").into());
-    }
-
-    // Extract per-transaction priority fee (in micro-lamports), assume 1 signature
-    let mut out = Vec::with_capacity(responses.len());
-    for item in responses {
-        if let Some(err) = item.error {
-            // Skip errored items (e.g., not found / too old)
-            eprintln!(
-                "getTransaction error (id {:?}, code {}): {}",
-                item.id, err.code, err.message
+/// Re-read the settings and apply them, unless one that needs a restart has changed,
+/// with the fee limits set at runtime, `fee_limits`, still overriding the configured
+/// ones. Refresh loops pick up a new refresh interval after their current sleep.
+fn reload_config(
+    estimator: &PriorityFeeEstimator,
+    clusters: &[ClusterEstimates],
+    fee_limits: &Mutex<FeeLimits>,
+) -> Result<(), String> {
+    let result = Config::load()
+        .and_then(|mut new| {
+            let limits = fee_limits.lock().unwrap();
+            (new.min_fee_floor, new.max_fee_clamp) = limits
+                .apply(new.min_fee_floor, new.max_fee_clamp)
+                .map_err(|e| format!("fee limits set with /admin/limits: {}", e))?;
+            Ok(new)
+        })
+        .and_then(|new| estimator.config().reloaded(new))
+        .and_then(|config| {
+            let cluster_configs = clusters
+                .iter()
+                .map(|c| {
+                    let cluster = config.cluster(&c.name).ok_or_else(|| {
+                        format!("cluster {} can't be removed without a restart", c.name)
+                    })?;
+                    c.estimator.config().reloaded(config.for_cluster(cluster))
+                })
+                .collect::<Result<Vec<Config>, String>>()?;
+            Ok((config, cluster_configs))
+        });
+    match result {
+        Ok((config, cluster_configs)) => {
+            log::info!(
+                "Config reloaded; RPC: {}, refresh interval: {}s",
+                config.rpc.urls().collect::<Vec<_>>().join(", "),
+                config.refresh_interval.as_secs()
             );
-            continue;
+            for (c, cluster_config) in clusters.iter().zip(cluster_configs) {
+                c.estimator.set_config(cluster_config);
+            }
+            estimator.set_config(config);
+            Ok(())
+        }
+        Err(e) => {
+            log::warn!("Config reload rejected: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Block `signals` on the calling thread, and so on every thread it spawns afterwards,
+/// leaving them to be taken with `wait_for_signal`.
+fn block_signals(signals: &[libc::c_int]) -> libc::sigset_t {
+    // SAFETY: the set is initialized by sigemptyset before use
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &signal in signals {
+            libc::sigaddset(&mut set, signal);
         }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    }
+}
 
-        let tr = match item.result {
-            Some(r) => r,
-            _ => continue,
-        };
+/// Wait for one of the signals in `set` to arrive, and return it.
+fn wait_for_signal(set: &libc::sigset_t) -> libc::c_int {
+    let mut signal = 0;
+    // SAFETY: `set` is a valid signal set and `signal` a valid out pointer
+    unsafe { libc::sigwait(set, &mut signal) };
+    signal
+}
 
-        let meta = match tr.meta {
-            Some(m) => m,
-            _ => continue,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str, key: Option<&str>) -> rouille::Request {
+        let headers = match key {
+            Some(key) => vec![("Authorization".to_string(), format!("Bearer {}", key))],
+            None => Vec::new(),
         };
+        rouille::Request::fake_http("GET", url, headers, Vec::new())
+    }
 
-        let compute_units = meta.compute_units_consumed.unwrap_or(0) as i64;
-        if compute_units <= 0 {
-            continue;
-        }
+    fn status(result: Result<(), Response>) -> u16 {
+        result.err().map_or(200, |r| r.status_code)
+    }
 
-        // we're assuming 1 signature for simplicity here
-        // priority_fee_micro_lamports = ((fee_lamports - (5000 * n_signatures)) * 1_000_000) / compute_units
-        let fee_lamports = meta.fee as u128;
-        let priority_fee = (((fee_lamports - 5000) * 1_000_000) / (compute_units as u128)) as u64;
+    #[test]
+    fn admin_endpoints_are_refused_without_keys() {
+        let usage = Usage::default();
+        for url in [
+            "/admin/refresh",
+            "/admin/reload",
+            "/admin/limits",
+            "/samples",
+            "/compare",
+        ] {
+            assert_eq!(
+                status(check_access(&get(url, None), &[], &usage)),
+                403,
+                "{}",
+                url
+            );
+        }
+        for url in ["/", "/levels", "/fee", "/health", "/readyz"] {
+            assert_eq!(
+                status(check_access(&get(url, None), &[], &usage)),
+                200,
+                "{}",
+                url
+            );
+        }
+    }
 
-        out.push(priority_fee);
+    #[test]
+    fn admin_endpoints_need_an_admin_key() {
+        let usage = Usage::default();
+        let keys = [
+            ApiKey::parse("user-key").unwrap(),
+            ApiKey::parse("ops-key;admin").unwrap(),
+        ];
+        let check = |url, key| status(check_access(&get(url, key), &keys, &usage));
+        assert_eq!(check("/admin/reload", None), 401);
+        assert_eq!(check("/admin/reload", Some("wrong")), 401);
+        assert_eq!(check("/admin/reload", Some("user-key")), 403);
+        assert_eq!(check("/admin/reload", Some("ops-key")), 200);
+        assert_eq!(check("/compare", Some("user-key")), 403);
+        assert_eq!(check("/", Some("user-key")), 200);
+        assert_eq!(check("/health", None), 200);
     }
 
-    Ok(out)
+    #[test]
+    fn concurrent_fee_limit_updates_are_all_kept() {
+        let path = std::env::temp_dir().join(format!("fee-limits-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let estimator = PriorityFeeEstimator::new(Config {
+            limits_path: Some(path.clone()),
+            ..Config::default()
+        });
+        let cache = EstimateCache::new(&[], None, None, None);
+        let fee_limits = Mutex::new(FeeLimits::default());
+        // One client moving only the floor, another only the clamp
+        thread::scope(|s| {
+            for clamp in [false, true] {
+                let (estimator, cache, fee_limits) = (&estimator, &cache, &fee_limits);
+                s.spawn(move || {
+                    for n in 1..=50 {
+                        let update = match clamp {
+                            false => FeeLimits {
+                                min_fee_floor: Some(n),
+                                max_fee_clamp: None,
+                            },
+                            true => FeeLimits {
+                                min_fee_floor: None,
+                                max_fee_clamp: Some(1000 + n),
+                            },
+                        };
+                        let response = set_fee_limits(update, fee_limits, estimator, cache, &[]);
+                        assert_eq!(response.status_code, 200);
+                    }
+                });
+            }
+        });
+        let saved = limits::read(&path);
+        let _ = std::fs::remove_file(&path);
+        let expected = FeeLimits {
+            min_fee_floor: Some(50),
+            max_fee_clamp: Some(1050),
+        };
+        assert_eq!(saved.unwrap(), expected);
+        assert_eq!(*fee_limits.lock().unwrap(), expected);
+        let config = estimator.config();
+        assert_eq!((config.min_fee_floor, config.max_fee_clamp), (50, 1050));
+    }
 }
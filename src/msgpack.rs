@@ -0,0 +1,230 @@
+//! JSON values encoded as MessagePack, for clients that ask for it instead of JSON.
+
+use serde_json::Value;
+
+/// `value` in MessagePack, using the smallest encoding of each item.
+pub fn to_vec(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write(&mut buf, value);
+    buf
+}
+
+fn write(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buf.push(0xc0),
+        Value::Bool(b) => buf.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => write_uint(buf, n),
+            (None, Some(n)) => write_int(buf, n),
+            (None, None) => {
+                buf.push(0xcb);
+                buf.extend_from_slice(&n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        },
+        Value::String(s) => write_str(buf, s),
+        Value::Array(items) => {
+            write_len(buf, items.len(), 0x90, 0xdc, 0xdd);
+            for item in items {
+                write(buf, item);
+            }
+        }
+        Value::Object(map) => {
+            write_len(buf, map.len(), 0x80, 0xde, 0xdf);
+            for (k, v) in map {
+                write_str(buf, k);
+                write(buf, v);
+            }
+        }
+    }
+}
+
+fn write_uint(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0x7f => buf.push(n as u8),
+        0x80..=0xff => buf.extend_from_slice(&[0xcc, n as u8]),
+        0x100..=0xffff => {
+            buf.push(0xcd);
+            buf.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xce);
+            buf.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xcf);
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+/// A negative integer.
+fn write_int(buf: &mut Vec<u8>, n: i64) {
+    match n {
+        -32..=-1 => buf.push(n as u8),
+        -0x80..=-33 => buf.extend_from_slice(&[0xd0, n as u8]),
+        -0x8000..=-0x81 => {
+            buf.push(0xd1);
+            buf.extend_from_slice(&(n as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            buf.push(0xd2);
+            buf.extend_from_slice(&(n as i32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xd3);
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    match s.len() {
+        len @ 0..=31 => buf.push(0xa0 | len as u8),
+        len @ 32..=0xff => buf.extend_from_slice(&[0xd9, len as u8]),
+        len @ 0x100..=0xffff => {
+            buf.push(0xda);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            buf.push(0xdb);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// The header of an array or map of `len` items: `fix` with the length in its low bits
+/// if it's under 16, else `marker16` or `marker32` with a 16- or 32-bit length.
+fn write_len(buf: &mut Vec<u8>, len: usize, fix: u8, marker16: u8, marker32: u8) {
+    match len {
+        0..=15 => buf.push(fix | len as u8),
+        16..=0xffff => {
+            buf.push(marker16);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(marker32);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn header(value: Value, len: usize) -> Vec<u8> {
+        let mut buf = to_vec(&value);
+        buf.truncate(buf.len() - len);
+        buf
+    }
+
+    #[test]
+    fn scalars_use_their_markers() {
+        assert_eq!(to_vec(&json!(null)), [0xc0]);
+        assert_eq!(to_vec(&json!(false)), [0xc2]);
+        assert_eq!(to_vec(&json!(true)), [0xc3]);
+        assert_eq!(
+            to_vec(&json!(1.5)),
+            [0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn unsigned_integers_switch_format_at_each_boundary() {
+        let cases: [(u64, &[u8]); 10] = [
+            (0, &[0x00]),
+            (0x7f, &[0x7f]),
+            (0x80, &[0xcc, 0x80]),
+            (0xff, &[0xcc, 0xff]),
+            (0x100, &[0xcd, 0x01, 0x00]),
+            (0xffff, &[0xcd, 0xff, 0xff]),
+            (0x1_0000, &[0xce, 0x00, 0x01, 0x00, 0x00]),
+            (0xffff_ffff, &[0xce, 0xff, 0xff, 0xff, 0xff]),
+            (0x1_0000_0000, &[0xcf, 0, 0, 0, 0x01, 0, 0, 0, 0]),
+            (
+                u64::MAX,
+                &[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+        ];
+        for (n, bytes) in cases {
+            assert_eq!(to_vec(&json!(n)), bytes, "{n}");
+        }
+    }
+
+    #[test]
+    fn negative_integers_switch_format_at_each_boundary() {
+        let cases: [(i64, &[u8]); 9] = [
+            (-1, &[0xff]),
+            (-32, &[0xe0]),
+            (-33, &[0xd0, 0xdf]),
+            (-0x80, &[0xd0, 0x80]),
+            (-0x81, &[0xd1, 0xff, 0x7f]),
+            (-0x8000, &[0xd1, 0x80, 0x00]),
+            (-0x8001, &[0xd2, 0xff, 0xff, 0x7f, 0xff]),
+            (-0x8000_0000, &[0xd2, 0x80, 0x00, 0x00, 0x00]),
+            (
+                -0x8000_0001,
+                &[0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff],
+            ),
+        ];
+        for (n, bytes) in cases {
+            assert_eq!(to_vec(&json!(n)), bytes, "{n}");
+        }
+    }
+
+    #[test]
+    fn strings_switch_format_at_each_boundary() {
+        assert_eq!(to_vec(&json!("")), [0xa0]);
+        assert_eq!(to_vec(&json!("fee")), [0xa3, b'f', b'e', b'e']);
+        let cases: [(usize, &[u8]); 6] = [
+            (31, &[0xbf]),
+            (32, &[0xd9, 0x20]),
+            (0xff, &[0xd9, 0xff]),
+            (0x100, &[0xda, 0x01, 0x00]),
+            (0xffff, &[0xda, 0xff, 0xff]),
+            (0x1_0000, &[0xdb, 0x00, 0x01, 0x00, 0x00]),
+        ];
+        for (len, bytes) in cases {
+            assert_eq!(header(json!("x".repeat(len)), len), bytes, "{len}");
+        }
+    }
+
+    #[test]
+    fn arrays_and_maps_switch_format_at_each_boundary() {
+        assert_eq!(to_vec(&json!([])), [0x90]);
+        assert_eq!(to_vec(&json!({})), [0x80]);
+        assert_eq!(to_vec(&json!([1, [2]])), [0x92, 0x01, 0x91, 0x02]);
+        assert_eq!(
+            to_vec(&json!({"a": 1, "b": [true]})),
+            [0x82, 0xa1, b'a', 0x01, 0xa1, b'b', 0x91, 0xc3]
+        );
+
+        let cases: [(usize, &[u8]); 4] = [
+            (15, &[0x9f]),
+            (16, &[0xdc, 0x00, 0x10]),
+            (0xffff, &[0xdc, 0xff, 0xff]),
+            (0x1_0000, &[0xdd, 0x00, 0x01, 0x00, 0x00]),
+        ];
+        for (len, bytes) in cases {
+            assert_eq!(header(json!(vec![0; len]), len), bytes, "{len}");
+        }
+
+        let map = |len: usize| {
+            let map: serde_json::Map<String, Value> =
+                (0..len).map(|i| (format!("{i:05}"), json!(0))).collect();
+            Value::Object(map)
+        };
+        // Each entry is a 6-byte key and a 1-byte value
+        let cases: [(usize, &[u8]); 4] = [
+            (15, &[0x8f]),
+            (16, &[0xde, 0x00, 0x10]),
+            (0xffff, &[0xde, 0xff, 0xff]),
+            (0x1_0000, &[0xdf, 0x00, 0x01, 0x00, 0x00]),
+        ];
+        for (len, bytes) in cases {
+            assert_eq!(header(map(len), len * 7), bytes, "{len}");
+        }
+    }
+}
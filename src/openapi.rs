@@ -0,0 +1,1286 @@
+//! The OpenAPI 3 document served at `GET /openapi.json`, describing every endpoint, its
+//! parameters and its responses, for generating typed clients.
+//!
+//! It's written out here beside the handlers it describes, whose JSON is built by hand
+//! rather than from types a schema could be derived from; a change to a handler's
+//! parameters or fields belongs here too.
+
+use serde_json::{Map, Value, json};
+
+/// The document.
+pub fn document() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ivy-priority-fee",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Solana priority fee estimates, from the fees recent transactions \
+                paid. Every path is also served under /v1/, where JSON responses are wrapped \
+                as {\"data\": ..., \"meta\": ..., \"error\": ...}; see the Envelope schema."
+        },
+        "paths": paths(),
+        "components": {
+            "parameters": parameters(),
+            "responses": responses(),
+            "schemas": schemas(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "apiKey": { "type": "apiKey", "in": "query", "name": "api-key" }
+            }
+        },
+        // Only enforced when API_KEYS is set
+        "security": [{}, { "bearer": [] }, { "apiKey": [] }]
+    })
+}
+
+// --------------------------- Paths ---------------------------
+
+fn paths() -> Value {
+    let fee_params = ["percentile", "includeFailed", "aggregation", "cu"];
+    let mut simulate_estimate = post(
+        "simulateEstimate",
+        "A compute unit limit and price for a transaction, by simulating it",
+        "TransactionEstimateRequest",
+        with_errors(
+            json!({
+                "200": json_response("OK", "SimulatedEstimate"),
+                "422": json_response("The transaction failed in simulation", "SimulationFailure")
+            }),
+            &["400", "502", "503"],
+        ),
+    );
+    simulate_estimate["parameters"] = json!(refs(&["commitment"]));
+    json!({
+        "/": {
+            "get": get(
+                "getFee",
+                "The primary program's fee",
+                &[&fee_params[..], &["format", "rpcUrl"]].concat(),
+                json!({
+                    "200": {
+                        "description": "The estimate, or just the fee with ?format=text",
+                        "content": {
+                            "application/json": { "schema": schema("Fee") },
+                            "text/plain": { "schema": { "type": "integer" } }
+                        }
+                    },
+                    "304": reference("NotModified"),
+                    "400": reference("BadRequest"),
+                    "403": reference("Forbidden"),
+                    "503": reference("Unavailable")
+                }),
+            ),
+            "post": {
+                "operationId": "jsonRpc",
+                "summary": "JSON-RPC 2.0 calls, singly or batched",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": {
+                        "oneOf": [
+                            schema("JsonRpcRequest"),
+                            { "type": "array", "items": schema("JsonRpcRequest") }
+                        ]
+                    } } }
+                },
+                "responses": {
+                    "200": {
+                        "description": "The response to each call that isn't a notification",
+                        "content": { "application/json": { "schema": {
+                            "oneOf": [
+                                schema("JsonRpcResponse"),
+                                { "type": "array", "items": schema("JsonRpcResponse") }
+                            ]
+                        } } }
+                    },
+                    "204": { "description": "Only notifications were sent" }
+                }
+            }
+        },
+        "/levels": {
+            "get": get(
+                "getLevels",
+                "The primary program's fee levels",
+                &[&fee_params[..], &["rpcUrl"]].concat(),
+                ok_json("Levels", &["304", "400", "403", "503"]),
+            )
+        },
+        "/fee": {
+            "get": get(
+                "getFees",
+                "Every tracked program's fee, and an aggregate over them all",
+                &fee_params,
+                ok_json("Fees", &["304", "400"]),
+            )
+        },
+        "/fee/{program}": {
+            "get": get(
+                "getProgramFee",
+                "A tracked program's fee",
+                &[&fee_params[..], &["programPath", "format", "rpcUrl"]].concat(),
+                json!({
+                    "200": {
+                        "description": "The estimate, or just the fee with ?format=text",
+                        "content": {
+                            "application/json": { "schema": schema("Fee") },
+                            "text/plain": { "schema": { "type": "integer" } }
+                        }
+                    },
+                    "304": reference("NotModified"),
+                    "400": reference("BadRequest"),
+                    "403": reference("Forbidden"),
+                    "404": reference("NotFound"),
+                    "503": reference("Unavailable")
+                }),
+            )
+        },
+        "/{cluster}/fee": {
+            "get": get(
+                "getClusterFees",
+                "Every tracked program's fee on a configured cluster",
+                &[&fee_params[..], &["cluster"]].concat(),
+                ok_json("Fees", &["304", "400", "404"]),
+            )
+        },
+        "/estimate": {
+            "post": post(
+                "estimateTransaction",
+                "A fee for a transaction, weighted toward its local fee markets",
+                "TransactionEstimateRequest",
+                ok_json("TransactionEstimate", &["400", "502", "503"]),
+            )
+        },
+        "/simulate-estimate": { "post": simulate_estimate },
+        "/jito-tip": {
+            "get": get(
+                "getJitoTip",
+                "A Jito bundle tip, from the tips sampled transactions paid",
+                &["percentile", "includeFailed", "aggregation", "program"],
+                ok_json("JitoTip", &["400", "404", "503"]),
+            )
+        },
+        "/distribution": {
+            "get": get(
+                "getDistribution",
+                "A histogram of the sampled fees",
+                &[&fee_params[..], &["program"]].concat(),
+                ok_json("Distribution", &["304", "400", "404", "503"]),
+            )
+        },
+        "/congestion": {
+            "get": get(
+                "getCongestion",
+                "How congested the network is",
+                &["program"],
+                ok_json("Congestion", &["404", "503"]),
+            )
+        },
+        "/hot-accounts": {
+            "get": get(
+                "getHotAccounts",
+                "The accounts sampled transactions contend for most, with their local fee levels",
+                &[&fee_params[..], &["hotAccountsLimit", "program"]].concat(),
+                ok_json("HotAccounts", &["400", "404", "503"]),
+            )
+        },
+        "/explain": {
+            "get": get(
+                "explainEstimate",
+                "How the estimate was made: each stage of the refresh, then the fee's selection and clamping",
+                &[&fee_params[..], &["program"]].concat(),
+                ok_json("Explanation", &["400", "404", "503"]),
+            )
+        },
+        "/samples": {
+            "get": get(
+                "getSamples",
+                "The transactions the latest estimate was read from; admin only",
+                &["samplesLimit", "program"],
+                ok_json("Samples", &["400", "401", "403", "404", "503"]),
+            )
+        },
+        "/compare": {
+            "get": get(
+                "compareProviders",
+                "A program's fee from each configured RPC endpoint, and how far they diverge; admin only",
+                &[&fee_params[..], &["program"]].concat(),
+                ok_json("Comparison", &["400", "401", "403", "404"]),
+            )
+        },
+        "/target": {
+            "get": get(
+                "getTargetFee",
+                "The fee to land within a number of slots",
+                &["slots", "confidence", "includeFailed", "program"],
+                ok_json("TargetFee", &["400", "404", "502", "503"]),
+            )
+        },
+        "/account/{account}/fee": {
+            "get": get(
+                "getAccountFee",
+                "The fee to write-lock an account",
+                &[&fee_params[..], &["account", "accountSource"]].concat(),
+                ok_json("AccountFee", &["400", "503"]),
+            )
+        },
+        "/stream": {
+            "get": get(
+                "streamFees",
+                "Server-sent events: a fee event each refresh, or an error event",
+                &[&fee_params[..], &["program"]].concat(),
+                json!({
+                    "200": {
+                        "description": "`fee` events carry a StreamedFee, `error` events an ErrorBody",
+                        "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                    },
+                    "400": reference("BadRequest"),
+                    "404": reference("NotFound")
+                }),
+            )
+        },
+        "/wait": {
+            "get": get(
+                "waitForChange",
+                "Long-poll until the fee moves by minChangePct percent, or the timeout",
+                &[
+                    &fee_params[..],
+                    &["program", "minChangePct", "timeout", "lastFee", "format"],
+                ]
+                .concat(),
+                json!({
+                    "200": {
+                        "description": "The latest estimate, and whether it moved",
+                        "content": {
+                            "application/json": { "schema": schema("WaitResult") },
+                            "text/plain": { "schema": { "type": "integer" } }
+                        }
+                    },
+                    "400": reference("BadRequest"),
+                    "404": reference("NotFound"),
+                    "503": reference("Unavailable")
+                }),
+            )
+        },
+        "/ws": {
+            "get": {
+                "operationId": "subscribe",
+                "summary": "A WebSocket that pushes the fee when it moves; see the README",
+                "responses": {
+                    "101": { "description": "Switching to the WebSocket protocol" },
+                    "400": reference("BadRequest")
+                }
+            }
+        },
+        "/history": {
+            "get": get(
+                "getHistory",
+                "A program's fee percentiles over time",
+                &["program", "window", "step"],
+                ok_json("History", &["400", "404", "500"]),
+            )
+        },
+        "/history/export": {
+            "get": get(
+                "exportHistory",
+                "Every recorded point in a time range, as a file",
+                &["exportFormat", "exportProgram", "from", "to"],
+                json!({
+                    "200": {
+                        "description": "The points, as an attachment",
+                        "content": {
+                            "text/csv": { "schema": { "type": "string" } },
+                            "application/vnd.apache.parquet": {
+                                "schema": { "type": "string", "format": "binary" }
+                            }
+                        }
+                    },
+                    "400": reference("BadRequest"),
+                    "404": reference("NotFound"),
+                    "500": reference("Internal")
+                }),
+            )
+        },
+        "/graphql": {
+            "get": get(
+                "graphqlQuery",
+                "Run a GraphQL query against graphql/schema.graphql",
+                &["graphqlQuery", "graphqlVariables", "operationName"],
+                graphql_responses(),
+            ),
+            "post": post(
+                "graphqlPost",
+                "Run a GraphQL query against graphql/schema.graphql",
+                "GraphqlRequest",
+                graphql_responses(),
+            )
+        },
+        "/graphql/schema": {
+            "get": {
+                "operationId": "getGraphqlSchema",
+                "summary": "The GraphQL schema, in SDL",
+                "responses": {
+                    "200": {
+                        "description": "The schema",
+                        "content": { "text/plain": { "schema": { "type": "string" } } }
+                    }
+                }
+            }
+        },
+        "/health": { "get": probe("getHealth", "Whether the process is up") },
+        "/livez": { "get": probe("getLivez", "Whether the process is up") },
+        "/readyz": {
+            "get": get(
+                "getReadyz",
+                "Whether every program has a recent estimate",
+                &[],
+                json!({
+                    "200": json_response("Ready", "Readiness"),
+                    "503": json_response("Not ready", "Readiness")
+                }),
+            )
+        },
+        "/version": {
+            "get": get("getVersion", "The build and its features", &[], ok_json("Version", &[]))
+        },
+        "/openapi.json": {
+            "get": {
+                "operationId": "getOpenApi",
+                "summary": "This document",
+                "responses": {
+                    "200": {
+                        "description": "The OpenAPI document",
+                        "content": { "application/json": { "schema": { "type": "object" } } }
+                    }
+                }
+            }
+        },
+        "/admin/usage": {
+            "get": get(
+                "getUsage",
+                "Each API key's requests, today and on earlier days",
+                &[],
+                ok_json("Usage", &["401", "403"]),
+            )
+        },
+        "/admin/refresh": {
+            "post": {
+                "operationId": "refresh",
+                "summary": "Refresh now, rather than waiting for the next interval",
+                "parameters": refs(&[&fee_params[..], &["program"]].concat()),
+                "responses": {
+                    "200": json_response("Each program's new estimate", "Refreshed"),
+                    "400": reference("BadRequest"),
+                    "404": reference("NotFound"),
+                    "503": json_response("A refresh failed, or shutdown has begun", "Refreshed")
+                }
+            }
+        },
+        "/admin/limits": {
+            "get": get("getLimits", "The fee floor and clamp", &[], ok_json("Limits", &["401", "403"])),
+            "put": {
+                "operationId": "setLimits",
+                "summary": "Override the fee floor or clamp",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": schema("FeeLimits") } }
+                },
+                "responses": with_errors(
+                    json!({ "200": json_response("The limits now in effect", "Limits") }),
+                    &["400", "401", "403"],
+                )
+            }
+        },
+        "/admin/webhooks": {
+            "get": get(
+                "getWebhooks",
+                "The configured and registered webhooks",
+                &[],
+                ok_json("Webhooks", &["401", "403"]),
+            ),
+            "post": {
+                "operationId": "registerWebhook",
+                "summary": "Register a webhook",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": schema("WebhookRequest") } }
+                },
+                "responses": with_errors(
+                    json!({ "201": json_response("The registered webhook", "RegisteredWebhook") }),
+                    &["400", "401", "403"],
+                )
+            }
+        },
+        "/admin/webhooks/{id}": {
+            "delete": {
+                "operationId": "unregisterWebhook",
+                "summary": "Unregister a webhook",
+                "parameters": refs(&["webhookId"]),
+                "responses": with_errors(
+                    json!({ "204": { "description": "Unregistered" } }),
+                    &["401", "403", "404"],
+                )
+            }
+        },
+        "/admin/reload": {
+            "post": {
+                "operationId": "reloadConfig",
+                "summary": "Re-read the configuration",
+                "responses": with_errors(
+                    json!({ "200": json_response("Reloaded", "Reloaded") }),
+                    &["400", "401", "403"],
+                )
+            }
+        }
+    })
+}
+
+/// A GraphQL response, with field errors alongside the data, or a 400 with only errors
+/// if the document can't be run.
+fn graphql_responses() -> Value {
+    with_errors(
+        json!({
+            "200": json_response("OK", "GraphqlResponse"),
+            "400": json_response("The document can't be run", "GraphqlResponse")
+        }),
+        &["401", "403"],
+    )
+}
+
+fn get(id: &str, summary: &str, params: &[&str], responses: Value) -> Value {
+    json!({
+        "operationId": id,
+        "summary": summary,
+        "parameters": refs(params),
+        "responses": responses
+    })
+}
+
+fn post(id: &str, summary: &str, body: &str, responses: Value) -> Value {
+    json!({
+        "operationId": id,
+        "summary": summary,
+        "requestBody": {
+            "required": true,
+            "content": { "application/json": { "schema": schema(body) } }
+        },
+        "responses": responses
+    })
+}
+
+fn probe(id: &str, summary: &str) -> Value {
+    json!({
+        "operationId": id,
+        "summary": summary,
+        "security": [],
+        "responses": {
+            "200": {
+                "description": "ok",
+                "content": { "text/plain": { "schema": { "type": "string" } } }
+            }
+        }
+    })
+}
+
+/// A 200 of `schema`, then the shared responses for each status in `errors`.
+fn ok_json(schema: &str, errors: &[&str]) -> Value {
+    with_errors(json!({ "200": json_response("OK", schema) }), errors)
+}
+
+fn with_errors(mut responses: Value, errors: &[&str]) -> Value {
+    for &status in errors {
+        let name = match status {
+            "304" => "NotModified",
+            "400" => "BadRequest",
+            "401" => "Unauthorized",
+            "403" => "Forbidden",
+            "404" => "NotFound",
+            "502" => "RpcUnavailable",
+            "503" => "Unavailable",
+            _ => "Internal",
+        };
+        responses[status] = reference(name);
+    }
+    responses
+}
+
+fn json_response(description: &str, name: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema(name) } }
+    })
+}
+
+fn refs(params: &[&str]) -> Vec<Value> {
+    params
+        .iter()
+        .map(|p| json!({ "$ref": format!("#/components/parameters/{}", p) }))
+        .collect()
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/responses/{}", name) })
+}
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+// --------------------------- Components ---------------------------
+
+fn parameters() -> Value {
+    let query = |name: &str, description: &str, schema: Value| json!({ "name": name, "in": "query", "description": description, "schema": schema });
+    let path = |name: &str, description: &str, schema: Value| {
+        json!({
+            "name": name,
+            "in": "path",
+            "required": true,
+            "description": description,
+            "schema": schema
+        })
+    };
+    let string = json!({ "type": "string" });
+    json!({
+        "percentile": query(
+            "percentile",
+            "Percentile of the sampled fees to estimate at",
+            json!({ "type": "number", "minimum": 0, "maximum": 100 }),
+        ),
+        "includeFailed": query(
+            "includeFailed",
+            "Whether to sample failed transactions too, overriding INCLUDE_FAILED",
+            json!({ "type": "boolean" }),
+        ),
+        "aggregation": query(
+            "aggregation",
+            "percentile, trimmed_mean[:pct], winsorized[:pct], cu_weighted, recency_weighted[:slots], per_slot or per_slot_max",
+            string.clone(),
+        ),
+        "cu": query(
+            "cu",
+            "Compute units, to price the fee in lamports and SOL",
+            json!({ "type": "integer", "minimum": 0, "maximum": 1_400_000 }),
+        ),
+        "format": query(
+            "format",
+            "text for just the fee, msgpack for MessagePack; JSON otherwise",
+            json!({ "type": "string", "enum": ["json", "text", "msgpack"] }),
+        ),
+        "program": query(
+            "program",
+            "A tracked program; the first configured one by default",
+            string.clone(),
+        ),
+        "programPath": path("program", "A tracked program", string.clone()),
+        "rpcUrl": {
+            "name": "X-Rpc-Url",
+            "in": "header",
+            "description": "An endpoint in RPC_URL_ALLOWLIST to sample instead of the configured ones",
+            "schema": { "type": "string", "format": "uri" }
+        },
+        "cluster": path(
+            "cluster",
+            "CLUSTER, or one of CLUSTERS",
+            string.clone(),
+        ),
+        "account": path("account", "An account address", string.clone()),
+        "accountSource": query(
+            "source",
+            "samples or recent; sampled transactions first, falling back to recent, by default",
+            json!({ "type": "string", "enum": ["samples", "recent"] }),
+        ),
+        "commitment": query(
+            "commitment",
+            "The commitment to simulate at, overriding COMMITMENT",
+            json!({ "type": "string", "enum": ["processed", "confirmed", "finalized"] }),
+        ),
+        "hotAccountsLimit": query(
+            "limit",
+            "Most accounts to list",
+            json!({ "type": "integer", "minimum": 1, "default": 10 }),
+        ),
+        "samplesLimit": query(
+            "limit",
+            "Most samples to list, newest first",
+            json!({ "type": "integer", "minimum": 1, "default": 100 }),
+        ),
+        "slots": {
+            "name": "slots",
+            "in": "query",
+            "required": true,
+            "description": "How many slots after its blockhash's the transaction should land within",
+            "schema": { "type": "integer", "minimum": 1, "maximum": 150 }
+        },
+        "confidence": query(
+            "confidence",
+            "Share of transactions paying the fee that must have landed in time",
+            json!({ "type": "number", "minimum": 0, "maximum": 1, "exclusiveMinimum": true, "exclusiveMaximum": true, "default": 0.9 }),
+        ),
+        "minChangePct": query(
+            "minChangePct",
+            "How far the fee must move, in percent",
+            json!({ "type": "number", "minimum": 0, "default": 0 }),
+        ),
+        "timeout": query(
+            "timeout",
+            "Longest to wait, in seconds",
+            json!({ "type": "integer", "minimum": 1, "maximum": 300, "default": 60 }),
+        ),
+        "lastFee": query(
+            "fee",
+            "The fee last seen, to measure the change from; the current fee by default",
+            json!({ "type": "integer", "minimum": 0 }),
+        ),
+        "window": query(
+            "window",
+            "How far back to go, as a duration such as 6h",
+            json!({ "type": "string", "default": "1h" }),
+        ),
+        "step": query(
+            "step",
+            "Width of each point, as a duration such as 1m",
+            json!({ "type": "string", "default": "1m" }),
+        ),
+        "exportFormat": query(
+            "format",
+            "File format",
+            json!({ "type": "string", "enum": ["csv", "parquet"], "default": "csv" }),
+        ),
+        "exportProgram": query(
+            "program",
+            "Only this tracked program; every program by default",
+            string.clone(),
+        ),
+        "from": query(
+            "from",
+            "Start of the range, in unix seconds; the oldest point kept by default",
+            json!({ "type": "integer" }),
+        ),
+        "to": query(
+            "to",
+            "End of the range, exclusive, in unix seconds; now by default",
+            json!({ "type": "integer" }),
+        ),
+        "graphqlQuery": {
+            "name": "query",
+            "in": "query",
+            "required": true,
+            "description": "The GraphQL document",
+            "schema": string.clone()
+        },
+        "graphqlVariables": query(
+            "variables",
+            "The operation's variables, as a JSON object",
+            string.clone(),
+        ),
+        "operationName": query(
+            "operationName",
+            "Which of the document's operations to run, if it has several",
+            string,
+        ),
+        "webhookId": path("id", "The id it was registered with", json!({ "type": "integer" }))
+    })
+}
+
+fn responses() -> Value {
+    let error = |description: &str| json_response(description, "ErrorBody");
+    json!({
+        "NotModified": { "description": "The If-None-Match tag still matches" },
+        "BadRequest": error("A parameter or the body is invalid"),
+        "Unauthorized": error("No valid API key"),
+        "Forbidden": error(
+            "The key may not call this endpoint, no admin key is configured for it, or the RPC URL isn't allowlisted",
+        ),
+        "NotFound": error("The program or resource isn't known"),
+        "RpcUnavailable": error("The RPC failed"),
+        "Unavailable": error("No estimate yet, or the latest refresh failed"),
+        "Internal": error("The server failed")
+    })
+}
+
+fn schemas() -> Value {
+    let int = json!({ "type": "integer", "format": "int64" });
+    let nullable_int = json!({ "type": "integer", "format": "int64", "nullable": true });
+    let number = json!({ "type": "number" });
+    let string = json!({ "type": "string" });
+    let nullable_string = json!({ "type": "string", "nullable": true });
+    let strings = json!({ "type": "array", "items": { "type": "string" } });
+    let boolean = json!({ "type": "boolean" });
+    let ci95 = json!({ "type": "array", "items": int, "minItems": 2, "maxItems": 2 });
+    let sample_metadata = [
+        ("stale", boolean.clone()),
+        ("lowConfidence", boolean.clone()),
+        ("sampleSize", int.clone()),
+        ("oldestSlot", nullable_int.clone()),
+        ("newestSlot", nullable_int.clone()),
+        ("computedAt", int.clone()),
+        ("ageSeconds", int.clone()),
+    ];
+    let trend = [
+        (
+            "trend",
+            json!({ "type": "string", "enum": ["rising", "falling", "stable"], "nullable": true }),
+        ),
+        (
+            "forecast",
+            json!({
+                "type": "object",
+                "nullable": true,
+                "description": "The fee forecast for each of the next few minutes, keyed 1m to 5m",
+                "additionalProperties": int
+            }),
+        ),
+    ];
+    let fee_fields = [
+        ("reasonablePriorityFee", int.clone()),
+        ("ci95", ci95.clone()),
+        ("smoothedPriorityFee", int.clone()),
+        ("computeUnitLimit", int.clone()),
+        ("percentile", number.clone()),
+        ("aggregation", string.clone()),
+        ("minSlot", nullable_int.clone()),
+        ("maxSlot", nullable_int.clone()),
+        ("source", string.clone()),
+        ("lastUpdated", int.clone()),
+    ];
+    let priced = [
+        ("computeUnits", int.clone()),
+        ("priorityFeeLamports", int.clone()),
+        ("priorityFeeSol", number.clone()),
+    ];
+    let fee = [&fee_fields[..], &trend, &sample_metadata].concat();
+    let fee_required: Vec<&str> = fee.iter().map(|(name, _)| *name).collect();
+    let fee_schema = |extra: &[(&str, Value)], required: &[&str]| {
+        object(
+            &[&fee[..], &priced, extra].concat(),
+            &[&fee_required[..], required].concat(),
+        )
+    };
+    let levels = [
+        ("priorityFeeLevels", schema("PriorityFeeLevels")),
+        ("computeUnitLimit", int.clone()),
+        ("smoothedPriorityFeeLevels", schema("PriorityFeeLevels")),
+        ("minSlot", nullable_int.clone()),
+        ("maxSlot", nullable_int.clone()),
+        ("aggregation", string.clone()),
+        ("source", string.clone()),
+        ("lastUpdated", int.clone()),
+    ];
+    let levels_all = [&levels[..], &sample_metadata].concat();
+    let program_fee = [
+        &[
+            ("reasonablePriorityFee", int.clone()),
+            ("ci95", ci95.clone()),
+            ("smoothedPriorityFee", int.clone()),
+            ("computeUnitLimit", int.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ][..],
+        &trend,
+        &sample_metadata,
+    ]
+    .concat();
+    let aggregate_fee = [
+        &[
+            ("reasonablePriorityFee", int.clone()),
+            ("ci95", ci95.clone()),
+            ("computeUnitLimit", int.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ][..],
+        &sample_metadata,
+    ]
+    .concat();
+    let or_error = |value: Value| json!({ "oneOf": [value, schema("ErrorBody")] });
+    // Written out where nullable, as `nullable` doesn't apply to a `$ref`
+    let error = required_object(&[
+        (
+            "code",
+            json!({ "type": "string", "example": "RPC_UNAVAILABLE" }),
+        ),
+        ("message", string.clone()),
+        ("retryable", boolean.clone()),
+    ]);
+    let fee_levels = required_object(&[
+        ("min", int.clone()),
+        ("low", int.clone()),
+        ("medium", int.clone()),
+        ("high", int.clone()),
+        ("veryHigh", int.clone()),
+        ("unsafeMax", int.clone()),
+    ]);
+    let performance = required_object(&[
+        ("tps", number.clone()),
+        ("nonVoteTps", json!({ "type": "number", "nullable": true })),
+        ("slotTimeMs", number.clone()),
+        ("samples", int.clone()),
+    ]);
+    json!({
+        "Error": error,
+        "ErrorBody": object(&[("error", schema("Error"))], &["error"]),
+        "Envelope": object(
+            &[
+                ("data", json!({ "nullable": true, "description": "The unversioned path's response" })),
+                ("meta", object(&[("apiVersion", string.clone())], &["apiVersion"])),
+                ("error", nullable(&error)),
+            ],
+            &["data", "meta", "error"],
+        ),
+        "PriorityFeeLevels": fee_levels,
+        "Fee": fee_schema(&[], &[]),
+        "StreamedFee": fee_schema(&[("program", string.clone())], &["program"]),
+        "WaitResult": fee_schema(
+            &[("program", string.clone()), ("changed", boolean.clone())],
+            &["program", "changed"],
+        ),
+        "AccountFee": fee_schema(
+            &[("account", string.clone()), ("samples", int.clone())],
+            &["account", "samples"],
+        ),
+        "Levels": required_object(&levels_all),
+        "Fees": object(
+            &[
+                ("percentile", number.clone()),
+                ("aggregation", string.clone()),
+                (
+                    "programs",
+                    json!({
+                        "type": "object",
+                        "additionalProperties": or_error(required_object(&program_fee))
+                    }),
+                ),
+                ("aggregate", or_error(required_object(&aggregate_fee))),
+            ],
+            &["percentile", "aggregation", "programs", "aggregate"],
+        ),
+        "TransactionEstimateRequest": object(
+            &[
+                ("transaction", json!({ "type": "string", "description": "The serialized transaction" })),
+                ("encoding", json!({ "type": "string", "enum": ["base64", "base58"], "default": "base64" })),
+                ("percentile", json!({ "type": "number", "minimum": 0, "maximum": 100 })),
+                ("includeFailed", boolean.clone()),
+            ],
+            &["transaction"],
+        ),
+        "TransactionEstimate": required_object(&[
+            ("reasonablePriorityFee", int.clone()),
+            ("computeUnitLimit", int.clone()),
+            ("percentile", number.clone()),
+            ("writableAccounts", strings.clone()),
+            ("localSamples", int.clone()),
+            ("totalSamples", int.clone()),
+            ("programs", strings.clone()),
+            ("computeUnitPrice", nullable_int.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "SimulatedEstimate": required_object(&[
+            ("computeUnitLimit", int.clone()),
+            ("computeUnitPrice", int.clone()),
+            ("unitsConsumed", int.clone()),
+            ("baseFeeLamports", int.clone()),
+            ("priorityFeeLamports", int.clone()),
+            ("totalFeeLamports", int.clone()),
+            ("percentile", number.clone()),
+            ("localSamples", int.clone()),
+            ("totalSamples", int.clone()),
+            ("programs", strings.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "SimulationFailure": object(
+            &[
+                ("error", schema("Error")),
+                ("simulationError", json!({ "description": "The RPC's `err`, as it gave it" })),
+                ("logs", json!({ "type": "array", "items": string, "nullable": true })),
+            ],
+            &["error", "simulationError"],
+        ),
+        "JitoTip": required_object(&[
+            ("jitoTip", nullable_int.clone()),
+            ("jitoTipSol", json!({ "type": "number", "nullable": true })),
+            ("jitoTipLevels", nullable(&fee_levels)),
+            ("percentile", number.clone()),
+            ("tippedSamples", int.clone()),
+            ("totalSamples", int.clone()),
+            ("programs", strings.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "Bucket": object(
+            &[("min", int.clone()), ("max", int.clone()), ("count", int.clone())],
+            &["min", "max", "count"],
+        ),
+        "Distribution": required_object(&[
+            ("buckets", json!({ "type": "array", "items": schema("Bucket") })),
+            ("min", int.clone()),
+            ("max", int.clone()),
+            ("mean", number.clone()),
+            ("stddev", number.clone()),
+            ("sampleSize", int.clone()),
+            ("program", string.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "Performance": performance,
+        "Congestion": required_object(&[
+            ("level", json!({ "type": "string", "enum": ["low", "normal", "high", "extreme"] })),
+            ("medianFee", int.clone()),
+            ("priorityFeeLevels", schema("PriorityFeeLevels")),
+            ("performance", nullable(&performance)),
+            ("programs", strings.clone()),
+            ("sampleSize", int.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "HotAccount": required_object(&[
+            ("account", string.clone()),
+            ("transactions", int.clone()),
+            ("payers", json!({ "type": "integer", "description": "Distinct fee payers among the transactions" })),
+            ("share", json!({ "type": "number", "description": "Fraction of the sampled transactions" })),
+            ("reasonablePriorityFee", int.clone()),
+            ("priorityFeeLevels", schema("PriorityFeeLevels")),
+        ]),
+        "HotAccounts": required_object(&[
+            ("accounts", json!({ "type": "array", "items": schema("HotAccount") })),
+            ("percentile", number.clone()),
+            ("aggregation", string.clone()),
+            ("totalSamples", int.clone()),
+            ("programs", strings.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "ProviderEstimate": {
+            "oneOf": [
+                required_object(&[
+                    ("url", string.clone()),
+                    ("reasonablePriorityFee", int.clone()),
+                    ("feeDeviation", json!({ "type": "number", "nullable": true, "description": "Relative to the median endpoint's fee; null if that's 0" })),
+                    ("slotLag", json!({ "type": "integer", "nullable": true, "description": "Slots behind the endpoint with the newest sample" })),
+                    ("newestSlot", nullable_int.clone()),
+                    ("sampleSize", int.clone()),
+                    ("source", string.clone()),
+                    ("stale", boolean.clone()),
+                    ("lastUpdated", int.clone()),
+                ]),
+                required_object(&[("url", string.clone()), ("error", schema("Error"))]),
+            ]
+        },
+        "Comparison": required_object(&[
+            ("program", string.clone()),
+            ("percentile", number.clone()),
+            ("aggregation", string.clone()),
+            ("providers", json!({ "type": "array", "items": schema("ProviderEstimate") })),
+            (
+                "divergence",
+                required_object(&[
+                    ("feeSpread", nullable_int.clone()),
+                    ("relativeFeeSpread", nullable(&number)),
+                    ("maxSlotLag", nullable_int.clone()),
+                    ("failed", json!({ "type": "integer", "description": "Endpoints without an estimate" })),
+                ]),
+            ),
+        ]),
+        "Stage": required_object(&[
+            ("name", json!({ "type": "string", "enum": ["sample", "minComputeUnits", "maxSampleAge", "select"] })),
+            ("source", string.clone()),
+            ("samplesIn", nullable_int.clone()),
+            ("samplesOut", json!({ "type": "integer", "nullable": true, "description": "Null if the stage failed" })),
+            ("durationMs", number.clone()),
+            ("error", nullable_string.clone()),
+        ]),
+        "Explanation": required_object(&[
+            ("program", string.clone()),
+            ("configuredSource", string.clone()),
+            ("source", string.clone()),
+            ("fallbackReason", nullable_string.clone()),
+            ("stages", json!({ "type": "array", "items": schema("Stage"), "nullable": true })),
+            ("durationMs", json!({ "type": "number", "nullable": true })),
+            ("targetSamples", nullable_int.clone()),
+            ("minSampleFraction", number.clone()),
+            ("lowConfidence", boolean.clone()),
+            ("includeFailed", boolean.clone()),
+            ("sampleSize", int.clone()),
+            ("selected", int.clone()),
+            ("aggregation", string.clone()),
+            ("percentile", number.clone()),
+            ("rawFee", int.clone()),
+            ("minFee", int.clone()),
+            ("maxFee", int.clone()),
+            ("clamped", json!({ "type": "string", "enum": ["floor", "ceiling"], "nullable": true })),
+            ("reasonablePriorityFee", int.clone()),
+            ("smoothedPriorityFee", nullable_int.clone()),
+            ("stale", boolean.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "Sample": required_object(&[
+            ("signature", nullable_string.clone()),
+            ("price", json!({ "type": "integer", "description": "Micro-lamports per CU, as set or derived from the fee" })),
+            ("feeLamports", nullable_int.clone()),
+            ("failed", boolean.clone()),
+            ("computeUnits", nullable_int.clone()),
+            ("slot", nullable_int.clone()),
+            ("blockTime", nullable_int.clone()),
+            ("jitoTip", nullable_int.clone()),
+            ("writableAccounts", strings.clone()),
+            ("recentBlockhash", nullable_string.clone()),
+        ]),
+        "Samples": required_object(&[
+            ("samples", json!({ "type": "array", "items": schema("Sample") })),
+            ("sampleSize", int.clone()),
+            ("selected", int.clone()),
+            ("includeFailed", boolean.clone()),
+            ("program", string.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "TargetFee": required_object(&[
+            ("priorityFee", json!({ "type": "integer", "nullable": true, "description": "Null if no fee landed in time often enough" })),
+            ("slots", int.clone()),
+            ("confidence", number.clone()),
+            ("landingProbability", json!({ "type": "number", "nullable": true })),
+            ("payingSamples", int.clone()),
+            ("medianLatencySlots", nullable_int.clone()),
+            ("sampleSize", int.clone()),
+            ("program", string.clone()),
+            ("source", string.clone()),
+            ("lastUpdated", int.clone()),
+        ]),
+        "HistoryPoint": object(
+            &[
+                ("program", string.clone()),
+                ("timestamp", int.clone()),
+                ("p25", int.clone()),
+                ("p50", int.clone()),
+                ("p75", int.clone()),
+                ("p90", int.clone()),
+                ("sampleSize", int.clone()),
+                ("minSlot", nullable_int.clone()),
+                ("maxSlot", nullable_int.clone()),
+                ("source", string.clone()),
+            ],
+            &["program", "timestamp", "p25", "p50", "p75", "p90", "sampleSize", "minSlot", "maxSlot"],
+        ),
+        "History": object(
+            &[
+                ("program", string.clone()),
+                ("window", int.clone()),
+                ("step", int.clone()),
+                ("points", json!({ "type": "array", "items": schema("HistoryPoint") })),
+            ],
+            &["program", "window", "step", "points"],
+        ),
+        "Readiness": object(
+            &[
+                ("ready", boolean.clone()),
+                ("shuttingDown", boolean.clone()),
+                (
+                    "programs",
+                    json!({
+                        "type": "object",
+                        "additionalProperties": object(
+                            &[
+                                ("ready", boolean.clone()),
+                                ("ageSeconds", nullable_int.clone()),
+                                ("error", nullable(&error)),
+                                ("leader", boolean.clone()),
+                            ],
+                            &["ready", "ageSeconds", "error"],
+                        )
+                    }),
+                ),
+            ],
+            &["ready", "shuttingDown", "programs"],
+        ),
+        "Version": object(
+            &[
+                ("version", string.clone()),
+                ("gitCommit", json!({ "type": "string", "nullable": true })),
+                ("builtAt", json!({ "type": "string", "format": "date-time" })),
+                ("apiVersions", strings.clone()),
+                (
+                    "features",
+                    object(
+                        &[
+                            ("feeSources", strings.clone()),
+                            ("feeSource", string.clone()),
+                            ("persistence", boolean.clone()),
+                            ("tls", boolean.clone()),
+                            ("unixSocket", boolean.clone()),
+                            ("grpc", boolean.clone()),
+                            ("apiKeys", boolean.clone()),
+                            ("rateLimit", boolean.clone()),
+                        ],
+                        &[],
+                    ),
+                ),
+            ],
+            &["version", "gitCommit", "builtAt", "apiVersions", "features"],
+        ),
+        "Usage": object(
+            &[(
+                "keys",
+                json!({
+                    "type": "array",
+                    "items": object(
+                        &[
+                            ("name", string.clone()),
+                            ("admin", boolean.clone()),
+                            ("dailyQuota", nullable_int.clone()),
+                            ("today", int.clone()),
+                            ("remaining", nullable_int.clone()),
+                            (
+                                "days",
+                                json!({
+                                    "type": "array",
+                                    "items": object(
+                                        &[("date", string.clone()), ("requests", int.clone())],
+                                        &["date", "requests"],
+                                    )
+                                }),
+                            ),
+                        ],
+                        &["name", "admin", "dailyQuota", "today", "remaining", "days"],
+                    )
+                }),
+            )],
+            &["keys"],
+        ),
+        "Refreshed": object(
+            &[(
+                "programs",
+                json!({ "type": "object", "additionalProperties": or_error(schema("Fee")) }),
+            )],
+            &["programs"],
+        ),
+        "FeeLimits": object(
+            &[("minFeeFloor", int.clone()), ("maxFeeClamp", int.clone())],
+            &[],
+        ),
+        "Limits": object(
+            &[
+                ("minFeeFloor", int.clone()),
+                ("maxFeeClamp", int.clone()),
+                ("persisted", boolean.clone()),
+            ],
+            &["minFeeFloor", "maxFeeClamp", "persisted"],
+        ),
+        "WebhookRequest": object(
+            &[
+                ("url", string.clone()),
+                ("secret", json!({ "type": "string", "description": "Key of the payload's HMAC-SHA256 signature" })),
+                ("thresholds", json!({ "type": "array", "items": int })),
+                ("changePercent", number.clone()),
+                ("percentile", number.clone()),
+                ("program", string.clone()),
+                ("format", json!({ "type": "string", "enum": ["json", "slack", "discord"], "default": "json" })),
+            ],
+            &["url"],
+        ),
+        "Webhook": object(
+            &[
+                ("url", string.clone()),
+                ("thresholds", json!({ "type": "array", "items": int })),
+                ("changePercent", number.clone()),
+                ("percentile", number.clone()),
+                ("program", string.clone()),
+                ("format", json!({ "type": "string", "enum": ["json", "slack", "discord"] })),
+            ],
+            &["url", "thresholds", "format"],
+        ),
+        "RegisteredWebhook": {
+            "allOf": [
+                schema("Webhook"),
+                object(&[("id", int.clone()), ("signed", boolean.clone())], &["id", "signed"])
+            ]
+        },
+        "Webhooks": object(
+            &[
+                ("configured", json!({ "type": "array", "items": schema("Webhook") })),
+                ("registered", json!({ "type": "array", "items": schema("RegisteredWebhook") })),
+            ],
+            &["configured", "registered"],
+        ),
+        "Reloaded": object(&[("reloaded", boolean)], &["reloaded"]),
+        "GraphqlRequest": object(
+            &[
+                ("query", string.clone()),
+                ("operationName", nullable(&string)),
+                ("variables", json!({ "type": "object", "nullable": true })),
+            ],
+            &["query"],
+        ),
+        "GraphqlResponse": object(
+            &[
+                ("data", json!({ "type": "object", "nullable": true })),
+                (
+                    "errors",
+                    json!({
+                        "type": "array",
+                        "items": object(
+                            &[
+                                ("message", string.clone()),
+                                ("path", json!({ "type": "array", "items": {} })),
+                            ],
+                            &["message"],
+                        )
+                    }),
+                ),
+            ],
+            &[],
+        ),
+        "JsonRpcRequest": object(
+            &[
+                ("jsonrpc", json!({ "type": "string", "enum": ["2.0"] })),
+                ("id", json!({ "description": "Omitted for notifications" })),
+                (
+                    "method",
+                    json!({
+                        "type": "string",
+                        "enum": ["getReasonablePriorityFee", "getPriorityFeeLevels", "getPriorityFeeEstimate"]
+                    }),
+                ),
+                ("params", json!({ "description": "Positional ([{...}]) or named ({...})" })),
+            ],
+            &["jsonrpc", "method"],
+        ),
+        "JsonRpcResponse": object(
+            &[
+                ("jsonrpc", json!({ "type": "string", "enum": ["2.0"] })),
+                ("id", json!({})),
+                ("result", json!({ "description": "The method's result, if it succeeded" })),
+                (
+                    "error",
+                    object(&[("code", int), ("message", string)], &["code", "message"]),
+                ),
+            ],
+            &["jsonrpc", "id"],
+        ),
+    })
+}
+
+/// An object schema with `fields`, of which `required` must be present.
+fn object(fields: &[(&str, Value)], required: &[&str]) -> Value {
+    let properties: Map<String, Value> = fields
+        .iter()
+        .map(|(name, schema)| (name.to_string(), schema.clone()))
+        .collect();
+    let mut value = json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        value["required"] = json!(required);
+    }
+    value
+}
+
+fn nullable(schema: &Value) -> Value {
+    let mut schema = schema.clone();
+    schema["nullable"] = json!(true);
+    schema
+}
+
+/// An object schema with `fields`, all of which must be present.
+fn required_object(fields: &[(&str, Value)]) -> Value {
+    object(fields, &names(fields))
+}
+
+fn names<'a>(fields: &[(&'a str, Value)]) -> Vec<&'a str> {
+    fields.iter().map(|(name, _)| *name).collect()
+}
@@ -0,0 +1,411 @@
+//! Just enough of the Parquet format to write a table of integer, timestamp and string
+//! columns: one row group of uncompressed, PLAIN-encoded pages, which pandas, pyarrow,
+//! DuckDB and Spark all read.
+
+/// A column's values, `None` for nulls.
+pub enum Values {
+    Int64(Vec<Option<i64>>),
+    TimestampMillis(Vec<Option<i64>>), // unix milliseconds
+    String(Vec<Option<String>>),
+}
+
+impl Values {
+    fn len(&self) -> usize {
+        match self {
+            Values::Int64(v) | Values::TimestampMillis(v) => v.len(),
+            Values::String(v) => v.len(),
+        }
+    }
+
+    fn is_null(&self, i: usize) -> bool {
+        match self {
+            Values::Int64(v) | Values::TimestampMillis(v) => v[i].is_none(),
+            Values::String(v) => v[i].is_none(),
+        }
+    }
+}
+
+pub struct Column {
+    pub name: &'static str,
+    pub nullable: bool, // if not, every value is `Some`
+    pub values: Values,
+}
+
+const MAGIC: &[u8] = b"PAR1";
+// Enum values from parquet.thrift
+const TYPE_INT64: i32 = 2;
+const TYPE_BYTE_ARRAY: i32 = 6;
+const REPETITION_REQUIRED: i32 = 0;
+const REPETITION_OPTIONAL: i32 = 1;
+const CONVERTED_UTF8: i32 = 0;
+const CONVERTED_TIMESTAMP_MILLIS: i32 = 9;
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const CODEC_UNCOMPRESSED: i32 = 0;
+const PAGE_DATA: i32 = 0;
+const ENCODING_PLAIN_AND_RLE: [i32; 2] = [ENCODING_PLAIN, ENCODING_RLE];
+
+/// A Parquet file holding `columns`, which must all be as long as each other.
+pub fn write(columns: &[Column], created_by: &str) -> Vec<u8> {
+    let num_rows = columns.first().map_or(0, |c| c.values.len());
+    let mut file = MAGIC.to_vec();
+    let mut chunks = Vec::new();
+    if num_rows > 0 {
+        for column in columns {
+            let offset = file.len();
+            let page = page(column);
+            let mut header = Compact::default();
+            header.i32(1, PAGE_DATA);
+            header.i32(2, page.len() as i32);
+            header.i32(3, page.len() as i32);
+            header.begin_struct(5);
+            header.i32(1, column.values.len() as i32);
+            header.i32(2, ENCODING_PLAIN);
+            header.i32(3, ENCODING_RLE);
+            header.i32(4, ENCODING_RLE);
+            header.end_struct();
+            header.end_struct();
+            file.extend_from_slice(&header.buf);
+            file.extend_from_slice(&page);
+            chunks.push((offset, file.len() - offset));
+        }
+    }
+
+    let mut meta = Compact::default();
+    meta.i32(1, 1); // version
+    meta.begin_list(2, columns.len() + 1);
+    meta.begin_element();
+    meta.binary(4, b"schema");
+    meta.i32(5, columns.len() as i32);
+    meta.end_struct();
+    for column in columns {
+        meta.begin_element();
+        meta.i32(1, physical_type(&column.values));
+        meta.i32(
+            3,
+            match column.nullable {
+                true => REPETITION_OPTIONAL,
+                false => REPETITION_REQUIRED,
+            },
+        );
+        meta.binary(4, column.name.as_bytes());
+        match column.values {
+            Values::Int64(_) => {}
+            Values::TimestampMillis(_) => {
+                meta.i32(6, CONVERTED_TIMESTAMP_MILLIS);
+                meta.begin_struct(10); // LogicalType
+                meta.begin_struct(8); // TimestampType
+                meta.bool(1, true); // isAdjustedToUTC
+                meta.begin_struct(2); // TimeUnit
+                meta.begin_struct(1); // MILLIS
+                meta.end_struct();
+                meta.end_struct();
+                meta.end_struct();
+                meta.end_struct();
+            }
+            Values::String(_) => {
+                meta.i32(6, CONVERTED_UTF8);
+                meta.begin_struct(10); // LogicalType
+                meta.begin_struct(1); // StringType
+                meta.end_struct();
+                meta.end_struct();
+            }
+        }
+        meta.end_struct();
+    }
+    meta.i64(3, num_rows as i64);
+    // Empty files have no row groups, since row groups can't be empty
+    meta.begin_list(4, usize::from(num_rows > 0));
+    if num_rows > 0 {
+        meta.begin_element(); // RowGroup
+        meta.begin_list(1, columns.len());
+        for (column, &(offset, size)) in columns.iter().zip(&chunks) {
+            meta.begin_element(); // ColumnChunk
+            meta.i64(2, offset as i64);
+            meta.begin_struct(3); // ColumnMetaData
+            meta.i32(1, physical_type(&column.values));
+            meta.begin_list_of(2, ENCODING_PLAIN_AND_RLE.len(), TYPE_I32);
+            for encoding in ENCODING_PLAIN_AND_RLE {
+                meta.varint(zigzag(encoding as i64));
+            }
+            meta.begin_list_of(3, 1, TYPE_BINARY);
+            meta.varint(column.name.len() as u64);
+            meta.buf.extend_from_slice(column.name.as_bytes());
+            meta.i32(4, CODEC_UNCOMPRESSED);
+            meta.i64(5, num_rows as i64);
+            meta.i64(6, size as i64);
+            meta.i64(7, size as i64);
+            meta.i64(9, offset as i64);
+            meta.end_struct();
+            meta.end_struct();
+        }
+        meta.i64(2, chunks.iter().map(|(_, size)| *size as i64).sum());
+        meta.i64(3, num_rows as i64);
+        meta.end_struct();
+    }
+    meta.binary(6, created_by.as_bytes());
+    meta.buf.push(0); // end of FileMetaData
+
+    file.extend_from_slice(&meta.buf);
+    file.extend_from_slice(&(meta.buf.len() as u32).to_le_bytes());
+    file.extend_from_slice(MAGIC);
+    file
+}
+
+fn physical_type(values: &Values) -> i32 {
+    match values {
+        Values::Int64(_) | Values::TimestampMillis(_) => TYPE_INT64,
+        Values::String(_) => TYPE_BYTE_ARRAY,
+    }
+}
+
+/// A data page's body: the definition levels of a nullable column, then its non-null
+/// values.
+fn page(column: &Column) -> Vec<u8> {
+    let mut page = Vec::new();
+    if column.nullable {
+        // Runs of 1 (present) and 0 (null), in the RLE/bit-packing hybrid at bit width 1
+        let mut levels = Vec::new();
+        let mut i = 0;
+        while i < column.values.len() {
+            let null = column.values.is_null(i);
+            let run = (i..column.values.len())
+                .take_while(|&j| column.values.is_null(j) == null)
+                .count();
+            write_varint(&mut levels, (run as u64) << 1);
+            levels.push(u8::from(!null));
+            i += run;
+        }
+        page.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+        page.extend_from_slice(&levels);
+    }
+    match &column.values {
+        Values::Int64(v) | Values::TimestampMillis(v) => {
+            for n in v.iter().flatten() {
+                page.extend_from_slice(&n.to_le_bytes());
+            }
+        }
+        Values::String(v) => {
+            for s in v.iter().flatten() {
+                page.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                page.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+    page
+}
+
+// Thrift compact protocol type IDs
+const TYPE_BOOL_TRUE: u8 = 1;
+const TYPE_BOOL_FALSE: u8 = 2;
+const TYPE_I32: u8 = 5;
+const TYPE_I64: u8 = 6;
+const TYPE_BINARY: u8 = 8;
+const TYPE_LIST: u8 = 9;
+const TYPE_STRUCT: u8 = 12;
+
+/// A writer of Thrift's compact protocol, in which Parquet's metadata is encoded.
+/// Fields must be written in increasing order of ID within each struct.
+#[derive(Default)]
+struct Compact {
+    buf: Vec<u8>,
+    last_id: i16,        // of the current struct's last field
+    outer_ids: Vec<i16>, // `last_id`s of the structs the current one is nested in
+}
+
+impl Compact {
+    fn field(&mut self, id: i16, kind: u8) {
+        let delta = id - self.last_id;
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | kind);
+        } else {
+            self.buf.push(kind);
+            self.varint(zigzag(id as i64));
+        }
+        self.last_id = id;
+    }
+
+    fn varint(&mut self, n: u64) {
+        write_varint(&mut self.buf, n);
+    }
+
+    fn bool(&mut self, id: i16, value: bool) {
+        self.field(
+            id,
+            if value {
+                TYPE_BOOL_TRUE
+            } else {
+                TYPE_BOOL_FALSE
+            },
+        );
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, TYPE_I32);
+        self.varint(zigzag(value as i64));
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, TYPE_I64);
+        self.varint(zigzag(value));
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, TYPE_BINARY);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, TYPE_STRUCT);
+        self.begin_element();
+    }
+
+    /// Begin a struct that's an element of a list.
+    fn begin_element(&mut self) {
+        self.outer_ids.push(self.last_id);
+        self.last_id = 0;
+    }
+
+    fn end_struct(&mut self) {
+        self.buf.push(0);
+        self.last_id = self.outer_ids.pop().unwrap_or(0);
+    }
+
+    /// Begin a list of `len` structs, each written between `begin_element` and
+    /// `end_struct`.
+    fn begin_list(&mut self, id: i16, len: usize) {
+        self.begin_list_of(id, len, TYPE_STRUCT);
+    }
+
+    /// Begin a list of `len` elements of type `kind`, written bare.
+    fn begin_list_of(&mut self, id: i16, len: usize, kind: u8) {
+        self.field(id, TYPE_LIST);
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            self.varint(len as u64);
+        }
+    }
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three rows of a string, a timestamp and a nullable integer column.
+    fn table() -> Vec<Column> {
+        vec![
+            Column {
+                name: "program",
+                nullable: false,
+                values: Values::String(vec![Some("a".into()), Some("bc".into()), Some("".into())]),
+            },
+            Column {
+                name: "timestamp",
+                nullable: false,
+                values: Values::TimestampMillis(vec![
+                    Some(1_700_000_000_000),
+                    Some(1_700_000_001_000),
+                    Some(1_700_000_002_000),
+                ]),
+            },
+            Column {
+                name: "min_slot",
+                nullable: true,
+                values: Values::Int64(vec![Some(-1), None, Some(300)]),
+            },
+        ]
+    }
+
+    /// The footer of `file`: its FileMetaData, checked to sit between the page data and
+    /// the trailing length and magic bytes.
+    fn footer(file: &[u8]) -> &[u8] {
+        assert_eq!(&file[..4], MAGIC);
+        assert_eq!(&file[file.len() - 4..], MAGIC);
+        let len = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        &file[file.len() - 8 - len as usize..file.len() - 8]
+    }
+
+    #[test]
+    fn tables_match_the_golden_file() {
+        // Checked against parquet.thrift field by field when it was written
+        assert_eq!(
+            write(&table(), "test"),
+            include_bytes!("../testdata/fees.parquet")
+        );
+    }
+
+    #[test]
+    fn files_start_and_end_with_the_magic_bytes() {
+        let file = write(&table(), "test");
+        let meta = footer(&file);
+        assert_eq!(&meta[..2], [0x15, 0x02]); // version 1
+        assert_eq!(&meta[meta.len() - 7..], b"\x28\x04test\x00"); // created_by, stop
+        // The first page header follows the leading magic bytes
+        assert_eq!(&file[4..6], [0x15, 0x00]); // DATA_PAGE
+    }
+
+    #[test]
+    fn empty_tables_have_a_schema_and_no_row_groups() {
+        let file = write(&[], "test");
+        let meta = [
+            0x15, 0x02, // version 1
+            0x19, 0x1c, // schema: one element
+            0x48, 0x06, b's', b'c', b'h', b'e', b'm', b'a', // name
+            0x15, 0x00, // no children
+            0x00, //
+            0x16, 0x00, // no rows
+            0x19, 0x0c, // no row groups
+            0x28, 0x04, b't', b'e', b's', b't', // created_by
+            0x00,
+        ];
+        assert_eq!(footer(&file), meta);
+        assert_eq!(file.len(), 4 + meta.len() + 4 + 4);
+    }
+
+    #[test]
+    fn definition_levels_are_run_length_encoded() {
+        let column = Column {
+            name: "n",
+            nullable: true,
+            values: Values::Int64(vec![None, None, Some(7), Some(8), Some(9), None]),
+        };
+        let mut expected = vec![6, 0, 0, 0, 0x04, 0x00, 0x06, 0x01, 0x02, 0x00];
+        expected.extend_from_slice(&7i64.to_le_bytes());
+        expected.extend_from_slice(&8i64.to_le_bytes());
+        expected.extend_from_slice(&9i64.to_le_bytes());
+        assert_eq!(page(&column), expected);
+    }
+
+    #[test]
+    fn compact_fields_fall_back_to_the_long_form() {
+        let mut c = Compact::default();
+        c.i32(1, 1);
+        c.i64(17, -2); // a jump of 16
+        c.begin_struct(18);
+        c.bool(1, true);
+        c.end_struct();
+        c.bool(19, false);
+        c.begin_list_of(20, 15, TYPE_I32);
+        assert_eq!(
+            c.buf,
+            [
+                0x15, 0x02, 0x06, 0x22, 0x03, 0x1c, 0x11, 0x00, 0x12, 0x19, 0xf5, 0x0f
+            ]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Just enough of libpq, PostgreSQL's C client, for the history database. Like SQLite,
+//! the system's libpq is loaded when a database is first opened.
+
+use crate::database::Value;
+use crate::dylib::{self, dylib_api};
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::io;
+use std::sync::OnceLock;
+
+const LIBRARY_NAMES: [&str; 3] = ["libpq.so.5", "libpq.so", "libpq.dylib"];
+const CONNECTION_OK: c_int = 0;
+const PGRES_COMMAND_OK: c_int = 1;
+const PGRES_TUPLES_OK: c_int = 2;
+// Type OIDs of the columns read as numbers
+const INT8_OID: u32 = 20;
+const INT2_OID: u32 = 21;
+const INT4_OID: u32 = 23;
+const FLOAT4_OID: u32 = 700;
+const FLOAT8_OID: u32 = 701;
+const NUMERIC_OID: u32 = 1700;
+
+type NoticeProcessor = Option<unsafe extern "C" fn(*mut c_void, *const c_char)>;
+
+dylib_api!(Api {
+    PQconnectdb: fn(*const c_char) -> *mut c_void,
+    PQstatus: fn(*mut c_void) -> c_int,
+    PQerrorMessage: fn(*mut c_void) -> *const c_char,
+    PQreset: fn(*mut c_void) -> (),
+    PQfinish: fn(*mut c_void) -> (),
+    PQsetNoticeProcessor: fn(*mut c_void, NoticeProcessor, *mut c_void) -> NoticeProcessor,
+    PQexecParams: fn(*mut c_void, *const c_char, c_int, *const u32, *const *const c_char, *const c_int, *const c_int, c_int) -> *mut c_void,
+    PQresultStatus: fn(*mut c_void) -> c_int,
+    PQntuples: fn(*mut c_void) -> c_int,
+    PQnfields: fn(*mut c_void) -> c_int,
+    PQftype: fn(*mut c_void, c_int) -> u32,
+    PQgetisnull: fn(*mut c_void, c_int, c_int) -> c_int,
+    PQgetvalue: fn(*mut c_void, c_int, c_int) -> *const c_char,
+    PQclear: fn(*mut c_void) -> (),
+});
+
+fn api() -> io::Result<&'static Api> {
+    static API: OnceLock<Result<Api, String>> = OnceLock::new();
+    API.get_or_init(|| dylib::open(&LIBRARY_NAMES).and_then(Api::load))
+        .as_ref()
+        .map_err(|e| io::Error::other(e.clone()))
+}
+
+/// Logs the server's notices (such as "relation already exists, skipping") at debug
+/// level, rather than libpq printing them to stderr.
+unsafe extern "C" fn log_notice(_: *mut c_void, message: *const c_char) {
+    // SAFETY: libpq passes a valid C string
+    let message = unsafe { CStr::from_ptr(message) };
+    log::debug!("postgres: {}", message.to_string_lossy().trim_end());
+}
+
+/// A connection to a server, which reconnects if it's lost.
+pub struct Connection {
+    api: &'static Api,
+    conn: *mut c_void,
+}
+
+// SAFETY: libpq connections may move between threads as long as two don't use one at
+// once, which the history database's mutex ensures
+unsafe impl Send for Connection {}
+
+impl Connection {
+    /// Connect to the server at `url`, a `postgres://` URL or libpq connection string.
+    pub fn open(url: &str) -> io::Result<Connection> {
+        let api = api()?;
+        let c_url = CString::new(url)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "URL contains NUL"))?;
+        // SAFETY: `c_url` is a valid C string
+        let conn = unsafe { (api.PQconnectdb)(c_url.as_ptr()) };
+        if conn.is_null() {
+            return Err(io::Error::other("out of memory"));
+        }
+        // Even a failed connection is a handle, which carries the error
+        let conn = Connection { api, conn };
+        // SAFETY: `conn` is a handle from `PQconnectdb`
+        if unsafe { (api.PQstatus)(conn.conn) } != CONNECTION_OK {
+            return Err(conn.error());
+        }
+        // SAFETY: as above, and `log_notice` ignores its argument
+        unsafe { (api.PQsetNoticeProcessor)(conn.conn, Some(log_notice), std::ptr::null_mut()) };
+        Ok(conn)
+    }
+
+    /// Run `sql`, a single statement, with `params` bound to its `$1`, `$2`, …
+    pub fn execute(&self, sql: &str, params: &[Value]) -> io::Result<()> {
+        self.exec(sql, params).map(drop)
+    }
+
+    /// The rows `sql`, a single statement, returns with `params` bound to its `$1`, `$2`, …
+    pub fn query(&self, sql: &str, params: &[Value]) -> io::Result<Vec<Vec<Value>>> {
+        let result = self.exec(sql, params)?;
+        let api = self.api;
+        // SAFETY: `raw` is a result with rows, and rows and columns are in range
+        unsafe {
+            let columns = (api.PQnfields)(result.raw);
+            Ok((0..(api.PQntuples)(result.raw))
+                .map(|row| {
+                    (0..columns)
+                        .map(|col| {
+                            if (api.PQgetisnull)(result.raw, row, col) != 0 {
+                                return Value::Null;
+                            }
+                            let text = CStr::from_ptr((api.PQgetvalue)(result.raw, row, col))
+                                .to_string_lossy()
+                                .into_owned();
+                            match (api.PQftype)(result.raw, col) {
+                                INT2_OID | INT4_OID | INT8_OID => {
+                                    text.parse().map_or(Value::Text(text), Value::Integer)
+                                }
+                                FLOAT4_OID | FLOAT8_OID | NUMERIC_OID => {
+                                    text.parse().map_or(Value::Text(text), Value::Real)
+                                }
+                                _ => Value::Text(text),
+                            }
+                        })
+                        .collect()
+                })
+                .collect())
+        }
+    }
+
+    /// Run `sql` with `params` sent as text for the server to convert, reconnecting if
+    /// the connection was lost, before or during the call.
+    fn exec(&self, sql: &str, params: &[Value]) -> io::Result<PgResult<'_>> {
+        let c_sql = CString::new(sql)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "SQL contains NUL"))?;
+        let values = params
+            .iter()
+            .map(|param| match param {
+                Value::Null => Ok(None),
+                Value::Integer(n) => Ok(Some(CString::new(n.to_string()).unwrap())),
+                Value::Real(x) => Ok(Some(CString::new(x.to_string()).unwrap())),
+                Value::Text(s) => CString::new(s.as_str()).map(Some),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value contains NUL"))?;
+        let pointers: Vec<*const c_char> = values
+            .iter()
+            .map(|v| v.as_ref().map_or(std::ptr::null(), |v| v.as_ptr()))
+            .collect();
+        let mut retried = false;
+        loop {
+            self.reconnect()?;
+            // SAFETY: `conn` is open, `c_sql` is a valid C string, and `pointers` holds
+            // `params.len()` C strings or nulls, which outlive the call
+            let raw = unsafe {
+                (self.api.PQexecParams)(
+                    self.conn,
+                    c_sql.as_ptr(),
+                    pointers.len() as c_int,
+                    std::ptr::null(),
+                    pointers.as_ptr(),
+                    std::ptr::null(),
+                    std::ptr::null(),
+                    0,
+                )
+            };
+            let result = (!raw.is_null()).then_some(PgResult { conn: self, raw });
+            // SAFETY: `raw` is a result from `PQexecParams`
+            if let Some(result) = result
+                && let PGRES_COMMAND_OK | PGRES_TUPLES_OK =
+                    unsafe { (self.api.PQresultStatus)(result.raw) }
+            {
+                return Ok(result);
+            }
+            // A connection the server closed while idle only shows as lost once used
+            // SAFETY: `conn` is a handle from `PQconnectdb`
+            if retried || unsafe { (self.api.PQstatus)(self.conn) } == CONNECTION_OK {
+                return Err(self.error());
+            }
+            retried = true;
+        }
+    }
+
+    /// Reconnect if the connection was lost.
+    fn reconnect(&self) -> io::Result<()> {
+        // SAFETY: `conn` is a handle from `PQconnectdb`
+        unsafe {
+            if (self.api.PQstatus)(self.conn) != CONNECTION_OK {
+                (self.api.PQreset)(self.conn);
+                if (self.api.PQstatus)(self.conn) != CONNECTION_OK {
+                    return Err(self.error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The error of the last call that failed.
+    fn error(&self) -> io::Error {
+        // SAFETY: `conn` is a handle from `PQconnectdb`, whose message libpq owns
+        let message = unsafe { CStr::from_ptr((self.api.PQerrorMessage)(self.conn)) };
+        io::Error::other(message.to_string_lossy().trim_end().to_string())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // SAFETY: `conn` is a handle from `PQconnectdb`, not used again
+        unsafe { (self.api.PQfinish)(self.conn) };
+    }
+}
+
+struct PgResult<'a> {
+    conn: &'a Connection,
+    raw: *mut c_void,
+}
+
+impl Drop for PgResult<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `raw` is a result from `PQexecParams`, not used again
+        unsafe { (self.conn.api.PQclear)(self.raw) };
+    }
+}
@@ -0,0 +1,246 @@
+//! Just enough of the Protocol Buffers wire format for the gRPC server's messages:
+//! writing scalar, string and nested message fields, and reading fields back.
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_BYTES: u32 = 2;
+const WIRE_FIXED32: u32 = 5;
+
+/// A message being written. Fields are written as given, defaults included, which
+/// readers treat the same as absent ones.
+#[derive(Default)]
+pub struct Writer {
+    pub buf: Vec<u8>,
+}
+
+impl Writer {
+    fn key(&mut self, field: u32, wire_type: u32) {
+        write_varint(&mut self.buf, (field << 3 | wire_type) as u64);
+    }
+
+    pub fn uint64(&mut self, field: u32, value: u64) {
+        self.key(field, WIRE_VARINT);
+        write_varint(&mut self.buf, value);
+    }
+
+    pub fn bool(&mut self, field: u32, value: bool) {
+        self.uint64(field, value as u64);
+    }
+
+    pub fn double(&mut self, field: u32, value: f64) {
+        self.key(field, WIRE_FIXED64);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, WIRE_BYTES);
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    /// A nested message, written by `write`.
+    pub fn message(&mut self, field: u32, write: impl FnOnce(&mut Writer)) {
+        let mut nested = Writer::default();
+        write(&mut nested);
+        self.bytes(field, &nested.buf);
+    }
+
+    /// An `optional` field, which is written only when set.
+    pub fn optional<T>(&mut self, field: u32, value: Option<T>, write: fn(&mut Writer, u32, T)) {
+        if let Some(value) = value {
+            write(self, field, value);
+        }
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// A field's value as it appears on the wire.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// The fields of a message, in the order they were written, as (field number, value).
+pub fn fields(message: &[u8]) -> Fields<'_> {
+    Fields { input: message }
+}
+
+pub struct Fields<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.input.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            self.input = &[]; // nothing after a malformed field can be read
+        }
+        Some(field)
+    }
+}
+
+impl<'a> Fields<'a> {
+    fn field(&mut self) -> Result<(u32, Value<'a>), String> {
+        let key = self.varint()?;
+        let number = u32::try_from(key >> 3)
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("invalid field number {}", key >> 3))?;
+        let value = match key as u32 & 7 {
+            WIRE_VARINT => Value::Varint(self.varint()?),
+            WIRE_FIXED64 => Value::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            WIRE_BYTES => {
+                let len = self.varint()?;
+                Value::Bytes(self.take(usize::try_from(len).unwrap_or(usize::MAX))?)
+            }
+            WIRE_FIXED32 => Value::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            other => return Err(format!("unsupported wire type {}", other)),
+        };
+        Ok((number, value))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        for (i, &byte) in self.input.iter().enumerate().take(10) {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.input = &self.input[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err("truncated or overlong varint".to_string())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.input.len() {
+            return Err("truncated field".to_string());
+        }
+        let (value, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(f: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut w = Writer::default();
+        f(&mut w);
+        w.buf
+    }
+
+    #[test]
+    fn varints_use_seven_bits_a_byte() {
+        let cases: [(u64, &[u8]); 7] = [
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (150, &[0x96, 0x01]),
+            (16_383, &[0xff, 0x7f]),
+            (
+                u64::MAX,
+                &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+            ),
+        ];
+        for (n, bytes) in cases {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            assert_eq!(buf, bytes, "{n}");
+            let mut fields = Fields { input: bytes };
+            assert_eq!(fields.varint(), Ok(n));
+            assert!(fields.input.is_empty());
+        }
+    }
+
+    #[test]
+    fn fields_are_keyed_by_number_and_wire_type() {
+        // The examples of the encoding guide
+        assert_eq!(write(|w| w.uint64(1, 150)), [0x08, 0x96, 0x01]);
+        assert_eq!(write(|w| w.string(2, "testing")), b"\x12\x07testing");
+        assert_eq!(
+            write(|w| w.message(3, |w| w.uint64(1, 150))),
+            [0x1a, 0x03, 0x08, 0x96, 0x01]
+        );
+        assert_eq!(write(|w| w.bool(15, true)), [0x78, 0x01]);
+        assert_eq!(write(|w| w.bool(16, false)), [0x80, 0x01, 0x00]);
+        assert_eq!(
+            write(|w| w.double(1, 1.0)),
+            [0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f]
+        );
+        assert_eq!(write(|w| w.optional(4, None, Writer::uint64)), [0u8; 0]);
+        assert_eq!(
+            write(|w| w.optional(4, Some(1), Writer::uint64)),
+            [0x20, 0x01]
+        );
+    }
+
+    #[test]
+    fn fields_read_back_in_order() {
+        let message = write(|w| {
+            w.uint64(1, 150);
+            w.double(2, -2.5);
+            w.message(3, |w| w.string(1, "nested"));
+            w.uint64(1, 7);
+            w.buf.extend_from_slice(&[0x25, 0x01, 0x00, 0x00, 0x80]); // fixed32, field 4
+        });
+        let read: Vec<_> = fields(&message).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            read,
+            [
+                (1, Value::Varint(150)),
+                (2, Value::Fixed64((-2.5f64).to_bits())),
+                (3, Value::Bytes(b"\x0a\x06nested")),
+                (1, Value::Varint(7)),
+                (4, Value::Fixed32(0x8000_0001)),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_fields_end_the_message() {
+        let error = |message: &[u8]| {
+            let mut fields = fields(message);
+            let error = fields.next().unwrap().unwrap_err();
+            assert!(fields.next().is_none());
+            error
+        };
+        assert_eq!(error(&[0x00, 0x01]), "invalid field number 0");
+        assert_eq!(error(&[0x0b]), "unsupported wire type 3");
+        assert_eq!(error(&[0x08, 0x80]), "truncated or overlong varint");
+        assert_eq!(
+            error(&[
+                0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01
+            ]),
+            "truncated or overlong varint"
+        );
+        assert_eq!(error(&[0x12, 0x05, b'a']), "truncated field");
+        assert_eq!(error(&[0x09, 0x00]), "truncated field");
+
+        let mut fields = fields(&[0x08, 0x01, 0x0b, 0x08, 0x02]);
+        assert_eq!(fields.next(), Some(Ok((1, Value::Varint(1)))));
+        assert!(fields.next().unwrap().is_err());
+        assert_eq!(fields.next(), None);
+    }
+}
@@ -0,0 +1,617 @@
+//! Outbound proxies for RPC calls. The HTTP client speaks to HTTP proxies itself; it
+//! can't speak SOCKS5, so a SOCKS5 proxy is reached through a bridge: an HTTP proxy on a
+//! loopback address that opens a SOCKS5 tunnel for each connection.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// Most bytes of a request head the bridge reads before giving up on it.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// How long opening a tunnel may wait on the proxy.
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyKind {
+    Http,
+    Socks5,
+}
+
+/// A proxy as configured: `[http|socks5|socks5h]://[user:password@]host[:port]`. A bare
+/// `host:port` is an HTTP proxy. As with curl, `socks5` resolves endpoint host names
+/// locally and `socks5h` leaves that to the proxy.
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    credentials: Option<(String, String)>,
+    remote_dns: bool, // for SOCKS5, whether the proxy resolves host names
+    bridge: OnceLock<Result<SocketAddr, String>>, // for SOCKS5, started on first use
+}
+
+impl PartialEq for Proxy {
+    fn eq(&self, other: &Proxy) -> bool {
+        self.kind == other.kind
+            && self.host == other.host
+            && self.port == other.port
+            && self.credentials == other.credentials
+            && self.remote_dns == other.remote_dns
+    }
+}
+
+impl Proxy {
+    pub fn parse(s: &str) -> Result<Proxy, String> {
+        let with_scheme = match s.contains("://") {
+            true => s.to_string(),
+            false => format!("http://{}", s),
+        };
+        let invalid = || format!("Invalid proxy: {}", redact(s));
+        let url = url::Url::parse(&with_scheme).map_err(|_| invalid())?;
+        let (kind, default_port) = match url.scheme() {
+            "http" => (ProxyKind::Http, 80),
+            "socks5" | "socks5h" => (ProxyKind::Socks5, 1080),
+            "https" => return Err("HTTPS proxies aren't supported; use http://".to_string()),
+            _ => return Err(invalid()),
+        };
+        if !matches!(url.path(), "" | "/") || url.query().is_some() {
+            return Err(invalid());
+        }
+        let host = url
+            .host_str()
+            .filter(|h| !h.is_empty())
+            .ok_or_else(invalid)?;
+        let credentials = url_credentials(&url).map_err(|_| invalid())?;
+        // Basic authentication can't tell where such a user name ends
+        if kind == ProxyKind::Http
+            && credentials
+                .as_ref()
+                .is_some_and(|(user, _)| user.contains(':'))
+        {
+            return Err("An HTTP proxy's user name can't contain ':'".to_string());
+        }
+        Ok(Proxy {
+            kind,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: url.port().unwrap_or(default_port),
+            credentials,
+            remote_dns: url.scheme() == "socks5h",
+            bridge: OnceLock::new(),
+        })
+    }
+
+    /// The proxy for the HTTP client to use: this one, or the bridge to it for SOCKS5,
+    /// which is started on the first call.
+    pub fn http_client_proxy(self: &Arc<Self>) -> Result<ureq::Proxy, String> {
+        let url = match self.kind {
+            ProxyKind::Http => {
+                // The HTTP client takes credentials as they are, not percent-encoded,
+                // splitting them at the first ':' and the host at the last '@'
+                let credentials = match &self.credentials {
+                    Some((user, password)) => format!("{}:{}@", user, password),
+                    None => String::new(),
+                };
+                format!("http://{}{}", credentials, self.address())
+            }
+            ProxyKind::Socks5 => {
+                let bridge = self.bridge.get_or_init(|| {
+                    self.start_bridge()
+                        .map_err(|e| format!("Failed to start SOCKS5 bridge: {}", e))
+                });
+                format!("http://{}", bridge.clone()?)
+            }
+        };
+        ureq::Proxy::new(url).map_err(|e| e.to_string())
+    }
+
+    pub fn has_credentials(&self) -> bool {
+        self.credentials.is_some()
+    }
+
+    /// A connection to `host:port` through the proxy.
+    pub fn tunnel(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("could not resolve {}", self.host)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TUNNEL_TIMEOUT)?;
+        stream.set_read_timeout(Some(TUNNEL_TIMEOUT))?;
+        match self.kind {
+            ProxyKind::Http => self.http_connect(&mut stream, host, port)?,
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, host, port)?,
+        }
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+
+    fn address(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let authorization = match &self.credentials {
+            Some((user, password)) => format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                crate::transaction::base64_encode(format!("{}:{}", user, password).as_bytes())
+            ),
+            None => String::new(),
+        };
+        write!(
+            stream,
+            "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n{authorization}\r\n"
+        )?;
+        let head = read_head(stream)?;
+        let status = head.lines().next().unwrap_or("");
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(io::Error::other(format!(
+                "proxy refused tunnel: {}",
+                status
+            )));
+        }
+        Ok(())
+    }
+
+    /// The SOCKS5 handshake of RFC 1928, with RFC 1929 authentication if configured.
+    /// `host` is resolved here, unless the proxy is to resolve it.
+    fn socks5_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let method = match self.credentials {
+            Some(_) => 0x02,
+            None => 0x00,
+        };
+        stream.write_all(&[0x05, 0x01, method])?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply != [0x05, method] {
+            return Err(io::Error::other(
+                "SOCKS5 proxy refused authentication method",
+            ));
+        }
+        if let Some((user, password)) = &self.credentials {
+            let mut auth = vec![0x01];
+            for field in [user, password] {
+                let len = u8::try_from(field.len())
+                    .map_err(|_| io::Error::other("SOCKS5 credentials too long"))?;
+                auth.push(len);
+                auth.extend_from_slice(field.as_bytes());
+            }
+            stream.write_all(&auth)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0x00 {
+                return Err(io::Error::other("SOCKS5 proxy rejected credentials"));
+            }
+        }
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let ip = match host.parse::<std::net::IpAddr>() {
+            Ok(ip) => Some(ip),
+            Err(_) if self.remote_dns => None,
+            Err(_) => Some(
+                (host, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| io::Error::other(format!("could not resolve {}", host)))?
+                    .ip(),
+            ),
+        };
+        let mut request = vec![0x05, 0x01, 0x00];
+        match ip {
+            Some(std::net::IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Some(std::net::IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            None => {
+                let len = u8::try_from(host.len())
+                    .map_err(|_| io::Error::other("host name too long for SOCKS5"))?;
+                request.push(0x03);
+                request.push(len);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 0x05 {
+            return Err(io::Error::other("invalid SOCKS5 reply"));
+        }
+        if reply[1] != 0x00 {
+            return Err(io::Error::other(format!(
+                "SOCKS5 proxy couldn't connect to {}:{}: {}",
+                host,
+                port,
+                socks5_error(reply[1])
+            )));
+        }
+        // Skip the address the proxy bound
+        let addr_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(io::Error::other("invalid SOCKS5 reply")),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound)?;
+        Ok(())
+    }
+
+    fn start_bridge(self: &Arc<Self>) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let proxy = self.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else { continue };
+                let proxy = proxy.clone();
+                thread::spawn(move || {
+                    if let Err(e) = proxy.bridge(client) {
+                        log::debug!("SOCKS5 bridge connection ended: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    /// Serve one connection from the HTTP client: a `CONNECT` tunnel for an HTTPS
+    /// endpoint, or requests in absolute form for an HTTP one, which are passed on as
+    /// they are, since servers must accept that form too.
+    fn bridge(&self, mut client: TcpStream) -> io::Result<()> {
+        let head = read_head(&mut client)?;
+        let request_line = head.lines().next().unwrap_or("");
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let target = match method {
+            "CONNECT" => target.to_string(),
+            _ => url::Url::parse(target)
+                .ok()
+                .and_then(|url| {
+                    Some(format!(
+                        "{}:{}",
+                        url.host_str()?,
+                        url.port_or_known_default()?
+                    ))
+                })
+                .unwrap_or_default(),
+        };
+        let Some((host, port)) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        else {
+            client.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+            return Ok(());
+        };
+        let mut upstream = match self.tunnel(host, port) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                let message = e.to_string();
+                write!(
+                    client,
+                    "HTTP/1.1 502 Bad Gateway\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    message.len(),
+                    message
+                )?;
+                return Ok(());
+            }
+        };
+        match method {
+            "CONNECT" => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?,
+            _ => upstream.write_all(head.as_bytes())?,
+        }
+        relay(client, upstream)
+    }
+}
+
+/// Shows the proxy without its password.
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.kind {
+            ProxyKind::Http => "http",
+            ProxyKind::Socks5 if self.remote_dns => "socks5h",
+            ProxyKind::Socks5 => "socks5",
+        };
+        match &self.credentials {
+            Some((user, _)) => write!(f, "{}://{}:***@{}", scheme, user, self.address()),
+            None => write!(f, "{}://{}", scheme, self.address()),
+        }
+    }
+}
+
+/// Whether `host` is exempted from the proxy by a `NO_PROXY`-style list: `*`, or host
+/// names and IP addresses, each also matching its subdomains.
+pub fn bypasses(no_proxy: &[String], host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim_start_matches('.');
+        entry == "*"
+            || host.eq_ignore_ascii_case(entry)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", entry.to_ascii_lowercase()))
+    })
+}
+
+/// `s` with any password in it masked, for error messages.
+pub(crate) fn redact(s: &str) -> String {
+    match (s.find("://"), s.rfind('@')) {
+        (Some(start), Some(end)) if start + 3 < end => match s[start + 3..end].split_once(':') {
+            Some((user, _)) => format!("{}{}:***{}", &s[..start + 3], user, &s[end..]),
+            None => s.to_string(),
+        },
+        _ => s.to_string(),
+    }
+}
+
+/// The user name and password of `url`, percent-decoded, or `None` if it has neither.
+pub(crate) fn url_credentials(url: &url::Url) -> Result<Option<(String, String)>, String> {
+    let decode = |s: &str| {
+        percent_encoding::percent_decode_str(s)
+            .decode_utf8()
+            .map(String::from)
+            .map_err(|_| "credentials aren't UTF-8 once percent-decoded".to_string())
+    };
+    Ok(match (url.username(), url.password()) {
+        ("", None) => None,
+        (user, password) => Some((decode(user)?, decode(password.unwrap_or(""))?)),
+    })
+}
+
+/// Read a request or response head, up to and including the blank line.
+fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "head too long"));
+        }
+        let mut b = [0u8; 1];
+        stream.read_exact(&mut b)?;
+        head.push(b[0]);
+    }
+    String::from_utf8(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Copy bytes both ways until either side closes the connection.
+fn relay(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let (mut client_reader, mut upstream_writer) = (client.try_clone()?, upstream.try_clone()?);
+    let requests = thread::spawn(move || {
+        let copied = io::copy(&mut client_reader, &mut upstream_writer);
+        let _ = upstream_writer.shutdown(Shutdown::Write);
+        copied
+    });
+    let (mut upstream_reader, mut client_writer) = (upstream, client);
+    io::copy(&mut upstream_reader, &mut client_writer)?;
+    let _ = client_writer.shutdown(Shutdown::Both);
+    requests.join().expect("relay thread panicked")?;
+    Ok(())
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: &str = "us%40er"; // us@er
+    const PASSWORD: &str = "p%40ss%3Aw%2Frd%25%23"; // p@ss:w/rd%#
+
+    /// A proxy on a loopback port that, for each step of `script`, reads that many
+    /// bytes and then writes the reply. Returns its port, and what it read.
+    fn scripted(script: Vec<(usize, &'static [u8])>) -> (u16, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proxy = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            for (len, reply) in script {
+                let mut buf = vec![0; len];
+                stream.read_exact(&mut buf).unwrap();
+                received.extend_from_slice(&buf);
+                stream.write_all(reply).unwrap();
+            }
+            received
+        });
+        (port, proxy)
+    }
+
+    #[test]
+    fn credentials_are_percent_decoded() {
+        let proxy = Proxy::parse(&format!("socks5h://{}:{}@proxy:1081", USER, PASSWORD)).unwrap();
+        let credentials = ("us@er".to_string(), "p@ss:w/rd%#".to_string());
+        assert_eq!(proxy.credentials, Some(credentials));
+        assert_eq!(proxy.to_string(), "socks5h://us@er:***@proxy:1081");
+
+        let proxy = Proxy::parse("http://:secret@proxy").unwrap();
+        assert_eq!(
+            proxy.credentials,
+            Some((String::new(), "secret".to_string()))
+        );
+        assert_eq!(Proxy::parse("proxy:3128").unwrap().credentials, None);
+        assert!(Proxy::parse("http://a%3Ab:c@proxy").is_err());
+        assert!(Proxy::parse("socks5h://%ff:c@proxy").is_err());
+    }
+
+    #[test]
+    fn socks5_proxies_get_decoded_credentials() {
+        let (port, server) = scripted(vec![
+            (3, &[0x05, 0x02]),
+            (1 + 1 + 5 + 1 + 11, &[0x01, 0x00]),
+            (4 + 1 + 11 + 2, &[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]),
+        ]);
+        let url = format!("socks5h://{}:{}@127.0.0.1:{}", USER, PASSWORD, port);
+        Proxy::parse(&url)
+            .unwrap()
+            .tunnel("example.com", 443)
+            .unwrap();
+        let mut expected = vec![0x05, 0x01, 0x02]; // one method: user name and password
+        expected.extend_from_slice(b"\x01\x05us@er\x0bp@ss:w/rd%#");
+        expected.extend_from_slice(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+        assert_eq!(server.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn http_proxies_get_decoded_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let head = read_head(&mut stream).unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            head
+        });
+        let url = format!("http://{}:{}@127.0.0.1:{}", USER, PASSWORD, port);
+        Proxy::parse(&url)
+            .unwrap()
+            .tunnel("example.com", 443)
+            .unwrap();
+        let auth = crate::transaction::base64_encode(b"us@er:p@ss:w/rd%#");
+        assert_eq!(
+            server.join().unwrap(),
+            format!(
+                "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+                 Proxy-Authorization: Basic {}\r\n\r\n",
+                auth
+            )
+        );
+    }
+
+    /// The address in the CONNECT request a SOCKS5 proxy without credentials gets for
+    /// `host:443`, `request_len` bytes long, when it replies with `bound` and then a
+    /// byte of tunnelled data.
+    fn socks5_request(
+        scheme: &str,
+        host: &str,
+        request_len: usize,
+        bound: &'static [u8],
+    ) -> Vec<u8> {
+        let (port, server) = scripted(vec![(3, &[0x05, 0x00]), (request_len, bound), (0, b"!")]);
+        let proxy = Proxy::parse(&format!("{}://127.0.0.1:{}", scheme, port)).unwrap();
+        let mut stream = proxy.tunnel(host, 443).unwrap();
+        let mut tunnelled = [0u8; 1];
+        stream.read_exact(&mut tunnelled).unwrap();
+        assert_eq!(&tunnelled, b"!", "the whole reply was read");
+        let received = server.join().unwrap();
+        assert_eq!(&received[..3], [0x05, 0x01, 0x00]); // one method: none
+        assert_eq!(&received[3..6], [0x05, 0x01, 0x00]); // CONNECT
+        assert_eq!(&received[received.len() - 2..], [0x01, 0xbb]);
+        received[6..received.len() - 2].to_vec()
+    }
+
+    const BOUND_V4: &[u8] = &[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x1f, 0x90];
+
+    #[test]
+    fn socks5_resolves_host_names_locally() {
+        let ip = ("localhost", 443)
+            .to_socket_addrs()
+            .unwrap()
+            .next()
+            .unwrap()
+            .ip();
+        let expected = match ip {
+            std::net::IpAddr::V4(ip) => [&[0x01][..], &ip.octets()].concat(),
+            std::net::IpAddr::V6(ip) => [&[0x04][..], &ip.octets()].concat(),
+        };
+        let len = 3 + expected.len() + 2;
+        assert_eq!(
+            socks5_request("socks5", "localhost", len, BOUND_V4),
+            expected
+        );
+    }
+
+    #[test]
+    fn socks5h_leaves_host_names_to_the_proxy() {
+        assert_eq!(
+            socks5_request("socks5h", "localhost", 3 + 2 + 9 + 2, BOUND_V4),
+            b"\x03\x09localhost"
+        );
+        // Addresses are sent as they are either way
+        assert_eq!(
+            socks5_request("socks5h", "192.0.2.7", 3 + 5 + 2, BOUND_V4),
+            [0x01, 192, 0, 2, 7]
+        );
+        let mut v6 = vec![0x04];
+        v6.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        assert_eq!(
+            socks5_request("socks5", "[2001:db8::1]", 3 + 17 + 2, BOUND_V4),
+            v6
+        );
+    }
+
+    #[test]
+    fn socks5_replies_skip_any_bound_address() {
+        let bound_name = b"\x05\x00\x00\x03\x05proxy\x1f\x90";
+        socks5_request("socks5h", "192.0.2.7", 3 + 5 + 2, bound_name);
+        let bound_v6 = &[
+            0x05, 0x00, 0x00, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1f, 0x90,
+        ];
+        socks5_request("socks5h", "192.0.2.7", 3 + 5 + 2, bound_v6);
+    }
+
+    #[test]
+    fn socks5_failures_are_reported() {
+        let tunnel = |script: Vec<(usize, &'static [u8])>, credentials: &str| {
+            let (port, _) = scripted(script);
+            let url = format!("socks5h://{}127.0.0.1:{}", credentials, port);
+            Proxy::parse(&url)
+                .unwrap()
+                .tunnel("example.com", 443)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            tunnel(vec![(3, &[0x05, 0xff])], ""),
+            "SOCKS5 proxy refused authentication method"
+        );
+        assert_eq!(
+            tunnel(vec![(3, &[0x05, 0x02]), (5, &[0x01, 0x01])], "a:b@"),
+            "SOCKS5 proxy rejected credentials"
+        );
+        assert_eq!(
+            tunnel(
+                vec![(3, &[0x05, 0x00]), (18, &[0x05, 0x05, 0x00, 0x01])],
+                ""
+            ),
+            "SOCKS5 proxy couldn't connect to example.com:443: connection refused"
+        );
+        assert_eq!(
+            tunnel(
+                vec![(3, &[0x05, 0x00]), (18, &[0x04, 0x5a, 0x00, 0x00])],
+                ""
+            ),
+            "invalid SOCKS5 reply"
+        );
+    }
+}
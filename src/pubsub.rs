@@ -0,0 +1,468 @@
+//! Minimal client for Solana's WebSocket PubSub API: just enough of RFC 6455 to hold a
+//! `logsSubscribe` subscription open over `ws://` or `wss://`.
+
+use crate::proxy::Proxy;
+use crate::rpc::Commitment;
+use crate::rpc_pool::Header;
+use crate::transaction::base64_encode;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Largest message accepted; logs notifications are a few KB.
+const MAX_MESSAGE_LEN: usize = 16 << 20;
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A WebSocket connection to a PubSub endpoint.
+pub struct PubSubClient {
+    stream: Box<dyn Stream>,
+    mask_seed: u64,
+}
+
+/// One transaction announced by a `logsNotification`.
+#[derive(Deserialize, Debug)]
+pub struct LogsNotification {
+    pub signature: String,
+    #[serde(default)]
+    pub err: Option<serde_json::Value>, // null on success
+}
+
+impl PubSubClient {
+    /// Connect to `url`, through `proxy` if given, and complete the WebSocket handshake,
+    /// sending `headers` with it. Reads fail with a timeout error once the server has
+    /// been silent for `idle_timeout`.
+    pub fn connect(
+        url: &str,
+        idle_timeout: Duration,
+        headers: &[Header],
+        proxy: Option<&Proxy>,
+    ) -> Result<PubSubClient, Box<dyn std::error::Error>> {
+        let url = url::Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or("websocket URL has no host")?
+            .to_string();
+        let port = url
+            .port_or_known_default()
+            .ok_or("websocket URL has no port")?;
+        let tcp = match proxy {
+            Some(proxy) => proxy.tunnel(&host, port)?,
+            None => {
+                let addr = (host.as_str(), port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| format!("could not resolve {}", host))?;
+                TcpStream::connect_timeout(&addr, idle_timeout)?
+            }
+        };
+        tcp.set_read_timeout(Some(idle_timeout))?;
+        tcp.set_nodelay(true)?;
+        let stream: Box<dyn Stream> = match url.scheme() {
+            "ws" => Box::new(tcp),
+            "wss" => {
+                let name = rustls::pki_types::ServerName::try_from(host.clone())?;
+                let conn = rustls::ClientConnection::new(tls_config(), name)?;
+                Box::new(rustls::StreamOwned::new(conn, tcp))
+            }
+            other => return Err(format!("unsupported websocket scheme: {}", other).into()),
+        };
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let mut client = PubSubClient {
+            stream,
+            mask_seed: nanos | 1,
+        };
+        let mut key = [0u8; 16];
+        for chunk in key.chunks_mut(4) {
+            chunk.copy_from_slice(&client.next_mask());
+        }
+        let path = match url.query() {
+            Some(q) => format!("{}?{}", url.path(), q),
+            None => url.path().to_string(),
+        };
+        let host_header = match url.port() {
+            Some(p) => format!("{}:{}", host, p),
+            None => host.clone(),
+        };
+        let extra_headers: String = headers
+            .iter()
+            .map(|h| format!("{}: {}\r\n", h.name, h.value))
+            .collect();
+        write!(
+            client.stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+            path,
+            host_header,
+            base64_encode(&key),
+            extra_headers
+        )?;
+        client.stream.flush()?;
+
+        // The response head, up to the blank line; frames follow it
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > 8192 {
+                return Err("websocket handshake response too long".into());
+            }
+            let mut b = [0u8; 1];
+            client.stream.read_exact(&mut b)?;
+            head.push(b[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status = head.lines().next().unwrap_or("");
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(format!("websocket handshake failed: {}", status).into());
+        }
+        Ok(client)
+    }
+
+    /// Subscribe to the logs of transactions mentioning `address`, announced once they
+    /// reach `commitment`; returns the subscription id.
+    pub fn logs_subscribe(
+        &mut self,
+        address: &str,
+        commitment: Commitment,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let req = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "logsSubscribe",
+            "params": [{ "mentions": [address] }, { "commitment": commitment.as_str() }]
+        });
+        self.send_text(&req.to_string())?;
+        loop {
+            let msg: serde_json::Value = serde_json::from_str(&self.read_text()?)?;
+            if msg["id"] != json!(1) {
+                continue;
+            }
+            if let Some(err) = msg.get("error") {
+                return Err(format!("logsSubscribe error: {}", err).into());
+            }
+            return msg["result"]
+                .as_u64()
+                .ok_or_else(|| "logsSubscribe: missing result".into());
+        }
+    }
+
+    /// Wait for the next `logsNotification`, skipping any other message.
+    pub fn next_logs(&mut self) -> Result<LogsNotification, Box<dyn std::error::Error>> {
+        loop {
+            let mut msg: serde_json::Value = serde_json::from_str(&self.read_text()?)?;
+            if msg["method"] != "logsNotification" {
+                continue;
+            }
+            let value = msg["params"]["result"]["value"].take();
+            return Ok(serde_json::from_value(value)?);
+        }
+    }
+
+    fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_frame(OPCODE_TEXT, text.as_bytes())
+    }
+
+    /// Send one frame, masked as clients must.
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n if n <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        let mask = self.next_mask();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    /// Read the next text message, answering pings along the way.
+    fn read_text(&mut self) -> io::Result<String> {
+        let mut message = Vec::new();
+        loop {
+            let mut head = [0u8; 2];
+            self.stream.read_exact(&mut head)?;
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0f;
+            let len = match head[1] & 0x7f {
+                126 => {
+                    let mut b = [0u8; 2];
+                    self.stream.read_exact(&mut b)?;
+                    u16::from_be_bytes(b) as usize
+                }
+                127 => {
+                    let mut b = [0u8; 8];
+                    self.stream.read_exact(&mut b)?;
+                    usize::try_from(u64::from_be_bytes(b)).unwrap_or(usize::MAX)
+                }
+                n => n as usize,
+            };
+            if message
+                .len()
+                .checked_add(len)
+                .is_none_or(|n| n > MAX_MESSAGE_LEN)
+            {
+                return Err(invalid_data("websocket message too large"));
+            }
+            let mut mask = [0u8; 4];
+            if head[1] & 0x80 != 0 {
+                self.stream.read_exact(&mut mask)?;
+            }
+            let mut payload = vec![0u8; len];
+            self.stream.read_exact(&mut payload)?;
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+
+            match opcode {
+                OPCODE_PING => self.send_frame(OPCODE_PONG, &payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let _ = self.send_frame(OPCODE_CLOSE, &payload);
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "websocket closed by server",
+                    ));
+                }
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return String::from_utf8(message)
+                            .map_err(|_| invalid_data("websocket message is not UTF-8"));
+                    }
+                }
+                _ => return Err(invalid_data("unknown websocket opcode")),
+            }
+        }
+    }
+
+    /// The next masking key, from a xorshift generator. Masks only need to be
+    /// unpredictable to intermediaries, not cryptographically random.
+    fn next_mask(&mut self) -> [u8; 4] {
+        let mut x = self.mask_seed;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.mask_seed = x;
+        (x as u32).to_le_bytes()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// TLS settings for `wss://`, matching those ureq uses for `https://`.
+pub(crate) fn tls_config() -> Arc<rustls::ClientConfig> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let config = rustls::ClientConfig::builder_with_provider(
+                rustls::crypto::ring::default_provider().into(),
+            )
+            .with_safe_default_protocol_versions()
+            .unwrap() // the ring provider supports the default protocol versions
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A connection whose server sends `input`, recording what the client sends.
+    struct Script {
+        input: io::Cursor<Vec<u8>>,
+        sent: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn client(input: &[u8]) -> (PubSubClient, Arc<Mutex<Vec<u8>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let stream = Script {
+            input: io::Cursor::new(input.to_vec()),
+            sent: sent.clone(),
+        };
+        let client = PubSubClient {
+            stream: Box::new(stream),
+            mask_seed: 1,
+        };
+        (client, sent)
+    }
+
+    /// The first mask of a client seeded with 1: xorshift gives 0x40822041.
+    const MASK: [u8; 4] = [0x41, 0x20, 0x82, 0x40];
+
+    #[test]
+    fn frames_are_sent_masked() {
+        let (mut client, sent) = client(&[]);
+        client.send_text("Hello").unwrap();
+        let mut expected = vec![0x81, 0x85];
+        expected.extend(MASK);
+        expected.extend(b"Hello".iter().zip(MASK.iter().cycle()).map(|(b, m)| b ^ m));
+        assert_eq!(*sent.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn frame_lengths_use_the_shortest_encoding() {
+        for (len, head) in [
+            (0, &[0x82, 0x80][..]),
+            (125, &[0x82, 0xfd]),
+            (126, &[0x82, 0xfe, 0x00, 0x7e]),
+            (65535, &[0x82, 0xfe, 0xff, 0xff]),
+            (65536, &[0x82, 0xff, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ] {
+            let (mut client, sent) = client(&[]);
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            client.send_frame(OPCODE_BINARY, &payload).unwrap();
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent[..head.len()], *head, "{} bytes", len);
+            let (mask, masked) = sent[head.len()..].split_at(4);
+            assert_eq!(mask, MASK);
+            let unmasked: Vec<u8> = masked
+                .iter()
+                .zip(mask.iter().cycle())
+                .map(|(b, m)| b ^ m)
+                .collect();
+            assert_eq!(unmasked, payload);
+        }
+    }
+
+    #[test]
+    fn messages_are_read_from_frames() {
+        // The examples of RFC 6455 section 5.7: unmasked, masked, fragmented
+        let (mut client, _) = client(&[
+            0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f, //
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58, //
+            0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f,
+        ]);
+        for _ in 0..3 {
+            assert_eq!(client.read_text().unwrap(), "Hello");
+        }
+        assert_eq!(
+            client.read_text().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let mut input = vec![0x81, 0x7e, 0x01, 0x00];
+        input.extend([b'a'; 256]);
+        input.extend([0x81, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]);
+        input.extend([b'b'; 65536]);
+        let (mut client, _) = self::client(&input);
+        assert_eq!(client.read_text().unwrap(), "a".repeat(256));
+        assert_eq!(client.read_text().unwrap(), "b".repeat(65536));
+    }
+
+    #[test]
+    fn pings_are_answered_between_fragments() {
+        let (mut client, sent) = client(&[
+            0x01, 0x03, 0x48, 0x65, 0x6c, //
+            0x89, 0x02, 0x68, 0x69, // ping "hi"
+            0x8a, 0x00, // unsolicited pong
+            0x80, 0x02, 0x6c, 0x6f,
+        ]);
+        assert_eq!(client.read_text().unwrap(), "Hello");
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                0x8a,
+                0x82,
+                MASK[0],
+                MASK[1],
+                MASK[2],
+                MASK[3],
+                b'h' ^ MASK[0],
+                b'i' ^ MASK[1]
+            ]
+        );
+    }
+
+    #[test]
+    fn close_frames_are_echoed() {
+        let (mut client, sent) = client(&[0x88, 0x02, 0x03, 0xe8]);
+        let e = client.read_text().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                0x88,
+                0x82,
+                MASK[0],
+                MASK[1],
+                MASK[2],
+                MASK[3],
+                0x03 ^ MASK[0],
+                0xe8 ^ MASK[1]
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_and_invalid_messages_are_rejected() {
+        let rejected = |input: &[u8]| {
+            let (mut client, _) = client(input);
+            client.read_text().unwrap_err().to_string()
+        };
+        // 16 MiB plus one, in one frame and split over two
+        let mut input = vec![0x81, 0x7f];
+        input.extend(((MAX_MESSAGE_LEN + 1) as u64).to_be_bytes());
+        assert_eq!(rejected(&input), "websocket message too large");
+        let mut input = vec![0x01, 0x01, b'a', 0x80, 0x7f];
+        input.extend((MAX_MESSAGE_LEN as u64).to_be_bytes());
+        assert_eq!(rejected(&input), "websocket message too large");
+        // A length that would overflow once added to the fragments so far
+        let mut input = vec![0x01, 0x01, b'a', 0x80, 0x7f];
+        input.extend(u64::MAX.to_be_bytes());
+        assert_eq!(rejected(&input), "websocket message too large");
+
+        assert_eq!(rejected(&[0x83, 0x00]), "unknown websocket opcode");
+        assert_eq!(
+            rejected(&[0x81, 0x02, 0xc3, 0x28]),
+            "websocket message is not UTF-8"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Token buckets, for rate limiting RPC endpoints and the binary's clients.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often `RateLimiter` forgets clients whose buckets have refilled.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Slowest rate a limit may be configured with: a call every 1000 seconds.
+pub const MIN_RATE: f64 = 0.001;
+
+/// Allows `rate` calls per second, with bursts of up to `burst` calls.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket. `burst` is raised to 1, so that a call can always get through.
+    pub fn new(rate: f64, burst: f64) -> TokenBucket {
+        let burst = burst.max(1.0);
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+    }
+
+    /// Take a token, or return how long until one is available.
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / self.rate;
+            Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+        }
+    }
+}
+
+/// A token bucket per client IP address.
+pub struct RateLimiter {
+    buckets: Mutex<Clients>,
+}
+
+struct Clients {
+    by_ip: HashMap<IpAddr, TokenBucket>,
+    swept_at: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter {
+            buckets: Mutex::new(Clients {
+                by_ip: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Take a token from `client`'s bucket, allowing `rate` requests per second with
+    /// bursts of `burst`, or return how long until one is available. The limits are
+    /// passed on every call so they can change at runtime.
+    pub fn check(&self, client: IpAddr, rate: f64, burst: f64) -> Result<(), Duration> {
+        let mut clients = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        // A full bucket is no different from a new one, so dropping it loses nothing
+        if now.duration_since(clients.swept_at) >= SWEEP_INTERVAL {
+            clients.by_ip.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.burst
+            });
+            clients.swept_at = now;
+        }
+        let bucket = clients
+            .by_ip
+            .entry(client)
+            .or_insert_with(|| TokenBucket::new(rate, burst));
+        bucket.rate = rate;
+        bucket.burst = burst.max(1.0);
+        bucket.try_acquire()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_allow_bursts_then_the_rate() {
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        for _ in 0..3 {
+            assert_eq!(bucket.try_acquire(), Ok(()));
+        }
+        let wait = bucket.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+
+        // Half a second later, one more call gets through
+        let now = bucket.refilled_at + Duration::from_millis(500);
+        bucket.refill(now);
+        assert!((bucket.tokens - 1.0).abs() < 0.01);
+        // And refilling never overfills
+        bucket.refill(now + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn bursts_below_one_still_allow_a_call() {
+        let mut bucket = TokenBucket::new(1.0, 0.0);
+        assert_eq!(bucket.try_acquire(), Ok(()));
+        assert!(bucket.try_acquire().is_err());
+    }
+
+    #[test]
+    fn waits_saturate_at_tiny_rates() {
+        let mut bucket = TokenBucket::new(1e-30, 1.0);
+        assert_eq!(bucket.try_acquire(), Ok(()));
+        assert_eq!(bucket.try_acquire(), Err(Duration::MAX));
+
+        let mut bucket = TokenBucket::new(MIN_RATE, 1.0);
+        bucket.try_acquire().unwrap();
+        let wait = bucket.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_secs(999) && wait <= Duration::from_secs(1000));
+    }
+
+    #[test]
+    fn clients_get_a_bucket_each() {
+        let limiter = RateLimiter::default();
+        let (a, b) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        assert_eq!(limiter.check(a, 1.0, 1.0), Ok(()));
+        assert!(limiter.check(a, 1.0, 1.0).is_err());
+        assert_eq!(limiter.check(b, 1.0, 1.0), Ok(()));
+    }
+}
@@ -0,0 +1,1113 @@
+//! Minimal Solana JSON-RPC client for the methods the estimator needs.
+
+use crate::compute_budget::bs58_decode;
+use crate::compute_budget::{COMPUTE_BUDGET_PROGRAM, ComputeBudget};
+use crate::error::EstimatorError;
+use crate::jito::{SYSTEM_PROGRAM, tip_lamports, transfer_lamports};
+use crate::sources::Sample;
+use crate::transaction::{ParsedTransaction, TransactionEncoding, base64_decode};
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub const LAMPORTS_PER_SIGNATURE: u64 = 5000;
+/// Most signatures getSignaturesForAddress returns per call.
+pub const MAX_SIGNATURES_PER_CALL: usize = 1000;
+pub const VOTE_PROGRAM: &str = "Vote111111111111111111111111111111111111111";
+pub const TOKEN_PROGRAM: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+pub const TOKEN_2022_PROGRAM: &str = "TokenzQdBNbLqP5VEhdkAS6EPFLC1PnBoXt9aMXDbv2";
+
+// Token program instructions that move tokens between accounts
+const TOKEN_TRANSFER: u8 = 3;
+const TOKEN_TRANSFER_CHECKED: u8 = 12;
+
+/// How settled the state an RPC call reads must be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Commitment {
+    Processed, // the node's latest block, which may yet be skipped
+    Confirmed, // voted on by a supermajority of the cluster
+    Finalized, // rooted, so it can't be rolled back
+}
+
+impl Commitment {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Commitment::Processed => "processed",
+            Commitment::Confirmed => "confirmed",
+            Commitment::Finalized => "finalized",
+        }
+    }
+
+    /// This, with `Confirmed` in place of `Processed`, which getSignaturesForAddress,
+    /// getTransaction and getBlock don't accept.
+    pub fn at_least_confirmed(self) -> Commitment {
+        match self {
+            Commitment::Processed => Commitment::Confirmed,
+            other => other,
+        }
+    }
+}
+
+impl FromStr for Commitment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Commitment, String> {
+        match s {
+            "processed" => Ok(Commitment::Processed),
+            "confirmed" => Ok(Commitment::Confirmed),
+            "finalized" => Ok(Commitment::Finalized),
+            _ => Err(format!("unknown commitment: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Commitment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How getTransaction and getBlock return transactions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RpcEncoding {
+    Json,   // the message as JSON, with base58 instruction data
+    Base64, // the serialized transaction, decoded here
+}
+
+impl RpcEncoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RpcEncoding::Json => "json",
+            RpcEncoding::Base64 => "base64",
+        }
+    }
+}
+
+impl FromStr for RpcEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RpcEncoding, String> {
+        match s {
+            "json" => Ok(RpcEncoding::Json),
+            "base64" => Ok(RpcEncoding::Base64),
+            _ => Err(format!("unknown encoding: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for RpcEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How transactions are fetched with getTransaction and getBlock.
+#[derive(Clone, Copy, Debug)]
+pub struct TransactionOptions {
+    pub commitment: Commitment,
+    pub encoding: RpcEncoding,
+    pub max_supported_version: Option<u8>, // `None` for legacy transactions only
+    pub max_response_len: u64,             // largest response read
+}
+
+impl TransactionOptions {
+    /// The options getTransaction and getBlock share, to add theirs to.
+    fn params(&self) -> serde_json::Value {
+        let mut params = json!({
+            "commitment": self.commitment.at_least_confirmed().as_str(),
+            "encoding": self.encoding.as_str()
+        });
+        if let Some(version) = self.max_supported_version {
+            params["maxSupportedTransactionVersion"] = json!(version);
+        }
+        params
+    }
+}
+
+// --------------------------- JSON-RPC plumbing ---------------------------
+
+#[derive(Serialize)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: &'static str,
+    pub id: serde_json::Value, // allow numeric or string
+    pub method: &'static str,
+    pub params: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+pub struct SingleResponse<T> {
+    #[serde(default)]
+    pub result: Option<T>,
+    #[serde(default)]
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchItem<T> {
+    #[serde(default)]
+    pub result: Option<T>,
+    #[serde(default)]
+    pub error: Option<JsonRpcError>,
+    pub id: serde_json::Value,
+}
+
+/// A response body, read up to `limit` bytes. Reading past the limit is an error rather
+/// than a truncated body, so an oversized response isn't mistaken for malformed JSON.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    limit: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    fn new(inner: R, limit: u64) -> LimitedReader<R> {
+        LimitedReader {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return match self.inner.read(&mut [0u8])? {
+                0 => Ok(0),
+                _ => Err(io::Error::other(format!(
+                    "response larger than {} bytes",
+                    self.limit
+                ))),
+            };
+        }
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..len])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// A reader for the body of `resp`, failing past `max_response_len` bytes.
+fn body_reader(resp: ureq::Response, max_response_len: u64) -> impl Read {
+    BufReader::new(LimitedReader::new(resp.into_reader(), max_response_len))
+}
+
+// --------------------------- getSignaturesForAddress ---------------------------
+
+#[derive(Deserialize)]
+pub struct SignatureInfo {
+    pub signature: String,
+    // other fields available but not required here
+}
+
+/// Signatures of `address`'s latest transactions, newest first, starting after
+/// `before` and stopping short of `until` if given.
+pub fn get_signatures_for_address(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    address: &str,
+    limit: usize,
+    before: Option<&str>,
+    until: Option<&str>,
+    commitment: Commitment,
+) -> Result<Vec<String>, EstimatorError> {
+    let limit = limit.min(MAX_SIGNATURES_PER_CALL);
+    let mut options = json!({
+        "commitment": commitment.at_least_confirmed().as_str(),
+        "limit": limit
+    });
+    if let Some(before) = before {
+        options["before"] = json!(before);
+    }
+    if let Some(until) = until {
+        options["until"] = json!(until);
+    }
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "getSignaturesForAddress",
+        params: json!([address, options]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<Vec<SignatureInfo>> = resp.into_json()?;
+
+    if let Some(err) = resp.error {
+        return Err(EstimatorError::Rpc {
+            method: "getSignaturesForAddress",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    let result = resp.result.ok_or_else(|| {
+        EstimatorError::Validation("getSignaturesForAddress: missing result".to_string())
+    })?;
+
+    Ok(result.into_iter().map(|s| s.signature).collect())
+}
+
+// --------------------------- getRecentPrioritizationFees ---------------------------
+
+#[derive(Deserialize)]
+pub struct PrioritizationFee {
+    #[serde(rename = "prioritizationFee")]
+    pub prioritization_fee: u64,
+    pub slot: u64,
+}
+
+/// Per-slot minimum priority fees (in micro-lamports) over the node's recent slots,
+/// for transactions write-locking all of `accounts` (or any transaction, if empty).
+pub fn get_recent_prioritization_fees(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    accounts: &[String],
+) -> Result<Vec<PrioritizationFee>, EstimatorError> {
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "getRecentPrioritizationFees",
+        params: json!([accounts]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<Vec<PrioritizationFee>> = resp.into_json()?;
+
+    if let Some(err) = resp.error {
+        return Err(EstimatorError::Rpc {
+            method: "getRecentPrioritizationFees",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    let result = resp.result.ok_or_else(|| {
+        EstimatorError::Validation("getRecentPrioritizationFees: missing result".to_string())
+    })?;
+
+    Ok(result)
+}
+
+// --------------------------- getSlot ---------------------------
+
+pub fn get_slot(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    commitment: Commitment,
+) -> Result<u64, EstimatorError> {
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "getSlot",
+        params: json!([{ "commitment": commitment.as_str() }]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<u64> = resp.into_json()?;
+
+    if let Some(err) = resp.error {
+        return Err(EstimatorError::Rpc {
+            method: "getSlot",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    resp.result
+        .ok_or_else(|| EstimatorError::Validation("getSlot: missing result".to_string()))
+}
+
+// --------------------------- getAccountInfo ---------------------------
+
+#[derive(Deserialize, Default)]
+struct AccountInfoResult {
+    value: Option<AccountInfo>,
+}
+
+#[derive(Deserialize)]
+struct AccountInfo {
+    data: (String, String), // [data, encoding]
+}
+
+/// The data of the account at `address`, or `None` if there's no such account.
+pub fn get_account_info(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    address: &str,
+    commitment: Commitment,
+) -> Result<Option<Vec<u8>>, EstimatorError> {
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "getAccountInfo",
+        params: json!([address, { "commitment": commitment.as_str(), "encoding": "base64" }]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<AccountInfoResult> = resp.into_json()?;
+
+    if let Some(err) = resp.error {
+        return Err(EstimatorError::Rpc {
+            method: "getAccountInfo",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    let result = resp
+        .result
+        .ok_or_else(|| EstimatorError::Validation("getAccountInfo: missing result".to_string()))?;
+    let Some(account) = result.value else {
+        return Ok(None);
+    };
+    base64_decode(&account.data.0).map(Some).ok_or_else(|| {
+        EstimatorError::Validation("getAccountInfo: data is not valid base64".to_string())
+    })
+}
+
+// --------------------------- getHealth ---------------------------
+
+pub fn get_health(agent: &ureq::Agent, rpc_url: &str) -> Result<(), EstimatorError> {
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "getHealth",
+        params: json!([]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<String> = resp.into_json()?;
+
+    if let Some(err) = resp.error {
+        return Err(EstimatorError::Rpc {
+            method: "getHealth",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    Ok(())
+}
+
+// --------------------------- getRecentPerformanceSamples ---------------------------
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PerformanceSample {
+    pub slot: u64,
+    pub num_transactions: u64,
+    pub num_non_vote_transactions: Option<u64>, // absent on older nodes
+    pub num_slots: u64,
+    pub sample_period_secs: u64,
+}
+
+/// The node's latest `limit` performance samples, newest first, each counting the
+/// transactions and slots of (usually) one minute.
+pub fn get_recent_performance_samples(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    limit: usize,
+) -> Result<Vec<PerformanceSample>, EstimatorError> {
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "getRecentPerformanceSamples",
+        params: json!([limit]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<Vec<PerformanceSample>> = resp.into_json()?;
+
+    if let Some(err) = resp.error {
+        return Err(EstimatorError::Rpc {
+            method: "getRecentPerformanceSamples",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    resp.result.ok_or_else(|| {
+        EstimatorError::Validation("getRecentPerformanceSamples: missing result".to_string())
+    })
+}
+
+// --------------------------- simulateTransaction ---------------------------
+
+#[derive(Deserialize, Default)]
+pub struct WithContext<T> {
+    pub value: T,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationResult {
+    #[serde(default)]
+    pub err: Option<serde_json::Value>, // null on success
+    #[serde(default)]
+    pub logs: Option<Vec<String>>,
+    pub units_consumed: Option<u64>,
+}
+
+/// Simulate a serialized transaction against the latest blockhash, without verifying
+/// its signatures, so unsigned transactions can be simulated too.
+pub fn simulate_transaction(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    transaction: &str,
+    encoding: TransactionEncoding,
+    commitment: Commitment,
+) -> Result<SimulationResult, EstimatorError> {
+    let encoding = match encoding {
+        TransactionEncoding::Base58 => "base58",
+        TransactionEncoding::Base64 => "base64",
+    };
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "simulateTransaction",
+        params: json!([
+            transaction,
+            {
+                "commitment": commitment.as_str(),
+                "encoding": encoding,
+                "sigVerify": false,
+                "replaceRecentBlockhash": true
+            }
+        ]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<WithContext<SimulationResult>> = resp.into_json()?;
+
+    if let Some(err) = resp.error {
+        return Err(EstimatorError::Rpc {
+            method: "simulateTransaction",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    Ok(resp
+        .result
+        .ok_or_else(|| {
+            EstimatorError::Validation("simulateTransaction: missing result".to_string())
+        })?
+        .value)
+}
+
+// --------------------------- getBlock ---------------------------
+
+// Slot was skipped, or the block is unavailable on this node
+pub const BLOCK_UNAVAILABLE_ERROR_CODES: [i64; 3] = [-32004, -32007, -32009];
+
+#[derive(Deserialize)]
+pub struct BlockTransaction {
+    pub meta: Option<TransactionMeta>,
+    pub transaction: Transaction,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Block {
+    pub transactions: Vec<BlockTransaction>,
+    #[serde(rename = "blockTime")]
+    pub block_time: Option<u64>,
+}
+
+/// Which of a block's transactions are left out of its fees as noise. All are by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockFilters {
+    pub votes: bool,             // validators' vote transactions
+    pub transfers: bool,         // transactions that only transfer SOL or tokens
+    pub no_compute_budget: bool, // transactions without a ComputeBudget instruction
+}
+
+impl Default for BlockFilters {
+    fn default() -> BlockFilters {
+        BlockFilters {
+            votes: true,
+            transfers: true,
+            no_compute_budget: true,
+        }
+    }
+}
+
+impl BlockFilters {
+    /// Whether a transaction with `message` is kept.
+    pub fn keeps(&self, message: &Message) -> bool {
+        !(self.votes && message.is_vote()
+            || self.transfers && message.is_transfer()
+            || self.no_compute_budget && !message.sets_compute_budget())
+    }
+}
+
+/// Priority fees of the transactions in `slot` that reference `program`, or of all of
+/// them if `program` is `None`, less those `filters` leave out. Skipped or unavailable
+/// slots yield no fees.
+pub fn get_block_priority_fees(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    slot: u64,
+    program: Option<&str>,
+    filters: BlockFilters,
+    options: &TransactionOptions,
+) -> Result<Vec<Sample>, EstimatorError> {
+    let mut params = options.params();
+    params["transactionDetails"] = json!("full");
+    params["rewards"] = json!(false);
+    let req = JsonRpcRequest {
+        jsonrpc: "2.0",
+        id: json!(1),
+        method: "getBlock",
+        params: json!([slot, params]),
+    };
+
+    let resp = agent.post(rpc_url).send_json(&req)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let resp: SingleResponse<Block> =
+        serde_json::from_reader(body_reader(resp, options.max_response_len))?;
+
+    if let Some(err) = resp.error {
+        if BLOCK_UNAVAILABLE_ERROR_CODES.contains(&err.code) {
+            return Ok(Vec::new());
+        }
+        return Err(EstimatorError::Rpc {
+            method: "getBlock",
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    let block = resp
+        .result
+        .ok_or_else(|| EstimatorError::Validation("getBlock: missing result".to_string()))?;
+    Ok(block
+        .transactions
+        .into_iter()
+        .filter(|tx| {
+            let keys = &tx.transaction.message.account_keys;
+            filters.keeps(&tx.transaction.message)
+                && program.is_none_or(|program| keys.iter().any(|k| k == program))
+        })
+        .filter_map(|tx| priority_fee(tx.meta.as_ref()?, &tx.transaction))
+        .map(|fee| Sample {
+            slot: Some(slot),
+            block_time: block.block_time,
+            ..fee
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Default)]
+struct BlockHeader {
+    blockhash: String,
+}
+
+/// The blockhash of each of `slots`, with one batch of getBlock calls that skip the
+/// transactions. Skipped or unavailable slots have none.
+pub fn get_blockhashes(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    slots: &[u64],
+    commitment: Commitment,
+) -> Result<Vec<(u64, Option<String>)>, EstimatorError> {
+    let params = json!({
+        "commitment": commitment.at_least_confirmed().as_str(),
+        "transactionDetails": "none",
+        "rewards": false
+    });
+    let batch: Vec<JsonRpcRequest> = slots
+        .iter()
+        .map(|&slot| JsonRpcRequest {
+            jsonrpc: "2.0",
+            id: json!(slot),
+            method: "getBlock",
+            params: json!([slot, params]),
+        })
+        .collect();
+
+    let resp = agent.post(rpc_url).send_json(&batch)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    let items: Vec<BatchItem<BlockHeader>> = resp.into_json()?;
+    if items.is_empty() && !slots.is_empty() {
+        return Err(EstimatorError::BatchTooLarge);
+    }
+    let mut hashes = Vec::with_capacity(items.len());
+    for item in items {
+        let Some(slot) = item.id.as_u64() else {
+            continue;
+        };
+        match (item.result, item.error) {
+            (Some(block), _) => hashes.push((slot, Some(block.blockhash))),
+            (None, Some(err)) if !BLOCK_UNAVAILABLE_ERROR_CODES.contains(&err.code) => {
+                return Err(EstimatorError::Rpc {
+                    method: "getBlock",
+                    code: err.code,
+                    message: err.message,
+                });
+            }
+            (None, _) => hashes.push((slot, None)),
+        }
+    }
+    Ok(hashes)
+}
+
+// --------------------------- getTransaction (batch) ---------------------------
+
+#[derive(Deserialize, Debug)]
+pub struct TransactionMeta {
+    pub fee: u64,
+    #[serde(default)]
+    pub err: Option<serde_json::Value>, // null on success
+    #[serde(rename = "computeUnitsConsumed")]
+    pub compute_units_consumed: Option<u64>,
+    #[serde(rename = "loadedAddresses")]
+    pub loaded_addresses: Option<LoadedAddresses>, // keys a v0 transaction loaded from lookup tables
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct LoadedAddresses {
+    #[serde(default)]
+    pub writable: Vec<String>,
+    #[serde(default)]
+    pub readonly: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageHeader {
+    pub num_required_signatures: u64,
+    #[serde(default)]
+    pub num_readonly_signed_accounts: u64,
+    #[serde(default)]
+    pub num_readonly_unsigned_accounts: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompiledInstruction {
+    pub program_id_index: usize,
+    #[serde(default)]
+    pub accounts: Vec<usize>,
+    pub data: InstructionData,
+}
+
+/// An instruction's data: base58, as the `json` encoding gives it, or the bytes of a
+/// transaction decoded here.
+#[derive(Deserialize, Debug)]
+#[serde(from = "String")]
+pub enum InstructionData {
+    Base58(String), // decoded only when read, as most instructions' data never is
+    Bytes(Vec<u8>),
+}
+
+impl InstructionData {
+    /// The data, or `None` if it isn't valid base58.
+    pub fn bytes(&self) -> Option<Cow<'_, [u8]>> {
+        match self {
+            InstructionData::Base58(s) => bs58_decode(s).map(Cow::Owned),
+            InstructionData::Bytes(b) => Some(Cow::Borrowed(b)),
+        }
+    }
+}
+
+impl From<String> for InstructionData {
+    fn from(s: String) -> InstructionData {
+        InstructionData::Base58(s)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub header: MessageHeader,
+    pub account_keys: Vec<String>, // static keys only; lookup-table keys are in meta
+    #[serde(default)]
+    pub recent_blockhash: String,
+    #[serde(default)]
+    pub instructions: Vec<CompiledInstruction>,
+}
+
+impl Message {
+    /// The program `ix` invokes, if its index is in range. Programs can't be loaded from
+    /// lookup tables, so the static keys are enough.
+    fn program(&self, ix: &CompiledInstruction) -> Option<&str> {
+        self.account_keys
+            .get(ix.program_id_index)
+            .map(String::as_str)
+    }
+
+    /// Whether the message invokes the Vote program, as validators' vote transactions do.
+    pub fn is_vote(&self) -> bool {
+        self.instructions
+            .iter()
+            .any(|ix| self.program(ix) == Some(VOTE_PROGRAM))
+    }
+
+    /// Whether the message only moves SOL or tokens: every instruction but ComputeBudget
+    /// ones is a System program or Token program transfer, and there's at least one.
+    pub fn is_transfer(&self) -> bool {
+        let mut transfers = 0;
+        for ix in &self.instructions {
+            let data = ix.data.bytes();
+            let data = data.as_deref().unwrap_or_default();
+            match self.program(ix) {
+                Some(COMPUTE_BUDGET_PROGRAM) => {}
+                Some(SYSTEM_PROGRAM) if transfer_lamports(data).is_some() => transfers += 1,
+                Some(TOKEN_PROGRAM | TOKEN_2022_PROGRAM)
+                    if matches!(
+                        data.first(),
+                        Some(&(TOKEN_TRANSFER | TOKEN_TRANSFER_CHECKED))
+                    ) =>
+                {
+                    transfers += 1
+                }
+                _ => return false,
+            }
+        }
+        transfers > 0
+    }
+
+    /// Whether the message has a ComputeBudget instruction. Without one it requests no
+    /// priority fee.
+    pub fn sets_compute_budget(&self) -> bool {
+        self.instructions
+            .iter()
+            .any(|ix| self.program(ix) == Some(COMPUTE_BUDGET_PROGRAM))
+    }
+
+    /// The static account keys the message write-locks.
+    pub fn writable_accounts(&self) -> impl Iterator<Item = &String> {
+        let header = &self.header;
+        let num_keys = self.account_keys.len() as u64;
+        let signed = header.num_required_signatures;
+        self.account_keys
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| {
+                let i = *i as u64;
+                if i < signed {
+                    i + header.num_readonly_signed_accounts < signed
+                } else {
+                    i + header.num_readonly_unsigned_accounts < num_keys
+                }
+            })
+            .map(|(_, k)| k)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "EncodedTransaction")]
+pub struct Transaction {
+    pub signature: Option<String>, // the first, which identifies the transaction
+    pub message: Message,
+}
+
+/// A transaction as getTransaction and getBlock return it: a JSON message, or
+/// `[data, encoding]` for the binary encodings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EncodedTransaction {
+    Json {
+        message: Message,
+        #[serde(default)]
+        signatures: Vec<String>,
+    },
+    Binary(String, String),
+}
+
+impl TryFrom<EncodedTransaction> for Transaction {
+    type Error = String;
+
+    fn try_from(encoded: EncodedTransaction) -> Result<Transaction, String> {
+        let (data, encoding) = match encoded {
+            EncodedTransaction::Json {
+                message,
+                signatures,
+            } => {
+                return Ok(Transaction {
+                    signature: signatures.into_iter().next(),
+                    message,
+                });
+            }
+            EncodedTransaction::Binary(data, encoding) => (data, encoding),
+        };
+        let encoding = match encoding.as_str() {
+            "base64" => TransactionEncoding::Base64,
+            "base58" => TransactionEncoding::Base58,
+            other => return Err(format!("unsupported transaction encoding: {}", other)),
+        };
+        let tx = ParsedTransaction::decode(&data, encoding)?;
+        Ok(Transaction {
+            signature: tx.signature.clone(),
+            message: Message::from(tx),
+        })
+    }
+}
+
+/// The message of a transaction decoded here, as the `json` encoding would give it.
+impl From<ParsedTransaction> for Message {
+    fn from(tx: ParsedTransaction) -> Message {
+        let signed = tx.num_required_signatures as usize;
+        let readonly = |writable: &[bool]| writable.iter().filter(|w| !**w).count() as u64;
+        let (signers, others) = tx.writable.split_at(signed.min(tx.writable.len()));
+        Message {
+            header: MessageHeader {
+                num_required_signatures: signed as u64,
+                num_readonly_signed_accounts: readonly(signers),
+                num_readonly_unsigned_accounts: readonly(others),
+            },
+            account_keys: tx.account_keys,
+            recent_blockhash: tx.recent_blockhash,
+            instructions: tx
+                .instructions
+                .into_iter()
+                .map(|ix| CompiledInstruction {
+                    program_id_index: ix.program_id_index,
+                    accounts: ix.accounts.into_iter().map(usize::from).collect(),
+                    data: InstructionData::Bytes(ix.data),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TransactionResult {
+    pub slot: Option<u64>,
+    #[serde(rename = "blockTime")]
+    pub block_time: Option<u64>,
+    pub meta: Option<TransactionMeta>,
+    pub transaction: Option<Transaction>,
+}
+
+/// What a getTransaction batch yielded.
+#[derive(Debug, Default)]
+pub struct PricedBatch {
+    pub samples: Vec<Sample>,
+    pub failed: Vec<String>, // signatures the node errored on or didn't find, worth retrying
+}
+
+/// Price `signatures` with one getTransaction batch. Transactions found but without a
+/// priority fee (no meta, no compute units consumed) are skipped; those the node
+/// couldn't return are listed as failed rather than failing the whole batch.
+pub fn get_priority_fees_for_signatures(
+    agent: &ureq::Agent,
+    rpc_url: &str,
+    signatures: &[String],
+    options: &TransactionOptions,
+) -> Result<PricedBatch, EstimatorError> {
+    // Build a JSON-RPC batch
+    let params = options.params();
+    let mut batch: Vec<JsonRpcRequest> = Vec::with_capacity(signatures.len());
+    for (i, sig) in signatures.iter().enumerate() {
+        batch.push(JsonRpcRequest {
+            jsonrpc: "2.0",
+            id: json!(i as u64),
+            method: "getTransaction",
+            params: json!([sig, params]),
+        });
+    }
+
+    // Send the batch
+    let resp = agent.post(rpc_url).send_json(&batch)?;
+    if resp.status() != 200 {
+        let status = resp.status();
+        let body = resp.into_string()?;
+        return Err(EstimatorError::Status { status, body });
+    }
+    // Price each transaction as it's parsed, rather than holding the whole batch
+    let mut samples = Vec::with_capacity(signatures.len());
+    let mut answered = vec![false; signatures.len()];
+    let mut de = serde_json::Deserializer::from_reader(body_reader(resp, options.max_response_len));
+    let items = de.deserialize_seq(BatchVisitor(|item: BatchItem<TransactionResult>| {
+        let index = item.id.as_u64().map(|i| i as usize);
+        match batch_item_priority_fee(item) {
+            BatchItemFee::Missing => return,
+            BatchItemFee::Priced(sample) => samples.push(sample),
+            BatchItemFee::Unpriced => {}
+        }
+        if let Some(answered) = index.and_then(|i| answered.get_mut(i)) {
+            *answered = true;
+        }
+    }))?;
+    de.end()?;
+    if items == 0 && !signatures.is_empty() {
+        return Err(EstimatorError::BatchTooLarge);
+    }
+
+    let failed = signatures
+        .iter()
+        .zip(answered)
+        .filter(|(_, answered)| !answered)
+        .map(|(signature, _)| signature.clone())
+        .collect();
+    Ok(PricedBatch { samples, failed })
+}
+
+/// Visits a JSON-RPC batch response one item at a time, passing each to the closure and
+/// counting them.
+struct BatchVisitor<F>(F);
+
+impl<'de, F: FnMut(BatchItem<TransactionResult>)> Visitor<'de> for BatchVisitor<F> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON-RPC batch response")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<usize, A::Error> {
+        let mut items = 0;
+        while let Some(item) = seq.next_element()? {
+            (self.0)(item);
+            items += 1;
+        }
+        Ok(items)
+    }
+}
+
+/// The error for a transaction newer than `maxSupportedTransactionVersion` allows.
+pub const UNSUPPORTED_TRANSACTION_VERSION: i64 = -32015;
+
+/// What a getTransaction batch item says about the transaction's priority fee.
+enum BatchItemFee {
+    Priced(Sample),
+    Unpriced, // found, but without meta or compute units consumed
+    Missing,  // errored (e.g. too old for the node) or not found
+}
+
+fn batch_item_priority_fee(item: BatchItem<TransactionResult>) -> BatchItemFee {
+    if let Some(err) = &item.error
+        && err.code == UNSUPPORTED_TRANSACTION_VERSION
+    {
+        return BatchItemFee::Unpriced; // asking again won't change the answer
+    }
+    if let Some(err) = item.error {
+        log::debug!(
+            "getTransaction error (id {:?}, code {}): {}",
+            item.id,
+            err.code,
+            err.message
+        );
+        return BatchItemFee::Missing;
+    }
+    let Some(tr) = item.result else {
+        return BatchItemFee::Missing;
+    };
+    let fee = tr
+        .meta
+        .as_ref()
+        .zip(tr.transaction.as_ref())
+        .and_then(|(meta, transaction)| priority_fee(meta, transaction));
+    match fee {
+        Some(fee) => BatchItemFee::Priced(Sample {
+            slot: tr.slot,
+            block_time: tr.block_time,
+            ..fee
+        }),
+        None => BatchItemFee::Unpriced,
+    }
+}
+
+/// A transaction's priority fee in micro-lamports per CU: the price it set with
+/// SetComputeUnitPrice, or, if its instructions can't be decoded, a price derived from
+/// its fee. Returns `None` if it consumed no compute units.
+pub fn priority_fee(meta: &TransactionMeta, transaction: &Transaction) -> Option<Sample> {
+    let compute_units = meta.compute_units_consumed.unwrap_or(0);
+    if compute_units == 0 {
+        return None;
+    }
+
+    let price = match ComputeBudget::from_message(&transaction.message) {
+        Some(budget) => budget.unit_price.unwrap_or(0),
+        None => derived_priority_fee(
+            meta.fee,
+            transaction.message.header.num_required_signatures,
+            compute_units,
+        ),
+    };
+    let loaded_writable = meta.loaded_addresses.iter().flat_map(|a| &a.writable);
+    Some(Sample {
+        signature: transaction.signature.as_deref().map(Arc::from),
+        price,
+        fee_lamports: Some(meta.fee),
+        failed: meta.err.is_some(),
+        compute_units: Some(compute_units),
+        slot: None,
+        block_time: None,
+        jito_tip: tip_lamports(&transaction.message, meta.loaded_addresses.as_ref()),
+        writable_accounts: transaction
+            .message
+            .writable_accounts()
+            .chain(loaded_writable)
+            .cloned()
+            .collect(),
+        recent_blockhash: Some(transaction.message.recent_blockhash.as_str())
+            .filter(|hash| !hash.is_empty())
+            .map(Arc::from),
+    })
+}
+
+/// Back-derive a priority fee from a transaction's total fee. Approximate: the division
+/// is by CUs consumed rather than the requested limit the fee was charged on.
+pub fn derived_priority_fee(fee_lamports: u64, num_signatures: u64, compute_units: u64) -> u64 {
+    // priority_fee_micro_lamports = ((fee_lamports - (5000 * n_signatures)) * 1_000_000) / compute_units
+    let base_fee = LAMPORTS_PER_SIGNATURE as u128 * num_signatures as u128;
+    let fee_lamports = fee_lamports as u128;
+    ((fee_lamports.saturating_sub(base_fee) * 1_000_000) / (compute_units as u128)) as u64
+}
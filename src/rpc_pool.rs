@@ -0,0 +1,978 @@
+//! Weighted load balancing and failover across several RPC endpoints, with a
+//! per-endpoint circuit breaker and optional rate limit, backing off from endpoints
+//! that rate limit calls.
+
+use crate::error::{CodedError, ErrorCode, EstimatorError, is_timeout};
+use crate::logging;
+use crate::proxy::{self, Proxy};
+use crate::rate_limit::{MIN_RATE, TokenBucket};
+use crate::rpc::get_health;
+use crate::telemetry;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+pub const DEFAULT_COOLDOWN_SECS: u64 = 30;
+pub const DEFAULT_POOL_SIZE: usize = 16;
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 50;
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_READ_TIMEOUT_SECS: u64 = 30;
+/// How long a rate-limited endpoint is left alone when it doesn't say, doubled for each
+/// rate limit in a row, up to `MAX_RATE_LIMIT_BACKOFF`.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(30);
+/// Times a call waits out rate limits before giving up on an endpoint.
+const RATE_LIMIT_RETRIES: u32 = 3;
+/// Lowest sampling level; see `RpcPool::sampling_level`.
+const MIN_SAMPLING_LEVEL: f64 = 0.125;
+/// How much the sampling level recovers each `SAMPLING_RAMP_INTERVAL` without a rate limit.
+const SAMPLING_RAMP_STEP: f64 = 0.125;
+const SAMPLING_RAMP_INTERVAL: Duration = Duration::from_secs(30);
+/// Rate limits this close together, as a burst of concurrent calls gets, lower the
+/// sampling level once.
+const SAMPLING_LOWER_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An RPC endpoint as configured: `url[;weight=N][;rps=N]`.
+#[derive(Clone, Debug)]
+pub struct EndpointConfig {
+    pub url: String,
+    pub weight: u32,          // relative share of calls
+    pub max_rps: Option<f64>, // calls per second, if rate limited
+}
+
+impl EndpointConfig {
+    pub fn new(url: &str) -> EndpointConfig {
+        EndpointConfig {
+            url: url.to_string(),
+            weight: 1,
+            max_rps: None,
+        }
+    }
+
+    /// Parse `url[;weight=N][;rps=N]`, e.g. `https://rpc.example.com;weight=80;rps=50`.
+    pub fn parse(s: &str) -> Result<EndpointConfig, String> {
+        let mut parts = s.split(';').map(str::trim);
+        let mut endpoint = EndpointConfig::new(parts.next().unwrap_or(""));
+        if endpoint.url.is_empty() {
+            return Err(format!("Invalid RPC endpoint: {}", s));
+        }
+        for part in parts {
+            match part.split_once('=') {
+                Some(("weight", v)) => match v.parse::<u32>() {
+                    Ok(n) if n > 0 => endpoint.weight = n,
+                    _ => return Err(format!("Invalid weight in RPC endpoint: {}", s)),
+                },
+                Some(("rps", v)) => match v.parse::<f64>() {
+                    Ok(n) if (MIN_RATE..=f64::MAX).contains(&n) => endpoint.max_rps = Some(n),
+                    _ => {
+                        return Err(format!(
+                            "Invalid rps in RPC endpoint (must be at least {}): {}",
+                            MIN_RATE, s
+                        ));
+                    }
+                },
+                _ => return Err(format!("Unknown option '{}' in RPC endpoint: {}", part, s)),
+            }
+        }
+        Ok(endpoint)
+    }
+}
+
+/// Formats as `parse` reads it, leaving out default options.
+impl fmt::Display for EndpointConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.url)?;
+        if self.weight != 1 {
+            write!(f, ";weight={}", self.weight)?;
+        }
+        if let Some(rps) = self.max_rps {
+            write!(f, ";rps={}", rps)?;
+        }
+        Ok(())
+    }
+}
+
+/// A header sent with every call, as configured: `name:value`, e.g. `x-api-key:3f9c...`.
+#[derive(Clone, PartialEq)]
+pub struct Header {
+    pub name: String,
+    pub value: String, // a secret, as likely as not; left out of logs and errors
+}
+
+impl Header {
+    pub fn parse(s: &str) -> Result<Header, String> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or("Invalid RPC header: expected name:value")?;
+        let (name, value) = (name.trim(), value.trim());
+        let is_token_char = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return Err("Invalid RPC header name".to_string());
+        }
+        if value.is_empty() || !value.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+            return Err(format!("Invalid value for RPC header {}", name));
+        }
+        Ok(Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+struct Endpoint {
+    url: String,
+    weight: u32,
+    breaker: Mutex<Breaker>,
+    limiter: Option<Mutex<TokenBucket>>,
+    throttle: Mutex<Throttle>,
+    connections: Mutex<Connections>,
+}
+
+/// Keep-alive connections to an endpoint. They're all closed once the endpoint has gone
+/// unused for the pool's idle timeout, before the server or a load balancer drops them.
+struct Connections {
+    agent: ureq::Agent,
+    used_at: Instant,
+}
+
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    quarantined_until: Option<Instant>, // set while the circuit is open
+}
+
+/// An endpoint's rate limiting of our calls.
+#[derive(Default)]
+struct Throttle {
+    consecutive: u32,       // rate limits in a row
+    until: Option<Instant>, // calls wait until then
+}
+
+impl Throttle {
+    /// Back off after a rate limit, for `retry_after` if the endpoint said how long.
+    fn limited(&mut self, retry_after: Option<Duration>) -> Duration {
+        self.consecutive += 1;
+        let backoff = retry_after
+            .unwrap_or_else(|| RATE_LIMIT_BACKOFF * 2u32.saturating_pow(self.consecutive - 1))
+            .min(MAX_RATE_LIMIT_BACKOFF);
+        self.until = Some(Instant::now() + backoff);
+        backoff
+    }
+
+    /// How long until calls may be made again, if they must wait.
+    fn wait(&self) -> Option<Duration> {
+        let wait = self.until?.saturating_duration_since(Instant::now());
+        (!wait.is_zero()).then_some(wait)
+    }
+}
+
+/// How far sampling is scaled down while endpoints rate limit calls: halved on each rate
+/// limit, down to `MIN_SAMPLING_LEVEL`, then raised by `SAMPLING_RAMP_STEP` every
+/// `SAMPLING_RAMP_INTERVAL` without one.
+struct SamplingLevel {
+    level: f64,                  // as of `lowered_at`
+    lowered_at: Option<Instant>, // `None` at full level
+}
+
+impl SamplingLevel {
+    fn current(&self) -> f64 {
+        let Some(at) = self.lowered_at else {
+            return 1.0;
+        };
+        let steps = (at.elapsed().as_secs_f64() / SAMPLING_RAMP_INTERVAL.as_secs_f64()).floor();
+        (self.level + steps * SAMPLING_RAMP_STEP).min(1.0)
+    }
+
+    /// Halve the level, unless it was just lowered. Returns the new level, if lowered.
+    fn lower(&mut self) -> Option<f64> {
+        if self
+            .lowered_at
+            .is_some_and(|at| at.elapsed() < SAMPLING_LOWER_INTERVAL)
+        {
+            return None;
+        }
+        self.level = (self.current() / 2.0).max(MIN_SAMPLING_LEVEL);
+        self.lowered_at = Some(Instant::now());
+        Some(self.level)
+    }
+}
+
+/// What a response said about the endpoint's rate limit, recorded by `WatchRateLimit`
+/// for `RpcPool::call` on the thread making the call.
+#[derive(Clone, Copy, Debug, Default)]
+struct RateLimitHint {
+    retry_after: Option<Duration>, // when the endpoint said to try again
+}
+
+thread_local! {
+    static DEADLINE: Cell<Option<Deadline>> = const { Cell::new(None) };
+    static RATE_LIMIT: Cell<Option<RateLimitHint>> = const { Cell::new(None) };
+}
+
+/// A time by which calls must be done, such as the end of a refresh's time budget.
+/// Once it has passed, `RpcPool::call` fails on threads that entered it.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    at: Instant,
+    timeout: Duration, // for error messages
+}
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline {
+            at: Instant::now() + timeout,
+            timeout,
+        }
+    }
+
+    /// How long until the deadline passes.
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    /// The deadline this thread entered, to pass on to threads it spawns.
+    pub fn current() -> Option<Deadline> {
+        DEADLINE.with(Cell::get)
+    }
+
+    /// Apply the deadline to calls on this thread until the guard is dropped.
+    pub fn enter(self) -> DeadlineGuard {
+        DeadlineGuard {
+            previous: DEADLINE.with(|d| d.replace(Some(self))),
+            _not_send: std::marker::PhantomData,
+        }
+    }
+
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        if Instant::now() >= self.at {
+            telemetry::set_current("error.type", "timeout");
+            let message = format!("refresh timed out after {}s", self.timeout.as_secs());
+            return Err(CodedError::new(ErrorCode::Timeout, message).into());
+        }
+        Ok(())
+    }
+}
+
+pub struct DeadlineGuard {
+    previous: Option<Deadline>,
+    _not_send: std::marker::PhantomData<*const ()>, // must be dropped on the thread that entered
+}
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.previous));
+    }
+}
+
+/// A set of interchangeable RPC endpoints. Calls are spread between them by weighted
+/// round-robin and fail over on error; an endpoint that fails `failure_threshold` times
+/// in a row is quarantined for `cooldown`, after which a single success (from a call or a
+/// health probe) restores it. Rate-limited endpoints are skipped while out of budget, and
+/// calls wait for budget only when every available endpoint is exhausted. Endpoints that
+/// rate limit calls, with a 429 or by saying they have no requests left, are likewise
+/// skipped until they say to try again, without counting as failures.
+pub struct RpcPool {
+    endpoints: Vec<Endpoint>,
+    current_weights: Mutex<Vec<i64>>, // smooth weighted round-robin state
+    sampling: Mutex<SamplingLevel>,
+    adaptive: bool, // whether rate limits lower the sampling level
+    failure_threshold: u32,
+    cooldown: Duration,
+    http: HttpOptions,
+}
+
+/// How the pool's HTTP agents connect.
+#[derive(Clone)]
+struct HttpOptions {
+    pool_size: usize, // idle connections kept per endpoint
+    idle_timeout: Duration,
+    connect_timeout: Duration,
+    read_timeout: Duration, // for each read from the socket, not the whole response
+    headers: Vec<Header>,
+    proxy: Option<Arc<Proxy>>,
+    no_proxy: Vec<String>, // hosts reached directly
+}
+
+impl HttpOptions {
+    fn proxy_for(&self, url: &str) -> Option<&Arc<Proxy>> {
+        let host = url::Url::parse(url).ok()?.host_str()?.to_string();
+        self.proxy
+            .as_ref()
+            .filter(|_| !proxy::bypasses(&self.no_proxy, &host))
+    }
+
+    /// An agent for calls to `url`.
+    fn agent(&self, url: &str) -> ureq::Agent {
+        let mut builder = ureq::AgentBuilder::new().try_proxy_from_env(false);
+        if let Some(proxy) = self.proxy_for(url) {
+            // Checked by `RpcPool::with_proxy`
+            builder = builder.proxy(proxy.http_client_proxy().expect("invalid proxy"));
+        }
+        builder
+            .max_idle_connections(self.pool_size)
+            .max_idle_connections_per_host(self.pool_size)
+            .timeout_connect(self.connect_timeout)
+            .timeout_read(self.read_timeout)
+            .middleware(SetHeaders(self.headers.clone()))
+            .middleware(WatchRateLimit)
+            .build()
+    }
+}
+
+/// Records whether a response was rate limited: a 429, or a response saying no
+/// requests are left (`x-ratelimit-remaining` or `ratelimit-remaining` of 0), along with
+/// when to try again (`retry-after` or the matching `-reset`), in seconds or as a unix
+/// timestamp.
+struct WatchRateLimit;
+
+impl ureq::Middleware for WatchRateLimit {
+    fn handle(
+        &self,
+        request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        let response = next.handle(request)?;
+        let header = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|&name| response.header(name)?.trim().parse::<u64>().ok())
+        };
+        let exhausted = header(&["x-ratelimit-remaining", "ratelimit-remaining"]) == Some(0);
+        if response.status() == 429 || exhausted {
+            let retry_after = header(&["retry-after", "x-ratelimit-reset", "ratelimit-reset"])
+                .map(seconds_from_now);
+            RATE_LIMIT.with(|r| r.set(Some(RateLimitHint { retry_after })));
+        }
+        Ok(response)
+    }
+}
+
+/// `n` seconds, or the time until `n` if it's a unix timestamp.
+fn seconds_from_now(n: u64) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    match n > now.as_secs() / 2 {
+        true => Duration::from_secs(n).saturating_sub(now),
+        false => Duration::from_secs(n),
+    }
+}
+
+/// Sets the configured headers on every request.
+struct SetHeaders(Vec<Header>);
+
+impl ureq::Middleware for SetHeaders {
+    fn handle(
+        &self,
+        request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        let request = self
+            .0
+            .iter()
+            .fold(request, |request, h| request.set(&h.name, &h.value));
+        next.handle(request)
+    }
+}
+
+impl Default for HttpOptions {
+    fn default() -> HttpOptions {
+        HttpOptions {
+            pool_size: DEFAULT_POOL_SIZE,
+            idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS),
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            read_timeout: Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS),
+            headers: Vec::new(),
+            proxy: None,
+            no_proxy: Vec::new(),
+        }
+    }
+}
+
+impl RpcPool {
+    pub fn new(
+        endpoints: Vec<EndpointConfig>,
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) -> RpcPool {
+        assert!(!endpoints.is_empty(), "RpcPool needs at least one endpoint");
+        let http = HttpOptions::default();
+        RpcPool {
+            current_weights: Mutex::new(vec![0; endpoints.len()]),
+            sampling: Mutex::new(SamplingLevel {
+                level: 1.0,
+                lowered_at: None,
+            }),
+            adaptive: true,
+            endpoints: endpoints
+                .into_iter()
+                .map(|e| Endpoint {
+                    connections: Mutex::new(Connections {
+                        agent: http.agent(&e.url),
+                        used_at: Instant::now(),
+                    }),
+                    url: e.url,
+                    weight: e.weight,
+                    breaker: Mutex::new(Breaker::default()),
+                    limiter: e.max_rps.map(|rps| Mutex::new(TokenBucket::new(rps, rps))),
+                    throttle: Mutex::new(Throttle::default()),
+                })
+                .collect(),
+            failure_threshold,
+            cooldown,
+            http,
+        }
+    }
+
+    /// Keep up to `size` idle connections open to each endpoint, closing them after
+    /// `idle_timeout` unused. A size of 0 opens a new connection for every call.
+    pub fn with_connection_pool(mut self, size: usize, idle_timeout: Duration) -> RpcPool {
+        self.http.pool_size = size;
+        self.http.idle_timeout = idle_timeout;
+        self.rebuild_agents();
+        self
+    }
+
+    /// Give up on connecting to an endpoint after `connect`, and on a response when it
+    /// sends nothing for `read`.
+    pub fn with_timeouts(mut self, connect: Duration, read: Duration) -> RpcPool {
+        self.http.connect_timeout = connect;
+        self.http.read_timeout = read;
+        self.rebuild_agents();
+        self
+    }
+
+    /// Send `headers` with every call, such as a provider's API key.
+    pub fn with_headers(mut self, headers: Vec<Header>) -> RpcPool {
+        self.http.headers = headers;
+        self.rebuild_agents();
+        self
+    }
+
+    /// Call endpoints through `proxy`, except those on hosts in `no_proxy` (see
+    /// `proxy::bypasses`). Fails if a SOCKS5 proxy's bridge can't be started.
+    pub fn with_proxy(mut self, proxy: Proxy, no_proxy: Vec<String>) -> Result<RpcPool, String> {
+        let proxy = Arc::new(proxy);
+        proxy.http_client_proxy()?;
+        self.http.proxy = Some(proxy);
+        self.http.no_proxy = no_proxy;
+        self.rebuild_agents();
+        Ok(self)
+    }
+
+    /// Whether rate limits lower `sampling_level`; if not, it stays at 1.
+    pub fn with_adaptive_sampling(mut self, adaptive: bool) -> RpcPool {
+        self.adaptive = adaptive;
+        self
+    }
+
+    fn rebuild_agents(&mut self) {
+        for endpoint in &mut self.endpoints {
+            endpoint.connections.get_mut().unwrap().agent = self.http.agent(&endpoint.url);
+        }
+    }
+
+    /// A pool with a single endpoint and the default breaker settings.
+    pub fn single(url: &str) -> RpcPool {
+        RpcPool::new(
+            vec![EndpointConfig::new(url)],
+            DEFAULT_FAILURE_THRESHOLD,
+            Duration::from_secs(DEFAULT_COOLDOWN_SECS),
+        )
+    }
+
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.endpoints.iter().map(|e| e.url.as_str())
+    }
+
+    /// The endpoints as they were configured.
+    pub fn endpoint_configs(&self) -> Vec<EndpointConfig> {
+        self.endpoints
+            .iter()
+            .map(|e| EndpointConfig {
+                url: e.url.clone(),
+                weight: e.weight,
+                max_rps: e.limiter.as_ref().map(|l| l.lock().unwrap().rate()),
+            })
+            .collect()
+    }
+
+    /// A pool of each endpoint on its own, connecting as this one does, to compare them.
+    /// Each has a rate limit budget of its own, apart from this pool's.
+    pub fn endpoint_pools(&self) -> Vec<RpcPool> {
+        self.endpoint_configs()
+            .into_iter()
+            .map(|e| {
+                let mut pool = RpcPool::new(vec![e], self.failure_threshold, self.cooldown);
+                pool.http = self.http.clone();
+                pool.adaptive = self.adaptive;
+                pool.rebuild_agents();
+                pool
+            })
+            .collect()
+    }
+
+    /// How much of the configured sampling to do, from `MIN_SAMPLING_LEVEL` to 1: lowered
+    /// while the endpoints rate limit calls, and raised back gradually once they stop.
+    pub fn sampling_level(&self) -> f64 {
+        self.sampling.lock().unwrap().current()
+    }
+
+    pub fn adaptive_sampling(&self) -> bool {
+        self.adaptive
+    }
+
+    pub fn failure_threshold(&self) -> u32 {
+        self.failure_threshold
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.http.pool_size
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.http.idle_timeout
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        self.http.connect_timeout
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        self.http.read_timeout
+    }
+
+    pub fn headers(&self) -> &[Header] {
+        &self.http.headers
+    }
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.http.proxy.as_deref()
+    }
+
+    pub fn no_proxy(&self) -> &[String] {
+        &self.http.no_proxy
+    }
+
+    /// The proxy to reach `url` through, if any.
+    pub fn proxy_for(&self, url: &str) -> Option<&Arc<Proxy>> {
+        self.http.proxy_for(url)
+    }
+
+    /// Run `f` against the endpoint chosen by weighted round-robin, failing over to the
+    /// others in turn until one succeeds. Quarantined endpoints are skipped. An endpoint
+    /// that rate limits the call is tried again once it's waited out, up to
+    /// `RATE_LIMIT_RETRIES` times.
+    pub fn call<T, E: Into<Box<dyn Error>>>(
+        &self,
+        f: impl Fn(&ureq::Agent, &str) -> Result<T, E>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let start = self.pick();
+        let mut tried = vec![false; self.endpoints.len()];
+        let mut rate_limited = 0;
+        let mut last_error: Option<Box<dyn std::error::Error>> = None;
+        let deadline = Deadline::current();
+        loop {
+            let mut wait: Option<Duration> = None;
+            for i in 0..self.endpoints.len() {
+                let idx = (start + i) % self.endpoints.len();
+                let endpoint = &self.endpoints[idx];
+                if tried[idx] || !self.is_available(endpoint) {
+                    continue;
+                }
+                if let Some(deadline) = deadline {
+                    deadline.check()?;
+                }
+                let throttled = endpoint.throttle.lock().unwrap().wait();
+                let limited = endpoint
+                    .limiter
+                    .as_ref()
+                    .and_then(|l| l.lock().unwrap().try_acquire().err());
+                if let Some(w) = throttled.or(limited) {
+                    wait = Some(wait.map_or(w, |cur| cur.min(w)));
+                    continue;
+                }
+                tried[idx] = true;
+                RATE_LIMIT.with(|r| r.set(None));
+                let result = f(&self.agent(endpoint), &endpoint.url);
+                let hint = RATE_LIMIT.with(Cell::take);
+                match result {
+                    Ok(v) => {
+                        self.record_success(endpoint);
+                        if let Some(hint) = hint {
+                            self.record_rate_limit(endpoint, hint);
+                        }
+                        logging::record("rpc", &endpoint.url);
+                        telemetry::set_current("rpc.endpoint", endpoint.url.as_str());
+                        return Ok(v);
+                    }
+                    Err(e) => {
+                        let e = self.describe_timeout(e.into(), &endpoint.url);
+                        let hint = hint.or_else(|| {
+                            (ErrorCode::of(&*e) == ErrorCode::RateLimited)
+                                .then(RateLimitHint::default)
+                        });
+                        match hint {
+                            Some(hint) => {
+                                self.record_rate_limit(endpoint, hint);
+                                rate_limited += 1;
+                                tried[idx] = rate_limited > RATE_LIMIT_RETRIES;
+                            }
+                            None => self.record_failure(endpoint, &e.to_string()),
+                        }
+                        last_error = Some(e);
+                    }
+                }
+            }
+            // Only rate-limited endpoints are left untried: wait for the soonest of them
+            match wait {
+                Some(w) => thread::sleep(match deadline {
+                    Some(d) => w.min(d.at.saturating_duration_since(Instant::now())),
+                    None => w,
+                }),
+                None => break,
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            CodedError::new(
+                ErrorCode::RpcUnavailable,
+                "all RPC endpoints are quarantined",
+            )
+            .into()
+        }))
+    }
+
+    /// `e`, reworded to say which timeout expired if it's a timeout.
+    fn describe_timeout(&self, e: Box<dyn Error>, url: &str) -> Box<dyn Error> {
+        let transport = match (e.downcast_ref(), e.downcast_ref()) {
+            (Some(ureq::Error::Transport(t)), _) => t,
+            (_, Some(EstimatorError::Transport(t))) => &**t,
+            _ => return e,
+        };
+        if !is_timeout(transport) {
+            return e;
+        }
+        telemetry::set_current("error.type", "timeout");
+        let message = match transport.kind() {
+            ureq::ErrorKind::ConnectionFailed => format!(
+                "timed out connecting to {} after {}s",
+                url,
+                self.http.connect_timeout.as_secs()
+            ),
+            _ => format!(
+                "timed out waiting on {} after {}s without data",
+                url,
+                self.http.read_timeout.as_secs()
+            ),
+        };
+        CodedError::new(ErrorCode::Timeout, message).into()
+    }
+
+    /// The agent to call `endpoint` with, starting afresh if its connections have idled
+    /// out.
+    fn agent(&self, endpoint: &Endpoint) -> ureq::Agent {
+        let mut connections = endpoint.connections.lock().unwrap();
+        if connections.used_at.elapsed() >= self.http.idle_timeout {
+            connections.agent = self.http.agent(&endpoint.url);
+        }
+        connections.used_at = Instant::now();
+        connections.agent.clone()
+    }
+
+    /// Choose the next endpoint by smooth weighted round-robin.
+    fn pick(&self) -> usize {
+        let mut current = self.current_weights.lock().unwrap();
+        let total: i64 = self.endpoints.iter().map(|e| e.weight as i64).sum();
+        let mut best = 0;
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            current[i] += endpoint.weight as i64;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        current[best] -= total;
+        best
+    }
+
+    /// Probe every endpoint that isn't cooling down with `getHealth`.
+    pub fn probe(&self) {
+        for endpoint in &self.endpoints {
+            if !self.is_available(endpoint) {
+                continue;
+            }
+            match get_health(&self.agent(endpoint), &endpoint.url) {
+                Ok(()) => self.record_success(endpoint),
+                Err(e) => self.record_failure(endpoint, &format!("health check: {}", e)),
+            }
+        }
+    }
+
+    /// Probe the endpoints every `interval` on a background thread.
+    pub fn spawn_health_checks(self: &Arc<Self>, interval: Duration) {
+        let pool = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                pool.probe();
+            }
+        });
+    }
+
+    fn is_available(&self, endpoint: &Endpoint) -> bool {
+        match endpoint.breaker.lock().unwrap().quarantined_until {
+            Some(until) => Instant::now() >= until, // half-open once the cooldown ends
+            None => true,
+        }
+    }
+
+    fn record_success(&self, endpoint: &Endpoint) {
+        endpoint.throttle.lock().unwrap().consecutive = 0;
+        let mut breaker = endpoint.breaker.lock().unwrap();
+        if breaker.quarantined_until.is_some() {
+            log::info!("RPC endpoint {} recovered", endpoint.url);
+        }
+        *breaker = Breaker::default();
+    }
+
+    /// Back off from `endpoint` after it rate limited a call, and sample less for now.
+    fn record_rate_limit(&self, endpoint: &Endpoint, hint: RateLimitHint) {
+        telemetry::set_current("error.type", "rate_limited");
+        let backoff = endpoint.throttle.lock().unwrap().limited(hint.retry_after);
+        if !self.adaptive {
+            log::warn!(
+                "RPC endpoint {} rate limited, backing off for {:.1}s",
+                endpoint.url,
+                backoff.as_secs_f64()
+            );
+        } else if let Some(level) = self.sampling.lock().unwrap().lower() {
+            log::warn!(
+                "RPC endpoint {} rate limited, backing off for {:.1}s; sampling at {}% for now",
+                endpoint.url,
+                backoff.as_secs_f64(),
+                (level * 100.0).round()
+            );
+        }
+    }
+
+    fn record_failure(&self, endpoint: &Endpoint, error: &str) {
+        let mut breaker = endpoint.breaker.lock().unwrap();
+        breaker.consecutive_failures += 1;
+        let tripped = breaker.consecutive_failures >= self.failure_threshold;
+        // Re-open immediately if the half-open trial call fails
+        if tripped || breaker.quarantined_until.is_some() {
+            breaker.quarantined_until = Some(Instant::now() + self.cooldown);
+            log::warn!(
+                "RPC endpoint {} quarantined for {}s after {} consecutive failures (last: {})",
+                endpoint.url,
+                self.cooldown.as_secs(),
+                breaker.consecutive_failures,
+                error
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(weights: &[u32], failure_threshold: u32) -> RpcPool {
+        let endpoints = weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| EndpointConfig {
+                weight,
+                ..EndpointConfig::new(&format!("http://rpc{}.invalid", i))
+            })
+            .collect();
+        RpcPool::new(endpoints, failure_threshold, Duration::from_secs(60))
+    }
+
+    /// Call `pool`, failing on the endpoints in `failing`. Returns the endpoints tried,
+    /// in order, and the result.
+    fn call(pool: &RpcPool, failing: &[usize]) -> (Vec<usize>, Result<usize, String>) {
+        let tried = Mutex::new(Vec::new());
+        let result = pool.call(|_, url| {
+            let i: usize = url["http://rpc".len()..url.len() - ".invalid".len()]
+                .parse()
+                .unwrap();
+            tried.lock().unwrap().push(i);
+            match failing.contains(&i) {
+                true => Err(format!("rpc{} failed", i)),
+                false => Ok(i),
+            }
+        });
+        (
+            tried.into_inner().unwrap(),
+            result.map_err(|e| e.to_string()),
+        )
+    }
+
+    fn breaker(pool: &RpcPool, i: usize) -> (u32, bool) {
+        let breaker = pool.endpoints[i].breaker.lock().unwrap();
+        (
+            breaker.consecutive_failures,
+            breaker.quarantined_until.is_some(),
+        )
+    }
+
+    #[test]
+    fn endpoints_parse_and_format() {
+        let endpoint =
+            EndpointConfig::parse("https://rpc.example.com; weight=80 ;rps=0.5").unwrap();
+        assert_eq!(endpoint.url, "https://rpc.example.com");
+        assert_eq!((endpoint.weight, endpoint.max_rps), (80, Some(0.5)));
+        assert_eq!(
+            endpoint.to_string(),
+            "https://rpc.example.com;weight=80;rps=0.5"
+        );
+        assert_eq!(
+            EndpointConfig::parse("http://a").unwrap().to_string(),
+            "http://a"
+        );
+
+        for s in [
+            "",
+            "http://a;weight=0",
+            "http://a;weight=-1",
+            "http://a;rps=0",
+            "http://a;rps=1e-30",
+            "http://a;rps=inf",
+            "http://a;rps=NaN",
+            "http://a;rps=fast",
+            "http://a;burst=5",
+        ] {
+            assert!(EndpointConfig::parse(s).is_err(), "{}", s);
+        }
+        assert!(EndpointConfig::parse("http://a;rps=0.001").is_ok());
+    }
+
+    #[test]
+    fn calls_are_spread_by_smooth_weighted_round_robin() {
+        let pool = pool(&[5, 1, 1], 3);
+        let picks: Vec<usize> = (0..14).map(|_| pool.pick()).collect();
+        // Interleaved rather than in runs, and in proportion over each cycle of 7
+        assert_eq!(picks, [0, 0, 1, 0, 2, 0, 0, 0, 0, 1, 0, 2, 0, 0]);
+
+        let pool = self::pool(&[2, 1], 3);
+        let successes: Vec<usize> = (0..6).map(|_| call(&pool, &[]).1.unwrap()).collect();
+        assert_eq!(successes, [0, 1, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn breakers_open_after_consecutive_failures() {
+        let pool = pool(&[1, 1], 2);
+        // Each call fails over from endpoint 0 to 1, or starts on 1
+        assert_eq!(call(&pool, &[0]), (vec![0, 1], Ok(1)));
+        assert_eq!(breaker(&pool, 0), (1, false));
+        assert_eq!(call(&pool, &[0]), (vec![1], Ok(1)));
+        assert_eq!(call(&pool, &[0]), (vec![0, 1], Ok(1)));
+        assert_eq!(breaker(&pool, 0), (2, true));
+
+        // Quarantined, endpoint 0 isn't tried while it cools down
+        for _ in 0..4 {
+            assert_eq!(call(&pool, &[0]), (vec![1], Ok(1)));
+        }
+        assert_eq!(breaker(&pool, 1), (0, false));
+
+        // With both quarantined, calls fail without trying either
+        assert_eq!(call(&pool, &[1]), (vec![1], Err("rpc1 failed".to_string())));
+        assert_eq!(call(&pool, &[1]).0, [1]);
+        assert_eq!(breaker(&pool, 1), (2, true));
+        assert_eq!(
+            call(&pool, &[]),
+            (vec![], Err("all RPC endpoints are quarantined".to_string()))
+        );
+    }
+
+    #[test]
+    fn breakers_half_open_once_cooled_down() {
+        let pool = pool(&[1], 2);
+        let cool_down = |pool: &RpcPool| {
+            let mut breaker = pool.endpoints[0].breaker.lock().unwrap();
+            breaker.quarantined_until = Some(Instant::now() - Duration::from_secs(1));
+        };
+        assert_eq!(call(&pool, &[0]).0, [0]);
+        assert_eq!(call(&pool, &[0]).0, [0]);
+        assert_eq!(breaker(&pool, 0), (2, true));
+
+        // A failed trial call re-opens the circuit at once
+        cool_down(&pool);
+        assert_eq!(call(&pool, &[0]).0, [0]);
+        assert_eq!(breaker(&pool, 0), (3, true));
+        assert!(call(&pool, &[]).0.is_empty());
+
+        // A successful one closes it
+        cool_down(&pool);
+        assert_eq!(call(&pool, &[]), (vec![0], Ok(0)));
+        assert_eq!(breaker(&pool, 0), (0, false));
+    }
+
+    #[test]
+    fn rate_limits_back_off_without_tripping_the_breaker() {
+        let mut throttle = Throttle::default();
+        let backoffs: Vec<u64> = (0..7).map(|_| throttle.limited(None).as_secs()).collect();
+        assert_eq!(backoffs, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(
+            throttle.limited(Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            throttle.limited(Some(Duration::from_secs(600))),
+            MAX_RATE_LIMIT_BACKOFF
+        );
+        assert!(throttle.wait().is_some());
+
+        let pool = pool(&[1], 1);
+        pool.record_rate_limit(&pool.endpoints[0], RateLimitHint::default());
+        assert_eq!(breaker(&pool, 0), (0, false));
+        pool.record_success(&pool.endpoints[0]);
+        assert_eq!(pool.endpoints[0].throttle.lock().unwrap().consecutive, 0);
+    }
+
+    #[test]
+    fn sampling_halves_on_rate_limits_and_ramps_back() {
+        let mut sampling = SamplingLevel {
+            level: 1.0,
+            lowered_at: None,
+        };
+        assert_eq!(sampling.current(), 1.0);
+        assert_eq!(sampling.lower(), Some(0.5));
+        // A burst of rate limits lowers it once
+        assert_eq!(sampling.lower(), None);
+        assert_eq!(sampling.current(), 0.5);
+
+        let ago = |secs| Some(Instant::now() - Duration::from_secs(secs));
+        sampling.lowered_at = ago(3);
+        assert_eq!(sampling.lower(), Some(0.25));
+        sampling.lowered_at = ago(3);
+        assert_eq!(sampling.lower(), Some(0.125));
+        sampling.lowered_at = ago(3);
+        assert_eq!(sampling.lower(), Some(MIN_SAMPLING_LEVEL));
+
+        // Up a step for each ramp interval since, back to full
+        sampling.lowered_at = ago(61);
+        assert_eq!(sampling.current(), 0.375);
+        sampling.lowered_at = ago(600);
+        assert_eq!(sampling.current(), 1.0);
+    }
+
+    #[test]
+    fn sampling_follows_rate_limits_only_when_adaptive() {
+        let pool = pool(&[1], 1);
+        pool.record_rate_limit(&pool.endpoints[0], RateLimitHint::default());
+        assert_eq!(pool.sampling_level(), 0.5);
+
+        let pool = self::pool(&[1], 1).with_adaptive_sampling(false);
+        pool.record_rate_limit(&pool.endpoints[0], RateLimitHint::default());
+        assert_eq!(pool.sampling_level(), 1.0);
+    }
+}
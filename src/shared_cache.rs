@@ -0,0 +1,361 @@
+//! Lets replicas share one set of estimates, so that only one of them samples the RPC
+//! endpoint for each program: whichever holds a program's leader lock refreshes it and
+//! stores each result, and the others serve what it stored. Replicas share through
+//! Redis, or through a directory if they run on one host.
+
+use crate::Estimate;
+use crate::error::CodedError;
+use crate::redis::{Redis, RedisUrl, Reply};
+use crate::snapshot::SavedEstimate;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_SHARED_CACHE_PREFIX: &str = "ivy-priority-fee";
+
+/// Takes the lock KEYS[1] for ARGV[1] for ARGV[2] milliseconds if it's free, or extends
+/// it if ARGV[1] already holds it. Returns 1 if ARGV[1] holds it now.
+const ACQUIRE_SCRIPT: &str = "\
+local holder = redis.call('GET', KEYS[1])
+if holder == ARGV[1] then
+  redis.call('PEXPIRE', KEYS[1], ARGV[2])
+  return 1
+elseif holder then
+  return 0
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+return 1";
+
+/// Deletes the lock KEYS[1] if ARGV[1] holds it.
+const RELEASE_SCRIPT: &str = "\
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('DEL', KEYS[1])
+end
+return 0";
+
+/// Where replicas share estimates: `redis://[[user]:password@]host[:port][/db]`, or
+/// `file:///path/to/directory` for replicas on one host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SharedCacheUrl {
+    Redis(RedisUrl),
+    Directory(PathBuf),
+}
+
+impl SharedCacheUrl {
+    pub fn parse(s: &str) -> Result<SharedCacheUrl, String> {
+        if s.starts_with("redis://") || s.starts_with("rediss://") {
+            return RedisUrl::parse(s).map(SharedCacheUrl::Redis);
+        }
+        let invalid = || format!("Invalid SHARED_CACHE_URL: {}", crate::proxy::redact(s));
+        let url = url::Url::parse(s).map_err(|_| invalid())?;
+        match url.scheme() {
+            "file" => url
+                .to_file_path()
+                .map(SharedCacheUrl::Directory)
+                .map_err(|()| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn has_credentials(&self) -> bool {
+        match self {
+            SharedCacheUrl::Redis(url) => url.has_credentials(),
+            SharedCacheUrl::Directory(_) => false,
+        }
+    }
+}
+
+/// Shows the URL without its password.
+impl fmt::Display for SharedCacheUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SharedCacheUrl::Redis(url) => url.fmt(f),
+            SharedCacheUrl::Directory(path) => write!(f, "file://{}", path.display()),
+        }
+    }
+}
+
+/// This replica's view of the estimates it shares with the others.
+pub struct SharedCache {
+    url: SharedCacheUrl,
+    backend: Backend,
+    prefix: String,   // of every key, so that several deployments can share a database
+    instance: String, // identifies this replica as the holder of a lock
+    leading: Mutex<HashMap<String, bool>>, // by program, whether this replica held its lock when it last checked
+}
+
+enum Backend {
+    Redis(Redis),
+    Directory(Directory),
+}
+
+/// A refresh's result as a replica stored it.
+pub struct Snapshot {
+    pub refreshed_at: u64, // unix milliseconds, which tells one refresh from the next
+    pub leader: String,    // the replica that refreshed
+    pub result: Result<Estimate, CodedError>, // not clamped, smoothed or tracked
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Stored {
+    refreshed_at: u64,
+    expires_at: u64, // unix milliseconds
+    leader: String,
+    estimate: Option<SavedEstimate>,
+    error: Option<StoredError>,
+}
+
+#[derive(Deserialize)]
+struct StoredError {
+    code: String,
+    message: String,
+}
+
+impl SharedCache {
+    pub fn new(url: SharedCacheUrl, prefix: &str) -> SharedCache {
+        let backend = match &url {
+            SharedCacheUrl::Redis(redis) => Backend::Redis(Redis::new(redis.clone())),
+            SharedCacheUrl::Directory(path) => Backend::Directory(Directory {
+                path: path.clone(),
+                held: Mutex::new(HashMap::new()),
+            }),
+        };
+        SharedCache {
+            url,
+            backend,
+            prefix: prefix.to_string(),
+            instance: instance_id(),
+            leading: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn url(&self) -> &SharedCacheUrl {
+        &self.url
+    }
+
+    /// Take `program`'s leader lock for `lease`, or extend it if this replica holds it
+    /// already. Returns whether it does now. A directory's locks are held until released
+    /// or the replica exits, whatever the lease.
+    pub fn acquire(&self, program: &str, lease: Duration) -> io::Result<bool> {
+        let key = self.key("leader", program);
+        let leading = match &self.backend {
+            Backend::Redis(redis) => {
+                let lease = lease.as_millis().max(1).to_string();
+                let reply = redis.command(&[
+                    b"EVAL",
+                    ACQUIRE_SCRIPT.as_bytes(),
+                    b"1",
+                    key.as_bytes(),
+                    self.instance.as_bytes(),
+                    lease.as_bytes(),
+                ])?;
+                reply == Reply::Integer(1)
+            }
+            Backend::Directory(dir) => dir.lock(&key, &self.instance)?,
+        };
+        let mut by_program = self.leading.lock().unwrap();
+        if by_program.insert(program.to_string(), leading) != Some(leading) {
+            match leading {
+                true => log::info!("{} now leads {}, refreshing it", self.instance, program),
+                false => log::info!("{} follows the leader of {}", self.instance, program),
+            }
+        }
+        Ok(leading)
+    }
+
+    /// Whether this replica held `program`'s leader lock when it last checked, or
+    /// `None` if it hasn't.
+    pub fn is_leading(&self, program: &str) -> Option<bool> {
+        self.leading.lock().unwrap().get(program).copied()
+    }
+
+    /// Give up every leader lock this replica holds, so others can take over without
+    /// waiting for the leases to run out.
+    pub fn release(&self) -> io::Result<()> {
+        let mut by_program = self.leading.lock().unwrap();
+        for (program, leading) in by_program.iter_mut().filter(|(_, leading)| **leading) {
+            let key = self.key("leader", program);
+            match &self.backend {
+                Backend::Redis(redis) => {
+                    redis.command(&[
+                        b"EVAL",
+                        RELEASE_SCRIPT.as_bytes(),
+                        b"1",
+                        key.as_bytes(),
+                        self.instance.as_bytes(),
+                    ])?;
+                }
+                Backend::Directory(dir) => dir.unlock(&key),
+            }
+            *leading = false;
+        }
+        Ok(())
+    }
+
+    /// Store the result of a refresh of `program` for the other replicas, to expire
+    /// after `ttl`.
+    pub fn store(
+        &self,
+        program: &str,
+        result: &Result<Estimate, CodedError>,
+        ttl: Duration,
+    ) -> io::Result<()> {
+        let now = unix_millis();
+        let mut stored = json!({
+            "refreshedAt": now,
+            "expiresAt": now.saturating_add(ttl.as_millis() as u64),
+            "leader": self.instance,
+        });
+        match result {
+            Ok(e) => stored["estimate"] = json!(SavedEstimate::new(e)),
+            Err(err) => {
+                stored["error"] = json!({ "code": err.code.as_str(), "message": err.message })
+            }
+        }
+        let key = self.key("estimate", program);
+        let value = stored.to_string();
+        match &self.backend {
+            Backend::Redis(redis) => {
+                let ttl = ttl.as_millis().max(1).to_string();
+                redis.command(&[
+                    b"SET",
+                    key.as_bytes(),
+                    value.as_bytes(),
+                    b"PX",
+                    ttl.as_bytes(),
+                ])?;
+                Ok(())
+            }
+            Backend::Directory(dir) => dir.write(&key, value.as_bytes()),
+        }
+    }
+
+    /// The latest result stored for `program`, or `None` if there's none or it expired.
+    pub fn load(&self, program: &str) -> io::Result<Option<Snapshot>> {
+        let key = self.key("estimate", program);
+        let value = match &self.backend {
+            Backend::Redis(redis) => match redis.command(&[b"GET", key.as_bytes()])? {
+                Reply::Bulk(Some(value)) => value,
+                _ => return Ok(None),
+            },
+            Backend::Directory(dir) => match dir.read(&key)? {
+                Some(value) => value,
+                None => return Ok(None),
+            },
+        };
+        let stored: Stored = serde_json::from_slice(&value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if stored.expires_at < unix_millis() {
+            return Ok(None);
+        }
+        let result = match (stored.estimate, stored.error) {
+            (Some(e), _) => Ok(e.into_estimate()),
+            (None, Some(err)) => Err(CodedError::new(
+                err.code.parse().map_err(io::Error::other)?,
+                err.message,
+            )),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Snapshot {
+            refreshed_at: stored.refreshed_at,
+            leader: stored.leader,
+            result,
+        }))
+    }
+
+    /// `<prefix>:<kind>:<program>` in Redis, `<prefix>.<kind>.<program>` as a file name.
+    fn key(&self, kind: &str, program: &str) -> String {
+        let separator = match self.backend {
+            Backend::Redis(_) => ":",
+            Backend::Directory(_) => ".",
+        };
+        [self.prefix.as_str(), kind, program].join(separator)
+    }
+}
+
+/// Shares through files in a directory, locked with `flock` so that a lock is released
+/// as soon as the replica holding it exits, however it exits.
+struct Directory {
+    path: PathBuf,
+    held: Mutex<HashMap<String, File>>, // lock files open and locked, by key
+}
+
+impl Directory {
+    /// Lock `<key>.lock` unless another process has, writing `instance` into it.
+    fn lock(&self, key: &str, instance: &str) -> io::Result<bool> {
+        let mut held = self.held.lock().unwrap();
+        if held.contains_key(key) {
+            return Ok(true);
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.join(format!("{}.lock", key)))?;
+        // SAFETY: the descriptor belongs to `file`, which is open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => Ok(false),
+                _ => Err(e),
+            };
+        }
+        // Only for whoever looks at the file; the lock is what counts
+        file.set_len(0)?;
+        writeln!(file, "{}", instance)?;
+        held.insert(key.to_string(), file);
+        Ok(true)
+    }
+
+    /// Closing the lock file releases the lock.
+    fn unlock(&self, key: &str) {
+        self.held.lock().unwrap().remove(key);
+    }
+
+    /// Replace `<key>.json` with `value`, atomically for readers.
+    fn write(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        let path = self.path.join(format!("{}.json", key));
+        let tmp = self
+            .path
+            .join(format!(".{}.json.{}", key, std::process::id()));
+        fs::write(&tmp, value)?;
+        fs::rename(&tmp, &path)
+    }
+
+    fn read(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path.join(format!("{}.json", key))) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// `<hostname>-<pid>`, which tells replicas apart both across hosts and on one.
+fn instance_id() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is writable for its whole length, which is passed along
+    let hostname = match unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } {
+        0 => {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        }
+        _ => "unknown".to_string(),
+    };
+    format!("{}-{}", hostname, std::process::id())
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,73 @@
+//! Exponentially weighted moving averages of fees across refreshes.
+
+use crate::aggregation::Aggregation;
+use crate::estimator::{Estimate, LEVEL_PERCENTILES, PriorityFeeLevels};
+
+pub const DEFAULT_EWMA_ALPHA: f64 = 0.3;
+
+/// The EWMA of a program's fee curve, as of its latest refresh. Only meaningful for
+/// the aggregation and failed-transaction selection it was computed with.
+#[derive(Clone, Debug)]
+pub struct Smoothed {
+    pub aggregation: Aggregation,
+    pub include_failed: bool,
+    fees: Vec<f64>,   // at each whole percentile 0..=100
+    levels: Vec<f64>, // at LEVEL_PERCENTILES
+}
+
+impl Smoothed {
+    /// Fold `e` into `previous` with weight `alpha`, or start from `e` if there is no
+    /// comparable previous value.
+    pub fn update(
+        previous: Option<&Smoothed>,
+        e: &Estimate,
+        aggregation: Aggregation,
+        alpha: f64,
+    ) -> Smoothed {
+        let fees: Vec<f64> = (0..=100)
+            .map(|p| e.fee(p as f64, aggregation) as f64)
+            .collect();
+        let levels: Vec<f64> = LEVEL_PERCENTILES
+            .iter()
+            .map(|&p| e.level(p, aggregation) as f64)
+            .collect();
+        let (fees, levels) = match previous {
+            Some(prev)
+                if prev.aggregation == aggregation && prev.include_failed == e.include_failed =>
+            {
+                (
+                    ewma(&prev.fees, &fees, alpha),
+                    ewma(&prev.levels, &levels, alpha),
+                )
+            }
+            _ => (fees, levels),
+        };
+        Smoothed {
+            aggregation,
+            include_failed: e.include_failed,
+            fees,
+            levels,
+        }
+    }
+
+    /// The smoothed fee at percentile `p` (0-100), interpolated between whole percentiles.
+    /// Averages of clamped fees, smoothed fees need no clamping of their own.
+    pub fn fee_at(&self, p: f64) -> u64 {
+        let p = p.clamp(0.0, 100.0);
+        let (lo, hi) = (p.floor() as usize, p.ceil() as usize);
+        let fee = self.fees[lo] + (self.fees[hi] - self.fees[lo]) * (p - lo as f64);
+        fee.round() as u64
+    }
+
+    pub fn levels(&self) -> PriorityFeeLevels {
+        PriorityFeeLevels::from_fn(|i| self.levels[i].round() as u64)
+    }
+}
+
+fn ewma(previous: &[f64], current: &[f64], alpha: f64) -> Vec<f64> {
+    previous
+        .iter()
+        .zip(current)
+        .map(|(prev, cur)| alpha * cur + (1.0 - alpha) * prev)
+        .collect()
+}
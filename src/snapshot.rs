@@ -0,0 +1,90 @@
+//! The latest estimates, saved to a file on shutdown and loaded at startup, so that a
+//! restarted server can answer while its first refreshes run.
+
+use crate::estimator::Estimate;
+use crate::history::HistoryPoint;
+use crate::sources::{FEE_SOURCE_NAMES, Sample, fee_source_by_name};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What's saved: each program's latest estimate and, unless it's persisted on its own,
+/// the history.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedState {
+    pub saved_at: u64,                              // unix seconds
+    pub estimates: BTreeMap<String, SavedEstimate>, // by program
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<HistoryPoint>,
+}
+
+/// An estimate's samples and what they were taken with. Clamping, smoothing and trends
+/// are left to whoever loads it.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedEstimate {
+    samples: Arc<Vec<Sample>>,
+    include_failed: bool,
+    last_updated: u64,
+    source: String,
+    low_confidence: bool,
+}
+
+impl SavedEstimate {
+    pub fn new(e: &Estimate) -> SavedEstimate {
+        SavedEstimate {
+            samples: e.samples.clone(),
+            include_failed: e.include_failed,
+            last_updated: e.last_updated,
+            source: e.source.to_string(),
+            low_confidence: e.low_confidence,
+        }
+    }
+
+    pub fn into_estimate(self) -> Estimate {
+        Estimate::new(
+            Arc::unwrap_or_clone(self.samples),
+            self.include_failed,
+            self.last_updated,
+            source_name(&self.source),
+        )
+        .with_low_confidence(self.low_confidence)
+    }
+}
+
+impl SavedState {
+    /// The state saved at `path`, or `None` if nothing has been.
+    pub fn load(path: &Path) -> io::Result<Option<SavedState>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Replace the state saved at `path`, so that it's never left half-written.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// The `FeeSource::name` of a built-in source that's `name`, or "unknown" for sources
+/// this process doesn't have.
+pub fn source_name(name: &str) -> &'static str {
+    FEE_SOURCE_NAMES
+        .iter()
+        .filter_map(|n| fee_source_by_name(n))
+        .map(|s| s.name())
+        .find(|n| *n == name)
+        .unwrap_or("unknown")
+}
@@ -0,0 +1,644 @@
+use crate::config::Config;
+use crate::error::{CodedError, ErrorCode};
+use crate::geyser::{self, Update};
+use crate::grpc::ClientCall;
+use crate::proxy::Proxy;
+use crate::pubsub::PubSubClient;
+use crate::rpc::{
+    Commitment, MAX_SIGNATURES_PER_CALL, get_block_priority_fees, get_priority_fees_for_signatures,
+    get_recent_prioritization_fees, get_signatures_for_address, get_slot,
+};
+use crate::rpc_pool::{Deadline, Header};
+use crate::telemetry::{self, SpanKind};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// One sampled transaction's priority fee.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Sample {
+    pub signature: Option<Arc<str>>, // the transaction's first signature, if known
+    pub price: u64,                  // micro-lamports per CU
+    pub fee_lamports: Option<u64>,   // the transaction's whole fee, if known
+    pub failed: bool,                // whether the transaction failed (meta.err != null)
+    pub compute_units: Option<u64>,  // CUs consumed, if the source knows them
+    pub slot: Option<u64>,           // slot the transaction landed in, if known
+    pub block_time: Option<u64>,     // unix seconds its block was produced, if known
+    pub jito_tip: Option<u64>,       // lamports tipped to Jito, if it tipped
+    pub writable_accounts: Arc<[String]>, // accounts it write-locked, if known
+    pub recent_blockhash: Option<Arc<str>>, // blockhash it was built on, if known
+}
+
+/// A strategy for sampling recent priority fees (in micro-lamports) relevant to a program.
+pub trait FeeSource: Send + Sync {
+    /// Identifier reported as `source` in responses.
+    fn name(&self) -> &'static str;
+
+    /// Sample recent priority fees, in no particular order.
+    fn sample(
+        &self,
+        config: &Config,
+        program: &str,
+    ) -> Result<Vec<Sample>, Box<dyn std::error::Error>>;
+
+    /// How many samples `sample` sets out to take, if a set number. Estimates from fewer
+    /// than `Config::min_sample_fraction` of it are flagged as low-confidence.
+    fn target_samples(&self, _config: &Config) -> Option<usize> {
+        None
+    }
+}
+
+/// FEE_SOURCE names of the built-in fee sources.
+pub const FEE_SOURCE_NAMES: [&str; 5] = ["transactions", "recent", "blocks", "logs", "geyser"];
+
+/// Look up a built-in fee source by its FEE_SOURCE name.
+pub fn fee_source_by_name(name: &str) -> Option<Arc<dyn FeeSource>> {
+    match name {
+        "transactions" => Some(Arc::new(TransactionSampling::default())),
+        "recent" => Some(Arc::new(RecentPrioritizationFees)),
+        "blocks" => Some(Arc::new(BlockScanning)),
+        "logs" => Some(Arc::new(LogsSubscription::default())),
+        "geyser" => Some(Arc::new(GeyserSubscription::default())),
+        _ => None,
+    }
+}
+
+/// The FEE_SOURCE name of a built-in fee source; other sources go by their `name()`.
+pub fn fee_source_setting_name(source: &dyn FeeSource) -> &'static str {
+    match source.name() {
+        "transactionSampling" => "transactions",
+        "recentPrioritizationFees" => "recent",
+        "blockScanning" => "blocks",
+        "logsSubscription" => "logs",
+        "geyserSubscription" => "geyser",
+        name => name,
+    }
+}
+
+/// Derives priority fees from the program's last `sample_size` transactions. After the first
+/// sample, each one fetches only the signatures newer than the last seen (`until`) and
+/// rolls them into the previous window.
+#[derive(Default)]
+pub struct TransactionSampling {
+    windows: Mutex<HashMap<String, SignatureWindow>>, // by program
+}
+
+/// A program's latest samples, oldest first, and the newest signature among them.
+#[derive(Default)]
+struct SignatureWindow {
+    newest: Option<String>,
+    samples: VecDeque<Sample>,
+}
+
+impl FeeSource for TransactionSampling {
+    fn name(&self) -> &'static str {
+        "transactionSampling"
+    }
+
+    fn target_samples(&self, config: &Config) -> Option<usize> {
+        Some(config.sample_size)
+    }
+
+    fn sample(
+        &self,
+        config: &Config,
+        program: &str,
+    ) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+        // 1) Fetch the signatures of the program's transactions since the last sample,
+        //    without holding the windows while calling the RPC
+        let until = (self.windows.lock().unwrap())
+            .get(program)
+            .and_then(|w| w.newest.clone());
+        let signatures = fetch_signatures(config, program, until.as_deref())?;
+
+        // 2) Price them with getTransaction
+        let mut samples = price_signatures(config, &signatures)?;
+
+        // 3) Roll them into the window. A full page means there may be a gap since the
+        //    last sample, so the window starts over. A sample taken meanwhile may have
+        //    covered some of the same transactions already.
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(program.to_string()).or_default();
+        if signatures.len() >= config.sample_size {
+            window.samples.clear();
+        } else {
+            let seen: HashSet<&str> = window
+                .samples
+                .iter()
+                .filter_map(|s| s.signature.as_deref())
+                .collect();
+            samples.retain(|s| s.signature.as_deref().is_none_or(|sig| !seen.contains(sig)));
+        }
+        window.samples.extend(samples);
+        let excess = window.samples.len().saturating_sub(config.sample_size);
+        window.samples.drain(..excess);
+        // Unless a sample taken meanwhile moved it on
+        if window.newest == until
+            && let Some(newest) = signatures.into_iter().next()
+        {
+            window.newest = Some(newest);
+        }
+        Ok(window.samples.iter().cloned().collect())
+    }
+}
+
+/// Price the program's latest `sample_size` transactions from scratch.
+fn sample_latest(
+    config: &Config,
+    program: &str,
+) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+    let signatures = fetch_signatures(config, program, None)?;
+    price_signatures(config, &signatures)
+}
+
+/// Signatures of the program's latest `sample_size` transactions, newest first,
+/// stopping short of `until`. Pages through getSignaturesForAddress as needed.
+fn fetch_signatures(
+    config: &Config,
+    program: &str,
+    until: Option<&str>,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut signatures: Vec<String> = Vec::new();
+    while signatures.len() < config.sample_size {
+        let limit = (config.sample_size - signatures.len()).min(MAX_SIGNATURES_PER_CALL);
+        let before = signatures.last().map(String::as_str);
+        let span = telemetry::span("getSignaturesForAddress", SpanKind::Client);
+        span.set("limit", limit);
+        let page = config
+            .rpc
+            .call(|agent, url| {
+                get_signatures_for_address(
+                    agent,
+                    url,
+                    program,
+                    limit,
+                    before,
+                    until,
+                    config.commitment,
+                )
+            })
+            .inspect_err(|e| span.set_error(&e.to_string()))?;
+        span.set("signatures", page.len());
+        let last_page = page.len() < limit;
+        signatures.extend(page);
+        if last_page {
+            break;
+        }
+    }
+    Ok(signatures)
+}
+
+/// Call getTransaction for `signatures` in chunks of `batch_size`, with up to
+/// `batch_concurrency` chunks in flight, and compute per-tx priority fees.
+fn price_signatures(
+    config: &Config,
+    signatures: &[String],
+) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+    let span = telemetry::span("getTransaction", SpanKind::Internal);
+    span.set("signatures", signatures.len());
+    let parent = telemetry::current();
+    let chunks: Vec<&[String]> = signatures.chunks(config.batch_size).collect();
+    let results = run_concurrently(&chunks, config.batch_concurrency, |chunk| {
+        let span = telemetry::span_with_parent("getTransaction batch", SpanKind::Client, parent);
+        span.set("signatures", chunk.len());
+        get_priority_fees_with_retries(config, chunk)
+            .inspect(|samples| span.set("samples", samples.len()))
+            .inspect_err(|e| span.set_error(&e.message))
+    });
+    let mut priority_fees: Vec<Sample> = Vec::with_capacity(signatures.len());
+    for result in results {
+        priority_fees.extend(result?);
+    }
+    Ok(priority_fees)
+}
+
+/// Per-slot minimum fees reported by the node's `getRecentPrioritizationFees`.
+/// Cheap but coarse; also serves as the fallback for the other sources.
+pub struct RecentPrioritizationFees;
+
+impl FeeSource for RecentPrioritizationFees {
+    fn name(&self) -> &'static str {
+        "recentPrioritizationFees"
+    }
+
+    fn sample(
+        &self,
+        config: &Config,
+        program: &str,
+    ) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+        let accounts = if config.fallback_scoped {
+            vec![program.to_string()]
+        } else {
+            Vec::new()
+        };
+        RecentPrioritizationFees::sample_accounts(config, &accounts)
+    }
+}
+
+impl RecentPrioritizationFees {
+    /// Fees of recent transactions write-locking all of `accounts` (or any
+    /// transaction, if empty).
+    pub fn sample_accounts(
+        config: &Config,
+        accounts: &[String],
+    ) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+        let fees = config
+            .rpc
+            .call(|agent, url| get_recent_prioritization_fees(agent, url, accounts))?;
+        Ok(fees
+            .into_iter()
+            .map(|f| Sample {
+                signature: None,
+                price: f.prioritization_fee,
+                fee_lamports: None,
+                failed: false,
+                compute_units: None,
+                slot: Some(f.slot),
+                block_time: None,
+                jito_tip: None,
+                writable_accounts: accounts.into(),
+                recent_blockhash: None,
+            })
+            .collect())
+    }
+}
+
+/// Derives priority fees from the program's transactions in the last few blocks, or
+/// from every transaction in them with `block_scan_network`, less the votes, transfers
+/// and other noise `block_filters` leaves out.
+pub struct BlockScanning;
+
+impl FeeSource for BlockScanning {
+    fn name(&self) -> &'static str {
+        "blockScanning"
+    }
+
+    fn sample(
+        &self,
+        config: &Config,
+        program: &str,
+    ) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+        // getBlock can't read past the confirmed tip, so neither does the scan
+        let options = config.transaction_options();
+        let commitment = options.commitment.at_least_confirmed();
+        let tip = config
+            .rpc
+            .call(|agent, url| get_slot(agent, url, commitment))?;
+        let slots: Vec<u64> = (tip.saturating_sub(config.block_scan_slots - 1)..=tip).collect();
+        let program = (!config.block_scan_network).then_some(program);
+        let filters = config.block_filters;
+        let results = run_concurrently(&slots, config.batch_concurrency, |&slot| {
+            config
+                .rpc
+                .call(|agent, url| {
+                    get_block_priority_fees(agent, url, slot, program, filters, &options)
+                })
+                .map_err(|e| e.to_string())
+        });
+        let mut priority_fees: Vec<Sample> = Vec::new();
+        for result in results {
+            priority_fees.extend(result?);
+        }
+        Ok(priority_fees)
+    }
+}
+
+/// Keeps a rolling window of each program's latest transactions, pricing only those a
+/// `logsSubscribe` stream has announced since the last sample. While the stream is
+/// down, samples are taken as `TransactionSampling` does.
+#[derive(Default)]
+pub struct LogsSubscription {
+    programs: Mutex<HashMap<String, Arc<ProgramLogs>>>,
+}
+
+/// A program's subscription and rolling window.
+#[derive(Default)]
+struct ProgramLogs {
+    connected: AtomicBool,
+    resubscribed: AtomicBool,         // (re)subscribed since the last sample
+    max_pending: AtomicUsize,         // the latest sample's `sample_size`
+    pending: Mutex<VecDeque<String>>, // announced signatures not yet priced, oldest first
+    window: Mutex<VecDeque<Sample>>,  // latest `sample_size` samples, oldest first
+}
+
+impl FeeSource for LogsSubscription {
+    fn name(&self) -> &'static str {
+        "logsSubscription"
+    }
+
+    fn target_samples(&self, config: &Config) -> Option<usize> {
+        Some(config.sample_size)
+    }
+
+    fn sample(
+        &self,
+        config: &Config,
+        program: &str,
+    ) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+        let logs = self
+            .programs
+            .lock()
+            .unwrap()
+            .entry(program.to_string())
+            .or_insert_with(|| {
+                let logs = Arc::new(ProgramLogs {
+                    max_pending: AtomicUsize::new(config.sample_size),
+                    ..Default::default()
+                });
+                let subscribed = logs.clone();
+                let url = config.rpc_ws_url.clone();
+                let headers = config.rpc.headers().to_vec();
+                let proxy = config.rpc.proxy_for(&url).cloned();
+                let program = program.to_string();
+                let commitment = config.commitment;
+                thread::spawn(move || {
+                    let (proxy, logs) = (proxy.as_deref(), &subscribed);
+                    subscribe_logs(&url, &headers, proxy, &program, commitment, logs)
+                });
+                logs
+            })
+            .clone();
+
+        logs.max_pending
+            .store(config.sample_size, Ordering::Relaxed);
+        let mut window = logs.window.lock().unwrap();
+        let resubscribed = logs.resubscribed.swap(false, Ordering::Relaxed);
+        if !logs.connected.load(Ordering::Relaxed) || resubscribed || window.is_empty() {
+            // Start the window over, as announcements may have been missed; anything
+            // announced so far is covered by it
+            let samples = sample_latest(config, program)?;
+            logs.pending.lock().unwrap().clear();
+            *window = samples.into();
+        } else {
+            let signatures: Vec<String> = logs.pending.lock().unwrap().drain(..).collect();
+            window.extend(price_signatures(config, &signatures)?);
+        }
+        let excess = window.len().saturating_sub(config.sample_size);
+        window.drain(..excess);
+        Ok(window.iter().cloned().collect())
+    }
+}
+
+/// How long the subscription may stay silent before it's presumed dead and reopened.
+const LOGS_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const LOGS_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Queue the signatures of `program`'s transactions as they're announced at
+/// `commitment`, up to the `sample_size` last sampled with, reconnecting whenever the
+/// subscription drops.
+fn subscribe_logs(
+    url: &str,
+    headers: &[Header],
+    proxy: Option<&Proxy>,
+    program: &str,
+    commitment: Commitment,
+    logs: &ProgramLogs,
+) {
+    loop {
+        let result: Result<(), Box<dyn std::error::Error>> =
+            PubSubClient::connect(url, LOGS_IDLE_TIMEOUT, headers, proxy).and_then(|mut client| {
+                client.logs_subscribe(program, commitment)?;
+                log::info!("Subscribed to logs of {} at {}", program, url);
+                logs.connected.store(true, Ordering::Relaxed);
+                logs.resubscribed.store(true, Ordering::Relaxed);
+                loop {
+                    let notification = client.next_logs()?;
+                    let mut pending = logs.pending.lock().unwrap();
+                    pending.push_back(notification.signature);
+                    // Keep up to a window's worth if nothing is sampling
+                    if pending.len() > logs.max_pending.load(Ordering::Relaxed) {
+                        pending.pop_front();
+                    }
+                }
+            });
+        logs.connected.store(false, Ordering::Relaxed);
+        if let Err(e) = result {
+            log::warn!("logsSubscribe for {} at {} failed: {}", program, url, e);
+        }
+        thread::sleep(LOGS_RECONNECT_DELAY);
+    }
+}
+
+/// Keeps a rolling window of each program's latest transactions, streamed with their
+/// fees by a Yellowstone Geyser subscription, so sampling takes no RPC calls. While the
+/// stream is down, samples are taken as `TransactionSampling` does.
+#[derive(Default)]
+pub struct GeyserSubscription {
+    programs: Mutex<HashMap<String, Arc<ProgramStream>>>,
+}
+
+/// A program's subscription and rolling window.
+#[derive(Default)]
+struct ProgramStream {
+    connected: AtomicBool,
+    max_len: AtomicUsize,            // the latest sample's `sample_size`
+    window: Mutex<VecDeque<Sample>>, // latest streamed (or sampled) samples, oldest first
+}
+
+impl FeeSource for GeyserSubscription {
+    fn name(&self) -> &'static str {
+        "geyserSubscription"
+    }
+
+    fn target_samples(&self, config: &Config) -> Option<usize> {
+        Some(config.sample_size)
+    }
+
+    fn sample(
+        &self,
+        config: &Config,
+        program: &str,
+    ) -> Result<Vec<Sample>, Box<dyn std::error::Error>> {
+        let url = config
+            .geyser_url
+            .clone()
+            .ok_or("the geyser source needs GEYSER_URL")?;
+        let stream = self
+            .programs
+            .lock()
+            .unwrap()
+            .entry(program.to_string())
+            .or_insert_with(|| {
+                let stream = Arc::new(ProgramStream {
+                    max_len: AtomicUsize::new(config.sample_size),
+                    ..Default::default()
+                });
+                let subscribed = stream.clone();
+                let token = config.geyser_x_token.clone();
+                let proxy = config.rpc.proxy_for(&url).cloned();
+                let program = program.to_string();
+                let commitment = config.commitment;
+                thread::spawn(move || {
+                    let (proxy, stream) = (proxy.as_deref(), &subscribed);
+                    let token = token.as_deref();
+                    subscribe_geyser(&url, token, proxy, &program, commitment, stream)
+                });
+                stream
+            })
+            .clone();
+
+        stream.max_len.store(config.sample_size, Ordering::Relaxed);
+        let mut window = stream.window.lock().unwrap();
+        if !stream.connected.load(Ordering::Relaxed) || window.is_empty() {
+            // The JSON-RPC fallback, which also seeds the window
+            let samples = sample_latest(config, program)?;
+            *window = samples.into();
+        }
+        let excess = window.len().saturating_sub(config.sample_size);
+        window.drain(..excess);
+        Ok(window.iter().cloned().collect())
+    }
+}
+
+/// How long the Geyser stream may stay silent before it's presumed dead and reopened;
+/// servers ping every 15 seconds or so.
+const GEYSER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const GEYSER_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Stream `program`'s transactions at `commitment` from the Geyser endpoint `url` into
+/// `stream`'s window, keeping the `sample_size` last sampled with, and reconnecting
+/// whenever the stream drops.
+fn subscribe_geyser(
+    url: &str,
+    token: Option<&str>,
+    proxy: Option<&Proxy>,
+    program: &str,
+    commitment: Commitment,
+    stream: &ProgramStream,
+) {
+    let metadata: Vec<(&str, &str)> = token.map(|t| ("x-token", t)).into_iter().collect();
+    loop {
+        let result: Result<(), Box<dyn std::error::Error>> = ClientCall::open(
+            url,
+            geyser::SUBSCRIBE_METHOD,
+            &metadata,
+            proxy,
+            GEYSER_IDLE_TIMEOUT,
+        )
+        .and_then(|mut call| {
+            call.send(&geyser::subscribe_request(program, commitment))?;
+            let mut pings = 0;
+            loop {
+                let message = call.receive()?.ok_or("the server ended the stream")?;
+                let update = geyser::parse_update(&message)?;
+                if !stream.connected.swap(true, Ordering::Relaxed) {
+                    log::info!("Subscribed to transactions of {} at {}", program, url);
+                }
+                match update {
+                    Update::Transaction(sample) => {
+                        let Some(sample) = sample else { continue };
+                        let mut window = stream.window.lock().unwrap();
+                        window.push_back(sample);
+                        let max_len = stream.max_len.load(Ordering::Relaxed);
+                        let excess = window.len().saturating_sub(max_len);
+                        window.drain(..excess);
+                    }
+                    Update::Ping => {
+                        pings += 1;
+                        call.send(&geyser::ping_request(pings))?;
+                    }
+                    Update::Other => {}
+                }
+            }
+        });
+        stream.connected.store(false, Ordering::Relaxed);
+        if let Err(e) = result {
+            log::warn!(
+                "Geyser subscription for {} at {} failed: {}",
+                program,
+                url,
+                e
+            );
+        }
+        thread::sleep(GEYSER_RECONNECT_DELAY);
+    }
+}
+
+/// Run `f` over `items` on up to `concurrency` scoped threads, returning results in
+/// completion order. The threads share the caller's deadline.
+pub(crate) fn run_concurrently<T: Sync, R: Send>(
+    items: &[T],
+    concurrency: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<R>> = Mutex::new(Vec::with_capacity(items.len()));
+    let deadline = Deadline::current();
+    thread::scope(|scope| {
+        for _ in 0..concurrency.min(items.len()) {
+            scope.spawn(|| {
+                let _deadline = deadline.map(Deadline::enter);
+                while let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = f(item);
+                    results.lock().unwrap().push(result);
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// Wait before the second attempt at a getTransaction batch; see
+/// `get_priority_fees_with_retries`.
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(4);
+
+/// Call `get_priority_fees_for_signatures`, making up to `config.max_retries` attempts,
+/// `RETRY_DELAY` apart, doubling each time up to `MAX_RETRY_DELAY`, for as long as the
+/// refresh's deadline allows. A failed call is retried in full; after a successful one
+/// only the transactions the node couldn't return, often ones it hasn't indexed yet,
+/// are requested again. Those still missing when the attempts run out are left out of
+/// the sample.
+fn get_priority_fees_with_retries(
+    config: &Config,
+    signatures: &[String],
+) -> Result<Vec<Sample>, CodedError> {
+    let mut samples: Vec<Sample> = Vec::with_capacity(signatures.len());
+    let mut pending: Vec<String> = signatures.to_vec();
+    let mut answered = false;
+    let mut last_error = CodedError::new(ErrorCode::RpcUnavailable, "no attempts made");
+    let deadline = Deadline::current();
+    let mut delay = RETRY_DELAY;
+    let mut attempts = 0;
+    for attempt in 1..=config.max_retries {
+        if pending.is_empty() {
+            break;
+        }
+        if attempt > 1 {
+            if deadline.is_some_and(|d| d.remaining() <= delay) {
+                break;
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+        attempts = attempt;
+        telemetry::set_current("attempts", attempt);
+        match config.rpc.call(|agent, url| {
+            get_priority_fees_for_signatures(agent, url, &pending, &config.transaction_options())
+        }) {
+            Ok(batch) => {
+                answered = true;
+                samples.extend(batch.samples);
+                pending = batch.failed;
+            }
+            Err(e) => last_error = CodedError::from_error(&*e),
+        }
+    }
+    if !answered {
+        return Err(last_error);
+    }
+    if !pending.is_empty() {
+        telemetry::set_current("missing", pending.len());
+        log::debug!(
+            "{} of {} transactions missing after {} attempts",
+            pending.len(),
+            signatures.len(),
+            attempts
+        );
+    }
+    Ok(samples)
+}
@@ -0,0 +1,197 @@
+//! Just enough of SQLite's C API for the history database. The system's libsqlite3 is
+//! loaded when a database is first opened, so builds don't need it.
+
+use crate::database::Value;
+use crate::dylib::{self, dylib_api};
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+const LIBRARY_NAMES: [&str; 3] = ["libsqlite3.so.0", "libsqlite3.so", "libsqlite3.dylib"];
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
+const SQLITE_NULL: c_int = 5;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+const SQLITE_OPEN_FULLMUTEX: c_int = 0x10000;
+/// `SQLITE_TRANSIENT`, the destructor that has SQLite copy bound text.
+const SQLITE_TRANSIENT: isize = -1;
+/// How long a statement waits for another process's lock on the database.
+const BUSY_TIMEOUT_MS: c_int = 5000;
+
+dylib_api!(Api {
+    sqlite3_open_v2: fn(*const c_char, *mut *mut c_void, c_int, *const c_char) -> c_int,
+    sqlite3_close_v2: fn(*mut c_void) -> c_int,
+    sqlite3_errmsg: fn(*mut c_void) -> *const c_char,
+    sqlite3_busy_timeout: fn(*mut c_void, c_int) -> c_int,
+    sqlite3_prepare_v2: fn(*mut c_void, *const c_char, c_int, *mut *mut c_void, *mut *const c_char) -> c_int,
+    sqlite3_bind_int64: fn(*mut c_void, c_int, i64) -> c_int,
+    sqlite3_bind_double: fn(*mut c_void, c_int, f64) -> c_int,
+    sqlite3_bind_text: fn(*mut c_void, c_int, *const c_char, c_int, isize) -> c_int,
+    sqlite3_bind_null: fn(*mut c_void, c_int) -> c_int,
+    sqlite3_step: fn(*mut c_void) -> c_int,
+    sqlite3_column_count: fn(*mut c_void) -> c_int,
+    sqlite3_column_type: fn(*mut c_void, c_int) -> c_int,
+    sqlite3_column_int64: fn(*mut c_void, c_int) -> i64,
+    sqlite3_column_double: fn(*mut c_void, c_int) -> f64,
+    sqlite3_column_text: fn(*mut c_void, c_int) -> *const c_char,
+    sqlite3_finalize: fn(*mut c_void) -> c_int,
+});
+
+fn api() -> io::Result<&'static Api> {
+    static API: OnceLock<Result<Api, String>> = OnceLock::new();
+    API.get_or_init(|| dylib::open(&LIBRARY_NAMES).and_then(Api::load))
+        .as_ref()
+        .map_err(|e| io::Error::other(e.clone()))
+}
+
+/// An open database. SQLite serializes calls on it, so it may move between threads.
+pub struct Connection {
+    api: &'static Api,
+    db: *mut c_void,
+}
+
+// SAFETY: opened with SQLITE_OPEN_FULLMUTEX, so SQLite serializes its use
+unsafe impl Send for Connection {}
+
+impl Connection {
+    /// Open the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Connection> {
+        let api = api()?;
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))?;
+        let mut db = std::ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_FULLMUTEX;
+        // SAFETY: `c_path` is a valid C string and `db` a valid out-pointer
+        let rc =
+            unsafe { (api.sqlite3_open_v2)(c_path.as_ptr(), &mut db, flags, std::ptr::null()) };
+        // Even a failed open allocates a handle, which carries the error
+        let conn = Connection { api, db };
+        if rc != SQLITE_OK {
+            return Err(conn.error());
+        }
+        // SAFETY: `db` is open
+        unsafe { (api.sqlite3_busy_timeout)(db, BUSY_TIMEOUT_MS) };
+        Ok(conn)
+    }
+
+    /// Run `sql`, a single statement, with `params` bound to its `$1`, `$2`, …
+    pub fn execute(&self, sql: &str, params: &[Value]) -> io::Result<()> {
+        let stmt = self.prepare(sql, params)?;
+        while stmt.step()? {}
+        Ok(())
+    }
+
+    /// The rows `sql`, a single statement, returns with `params` bound to its `$1`, `$2`, …
+    pub fn query(&self, sql: &str, params: &[Value]) -> io::Result<Vec<Vec<Value>>> {
+        let stmt = self.prepare(sql, params)?;
+        let mut rows = Vec::new();
+        while stmt.step()? {
+            rows.push(stmt.row());
+        }
+        Ok(rows)
+    }
+
+    fn prepare(&self, sql: &str, params: &[Value]) -> io::Result<Statement<'_>> {
+        let c_sql = CString::new(sql)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "SQL contains NUL"))?;
+        let mut raw = std::ptr::null_mut();
+        // SAFETY: `db` is open, `c_sql` is a valid C string and `raw` a valid out-pointer
+        let rc = unsafe {
+            (self.api.sqlite3_prepare_v2)(
+                self.db,
+                c_sql.as_ptr(),
+                -1,
+                &mut raw,
+                std::ptr::null_mut(),
+            )
+        };
+        if rc != SQLITE_OK {
+            return Err(self.error());
+        }
+        let stmt = Statement { conn: self, raw };
+        for (i, param) in params.iter().enumerate() {
+            let index = i as c_int + 1;
+            // SAFETY: `raw` is a prepared statement; SQLite copies bound text
+            let rc = unsafe {
+                match param {
+                    Value::Null => (self.api.sqlite3_bind_null)(raw, index),
+                    Value::Integer(n) => (self.api.sqlite3_bind_int64)(raw, index, *n),
+                    Value::Real(x) => (self.api.sqlite3_bind_double)(raw, index, *x),
+                    Value::Text(s) => (self.api.sqlite3_bind_text)(
+                        raw,
+                        index,
+                        s.as_ptr().cast(),
+                        s.len() as c_int,
+                        SQLITE_TRANSIENT,
+                    ),
+                }
+            };
+            if rc != SQLITE_OK {
+                return Err(self.error());
+            }
+        }
+        Ok(stmt)
+    }
+
+    /// The error of the last call that failed.
+    fn error(&self) -> io::Error {
+        // SAFETY: `db` is a handle from `sqlite3_open_v2`, whose message SQLite owns
+        let message = unsafe { CStr::from_ptr((self.api.sqlite3_errmsg)(self.db)) };
+        io::Error::other(message.to_string_lossy().into_owned())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // SAFETY: `db` is a handle from `sqlite3_open_v2`, not used again
+        unsafe { (self.api.sqlite3_close_v2)(self.db) };
+    }
+}
+
+struct Statement<'a> {
+    conn: &'a Connection,
+    raw: *mut c_void,
+}
+
+impl Statement<'_> {
+    /// Advance to the next row, returning whether there is one.
+    fn step(&self) -> io::Result<bool> {
+        // SAFETY: `raw` is a prepared statement
+        match unsafe { (self.conn.api.sqlite3_step)(self.raw) } {
+            SQLITE_ROW => Ok(true),
+            SQLITE_DONE => Ok(false),
+            _ => Err(self.conn.error()),
+        }
+    }
+
+    /// The current row's columns.
+    fn row(&self) -> Vec<Value> {
+        let api = self.conn.api;
+        // SAFETY: `raw` is a prepared statement on a row, and columns are in range
+        unsafe {
+            (0..(api.sqlite3_column_count)(self.raw))
+                .map(|i| match (api.sqlite3_column_type)(self.raw, i) {
+                    SQLITE_NULL => Value::Null,
+                    SQLITE_INTEGER => Value::Integer((api.sqlite3_column_int64)(self.raw, i)),
+                    SQLITE_FLOAT => Value::Real((api.sqlite3_column_double)(self.raw, i)),
+                    _ => match (api.sqlite3_column_text)(self.raw, i) {
+                        text if text.is_null() => Value::Null,
+                        text => Value::Text(CStr::from_ptr(text).to_string_lossy().into_owned()),
+                    },
+                })
+                .collect()
+        }
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `raw` is a prepared statement, not used again
+        unsafe { (self.conn.api.sqlite3_finalize)(self.raw) };
+    }
+}
@@ -0,0 +1,550 @@
+//! OpenTelemetry tracing: spans around the refresh pipeline and HTTP requests, exported
+//! in batches over OTLP/HTTP with JSON encoding. Configured by the standard `OTEL_*`
+//! environment variables; nothing is recorded unless an OTLP endpoint is set.
+
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{OnceLock, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_SERVICE_NAME: &str = "ivy-priority-fee";
+const DEFAULT_EXPORT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_SCHEDULE_DELAY_MS: u64 = 5_000;
+const DEFAULT_MAX_QUEUE_SIZE: usize = 2048;
+const DEFAULT_MAX_EXPORT_BATCH_SIZE: usize = 512;
+
+/// The trace and span a span belongs to, as carried across threads and in
+/// `traceparent` headers.
+#[derive(Clone, Copy, Debug)]
+pub struct SpanContext {
+    trace_id: u128,
+    span_id: u64,
+    sampled: bool,
+}
+
+impl SpanContext {
+    /// Parse a W3C `traceparent` header: `00-<trace id>-<parent id>-<flags>`.
+    pub fn from_traceparent(header: &str) -> Option<SpanContext> {
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 {
+            return None;
+        }
+        let context = SpanContext {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+        };
+        (context.trace_id != 0 && context.span_id != 0).then_some(context)
+    }
+}
+
+/// What a span stands for, per OTLP's `SpanKind`.
+#[derive(Clone, Copy, Debug)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// An attribute value.
+pub enum Attribute {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Double(f64),
+}
+
+impl From<&str> for Attribute {
+    fn from(v: &str) -> Attribute {
+        Attribute::Str(v.to_string())
+    }
+}
+
+impl From<String> for Attribute {
+    fn from(v: String) -> Attribute {
+        Attribute::Str(v)
+    }
+}
+
+impl From<u64> for Attribute {
+    fn from(v: u64) -> Attribute {
+        Attribute::Int(v as i64)
+    }
+}
+
+impl From<usize> for Attribute {
+    fn from(v: usize) -> Attribute {
+        Attribute::Int(v as i64)
+    }
+}
+
+impl From<u16> for Attribute {
+    fn from(v: u16) -> Attribute {
+        Attribute::Int(v as i64)
+    }
+}
+
+impl From<bool> for Attribute {
+    fn from(v: bool) -> Attribute {
+        Attribute::Bool(v)
+    }
+}
+
+impl From<f64> for Attribute {
+    fn from(v: f64) -> Attribute {
+        Attribute::Double(v)
+    }
+}
+
+impl Attribute {
+    /// OTLP's JSON `AnyValue`; 64-bit integers are strings.
+    fn to_json(&self) -> Value {
+        match self {
+            Attribute::Str(s) => json!({ "stringValue": s }),
+            Attribute::Int(n) => json!({ "intValue": n.to_string() }),
+            Attribute::Bool(b) => json!({ "boolValue": b }),
+            Attribute::Double(d) => json!({ "doubleValue": d }),
+        }
+    }
+}
+
+// --------------------------- Spans ---------------------------
+
+/// A span open on this thread.
+struct OpenSpan {
+    context: SpanContext,
+    parent_span_id: Option<u64>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(String, Attribute)>,
+    error: Option<String>,
+}
+
+thread_local! {
+    static OPEN: RefCell<Vec<OpenSpan>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A span, ended and queued for export when dropped. Inert if tracing is off or the
+/// trace isn't sampled.
+pub struct Span {
+    span_id: Option<u64>,              // None if inert
+    _not_send: PhantomData<*const ()>, // must end on the thread that opened it
+}
+
+/// Open a span as a child of the innermost span open on this thread, if any.
+pub fn span(name: &str, kind: SpanKind) -> Span {
+    span_with_parent(name, kind, current())
+}
+
+/// Open a span as a child of `parent`, e.g. a span on another thread or a remote one
+/// from a `traceparent` header; a new trace starts if there's none.
+pub fn span_with_parent(name: &str, kind: SpanKind, parent: Option<SpanContext>) -> Span {
+    let Some(tracer) = TRACER.get() else {
+        return Span::inert();
+    };
+    let trace_id = parent.map_or_else(
+        || random_u64() as u128 | (random_u64() as u128) << 64,
+        |p| p.trace_id,
+    );
+    let sampled = parent.map_or_else(|| tracer.sampler.sample(trace_id), |p| p.sampled);
+    let context = SpanContext {
+        trace_id,
+        span_id: random_u64().max(1),
+        sampled,
+    };
+    OPEN.with(|open| {
+        open.borrow_mut().push(OpenSpan {
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+            name: name.to_string(),
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        })
+    });
+    Span {
+        span_id: Some(context.span_id),
+        _not_send: PhantomData,
+    }
+}
+
+/// The innermost span open on this thread, to parent spans on other threads.
+pub fn current() -> Option<SpanContext> {
+    OPEN.with(|open| open.borrow().last().map(|s| s.context))
+}
+
+/// Set an attribute on the innermost span open on this thread, if any.
+pub fn set_current(key: &str, value: impl Into<Attribute>) {
+    OPEN.with(|open| {
+        if let Some(span) = open.borrow_mut().last_mut() {
+            set_attribute(span, key, value.into());
+        }
+    });
+}
+
+fn set_attribute(span: &mut OpenSpan, key: &str, value: Attribute) {
+    match span.attributes.iter_mut().find(|(k, _)| k == key) {
+        Some(attribute) => attribute.1 = value,
+        None => span.attributes.push((key.to_string(), value)),
+    }
+}
+
+impl Span {
+    fn inert() -> Span {
+        Span {
+            span_id: None,
+            _not_send: PhantomData,
+        }
+    }
+
+    fn with_open(&self, f: impl FnOnce(&mut OpenSpan)) {
+        let Some(span_id) = self.span_id else {
+            return;
+        };
+        OPEN.with(|open| {
+            if let Some(span) = open
+                .borrow_mut()
+                .iter_mut()
+                .rev()
+                .find(|s| s.context.span_id == span_id)
+            {
+                f(span);
+            }
+        });
+    }
+
+    pub fn set(&self, key: &str, value: impl Into<Attribute>) {
+        let value = value.into();
+        self.with_open(|span| set_attribute(span, key, value));
+    }
+
+    /// Mark the span as failed with `message`.
+    pub fn set_error(&self, message: &str) {
+        self.with_open(|span| span.error = Some(message.to_string()));
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(span_id) = self.span_id else {
+            return;
+        };
+        let span = OPEN.with(|open| {
+            let mut open = open.borrow_mut();
+            let i = open.iter().rposition(|s| s.context.span_id == span_id)?;
+            Some(open.remove(i))
+        });
+        if let (Some(span), Some(tracer)) = (span, TRACER.get())
+            && span.context.sampled
+        {
+            // Dropped if the exporter has fallen too far behind
+            let _ = tracer.queue.try_send(Message::Span(span.to_json()));
+        }
+    }
+}
+
+impl OpenSpan {
+    fn to_json(&self) -> Value {
+        let nanos = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string()
+        };
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.context.trace_id),
+            "spanId": format!("{:016x}", self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(k, v)| json!({ "key": k, "value": v.to_json() }))
+                .collect::<Vec<_>>(),
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        if let Some(message) = &self.error {
+            span["status"] = json!({ "code": 2, "message": message }); // STATUS_CODE_ERROR
+        }
+        span
+    }
+}
+
+// --------------------------- Export ---------------------------
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+struct Tracer {
+    sampler: Sampler,
+    queue: mpsc::SyncSender<Message>,
+}
+
+enum Message {
+    Span(Value),
+    Flush(mpsc::Sender<()>), // export what's queued, then acknowledge
+}
+
+/// Which new traces are recorded; spans with a parent follow its decision.
+#[derive(Clone, Copy)]
+enum Sampler {
+    AlwaysOn,
+    AlwaysOff,
+    Ratio(f64),
+}
+
+impl Sampler {
+    fn sample(&self, trace_id: u128) -> bool {
+        match self {
+            Sampler::AlwaysOn => true,
+            Sampler::AlwaysOff => false,
+            // The trace ID's low bits are random
+            Sampler::Ratio(r) => {
+                let fraction = ((trace_id as u64) >> 11) as f64 / (1u64 << 53) as f64;
+                fraction < *r
+            }
+        }
+    }
+}
+
+/// Exporter settings, from the `OTEL_*` environment variables.
+struct ExportConfig {
+    endpoint: String,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    resource: Vec<(String, String)>,
+    schedule_delay: Duration,
+    max_queue_size: usize,
+    max_batch_size: usize,
+}
+
+/// Start exporting spans if `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. Returns the endpoint, or `None` if
+/// tracing stays off.
+pub fn init() -> Result<Option<String>, String> {
+    let var = |name: &str| env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let traces_or = |specific: &str, general: &str| var(specific).or_else(|| var(general));
+    let number = |name: &str, default: u64| -> Result<u64, String> {
+        var(name).map_or(Ok(default), |v| {
+            v.trim()
+                .parse()
+                .map_err(|_| format!("Invalid {}: {}", name, v))
+        })
+    };
+
+    if var("OTEL_SDK_DISABLED").is_some_and(|v| v.eq_ignore_ascii_case("true")) {
+        return Ok(None);
+    }
+    match var("OTEL_TRACES_EXPORTER").as_deref() {
+        None | Some("otlp") => {}
+        Some("none") => return Ok(None),
+        Some(other) => return Err(format!("Unsupported OTEL_TRACES_EXPORTER: {}", other)),
+    }
+    let endpoint = match var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        Some(endpoint) => endpoint,
+        None => match var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            Some(base) => format!("{}/v1/traces", base.trim_end_matches('/')),
+            None => return Ok(None),
+        },
+    };
+    match traces_or(
+        "OTEL_EXPORTER_OTLP_TRACES_PROTOCOL",
+        "OTEL_EXPORTER_OTLP_PROTOCOL",
+    )
+    .as_deref()
+    {
+        None | Some("http/json") => {}
+        Some(other) => {
+            return Err(format!(
+                "Unsupported OTLP protocol {}; only http/json is",
+                other
+            ));
+        }
+    }
+    let ratio = || -> Result<f64, String> {
+        match var("OTEL_TRACES_SAMPLER_ARG") {
+            Some(v) => match v.trim().parse::<f64>() {
+                Ok(r) if (0.0..=1.0).contains(&r) => Ok(r),
+                _ => Err(format!("Invalid OTEL_TRACES_SAMPLER_ARG: {}", v)),
+            },
+            None => Ok(1.0),
+        }
+    };
+    let sampler = match var("OTEL_TRACES_SAMPLER").as_deref() {
+        None | Some("always_on" | "parentbased_always_on") => Sampler::AlwaysOn,
+        Some("always_off" | "parentbased_always_off") => Sampler::AlwaysOff,
+        Some("traceidratio" | "parentbased_traceidratio") => Sampler::Ratio(ratio()?),
+        Some(other) => return Err(format!("Unsupported OTEL_TRACES_SAMPLER: {}", other)),
+    };
+
+    let mut resource = key_values(&var("OTEL_RESOURCE_ATTRIBUTES").unwrap_or_default());
+    let service_name = var("OTEL_SERVICE_NAME")
+        .or_else(|| {
+            resource
+                .iter()
+                .find(|(k, _)| k == "service.name")
+                .map(|(_, v)| v.clone())
+        })
+        .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+    resource.retain(|(k, _)| k != "service.name");
+    resource.push(("service.name".to_string(), service_name));
+
+    let config = ExportConfig {
+        endpoint: endpoint.clone(),
+        headers: key_values(
+            &traces_or(
+                "OTEL_EXPORTER_OTLP_TRACES_HEADERS",
+                "OTEL_EXPORTER_OTLP_HEADERS",
+            )
+            .unwrap_or_default(),
+        ),
+        timeout: Duration::from_millis(match var("OTEL_EXPORTER_OTLP_TRACES_TIMEOUT") {
+            Some(_) => number(
+                "OTEL_EXPORTER_OTLP_TRACES_TIMEOUT",
+                DEFAULT_EXPORT_TIMEOUT_MS,
+            )?,
+            None => number("OTEL_EXPORTER_OTLP_TIMEOUT", DEFAULT_EXPORT_TIMEOUT_MS)?,
+        }),
+        resource,
+        schedule_delay: Duration::from_millis(number(
+            "OTEL_BSP_SCHEDULE_DELAY",
+            DEFAULT_SCHEDULE_DELAY_MS,
+        )?),
+        max_queue_size: number("OTEL_BSP_MAX_QUEUE_SIZE", DEFAULT_MAX_QUEUE_SIZE as u64)? as usize,
+        max_batch_size: number(
+            "OTEL_BSP_MAX_EXPORT_BATCH_SIZE",
+            DEFAULT_MAX_EXPORT_BATCH_SIZE as u64,
+        )?
+        .max(1) as usize,
+    };
+    let (queue, spans) = mpsc::sync_channel(config.max_queue_size);
+    if TRACER.set(Tracer { sampler, queue }).is_err() {
+        return Err("Tracing is already initialized".to_string());
+    }
+    thread::spawn(move || export_loop(&config, &spans));
+    Ok(Some(endpoint))
+}
+
+/// Export the spans queued so far, waiting up to `deadline`.
+pub fn flush(deadline: Instant) {
+    let Some(tracer) = TRACER.get() else {
+        return;
+    };
+    let (done, flushed) = mpsc::channel();
+    if tracer.queue.send(Message::Flush(done)).is_ok() {
+        let _ = flushed.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Send spans in batches of up to `max_batch_size`, at least every `schedule_delay`.
+fn export_loop(config: &ExportConfig, spans: &mpsc::Receiver<Message>) {
+    let mut batch: Vec<Value> = Vec::new();
+    let mut next_export = Instant::now() + config.schedule_delay;
+    loop {
+        let wait = next_export.saturating_duration_since(Instant::now());
+        let mut flushed = None;
+        match spans.recv_timeout(wait) {
+            Ok(Message::Span(span)) => batch.push(span),
+            Ok(Message::Flush(done)) => flushed = Some(done),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        if batch.len() >= config.max_batch_size
+            || flushed.is_some()
+            || Instant::now() >= next_export
+        {
+            if !batch.is_empty() {
+                export(config, &batch);
+                batch.clear();
+            }
+            next_export = Instant::now() + config.schedule_delay;
+        }
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+fn export(config: &ExportConfig, spans: &[Value]) {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": config
+                    .resource
+                    .iter()
+                    .map(|(k, v)| json!({ "key": k, "value": { "stringValue": v } }))
+                    .collect::<Vec<_>>()
+            },
+            "scopeSpans": [{
+                "scope": { "name": DEFAULT_SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans
+            }]
+        }]
+    });
+    let mut request = ureq::post(&config.endpoint).timeout(config.timeout);
+    for (k, v) in &config.headers {
+        request = request.set(k, v);
+    }
+    if let Err(e) = request.send_json(body) {
+        log::warn!(
+            "failed to export {} spans to {}: {}",
+            spans.len(),
+            config.endpoint,
+            e
+        );
+    }
+}
+
+/// Parse a `key=value,key=value` list, as in `OTEL_EXPORTER_OTLP_HEADERS`; values may
+/// be percent-encoded.
+fn key_values(s: &str) -> Vec<(String, String)> {
+    s.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), percent_decode(v.trim())))
+        .filter(|(k, _)| !k.is_empty())
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A random 64-bit number; not cryptographically secure.
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
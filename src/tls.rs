@@ -0,0 +1,263 @@
+//! HTTPS for the binary. The HTTP server only speaks plain HTTP, so TLS is terminated
+//! in front of it: each TLS connection is decrypted and relayed to the HTTP server on a
+//! loopback address, and the client's real address is kept for the server to look up.
+//! The certificate is reloaded when its files change.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often the certificate files are checked for changes.
+pub const CERT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the handshake may wait on the client.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A certificate chain and private key read from PEM files, reloaded when they change.
+pub struct ReloadingCert {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<(Arc<CertifiedKey>, Modified)>,
+}
+
+type Modified = (Option<SystemTime>, Option<SystemTime>); // of the cert and key files
+
+impl fmt::Debug for ReloadingCert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReloadingCert")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ReloadingCert {
+    pub fn load(cert_path: &str, key_path: &str) -> Result<ReloadingCert, String> {
+        let modified = modified(cert_path, key_path);
+        let key = certified_key(cert_path, key_path)?;
+        Ok(ReloadingCert {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: RwLock::new((Arc::new(key), modified)),
+        })
+    }
+
+    /// Reload the certificate if either file changed since it was last read. An invalid
+    /// new certificate, such as one caught halfway through being written, is reported and
+    /// the current one kept.
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let modified = modified(&self.cert_path, &self.key_path);
+        if self.current.read().unwrap().1 == modified {
+            return Ok(false);
+        }
+        let key = certified_key(&self.cert_path, &self.key_path);
+        // Record the attempt either way, so a bad certificate is reported once
+        let mut current = self.current.write().unwrap();
+        current.1 = modified;
+        current.0 = Arc::new(key?);
+        Ok(true)
+    }
+
+    /// Check the files every `CERT_POLL_INTERVAL` in a background thread.
+    pub fn watch(self: &Arc<Self>) {
+        let cert = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(CERT_POLL_INTERVAL);
+                match cert.reload_if_changed() {
+                    Ok(true) => log::info!("Reloaded TLS certificate {}", cert.cert_path),
+                    Ok(false) => {}
+                    Err(e) => log::error!("Failed to reload TLS certificate: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+fn modified(cert_path: &str, key_path: &str) -> Modified {
+    let mtime = |path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(cert_path), mtime(key_path))
+}
+
+fn certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read certificates from {}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Failed to read private key from {}: {}", key_path, e))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("Unsupported private key in {}: {}", key_path, e))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified
+        .keys_match()
+        .map_err(|e| format!("{} doesn't match {}: {}", key_path, cert_path, e))?;
+    Ok(certified)
+}
+
+/// Terminates TLS in front of the HTTP server.
+pub struct TlsFrontend {
+    config: Arc<rustls::ServerConfig>,
+    clients: Mutex<HashMap<SocketAddr, SocketAddr>>, // relay's local address -> client's
+}
+
+impl TlsFrontend {
+    pub fn new(cert: Arc<ReloadingCert>) -> TlsFrontend {
+        let mut config = rustls::ServerConfig::builder_with_provider(
+            rustls::crypto::ring::default_provider().into(),
+        )
+        .with_safe_default_protocol_versions()
+        .unwrap() // the ring provider supports the default protocol versions
+        .with_no_client_auth()
+        .with_cert_resolver(cert);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsFrontend {
+            config: Arc::new(config),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accept TLS connections on `addr` in a background thread, relaying them to the
+    /// HTTP server at `upstream`. Returns the address listened on.
+    pub fn listen(self: &Arc<Self>, addr: &str, upstream: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let frontend = self.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else { continue };
+                let frontend = frontend.clone();
+                thread::spawn(move || {
+                    if let Err(e) = frontend.relay(client, upstream) {
+                        log::debug!("TLS connection ended: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// The address of the client behind a connection to the HTTP server, if the
+    /// connection is relayed from a TLS client.
+    pub fn client_addr(&self, peer: &SocketAddr) -> Option<SocketAddr> {
+        self.clients.lock().unwrap().get(peer).copied()
+    }
+
+    fn relay(&self, mut client: TcpStream, upstream: SocketAddr) -> io::Result<()> {
+        let client_addr = client.peer_addr()?;
+        let mut conn =
+            rustls::ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut client)?;
+        }
+        client.set_read_timeout(None)?;
+
+        let upstream = TcpStream::connect(upstream)?;
+        let relay_addr = upstream.local_addr()?;
+        self.clients.lock().unwrap().insert(relay_addr, client_addr);
+        let result = pump(&mut conn, &mut client, upstream);
+        self.clients.lock().unwrap().remove(&relay_addr);
+        result
+    }
+}
+
+/// Relay between the TLS client and the HTTP server until the server closes the
+/// connection, or the client closes it and the server has answered.
+fn pump(
+    conn: &mut rustls::ServerConnection,
+    client: &mut TcpStream,
+    mut upstream: TcpStream,
+) -> io::Result<()> {
+    let mut buf = [0u8; 16 * 1024];
+    // The request may have arrived along with the end of the handshake
+    let mut client_open = forward_plaintext(conn, &mut upstream)?;
+    loop {
+        while conn.wants_write() {
+            conn.write_tls(client)?;
+        }
+        let mut fds = [
+            libc::pollfd {
+                fd: if client_open { client.as_raw_fd() } else { -1 },
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: upstream.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // SAFETY: `fds` is a valid array of two pollfds for the duration of the call
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if fds[0].revents != 0 {
+            match conn.read_tls(client) {
+                Ok(_) => {
+                    if let Err(e) = conn.process_new_packets() {
+                        // Tell the client why before giving up
+                        let _ = conn.write_tls(client);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            if !forward_plaintext(conn, &mut upstream)? {
+                client_open = false;
+                upstream.shutdown(Shutdown::Write)?;
+            }
+        }
+        if fds[1].revents != 0 {
+            let n = upstream.read(&mut buf)?;
+            if n == 0 {
+                conn.send_close_notify();
+                while conn.wants_write() {
+                    conn.write_tls(client)?;
+                }
+                return Ok(());
+            }
+            conn.writer().write_all(&buf[..n])?;
+        }
+    }
+}
+
+/// Pass the decrypted data received so far to the HTTP server. Returns whether the
+/// client may send more.
+fn forward_plaintext(
+    conn: &mut rustls::ServerConnection,
+    upstream: &mut TcpStream,
+) -> io::Result<bool> {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        match conn.reader().read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => upstream.write_all(&buf[..n])?,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
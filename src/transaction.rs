@@ -0,0 +1,451 @@
+//! Decoding of serialized (wire-format) transactions, legacy and v0.
+
+use crate::compute_budget::{COMPUTE_BUDGET_PROGRAM, ComputeBudget, bs58_decode, bs58_encode};
+
+/// How a serialized transaction is encoded for transport.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionEncoding {
+    Base58,
+    Base64,
+}
+
+/// The parts of a transaction's message that fee estimation needs.
+#[derive(Clone, Debug)]
+pub struct ParsedTransaction {
+    pub signature: Option<String>, // the first, which identifies the transaction
+    pub num_required_signatures: u8,
+    pub account_keys: Vec<String>, // static keys only; v0 lookups are in `address_table_lookups`
+    pub writable: Vec<bool>,       // parallel to `account_keys`
+    pub recent_blockhash: String,
+    pub instructions: Vec<ParsedInstruction>,
+    pub address_table_lookups: Vec<AddressTableLookup>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ParsedInstruction {
+    pub program_id_index: usize,
+    pub accounts: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AddressTableLookup {
+    pub account_key: String,
+    pub writable_indexes: Vec<u8>,
+    pub readonly_indexes: Vec<u8>,
+}
+
+impl ParsedTransaction {
+    /// Decode and parse a transaction encoded with `encoding`.
+    pub fn decode(s: &str, encoding: TransactionEncoding) -> Result<ParsedTransaction, String> {
+        let bytes = match encoding {
+            TransactionEncoding::Base58 => bs58_decode(s),
+            TransactionEncoding::Base64 => base64_decode(s),
+        }
+        .ok_or_else(|| format!("transaction is not valid {:?}", encoding))?;
+        ParsedTransaction::parse(&bytes)
+    }
+
+    /// Parse a serialized transaction: its signatures followed by its message.
+    pub fn parse(bytes: &[u8]) -> Result<ParsedTransaction, String> {
+        let mut r = Reader { bytes, pos: 0 };
+        let num_signatures = r.compact_u16()?;
+        let signatures = r.take(num_signatures * 64)?;
+        let signature = signatures.get(..64).map(bs58_encode);
+
+        let versioned = r.peek()? & 0x80 != 0;
+        if versioned {
+            let version = r.u8()? & 0x7f;
+            if version != 0 {
+                return Err(format!("unsupported transaction version {}", version));
+            }
+        }
+        let num_required_signatures = r.u8()?;
+        let num_readonly_signed = r.u8()? as usize;
+        let num_readonly_unsigned = r.u8()? as usize;
+        let num_keys = r.compact_u16()?;
+        let mut account_keys = Vec::with_capacity(num_keys);
+        for _ in 0..num_keys {
+            account_keys.push(bs58_encode(r.take(32)?));
+        }
+        let signed = num_required_signatures as usize;
+        if signed > num_keys
+            || num_readonly_signed > signed
+            || num_readonly_unsigned > num_keys - signed
+        {
+            return Err("invalid message header".to_string());
+        }
+        let writable = (0..num_keys)
+            .map(|i| {
+                if i < signed {
+                    i < signed - num_readonly_signed
+                } else {
+                    i < num_keys - num_readonly_unsigned
+                }
+            })
+            .collect();
+        let recent_blockhash = bs58_encode(r.take(32)?);
+
+        let num_instructions = r.compact_u16()?;
+        let mut instructions = Vec::with_capacity(num_instructions);
+        for _ in 0..num_instructions {
+            let program_id_index = r.u8()? as usize;
+            let num_accounts = r.compact_u16()?;
+            let accounts = r.take(num_accounts)?.to_vec();
+            let data_len = r.compact_u16()?;
+            let data = r.take(data_len)?.to_vec();
+            instructions.push(ParsedInstruction {
+                program_id_index,
+                accounts,
+                data,
+            });
+        }
+
+        let mut address_table_lookups = Vec::new();
+        if versioned {
+            for _ in 0..r.compact_u16()? {
+                let account_key = bs58_encode(r.take(32)?);
+                let n = r.compact_u16()?;
+                let writable_indexes = r.take(n)?.to_vec();
+                let n = r.compact_u16()?;
+                let readonly_indexes = r.take(n)?.to_vec();
+                address_table_lookups.push(AddressTableLookup {
+                    account_key,
+                    writable_indexes,
+                    readonly_indexes,
+                });
+            }
+        }
+
+        Ok(ParsedTransaction {
+            signature,
+            num_required_signatures,
+            account_keys,
+            writable,
+            recent_blockhash,
+            instructions,
+            address_table_lookups,
+        })
+    }
+
+    /// The static account keys the transaction write-locks.
+    pub fn writable_accounts(&self) -> Vec<String> {
+        self.account_keys
+            .iter()
+            .zip(&self.writable)
+            .filter(|(_, w)| **w)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// The compute budget the transaction requests, or `None` if an instruction can't
+    /// be decoded.
+    pub fn compute_budget(&self) -> Option<ComputeBudget> {
+        let mut budget = ComputeBudget::default();
+        for ix in &self.instructions {
+            if self
+                .account_keys
+                .get(ix.program_id_index)
+                .map(String::as_str)
+                == Some(COMPUTE_BUDGET_PROGRAM)
+            {
+                budget.apply(&ix.data)?;
+            }
+        }
+        Some(budget)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let out = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or("transaction is truncated")?;
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, String> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| "transaction is truncated".to_string())
+    }
+
+    /// Solana's "shortvec" length prefix: 7 bits per byte, least significant first. As
+    /// in Solana, values past `u16::MAX` and encodings longer than needed are invalid.
+    fn compact_u16(&mut self) -> Result<usize, String> {
+        let mut value = 0usize;
+        for i in 0..3 {
+            let b = self.u8()?;
+            if i > 0 && b == 0 {
+                break;
+            }
+            value |= ((b & 0x7f) as usize) << (7 * i);
+            if b & 0x80 == 0 {
+                return match value <= u16::MAX as usize {
+                    true => Ok(value),
+                    false => break,
+                };
+            }
+        }
+        Err("invalid compact-u16".to_string())
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Decode standard base64, with or without padding, or `None` if it's malformed.
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        acc = (acc << 6) | BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // Leftover bits must be zero padding, and a lone 6-bit group can't encode a byte
+    if bits >= 6 || acc & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// Encode `bytes` as standard, padded base64.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPUTE_BUDGET: [u8; 32] = [
+        3, 6, 70, 111, 229, 33, 23, 50, 255, 236, 173, 186, 114, 195, 155, 231, 188, 140, 229, 187,
+        197, 247, 18, 107, 44, 67, 155, 58, 64, 0, 0, 0,
+    ];
+
+    /// A SOL transfer from `[1; 32]` to `[2; 32]` paying 5000 micro-lamports per CU.
+    fn legacy() -> Vec<u8> {
+        let mut tx = vec![1];
+        tx.extend([0x11; 64]); // signature
+        tx.extend([1, 0, 2]); // header: 1 signer, 2 read-only unsigned keys
+        tx.push(4);
+        tx.extend([1; 32]); // fee payer
+        tx.extend([2; 32]); // recipient
+        tx.extend([0; 32]); // System program
+        tx.extend(COMPUTE_BUDGET);
+        tx.extend([9; 32]); // recent blockhash
+        tx.push(2);
+        tx.extend([3, 0, 9, 3]); // SetComputeUnitPrice
+        tx.extend(5000u64.to_le_bytes());
+        tx.extend([2, 2, 0, 1, 12, 2, 0, 0, 0]); // Transfer
+        tx.extend(1_000_000_000u64.to_le_bytes());
+        tx
+    }
+
+    /// A v0 transaction with a compute unit limit and price, calling `[5; 32]` with
+    /// accounts loaded from the lookup table `[7; 32]`.
+    fn v0() -> Vec<u8> {
+        let mut tx = vec![1];
+        tx.extend([0x22; 64]); // signature
+        tx.push(0x80); // version 0
+        tx.extend([1, 0, 2]);
+        tx.push(3);
+        tx.extend([1; 32]);
+        tx.extend([5; 32]);
+        tx.extend(COMPUTE_BUDGET);
+        tx.extend([9; 32]);
+        tx.push(3);
+        tx.extend([2, 0, 5, 2, 0x40, 0x0d, 0x03, 0x00]); // SetComputeUnitLimit(200_000)
+        tx.extend([2, 0, 9, 3]); // SetComputeUnitPrice
+        tx.extend(1_234_567u64.to_le_bytes());
+        tx.extend([1, 3, 0, 3, 4, 2, 0xaa, 0xbb]);
+        tx.push(1); // address table lookups
+        tx.extend([7; 32]);
+        tx.extend([1, 5, 2, 7, 9]);
+        tx
+    }
+
+    #[test]
+    fn legacy_transactions_are_parsed() {
+        let tx = ParsedTransaction::parse(&legacy()).unwrap();
+        assert_eq!(tx.signature, Some(bs58_encode(&[0x11; 64])));
+        assert_eq!(tx.num_required_signatures, 1);
+        assert_eq!(
+            tx.account_keys,
+            [
+                bs58_encode(&[1; 32]),
+                bs58_encode(&[2; 32]),
+                "11111111111111111111111111111111".to_string(),
+                COMPUTE_BUDGET_PROGRAM.to_string(),
+            ]
+        );
+        assert_eq!(tx.writable, [true, true, false, false]);
+        assert_eq!(tx.writable_accounts(), tx.account_keys[..2]);
+        assert_eq!(tx.recent_blockhash, bs58_encode(&[9; 32]));
+        assert_eq!(tx.instructions.len(), 2);
+        assert_eq!(tx.instructions[1].program_id_index, 2);
+        assert_eq!(tx.instructions[1].accounts, [0, 1]);
+        assert_eq!(tx.instructions[1].data[..4], [2, 0, 0, 0]);
+        assert!(tx.address_table_lookups.is_empty());
+        assert_eq!(
+            tx.compute_budget(),
+            Some(ComputeBudget {
+                unit_price: Some(5000),
+                unit_limit: None,
+            })
+        );
+    }
+
+    #[test]
+    fn v0_transactions_are_parsed_with_their_lookups() {
+        let tx = ParsedTransaction::parse(&v0()).unwrap();
+        assert_eq!(tx.signature, Some(bs58_encode(&[0x22; 64])));
+        assert_eq!(tx.writable, [true, false, false]);
+        assert_eq!(tx.writable_accounts(), [bs58_encode(&[1; 32])]);
+        assert_eq!(tx.instructions.len(), 3);
+        assert_eq!(tx.instructions[2].program_id_index, 1);
+        assert_eq!(tx.instructions[2].accounts, [0, 3, 4]);
+        assert_eq!(tx.instructions[2].data, [0xaa, 0xbb]);
+        assert_eq!(tx.address_table_lookups.len(), 1);
+        let lookup = &tx.address_table_lookups[0];
+        assert_eq!(lookup.account_key, bs58_encode(&[7; 32]));
+        assert_eq!(lookup.writable_indexes, [5]);
+        assert_eq!(lookup.readonly_indexes, [7, 9]);
+        assert_eq!(
+            tx.compute_budget(),
+            Some(ComputeBudget {
+                unit_price: Some(1_234_567),
+                unit_limit: Some(200_000),
+            })
+        );
+    }
+
+    #[test]
+    fn transactions_are_decoded_from_either_encoding() {
+        let bytes = v0();
+        for (s, encoding) in [
+            (bs58_encode(&bytes), TransactionEncoding::Base58),
+            (base64_encode(&bytes), TransactionEncoding::Base64),
+        ] {
+            let tx = ParsedTransaction::decode(&s, encoding).unwrap();
+            assert_eq!(tx.signature, Some(bs58_encode(&[0x22; 64])));
+        }
+        assert_eq!(
+            ParsedTransaction::decode("0OIl", TransactionEncoding::Base58).unwrap_err(),
+            "transaction is not valid Base58"
+        );
+        assert_eq!(
+            ParsedTransaction::decode("AA*=", TransactionEncoding::Base64).unwrap_err(),
+            "transaction is not valid Base64"
+        );
+    }
+
+    #[test]
+    fn truncated_transactions_are_rejected() {
+        for tx in [legacy(), v0()] {
+            for len in 0..tx.len() {
+                assert_eq!(
+                    ParsedTransaction::parse(&tx[..len]).unwrap_err(),
+                    "transaction is truncated",
+                    "{} of {} bytes",
+                    len,
+                    tx.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_transactions_are_rejected() {
+        let invalid = |patch: &dyn Fn(&mut Vec<u8>)| {
+            let mut tx = v0();
+            patch(&mut tx);
+            ParsedTransaction::parse(&tx).unwrap_err()
+        };
+        assert_eq!(
+            invalid(&|tx| tx[65] = 0x81),
+            "unsupported transaction version 1"
+        );
+        assert_eq!(invalid(&|tx| tx[66] = 4), "invalid message header");
+        assert_eq!(invalid(&|tx| tx[67] = 2), "invalid message header");
+        assert_eq!(invalid(&|tx| tx[68] = 3), "invalid message header");
+
+        // Shortvec lengths past u16::MAX, or longer than needed, as the signature count
+        for prefix in [
+            &[0xff, 0xff, 0xff][..],
+            &[0xff, 0xff, 0x04],
+            &[0x80, 0x80, 0x80, 0x01],
+            &[0x81, 0x00],
+            &[0x80, 0x80, 0x00],
+        ] {
+            let mut tx = prefix.to_vec();
+            tx.extend(&v0()[1..]);
+            assert_eq!(
+                ParsedTransaction::parse(&tx).unwrap_err(),
+                "invalid compact-u16",
+                "{:02x?}",
+                prefix
+            );
+        }
+        // The largest count is read, then found to be more than there is
+        let mut tx = vec![0xff, 0xff, 0x03];
+        tx.extend(&v0()[1..]);
+        assert_eq!(
+            ParsedTransaction::parse(&tx).unwrap_err(),
+            "transaction is truncated"
+        );
+    }
+
+    #[test]
+    fn base64_round_trips_with_or_without_padding() {
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (&[0xfb, 0xff], "+/8="),
+        ] {
+            assert_eq!(base64_encode(bytes), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), bytes);
+            assert_eq!(base64_decode(encoded.trim_end_matches('=')).unwrap(), bytes);
+        }
+        // A lone 6-bit group, leftover bits that aren't zero, and characters outside
+        // the alphabet
+        for s in ["Z", "Zm9vY", "Zh==", "Zm9=", "Zm-v", "Zm9v\n"] {
+            assert_eq!(base64_decode(s), None, "{}", s);
+        }
+    }
+}
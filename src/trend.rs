@@ -0,0 +1,141 @@
+//! Which way fees are heading, fitted to a program's last few refreshes, and where that
+//! puts them in the next few minutes.
+
+use crate::aggregation::Aggregation;
+use crate::estimator::Estimate;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::VecDeque;
+
+pub const DEFAULT_TREND_WINDOW: usize = 10;
+/// How fast, as a fraction of the current fee per minute, fees must change to count as
+/// rising or falling rather than stable.
+pub const TREND_THRESHOLD_PER_MINUTE: f64 = 0.05;
+/// How many minutes ahead fees are forecast, one minute at a time.
+pub const FORECAST_MINUTES: u64 = 5;
+
+/// A program's fee curves over its latest refreshes. Only meaningful for the
+/// aggregation and failed-transaction selection it was recorded with.
+#[derive(Clone, Debug)]
+pub struct Trend {
+    pub aggregation: Aggregation,
+    pub include_failed: bool,
+    points: VecDeque<(u64, Vec<f64>)>, // unix seconds, fees at each whole percentile 0..=100
+}
+
+/// Which way fees are heading.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Rising,
+    Falling,
+    Stable,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Rising => "rising",
+            Direction::Falling => "falling",
+            Direction::Stable => "stable",
+        }
+    }
+}
+
+/// The trend at one percentile.
+#[derive(Clone, Debug)]
+pub struct FeeTrend {
+    pub direction: Direction,
+    pub slope_per_minute: f64, // micro-lamports per CU
+    pub forecast: Vec<u64>,    // fees 1..=FORECAST_MINUTES minutes out, unclamped
+}
+
+impl Trend {
+    /// `previous` with `e` added, keeping the latest `window` refreshes, or a new trend
+    /// from `e` if there is no comparable previous one.
+    pub fn update(
+        previous: Option<&Trend>,
+        e: &Estimate,
+        aggregation: Aggregation,
+        window: usize,
+    ) -> Trend {
+        let mut points = match previous {
+            Some(prev)
+                if prev.aggregation == aggregation && prev.include_failed == e.include_failed =>
+            {
+                prev.points.clone()
+            }
+            _ => VecDeque::new(),
+        };
+        let fees = (0..=100)
+            .map(|p| e.fee(p as f64, aggregation) as f64)
+            .collect();
+        points.push_back((e.last_updated, fees));
+        while points.len() > window.max(1) {
+            points.pop_front();
+        }
+        Trend {
+            aggregation,
+            include_failed: e.include_failed,
+            points,
+        }
+    }
+
+    /// The least-squares line through the fees at percentile `p` (0-100), or `None` until
+    /// refreshes at two different times have been recorded.
+    pub fn fee_trend(&self, p: f64) -> Option<FeeTrend> {
+        let p = p.clamp(0.0, 100.0);
+        let (lo, hi) = (p.floor() as usize, p.ceil() as usize);
+        let series: Vec<(f64, f64)> = self
+            .points
+            .iter()
+            .map(|(t, fees)| {
+                (
+                    *t as f64,
+                    fees[lo] + (fees[hi] - fees[lo]) * (p - lo as f64),
+                )
+            })
+            .collect();
+        let n = series.len() as f64;
+        let mean_t = series.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_fee = series.iter().map(|(_, f)| f).sum::<f64>() / n;
+        let var_t: f64 = series.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+        if var_t == 0.0 {
+            return None;
+        }
+        let cov: f64 = series
+            .iter()
+            .map(|(t, f)| (t - mean_t) * (f - mean_fee))
+            .sum();
+        let slope_per_minute = cov / var_t * 60.0;
+        let (now, _) = self.points.back()?;
+        let fitted_now = mean_fee + slope_per_minute / 60.0 * (*now as f64 - mean_t);
+        let relative = slope_per_minute / fitted_now.abs().max(1.0);
+        let direction = if relative > TREND_THRESHOLD_PER_MINUTE {
+            Direction::Rising
+        } else if relative < -TREND_THRESHOLD_PER_MINUTE {
+            Direction::Falling
+        } else {
+            Direction::Stable
+        };
+        let forecast = (1..=FORECAST_MINUTES)
+            .map(|m| (fitted_now + slope_per_minute * m as f64).max(0.0).round() as u64)
+            .collect();
+        Some(FeeTrend {
+            direction,
+            slope_per_minute,
+            forecast,
+        })
+    }
+}
+
+impl FeeTrend {
+    /// The forecast keyed `1m` to `5m`.
+    pub fn forecast_by_minute(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.forecast
+            .iter()
+            .enumerate()
+            .map(|(i, fee)| (format!("{}m", i + 1), json!(fee)))
+            .collect()
+    }
+}
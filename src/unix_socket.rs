@@ -0,0 +1,63 @@
+//! Serving on a Unix domain socket. The HTTP server only listens on TCP, so each
+//! connection to the socket is relayed to it on a loopback address.
+
+use std::fs;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// Listen on a socket at `path` with permissions `mode`, relaying connections to the HTTP
+/// server at `upstream` from a background thread. A socket left at `path` by a previous
+/// run is replaced; any other file there is an error.
+pub fn listen(path: &str, mode: u32, upstream: SocketAddr) -> io::Result<()> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file that isn't a socket is in the way",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let Ok(client) = client else { continue };
+            thread::spawn(move || {
+                if let Err(e) = relay(client, upstream) {
+                    log::debug!("Unix socket connection ended: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Remove the socket file, as on shutdown.
+pub fn remove(path: &str) {
+    if let Err(e) = fs::remove_file(path) {
+        log::warn!("Failed to remove socket {}: {}", path, e);
+    }
+}
+
+/// Copy bytes both ways until the HTTP server closes the connection.
+fn relay(client: UnixStream, upstream: SocketAddr) -> io::Result<()> {
+    let upstream = TcpStream::connect(upstream)?;
+    let (mut client_reader, mut upstream_writer) = (client.try_clone()?, upstream.try_clone()?);
+    let requests = thread::spawn(move || {
+        let copied = io::copy(&mut client_reader, &mut upstream_writer);
+        let _ = upstream_writer.shutdown(Shutdown::Write);
+        copied
+    });
+    let (mut upstream_reader, mut client_writer) = (upstream, client);
+    io::copy(&mut upstream_reader, &mut client_writer)?;
+    // The server is done, so stop waiting on the client too
+    let _ = client_writer.shutdown(Shutdown::Both);
+    requests.join().expect("relay thread panicked")?;
+    Ok(())
+}
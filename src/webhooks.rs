@@ -0,0 +1,512 @@
+//! Webhooks called when a program's fee crosses a threshold or jumps between refreshes,
+//! with optionally signed payloads and retries, as JSON or as Slack or Discord messages.
+
+use crate::aggregation::Aggregation;
+use crate::estimator::{DEFAULT_PERCENTILE, Estimate, PriorityFeeLevels};
+use crate::trend::{Direction, FORECAST_MINUTES};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Attempts at delivering each notification, `RETRY_DELAY` apart, doubling each time.
+pub const DELIVERY_ATTEMPTS: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// The header carrying `sha256=<hex HMAC-SHA256 of the body>` for webhooks with a secret.
+pub const SIGNATURE_HEADER: &str = "X-Ivy-Signature-256";
+
+/// A webhook as configured, `url[;secret=S][;thresholds=N|N...][;change=PCT]
+/// [;percentile=P][;program=P][;format=F]`, or as registered with `POST /admin/webhooks`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>, // key of the payload's HMAC-SHA256 signature
+    #[serde(default)]
+    pub thresholds: Vec<u64>, // fees whose crossing, either way, calls the webhook
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_percent: Option<f64>, // a larger change between refreshes calls it too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentile: Option<f64>, // of the watched fee; DEFAULT_PERCENTILE if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub program: Option<String>, // only this program's fee, if set
+    #[serde(default)]
+    pub format: WebhookFormat,
+}
+
+impl Webhook {
+    /// Parse `url[;secret=S][;thresholds=N|N...][;change=PCT][;percentile=P][;program=P]
+    /// [;format=F]`, e.g. `https://ops.example/hook;secret=s3cret;thresholds=100000|1000000`.
+    pub fn parse(s: &str) -> Result<Webhook, String> {
+        let mut parts = s.split(';').map(str::trim);
+        let mut webhook = Webhook {
+            url: parts.next().unwrap_or("").to_string(),
+            secret: None,
+            thresholds: Vec::new(),
+            change_percent: None,
+            percentile: None,
+            program: None,
+            format: WebhookFormat::Json,
+        };
+        let invalid = |option: &str, url: &str| format!("Invalid {} for webhook {}", option, url);
+        for part in parts {
+            match part.split_once('=') {
+                Some(("secret", v)) if !v.is_empty() => webhook.secret = Some(v.to_string()),
+                Some(("thresholds", v)) => {
+                    webhook.thresholds = v
+                        .split('|')
+                        .map(|t| t.trim().parse::<u64>())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| invalid("thresholds", &webhook.url))?;
+                }
+                Some(("change", v)) => match v.trim_end_matches('%').parse::<f64>() {
+                    Ok(pct) => webhook.change_percent = Some(pct),
+                    _ => return Err(invalid("change", &webhook.url)),
+                },
+                Some(("percentile", v)) => match v.parse::<f64>() {
+                    Ok(p) => webhook.percentile = Some(p),
+                    _ => return Err(invalid("percentile", &webhook.url)),
+                },
+                Some(("program", v)) if !v.is_empty() => webhook.program = Some(v.to_string()),
+                Some(("format", v)) => webhook.format = v.parse()?,
+                _ => {
+                    return Err(format!(
+                        "Unknown option '{}' for webhook {}",
+                        part, webhook.url
+                    ));
+                }
+            }
+        }
+        webhook.validate()?;
+        Ok(webhook)
+    }
+
+    /// Check the URL is HTTP(S), not on an internal host (see `is_internal_address`),
+    /// and the webhook has something to fire on.
+    pub fn validate(&self) -> Result<(), String> {
+        let host = match url::Url::parse(&self.url) {
+            Ok(u) if u.scheme() == "http" || u.scheme() == "https" => {
+                u.host().map(|h| h.to_owned())
+            }
+            _ => return Err(format!("Invalid webhook URL: {}", self.url)),
+        };
+        let internal = match host {
+            Some(url::Host::Ipv4(ip)) => is_internal_address(ip.into()),
+            Some(url::Host::Ipv6(ip)) => is_internal_address(ip.into()),
+            Some(url::Host::Domain(name)) => name == "localhost" || name.ends_with(".localhost"),
+            None => true,
+        };
+        if internal {
+            return Err(format!(
+                "Webhook {} is on a loopback, link-local or private address",
+                self.url
+            ));
+        }
+        if self.thresholds.is_empty() && self.change_percent.is_none() {
+            return Err(format!(
+                "Webhook {} needs thresholds or a change percentage",
+                self.url
+            ));
+        }
+        if self
+            .change_percent
+            .is_some_and(|c| !(c > 0.0 && c.is_finite()))
+        {
+            return Err(format!(
+                "Webhook {} change percentage must be positive",
+                self.url
+            ));
+        }
+        if self.percentile.is_some_and(|p| !(0.0..=100.0).contains(&p)) {
+            return Err(format!(
+                "Webhook {} percentile must be between 0 and 100",
+                self.url
+            ));
+        }
+        Ok(())
+    }
+
+    /// What to report of a refresh of `program` from `previous` to `current`, or `None`
+    /// if the change doesn't concern this webhook.
+    fn event(
+        &self,
+        program: &str,
+        previous: &Estimate,
+        current: &Estimate,
+        aggregation: Aggregation,
+    ) -> Option<Event> {
+        if self.program.as_deref().is_some_and(|p| p != program) {
+            return None;
+        }
+        let percentile = self.percentile.unwrap_or(DEFAULT_PERCENTILE);
+        let (before, after) = (
+            previous.fee(percentile, aggregation),
+            current.fee(percentile, aggregation),
+        );
+        let crossed: Vec<u64> = self
+            .thresholds
+            .iter()
+            .copied()
+            .filter(|&t| (before < t) != (after < t))
+            .collect();
+        let change_percent =
+            (before > 0).then(|| (after as f64 - before as f64) / before as f64 * 100.0);
+        let changed = self
+            .change_percent
+            .is_some_and(|limit| match change_percent {
+                Some(change) => change.abs() > limit,
+                None => after != before,
+            });
+        if crossed.is_empty() && !changed {
+            return None;
+        }
+        let trend = current.fee_trend(percentile, aggregation);
+        Some(Event {
+            event: if crossed.is_empty() {
+                "fee_changed"
+            } else {
+                "threshold_crossed"
+            },
+            program: program.to_string(),
+            percentile,
+            previous_fee: before,
+            fee: after,
+            change_percent,
+            crossed,
+            direction: if after > before { "up" } else { "down" },
+            priority_fee_levels: current.levels_with(aggregation),
+            trend: trend.as_ref().map(|t| t.direction),
+            forecast: trend.map(|t| t.forecast_by_minute()),
+            source: current.source,
+            timestamp: current.last_updated,
+        })
+    }
+
+    /// The request body reporting `event`, in this webhook's format.
+    fn body(&self, event: &Event) -> serde_json::Value {
+        match self.format {
+            WebhookFormat::Json => json!(event),
+            WebhookFormat::Slack => json!({ "text": event.message("*") }),
+            WebhookFormat::Discord => json!({ "content": event.message("**") }),
+        }
+    }
+}
+
+/// What a webhook body is shaped for.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// The event itself, for programs.
+    #[default]
+    Json,
+    /// A message for a Slack incoming webhook.
+    Slack,
+    /// A message for a Discord webhook.
+    Discord,
+}
+
+impl FromStr for WebhookFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WebhookFormat, String> {
+        match s {
+            "json" => Ok(WebhookFormat::Json),
+            "slack" => Ok(WebhookFormat::Slack),
+            "discord" => Ok(WebhookFormat::Discord),
+            _ => Err(format!("unknown webhook format: {}", s)),
+        }
+    }
+}
+
+/// A fee movement a webhook is called about.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Event {
+    event: &'static str, // `threshold_crossed`, or `fee_changed` if only `change` was exceeded
+    program: String,
+    percentile: f64,
+    previous_fee: u64,
+    fee: u64,
+    change_percent: Option<f64>, // `None` if the previous fee was 0
+    crossed: Vec<u64>,
+    direction: &'static str,
+    priority_fee_levels: PriorityFeeLevels,
+    trend: Option<Direction>,
+    forecast: Option<serde_json::Map<String, serde_json::Value>>,
+    source: &'static str,
+    timestamp: u64,
+}
+
+impl Event {
+    /// The event as a few lines of chat, with `bold` around the headline fee.
+    fn message(&self, bold: &str) -> String {
+        let arrow = match self.direction {
+            "up" => ":chart_with_upwards_trend:",
+            _ => ":chart_with_downwards_trend:",
+        };
+        let change = match self.change_percent {
+            Some(pct) => format!(" ({:+.0}%)", pct),
+            None => String::new(),
+        };
+        let mut lines = vec![format!(
+            "{} Priority fee for {} went {} to {b}{}{b} micro-lamports/CU from {}{} at p{:.0}",
+            arrow,
+            self.program,
+            self.direction,
+            thousands(self.fee),
+            thousands(self.previous_fee),
+            change,
+            self.percentile,
+            b = bold,
+        )];
+        if !self.crossed.is_empty() {
+            let crossed: Vec<String> = self.crossed.iter().map(|&t| thousands(t)).collect();
+            lines.push(format!("Crossed {}", crossed.join(", ")));
+        }
+        let l = &self.priority_fee_levels;
+        lines.push(format!(
+            "Levels: min {} · low {} · medium {} · high {} · very high {}",
+            thousands(l.min),
+            thousands(l.low),
+            thousands(l.medium),
+            thousands(l.high),
+            thousands(l.very_high),
+        ));
+        if let Some(trend) = self.trend {
+            let outlook = self
+                .forecast
+                .as_ref()
+                .and_then(|f| f.get(&format!("{}m", FORECAST_MINUTES)))
+                .and_then(|fee| fee.as_u64())
+                .map(|fee| format!(", {} in {} minutes", thousands(fee), FORECAST_MINUTES))
+                .unwrap_or_default();
+            lines.push(format!("Trend: {}{}", trend.as_str(), outlook));
+        }
+        lines.join("\n")
+    }
+}
+
+/// `n` with commas between groups of three digits.
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Webhooks registered at runtime, and the last estimate of each program to compare
+/// the next one to.
+#[derive(Default)]
+pub struct Webhooks {
+    registered: Mutex<BTreeMap<u64, Webhook>>, // by id
+    next_id: Mutex<u64>,
+    last: Mutex<HashMap<String, Estimate>>,
+}
+
+impl Webhooks {
+    /// Register `webhook`, returning its id.
+    pub fn register(&self, webhook: Webhook) -> u64 {
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        self.registered.lock().unwrap().insert(*next_id, webhook);
+        *next_id
+    }
+
+    /// Remove the webhook registered as `id`; false if there is none.
+    pub fn unregister(&self, id: u64) -> bool {
+        self.registered.lock().unwrap().remove(&id).is_some()
+    }
+
+    pub fn registered(&self) -> Vec<(u64, Webhook)> {
+        self.registered
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, w)| (*id, w.clone()))
+            .collect()
+    }
+
+    /// Compare `estimate`, a refresh of `program`, to the last one and call each of
+    /// `configured` and the registered webhooks it concerns, in the background.
+    pub fn notify(
+        &self,
+        configured: &[Webhook],
+        program: &str,
+        estimate: &Estimate,
+        aggregation: Aggregation,
+    ) {
+        let previous = self
+            .last
+            .lock()
+            .unwrap()
+            .insert(program.to_string(), estimate.clone());
+        let Some(previous) = previous else {
+            return;
+        };
+        let registered = self.registered();
+        for webhook in configured.iter().chain(registered.iter().map(|(_, w)| w)) {
+            if let Some(event) = webhook.event(program, &previous, estimate, aggregation) {
+                let (webhook, body) = (webhook.clone(), webhook.body(&event).to_string());
+                thread::spawn(move || deliver(&webhook, &body));
+            }
+        }
+    }
+}
+
+/// Whether webhooks are kept from `ip`: loopback, link-local, private, shared (CGNAT),
+/// unique local, unspecified, broadcast and multicast addresses, so that a registered
+/// webhook can't reach into the network the service runs in.
+pub fn is_internal_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_internal_address(v4.into()),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || (first & 0xfe00) == 0xfc00 // unique local
+                    || (first & 0xffc0) == 0xfe80 // link-local
+            }
+        },
+    }
+}
+
+/// Resolves webhook hosts to their public addresses only, so a host name, or a
+/// redirect, can't lead a webhook to an internal one.
+struct PublicAddresses;
+
+impl ureq::Resolver for PublicAddresses {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = netloc
+            .to_socket_addrs()?
+            .filter(|a| !is_internal_address(a.ip()))
+            .collect();
+        if addrs.is_empty() {
+            let message = format!("{} has no public address", netloc);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+        }
+        Ok(addrs)
+    }
+}
+
+/// POST `body` to the webhook, retrying on connection failures, throttling and server
+/// errors.
+fn deliver(webhook: &Webhook, body: &str) {
+    let signature = webhook.secret.as_ref().map(|secret| {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let tag = hmac::sign(&key, body.as_bytes());
+        let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        format!("sha256={}", hex)
+    });
+    let agent = ureq::AgentBuilder::new()
+        .resolver(PublicAddresses)
+        .timeout(DELIVERY_TIMEOUT)
+        .build();
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let mut request = agent
+            .post(&webhook.url)
+            .set("Content-Type", "application/json");
+        if let Some(signature) = &signature {
+            request = request.set(SIGNATURE_HEADER, signature);
+        }
+        let error = match request.send_string(body) {
+            Ok(_) => return,
+            Err(ureq::Error::Status(status, _)) if status != 429 && status < 500 => {
+                log::warn!(
+                    "webhook {} rejected notification: status {}",
+                    webhook.url,
+                    status
+                );
+                return;
+            }
+            Err(e) => e,
+        };
+        if attempt == DELIVERY_ATTEMPTS {
+            log::warn!(
+                "webhook {} failed after {} attempts: {}",
+                webhook.url,
+                attempt,
+                error
+            );
+            return;
+        }
+        log::debug!("webhook {} failed, retrying: {}", webhook.url, error);
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(url: &str) -> Result<Webhook, String> {
+        Webhook::parse(&format!("{};thresholds=1000", url))
+    }
+
+    #[test]
+    fn internal_urls_are_rejected() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://localhost:8080/hook",
+            "http://api.localhost/hook",
+            "http://10.1.2.3/hook",
+            "http://172.16.0.1/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+            "http://0x7f.1/hook",
+            "http://[::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:10.0.0.1]/hook",
+        ] {
+            assert!(hook(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn public_urls_are_accepted() {
+        for url in [
+            "https://hooks.slack.com/services/T0/B0/x",
+            "http://8.8.8.8/hook",
+            "http://[2001:4860:4860::8888]/hook",
+        ] {
+            assert!(hook(url).is_ok(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn resolver_drops_internal_addresses() {
+        assert!(ureq::Resolver::resolve(&PublicAddresses, "127.0.0.1:80").is_err());
+        let addrs = ureq::Resolver::resolve(&PublicAddresses, "8.8.8.8:443").unwrap();
+        assert_eq!(addrs, vec!["8.8.8.8:443".parse().unwrap()]);
+    }
+}